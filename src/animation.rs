@@ -1,4 +1,29 @@
-use crate::color::{Color, RgbDevice};
+use serde::Deserialize;
+use crate::color::Color;
+use crate::config;
+use crate::frame::Frame;
+use crate::scene::Point;
+use std::collections::HashMap;
+
+
+//Anything that can color the leds over time
+pub trait Effect
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128);
+
+    //How much of the effect shows over the layers below it, 0.0 - 1.0, for effects that come and go on their own
+    fn opacity(&self) -> f32
+    {
+        return 1.0;
+    }
+
+    //Effects that only run for a while, the compositor removes their layer once they are done
+    fn is_finished(&self) -> bool
+    {
+        return false;
+    }
+}
+
 
 const MAX_MAP_GRAPH: u32 = 767;
 
@@ -61,15 +86,227 @@ fn get_color_from_graph(position: u128) -> Color
 }
 
 
-pub fn color_spectrum(rgb_devices: &mut Vec<Box<dyn RgbDevice>>, mut millis: u128)
+//Phase offsets per device, so the spectrum flows from device to device instead of everything changing in lockstep.
+//Loaded from spectrum.json next to the executable, for example:
+//{
+//    "flow": { "x": 1, "y": 0 },
+//    "spread": 383,
+//    "devices": { "sk621": { "offset": 100, "reverse": true } }
+//}
+//flow derives offsets from the scene positions: the device furthest along the flow direction lags spread graph positions behind the first one.
+//Offsets in devices are added on top, reverse runs that device through the spectrum backwards.
+const SPECTRUM_FILE: &str = "spectrum.json";
+
+#[derive(Clone, Default, Deserialize)]
+pub struct SpectrumOffset
 {
-    //scale
-    millis = millis / 100;
-    let color = get_color_from_graph(millis);
+    #[serde(default)]
+    pub offset: u32,  //Graph positions, 0 - 767 is the whole spectrum
+    #[serde(default)]
+    pub reverse: bool,
+}
+
+//Every led cycles through the spectrum, in lockstep unless offsets are configured
+#[derive(Clone, Default, Deserialize)]
+pub struct ColorSpectrum
+{
+    #[serde(default)]
+    pub flow: Option<Point>,
+    #[serde(default)]
+    pub spread: u32,
+    #[serde(default)]
+    pub devices: HashMap<String, SpectrumOffset>,
+}
 
-    //write data to all devices before displaying
-    for d in rgb_devices.iter_mut()
+impl ColorSpectrum
+{
+    pub fn load() -> Self
     {
-        d.set_color(color);
+        return config::load(SPECTRUM_FILE).unwrap_or_default();
     }
-}
\ No newline at end of file
+
+    //Graph position offset of every device derived from its place along the flow, 0 when there is no flow
+    fn flow_offsets(&self, frame: &Frame) -> Vec<u32>
+    {
+        let flow = match self.flow
+        {
+            Some(flow) => flow,
+            None => return vec![0; frame.devices.len()],
+        };
+
+        //The center of each device along the flow direction
+        let distances: Vec<f32> = frame.devices.iter()
+            .map(|d| d.leds.iter().map(|l| l.position.dot(flow)).sum::<f32>() / d.leds.len().max(1) as f32)
+            .collect();
+
+        let min = distances.iter().cloned().fold(f32::MAX, f32::min);
+        let max = distances.iter().cloned().fold(f32::MIN, f32::max);
+        let extent = (max - min).max(f32::EPSILON);
+
+        return distances.iter().map(|d| ((d - min) / extent * self.spread as f32) as u32).collect();
+    }
+}
+
+impl Effect for ColorSpectrum
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        //scale
+        let position = (millis / 100) as u32 % MAX_MAP_GRAPH;
+        let flow_offsets = self.flow_offsets(frame);
+
+        for (device, flow_offset) in frame.devices.iter_mut().zip(flow_offsets.iter())
+        {
+            let device_offset = self.devices.get(&device.name).cloned().unwrap_or_default();
+
+            //Lag behind the devices before it in the flow. Offsets can be anything in spectrum.json, only their place in the graph matters.
+            let mut device_position = (position + MAX_MAP_GRAPH - flow_offset % MAX_MAP_GRAPH + device_offset.offset % MAX_MAP_GRAPH) % MAX_MAP_GRAPH;
+            if device_offset.reverse
+            {
+                device_position = MAX_MAP_GRAPH - 1 - device_position;
+            }
+
+            let color = get_color_from_graph(device_position as u128);
+            for led in device.leds.iter_mut()
+            {
+                led.color = color;
+            }
+        }
+    }
+}
+
+
+//A single color on every led, mostly useful as a masked layer
+#[allow(dead_code)]
+pub struct SolidColor(pub Color);
+
+impl Effect for SolidColor
+{
+    fn render(&mut self, frame: &mut Frame, _millis: u128)
+    {
+        frame.fill(self.0);
+    }
+}
+
+
+//Fixed colors per led, by device name. For colors that come from somewhere else, like an openrgb client.
+pub struct LedColors(pub HashMap<String, Vec<Color>>);
+
+impl Effect for LedColors
+{
+    fn render(&mut self, frame: &mut Frame, _millis: u128)
+    {
+        for device in frame.devices.iter_mut()
+        {
+            if let Some(colors) = self.0.get(&device.name)
+            {
+                for (led, color) in device.leds.iter_mut().zip(colors.iter())
+                {
+                    led.color = *color;
+                }
+            }
+        }
+    }
+}
+
+
+pub enum WaveShape
+{
+    //Travels along the direction
+    Linear { direction: Point },
+    //Travels outwards from the center
+    Radial { center: Point },
+}
+
+//The spectrum laid out over the scene, so it visibly travels across the devices
+pub struct SpectrumWave
+{
+    pub shape: WaveShape,
+    pub wavelength: f32, //Distance in scene units covering the whole spectrum
+}
+
+impl Effect for SpectrumWave
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let time_position = (millis / 100) as f32;
+        let direction = match &self.shape
+        {
+            WaveShape::Linear { direction } => direction.scale(1.0 / direction.length().max(f32::EPSILON)),
+            WaveShape::Radial { .. } => Point::default(),
+        };
+
+        frame.paint(|position|
+        {
+            let distance = match &self.shape
+            {
+                WaveShape::Linear { .. } => position.dot(direction),
+                WaveShape::Radial { center } => position.distance(*center),
+            };
+
+            let graph_position = (time_position - distance / self.wavelength * MAX_MAP_GRAPH as f32).rem_euclid(MAX_MAP_GRAPH as f32);
+            return get_color_from_graph(graph_position as u128);
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::color::RgbDevice;
+    use crate::recording::VirtualDevice;
+    use crate::scene::Scene;
+
+    fn frame() -> Frame
+    {
+        let devices: Vec<Box<dyn RgbDevice>> = vec![Box::new(VirtualDevice::new("a", 1, false)), Box::new(VirtualDevice::new("b", 1, false))];
+        return Frame::new(&Scene::default(), &devices);
+    }
+
+    fn spectrum(offset: u32, reverse: bool) -> ColorSpectrum
+    {
+        let mut spectrum = ColorSpectrum::default();
+        spectrum.devices.insert("b".to_string(), SpectrumOffset { offset, reverse });
+        return spectrum;
+    }
+
+    #[test]
+    fn offsets_wrap_around_the_graph()
+    {
+        let mut frame = frame();
+        spectrum(100, false).render(&mut frame, 0);
+        assert_eq!(frame.devices[1].leds[0].color, get_color_from_graph(100));
+
+        //Any offset works, it ends up at the same place in the graph
+        for offset in [100 + MAX_MAP_GRAPH, 100 + MAX_MAP_GRAPH * 1000, u32::MAX]
+        {
+            spectrum(offset, false).render(&mut frame, 5000);
+            assert_eq!(frame.devices[1].leds[0].color, get_color_from_graph(((50 + offset as u64) % MAX_MAP_GRAPH as u64) as u128));
+        }
+    }
+
+    #[test]
+    fn reverse_runs_backwards()
+    {
+        let mut frame = frame();
+        spectrum(0, true).render(&mut frame, 1000);
+        assert_eq!(frame.devices[0].leds[0].color, get_color_from_graph(10));
+        assert_eq!(frame.devices[1].leds[0].color, get_color_from_graph((MAX_MAP_GRAPH - 1 - 10) as u128));
+    }
+
+    #[test]
+    fn flow_spreads_the_devices()
+    {
+        let mut frame = frame();
+        let mut spectrum = ColorSpectrum::default();
+        spectrum.flow = Some(Point::new(1.0, 0.0, 0.0));
+        spectrum.spread = 300;
+
+        //b is furthest along the flow, so it lags the whole spread behind a
+        spectrum.render(&mut frame, 50000);
+        assert_eq!(frame.devices[0].leds[0].color, get_color_from_graph(500));
+        assert_eq!(frame.devices[1].leds[0].color, get_color_from_graph(200));
+    }
+}
@@ -0,0 +1,675 @@
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::frame::Frame;
+use std::f32::consts::PI;
+use std::path::Path;
+
+
+//================================================================================================================================================================================================
+//Audio sources
+
+//Mono samples, -1.0 - 1.0
+pub trait AudioSource
+{
+    fn sample_rate(&self) -> u32;
+    //The most recent count samples at the given moment, fewer when there aren't that many yet
+    fn read(&mut self, millis: u128, count: usize) -> Vec<f32>;
+}
+
+
+//Plays a wav file by the animation clock, so the same millis always give the same samples. Without looping it is silent after the end.
+pub struct WavFile
+{
+    sample_rate: u32,
+    samples: Vec<f32>,
+    pub looping: bool,
+}
+
+impl WavFile
+{
+    //16 bit pcm or 32 bit float, any number of channels
+    pub fn load(path: &Path) -> Result<Self, String>
+    {
+        let bytes = std::fs::read(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE"
+        {
+            return Err(format!("{}: not a wav file", path.display()));
+        }
+
+        let mut format: Option<(u16, u16, u32, u16)> = None; //format, channels, sample rate, bits per sample
+        let mut data: Option<&[u8]> = None;
+
+        let mut offset = 12;
+        while offset + 8 <= bytes.len()
+        {
+            let id = &bytes[offset..offset + 4];
+            let size = u32::from_le_bytes([bytes[offset + 4], bytes[offset + 5], bytes[offset + 6], bytes[offset + 7]]) as usize;
+            let body = &bytes[offset + 8..(offset + 8 + size).min(bytes.len())];
+
+            if id == b"fmt " && body.len() >= 16
+            {
+                format = Some((
+                    u16::from_le_bytes([body[0], body[1]]),
+                    u16::from_le_bytes([body[2], body[3]]),
+                    u32::from_le_bytes([body[4], body[5], body[6], body[7]]),
+                    u16::from_le_bytes([body[14], body[15]]),
+                ));
+            }
+            else if id == b"data"
+            {
+                data = Some(body);
+            }
+
+            //Chunks are padded to an even size
+            offset += 8 + size + (size & 1);
+        }
+
+        let (format, channels, sample_rate, bits_per_sample) = format.ok_or(format!("{}: missing fmt chunk", path.display()))?;
+        let data = data.ok_or(format!("{}: missing data chunk", path.display()))?;
+        let channels = channels.max(1) as usize;
+
+        const WAVE_FORMAT_PCM: u16 = 1;
+        const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+        let interleaved: Vec<f32> = match (format, bits_per_sample)
+        {
+            (WAVE_FORMAT_PCM, 16) => data.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0).collect(),
+            (WAVE_FORMAT_IEEE_FLOAT, 32) => data.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect(),
+            _ => return Err(format!("{}: unsupported format {} with {} bits per sample", path.display(), format, bits_per_sample)),
+        };
+
+        //Mix down to mono
+        let samples = interleaved.chunks_exact(channels).map(|frame| frame.iter().sum::<f32>() / channels as f32).collect();
+
+        return Ok(WavFile
+        {
+            sample_rate,
+            samples,
+            looping: true,
+        });
+    }
+}
+
+impl AudioSource for WavFile
+{
+    fn sample_rate(&self) -> u32
+    {
+        return self.sample_rate;
+    }
+
+    fn read(&mut self, millis: u128, count: usize) -> Vec<f32>
+    {
+        if self.samples.is_empty()
+        {
+            return Vec::new();
+        }
+
+        let mut end = (millis * self.sample_rate as u128 / 1000) as usize;
+        if self.looping
+        {
+            end %= self.samples.len();
+        }
+        else if end > self.samples.len()
+        {
+            //Played to the end, silence from here on
+            return Vec::new();
+        }
+        return self.samples[end.saturating_sub(count)..end].to_vec();
+    }
+}
+
+
+//The default capture device. On windows, pick the "stereo mix" or a loopback device as default recording device to react to what is playing.
+#[cfg(feature = "audio-capture")]
+pub use self::capture::CaptureDevice;
+
+#[cfg(feature = "audio-capture")]
+mod capture
+{
+    use super::AudioSource;
+    use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+
+    //About a second of samples is plenty for the analyzer
+    const BUFFER_SECONDS: usize = 1;
+
+    pub struct CaptureDevice
+    {
+        sample_rate: u32,
+        buffer: Arc<Mutex<VecDeque<f32>>>,
+        _stream: cpal::Stream, //Capturing stops when the stream is dropped
+    }
+
+    impl CaptureDevice
+    {
+        pub fn new() -> Result<Self, String>
+        {
+            let device = cpal::default_host().default_input_device().ok_or("No audio capture device")?;
+            let config = device.default_input_config().map_err(|e| e.to_string())?;
+
+            let sample_rate = config.sample_rate().0;
+            let channels = config.channels() as usize;
+            let buffer = Arc::new(Mutex::new(VecDeque::new()));
+            let capacity = sample_rate as usize * BUFFER_SECONDS;
+
+            let error_callback = |e| println!("Audio capture error: {}", e);
+            let stream_buffer = buffer.clone();
+            let stream = match config.sample_format()
+            {
+                cpal::SampleFormat::F32 => device.build_input_stream(&config.into(), move |data: &[f32], _: &_| push_samples(data, channels, capacity, &stream_buffer), error_callback),
+                cpal::SampleFormat::I16 => device.build_input_stream(&config.into(), move |data: &[i16], _: &_| push_samples(data, channels, capacity, &stream_buffer), error_callback),
+                cpal::SampleFormat::U16 => device.build_input_stream(&config.into(), move |data: &[u16], _: &_| push_samples(data, channels, capacity, &stream_buffer), error_callback),
+            }.map_err(|e| e.to_string())?;
+            stream.play().map_err(|e| e.to_string())?;
+
+            return Ok(CaptureDevice
+            {
+                sample_rate,
+                buffer,
+                _stream: stream,
+            });
+        }
+    }
+
+    fn push_samples<T: cpal::Sample>(data: &[T], channels: usize, capacity: usize, buffer: &Mutex<VecDeque<f32>>)
+    {
+        let mut buffer = buffer.lock().unwrap();
+        for frame in data.chunks_exact(channels.max(1))
+        {
+            buffer.push_back(frame.iter().map(|s| s.to_f32()).sum::<f32>() / frame.len() as f32);
+        }
+        while buffer.len() > capacity
+        {
+            buffer.pop_front();
+        }
+    }
+
+    impl AudioSource for CaptureDevice
+    {
+        fn sample_rate(&self) -> u32
+        {
+            return self.sample_rate;
+        }
+
+        fn read(&mut self, _millis: u128, count: usize) -> Vec<f32>
+        {
+            let buffer = self.buffer.lock().unwrap();
+            return buffer.iter().skip(buffer.len().saturating_sub(count)).cloned().collect();
+        }
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Analysis
+
+const FFT_SIZE: usize = 1024;
+const LOWEST_FREQUENCY: f32 = 40.0;
+const HIGHEST_FREQUENCY: f32 = 16000.0;
+
+//Beats are bass energy peaks well above the recent average
+const BEAT_THRESHOLD: f32 = 1.5;
+const BEAT_MIN_INTERVAL: u128 = 250;
+const BEAT_HISTORY: usize = 43; //About a second at 100 frames per second
+
+//In place radix 2 fft, the length has to be a power of 2
+fn fft(real: &mut [f32], imaginary: &mut [f32])
+{
+    let n = real.len();
+
+    //Bit reversal permutation
+    let mut j = 0;
+    for i in 1..n
+    {
+        let mut bit = n >> 1;
+        while j & bit != 0
+        {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j |= bit;
+        if i < j
+        {
+            real.swap(i, j);
+            imaginary.swap(i, j);
+        }
+    }
+
+    let mut length = 2;
+    while length <= n
+    {
+        let angle = -2.0 * PI / length as f32;
+        for start in (0..n).step_by(length)
+        {
+            for k in 0..length / 2
+            {
+                let (w_imaginary, w_real) = (angle * k as f32).sin_cos();
+                let a = start + k;
+                let b = a + length / 2;
+
+                let t_real = real[b] * w_real - imaginary[b] * w_imaginary;
+                let t_imaginary = real[b] * w_imaginary + imaginary[b] * w_real;
+                real[b] = real[a] - t_real;
+                imaginary[b] = imaginary[a] - t_imaginary;
+                real[a] += t_real;
+                imaginary[a] += t_imaginary;
+            }
+        }
+        length <<= 1;
+    }
+}
+
+//Turns the samples of a source into levels the effects can use
+pub struct AudioAnalyzer
+{
+    source: Box<dyn AudioSource>,
+    pub bands: Vec<f32>,     //0.0 - 1.0 per frequency band, low to high
+    pub volume: f32,         //0.0 - 1.0
+    pub beat: bool,          //A beat started this frame
+    peaks: Vec<f32>,         //Slowly decaying maximum per band, for automatic gain
+    volume_peak: f32,
+    bass_history: Vec<f32>,
+    last_beat: u128,
+}
+
+impl AudioAnalyzer
+{
+    pub fn new(source: Box<dyn AudioSource>, band_count: usize) -> Self
+    {
+        AudioAnalyzer
+        {
+            source,
+            bands: vec![0.0; band_count],
+            volume: 0.0,
+            beat: false,
+            peaks: vec![0.0; band_count],
+            volume_peak: 0.0,
+            bass_history: Vec::new(),
+            last_beat: 0,
+        }
+    }
+
+    pub fn update(&mut self, millis: u128)
+    {
+        let samples = self.source.read(millis, FFT_SIZE);
+        let sample_rate = self.source.sample_rate() as f32;
+
+        //Hann window, zero padded when there aren't enough samples yet
+        let mut real = vec![0.0; FFT_SIZE];
+        let mut imaginary = vec![0.0; FFT_SIZE];
+        for (i, sample) in samples.iter().enumerate()
+        {
+            real[i] = sample * (0.5 - 0.5 * (2.0 * PI * i as f32 / (FFT_SIZE - 1) as f32).cos());
+        }
+        fft(&mut real, &mut imaginary);
+        let magnitudes: Vec<f32> = (0..FFT_SIZE / 2).map(|i| (real[i] * real[i] + imaginary[i] * imaginary[i]).sqrt()).collect();
+
+        //Logarithmically spaced bands, like we hear them
+        let band_count = self.bands.len();
+        let highest = HIGHEST_FREQUENCY.min(sample_rate / 2.0);
+        for band in 0..band_count
+        {
+            let low = LOWEST_FREQUENCY * (highest / LOWEST_FREQUENCY).powf(band as f32 / band_count as f32);
+            let high = LOWEST_FREQUENCY * (highest / LOWEST_FREQUENCY).powf((band + 1) as f32 / band_count as f32);
+            let first = ((low / sample_rate * FFT_SIZE as f32) as usize).min(magnitudes.len() - 1);
+            let last = ((high / sample_rate * FFT_SIZE as f32) as usize).max(first + 1).min(magnitudes.len());
+
+            let energy = magnitudes[first..last].iter().sum::<f32>() / (last - first) as f32;
+            self.peaks[band] = (self.peaks[band] * 0.995).max(energy).max(0.01);
+            self.bands[band] = energy / self.peaks[band];
+        }
+
+        let rms = (samples.iter().map(|s| s * s).sum::<f32>() / samples.len().max(1) as f32).sqrt();
+        self.volume_peak = (self.volume_peak * 0.995).max(rms).max(0.01);
+        self.volume = rms / self.volume_peak;
+
+        //Beat detection on the lowest bands
+        let bass_bins = ((150.0 / sample_rate * FFT_SIZE as f32) as usize).max(2).min(magnitudes.len());
+        let bass = magnitudes[1..bass_bins].iter().sum::<f32>();
+        let average = self.bass_history.iter().sum::<f32>() / self.bass_history.len().max(1) as f32;
+
+        self.beat = self.bass_history.len() == BEAT_HISTORY && bass > average * BEAT_THRESHOLD && millis.saturating_sub(self.last_beat) > BEAT_MIN_INTERVAL;
+        if self.beat
+        {
+            self.last_beat = millis;
+        }
+
+        self.bass_history.push(bass);
+        if self.bass_history.len() > BEAT_HISTORY
+        {
+            self.bass_history.remove(0);
+        }
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Effects
+
+#[derive(Clone, Copy)]
+pub enum BandMapping
+{
+    Brightness, //The band level scales the color
+    Hue,        //The band level picks the hue, quiet is red, loud is violet
+    LedCount,   //The band level lights up part of the leds of a device, like a bar
+}
+
+//Every device shows one frequency band, low bands on the first devices
+pub struct AudioBands
+{
+    analyzer: AudioAnalyzer,
+    pub mapping: BandMapping,
+    pub color: Color,
+}
+
+impl AudioBands
+{
+    pub fn new(source: Box<dyn AudioSource>, mapping: BandMapping, color: Color) -> Self
+    {
+        AudioBands
+        {
+            analyzer: AudioAnalyzer::new(source, 16),
+            mapping,
+            color,
+        }
+    }
+}
+
+impl Effect for AudioBands
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        self.analyzer.update(millis);
+
+        let device_count = frame.devices.len();
+        for (index, device) in frame.devices.iter_mut().enumerate()
+        {
+            let band = index * self.analyzer.bands.len() / device_count.max(1);
+            let level = self.analyzer.bands[band].min(1.0);
+            let led_count = device.leds.len();
+
+            for (led_index, led) in device.leds.iter_mut().enumerate()
+            {
+                led.color = match self.mapping
+                {
+                    BandMapping::Brightness => self.color.scale(level),
+                    BandMapping::Hue => Color::from_hsv(level * 270.0, 1.0, 1.0),
+                    BandMapping::LedCount => if (led_index as f32) < level * led_count as f32 { self.color } else { Color::new(0, 0, 0) },
+                };
+            }
+        }
+    }
+}
+
+
+//Flashes on every beat and fades out until the next one
+pub struct BeatFlash
+{
+    analyzer: AudioAnalyzer,
+    pub color: Color,
+    pub fade: u128, //milliseconds
+    last_beat: Option<u128>,
+}
+
+impl BeatFlash
+{
+    pub fn new(source: Box<dyn AudioSource>, color: Color) -> Self
+    {
+        BeatFlash
+        {
+            analyzer: AudioAnalyzer::new(source, 1),
+            color,
+            fade: 300,
+            last_beat: None,
+        }
+    }
+}
+
+impl Effect for BeatFlash
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        self.analyzer.update(millis);
+        if self.analyzer.beat
+        {
+            self.last_beat = Some(millis);
+        }
+
+        let brightness = match self.last_beat
+        {
+            Some(beat) if millis - beat < self.fade => 1.0 - (millis - beat) as f32 / self.fade as f32,
+            _ => 0.0,
+        };
+        frame.fill(self.color.scale(brightness));
+    }
+}
+
+
+//The volume as a bar on every device, green to red. Devices with a single led show it as brightness.
+pub struct VuMeter
+{
+    analyzer: AudioAnalyzer,
+    pub low: Color,
+    pub high: Color,
+}
+
+impl VuMeter
+{
+    pub fn new(source: Box<dyn AudioSource>) -> Self
+    {
+        VuMeter
+        {
+            analyzer: AudioAnalyzer::new(source, 1),
+            low: Color::new(0, 255, 0),
+            high: Color::new(255, 0, 0),
+        }
+    }
+}
+
+impl Effect for VuMeter
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        self.analyzer.update(millis);
+        let level = self.analyzer.volume.min(1.0);
+
+        for device in frame.devices.iter_mut()
+        {
+            let led_count = device.leds.len();
+            if led_count == 1
+            {
+                device.leds[0].color = self.low.blend(self.high, level).scale(level);
+                continue;
+            }
+
+            for (index, led) in device.leds.iter_mut().enumerate()
+            {
+                let position = index as f32 / (led_count - 1) as f32;
+                led.color = if position <= level { self.low.blend(self.high, position) } else { Color::new(0, 0, 0) };
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::TempPath;
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    //Sine tones, the closure gives the frequencies playing at every moment
+    struct Tones<F: Fn(u128) -> Vec<f32>>
+    {
+        frequencies: F,
+    }
+
+    impl<F: Fn(u128) -> Vec<f32>> AudioSource for Tones<F>
+    {
+        fn sample_rate(&self) -> u32
+        {
+            return SAMPLE_RATE;
+        }
+
+        fn read(&mut self, millis: u128, count: usize) -> Vec<f32>
+        {
+            let end = (millis * SAMPLE_RATE as u128 / 1000) as usize;
+            return (end.saturating_sub(count)..end).map(|i|
+            {
+                let sample_millis = i as u128 * 1000 / SAMPLE_RATE as u128;
+                return (self.frequencies)(sample_millis).iter().map(|frequency| (2.0 * PI * frequency * i as f32 / SAMPLE_RATE as f32).sin() * 0.5).sum();
+            }).collect();
+        }
+    }
+
+    //The band of the fft bin a frequency falls in, the same spacing as the analyzer
+    fn band_of(frequency: f32, band_count: usize) -> usize
+    {
+        let bin = (frequency / SAMPLE_RATE as f32 * FFT_SIZE as f32).round();
+        let band = (band_count as f32 * (bin / FFT_SIZE as f32 * SAMPLE_RATE as f32 / LOWEST_FREQUENCY).ln() / (HIGHEST_FREQUENCY / LOWEST_FREQUENCY).ln()) as usize;
+        return band;
+    }
+
+    #[test]
+    fn tones_end_up_in_their_band()
+    {
+        //A second of every band at once sets the automatic gain, then a single tone
+        let reference: Vec<f32> = (0..16).map(|band| LOWEST_FREQUENCY * (HIGHEST_FREQUENCY / LOWEST_FREQUENCY).powf((band as f32 + 0.5) / 16.0)).collect();
+        for frequency in [300.0, 1000.0, 5000.0, 12000.0]
+        {
+            let reference = reference.clone();
+            let mut analyzer = AudioAnalyzer::new(Box::new(Tones { frequencies: move |millis| if millis < 1000 { reference.clone() } else { vec![frequency] } }), 16);
+            for millis in (0..=1000).step_by(100)
+            {
+                analyzer.update(millis);
+            }
+            analyzer.update(1100);
+
+            let band = band_of(frequency, 16);
+            assert!(analyzer.bands[band] > 0.5, "{} Hz in band {}: {:?}", frequency, band, analyzer.bands);
+
+            //Far away bands only get what leaks through the window
+            for (other, level) in analyzer.bands.iter().enumerate()
+            {
+                if (other as i32 - band as i32).abs() > 1
+                {
+                    assert!(*level < 0.1, "{} Hz in band {}: {:?}", frequency, other, analyzer.bands);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn silence_has_no_levels()
+    {
+        let mut analyzer = AudioAnalyzer::new(Box::new(Tones { frequencies: |_| Vec::new() }), 8);
+        for millis in (0..1000).step_by(10)
+        {
+            analyzer.update(millis);
+            assert!(analyzer.bands.iter().all(|b| *b == 0.0));
+            assert_eq!(analyzer.volume, 0.0);
+            assert!(!analyzer.beat);
+        }
+    }
+
+    #[test]
+    fn beats_follow_bass_pulses()
+    {
+        //100ms of 60 Hz every half second
+        let mut analyzer = AudioAnalyzer::new(Box::new(Tones { frequencies: |millis| if millis % 500 < 100 { vec![60.0] } else { Vec::new() } }), 1);
+
+        let mut beats = Vec::new();
+        for millis in (0..4000).step_by(10)
+        {
+            analyzer.update(millis);
+            if analyzer.beat
+            {
+                beats.push(millis);
+            }
+        }
+
+        //Nothing until the history is full, then every pulse right as it starts
+        assert_eq!(beats.len(), 7, "{:?}", beats);
+        for beat in beats
+        {
+            assert!(beat % 500 <= 30, "beat at {}", beat);
+        }
+    }
+
+    //16 bit stereo, left and right as given
+    fn write_wav(path: &Path, left: &[i16], right: &[i16])
+    {
+        let mut data = Vec::new();
+        for (l, r) in left.iter().zip(right.iter())
+        {
+            data.extend_from_slice(&l.to_le_bytes());
+            data.extend_from_slice(&r.to_le_bytes());
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());                 //pcm
+        bytes.extend_from_slice(&2u16.to_le_bytes());                 //channels
+        bytes.extend_from_slice(&1000u32.to_le_bytes());              //sample rate
+        bytes.extend_from_slice(&4000u32.to_le_bytes());              //bytes per second
+        bytes.extend_from_slice(&4u16.to_le_bytes());                 //block align
+        bytes.extend_from_slice(&16u16.to_le_bytes());                //bits per sample
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(&data);
+        std::fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn wav_files_play_by_the_clock()
+    {
+        let file = TempPath::new("clock.wav");
+        let left: Vec<i16> = (0..100).map(|i| i * 100).collect();
+        write_wav(file.path(), &left, &vec![0; 100]);
+        let mut wav = WavFile::load(file.path()).unwrap();
+
+        //1000 samples per second, mixed down to mono
+        assert_eq!(wav.sample_rate(), 1000);
+        assert_eq!(wav.read(50, 2), vec![4800.0 / 65536.0, 4900.0 / 65536.0]);
+        assert_eq!(wav.read(1, 4), vec![0.0]);
+
+        //Looping starts over, otherwise the end is the last sound
+        assert_eq!(wav.read(150, 1), vec![4900.0 / 65536.0]);
+        wav.looping = false;
+        assert_eq!(wav.read(100, 1), vec![9900.0 / 65536.0]);
+        assert!(wav.read(150, 1).is_empty());
+        assert!(wav.read(100000, 1024).is_empty());
+    }
+
+    #[test]
+    fn finished_wav_files_go_quiet()
+    {
+        let file = TempPath::new("quiet.wav");
+        let loud: Vec<i16> = (0..500).map(|i| if i % 2 == 0 { 16000 } else { -16000 }).collect();
+        write_wav(file.path(), &loud, &loud);
+        let mut wav = WavFile::load(file.path()).unwrap();
+        wav.looping = false;
+
+        let mut analyzer = AudioAnalyzer::new(Box::new(wav), 4);
+        analyzer.update(400);
+        assert_eq!(analyzer.volume, 1.0);
+        analyzer.update(600);
+        assert_eq!(analyzer.volume, 0.0);
+        assert!(analyzer.bands.iter().all(|b| *b == 0.0));
+    }
+
+    #[test]
+    fn rejects_what_it_cannot_play()
+    {
+        let file = TempPath::new("bad.wav");
+        std::fs::write(file.path(), b"RIFF\0\0\0\0WAVEdata\0\0\0\0").unwrap();
+        assert!(WavFile::load(file.path()).err().unwrap().ends_with("missing fmt chunk"));
+
+        assert!(WavFile::load(Path::new("/nonexistent.wav")).is_err());
+    }
+}
@@ -0,0 +1,856 @@
+use crate::color::{Color, RgbColorFlags, RgbMode, RgbSpeed};
+use crate::effects::{self, EffectOptions};
+use crate::scene::{Point, Scene};
+use crate::audio::{self, AudioSource, BandMapping};
+use crate::animation::{self, Effect};
+use crate::compositor::{Compositor, Layer};
+use crate::notification::{Notification, Pattern};
+use crate::z390;
+use crate::timeline::Timeline;
+use crate::sensors::{SensorConfig, SensorGradient};
+use crate::config;
+use crate::ipc::Client;
+use serde_json::{json, Value};
+use crate::screen::{self, Ambilight, FrameSource, Sampling};
+use crate::recording::{self, Recording, VirtualDevice};
+use crate::reactive::{self, KeyEventSource};
+use crate::keyboard;
+use crate::color::RgbDevice;
+use std::path::Path;
+use std::io::{stdin, stdout, Write};
+
+
+//Commands that do a single thing and exit, instead of running the animation.
+pub fn run(arguments: &[String])
+{
+    match arguments[0].as_str()
+    {
+        "save-boot-profile" => save_boot_profile(&arguments[1..]),
+        "hardware"          => hardware(&arguments[1..]),
+        "leds"              => leds(&arguments[1..]),
+        "play"              => play(&arguments[1..]),
+        "effect"            => effect(&arguments[1..]),
+        "audio"             => audio(&arguments[1..]),
+        "keys"              => keys(&arguments[1..]),
+        "ambient"           => ambient(&arguments[1..]),
+        "sensor"            => sensor(&arguments[1..]),
+        "notify"            => notify(&arguments[1..]),
+        "layer"             => layer(&arguments[1..]),
+        "record"            => record(&arguments[1..]),
+        "replay"            => replay(&arguments[1..]),
+        "compare"           => compare(&arguments[1..]),
+        "state"             => send("get-state", Value::Null),
+        "devices"           => send("list-devices", Value::Null),
+        "pause"             => send("pause", Value::Null),
+        "resume"            => send("resume", Value::Null),
+        "color"             => color(&arguments[1..]),
+        "brightness"        => brightness(&arguments[1..]),
+        "subscribe"         => subscribe(),
+        command => println!("Unknown command: {}\n{}", command, USAGE),
+    }
+}
+
+const USAGE: &str =
+"Usage:
+    rustic_light                                  run the animation
+    rustic_light play <timeline.json|toml>        run a keyframe animation instead, see timeline.rs
+    rustic_light effect <name> [colors...]        run one of the effects in effects.rs, colors like red or #ff8000 [--speed n] [--direction x,y]
+    rustic_light audio <effect> [file.wav]        react to a wav file or the recording device: bands, hue, bars, beat or vu [--once]
+    rustic_light keys <effect> [color] [color2]   react to typing on the sk621: ripple, trail or heatmap [--device /dev/input/eventN]
+    rustic_light ambient [image|directory] [fps]  follow the screen colors, or an image / a directory of frames [--dominant]
+    rustic_light sensor [config.json|toml]        color by a temperature or load, sensor.json next to the exe by default, see sensors.rs
+    rustic_light notify --color <color>           flash over the animation [--blinks n | --pulses n] [--duration ms] [--devices a,b]
+    rustic_light save-boot-profile                save what the z390 shows right now to its flash, shown at POST [--yes]
+    rustic_light leds <device> [ms]               light the leds of a device one at a time and print their names, to check a layout [--rows | --keys w,a,s,d]
+    rustic_light hardware <mode> <color> [color2] let the devices animate on their own: static, breathing, flashing or double_flashing [--speed slow|medium|fast] [--devices a,b] [--rainbow] [--random]
+    rustic_light record <file> <effect> [colors]  render an effect on virtual devices, the same every time [--duration ms] [--step ms] [--devices name:leds,...] [--speed n] [--direction x,y]
+    rustic_light replay <file>                    show a recording on the devices, or print it [--virtual]
+    rustic_light compare <expected> <actual>      exits with 1 when two recordings differ [--tolerance n]
+Add --record <file> to anything that runs the animation to record what the devices show.
+Add --trace to anything to log every byte sent to the hardware, see trace.rs.
+
+Talking to the running instance, effect and notify also go to it when there is one:
+    rustic_light state | devices                  print the state or the devices as json
+    rustic_light pause | resume
+    rustic_light color <color>                    switch to a single color
+    rustic_light brightness <0-100>               master brightness
+    rustic_light subscribe                        print every change as json
+    rustic_light layer <name> <effect> [colors]   an effect on top, like red over wasd [--blend normal|add|multiply|screen|max] [--opacity 0-1]
+                                                  [--devices a,b | --leds device:0,1,2 | --keys w,a,s,d [--device sk621]] [--speed n] [--direction x,y]
+    rustic_light layer <name> --remove";
+
+
+fn save_boot_profile(arguments: &[String])
+{
+    if !arguments.iter().any(|a| a == "--yes") && !confirm("This writes what the z390 shows right now to its flash, which wears the chip. Continue?")
+    {
+        return;
+    }
+
+    match z390::save_z390_boot_profile()
+    {
+        Ok(_) => println!("Saved the current zone configuration to the z390"),
+        Err(e) => println!("Not saved: {}", e),
+    }
+}
+
+//Set once and exit, the controllers keep running the mode. save-boot-profile can store it on the z390 afterwards.
+fn hardware(arguments: &[String])
+{
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+    let positional = positional(arguments);
+    if positional.len() < 2
+    {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let mode = match positional[0].as_str()
+    {
+        "static"          => RgbMode::Static,
+        "breathing"       => RgbMode::Breathing,
+        "flashing"        => RgbMode::Flashing,
+        "double_flashing" => RgbMode::DoubleFlashing,
+        mode =>
+        {
+            println!("Unknown mode: {}, modes: static, breathing, flashing, double_flashing", mode);
+            return;
+        }
+    };
+    let speed = match option("--speed").map(|s| s.as_str())
+    {
+        None | Some("medium") => RgbSpeed::Medium,
+        Some("slow") => RgbSpeed::Slow,
+        Some("fast") => RgbSpeed::Fast,
+        Some(speed) =>
+        {
+            println!("Unknown speed: {}, speeds: slow, medium, fast", speed);
+            return;
+        }
+    };
+    let mut colors = Vec::new();
+    for argument in positional[1..].iter().take(2)
+    {
+        match Color::parse(argument)
+        {
+            Some(color) => colors.push(color),
+            None =>
+            {
+                println!("Unknown color: {}", argument);
+                return;
+            }
+        }
+    }
+    let devices: Option<Vec<&str>> = option("--devices").map(|d| d.split(',').collect());
+    let flags = RgbColorFlags { rainbow: arguments.iter().any(|a| a == "--rainbow"), random: arguments.iter().any(|a| a == "--random") };
+
+    //Devices without the mode, a second color or color flags ignore them
+    for device in crate::get_rgb_devices().iter_mut()
+    {
+        if devices.as_ref().map_or(false, |devices| !devices.contains(&device.get_name().as_str()))
+        {
+            continue;
+        }
+        device.set_mode(mode);
+        device.set_speed(speed);
+        device.set_color_flags(flags);
+        device.set_color(colors[0]);
+        if let Some(color2) = colors.get(1)
+        {
+            device.set_secondary_color(*color2);
+        }
+        device.display();
+        println!("{}", device.get_name());
+    }
+}
+
+fn leds(arguments: &[String])
+{
+    let name = match arguments.first()
+    {
+        Some(name) => name,
+        None =>
+        {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    let positional = positional(arguments);
+    let delay = positional.get(1).and_then(|d| d.parse().ok()).unwrap_or(500);
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+
+    let mut rgb_devices = crate::get_rgb_devices();
+    let device = match rgb_devices.iter_mut().find(|d| d.get_name().eq_ignore_ascii_case(name))
+    {
+        Some(device) => device,
+        None =>
+        {
+            println!("No device {}", name);
+            return;
+        }
+    };
+
+    let white = Color::new(255, 255, 255);
+    if let Some(keys) = option("--keys")
+    {
+        let names: Vec<&str> = keys.split(',').collect();
+        device.set_color(Color::new(0, 0, 0));
+        for name in keyboard::set_region_color(device.as_mut(), &names, white)
+        {
+            println!("No key {}", name);
+        }
+        device.display();
+        std::thread::sleep(std::time::Duration::from_millis(delay));
+    }
+    else if arguments.iter().any(|a| a == "--rows")
+    {
+        for row in 0..keyboard::row_count()
+        {
+            device.set_color(Color::new(0, 0, 0));
+            keyboard::set_row_color(device.as_mut(), row, white);
+            device.display();
+            println!("row {}: {}", row, keyboard::row_keys(row).collect::<Vec<&str>>().join(" "));
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+        }
+    }
+    else
+    {
+        for led in 0..device.get_led_count()
+        {
+            device.set_color(Color::new(0, 0, 0));
+            device.set_led_color(led, white);
+            device.display();
+            println!("{} {}", led, device.get_led_name(led).unwrap_or_default());
+            std::thread::sleep(std::time::Duration::from_millis(delay));
+        }
+    }
+    device.set_color(Color::new(0, 0, 0));
+    device.display();
+}
+
+fn play(arguments: &[String])
+{
+    if arguments.is_empty()
+    {
+        println!("{}", USAGE);
+        return;
+    }
+
+    match Timeline::load(Path::new(&arguments[0]))
+    {
+        Ok(timeline) => crate::run_animation(Box::new(timeline)),
+        Err(e) => println!("Unable to load timeline: {}", e),
+    }
+}
+
+fn effect(arguments: &[String])
+{
+    let positional = positional(arguments);
+    if positional.is_empty()
+    {
+        println!("{}\nEffects: {}", USAGE, effects::NAMES.join(", "));
+        return;
+    }
+
+    let options = match effect_options(arguments, &positional[1..])
+    {
+        Ok(options) => options,
+        Err(e) =>
+        {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    if let Ok(mut client) = Client::connect()
+    {
+        let direction = options.direction.map(|d| json!({ "x": d.x, "y": d.y, "z": d.z }));
+        print_result(client.call("set-effect", json!({ "name": positional[0], "colors": options.colors, "speed": options.speed, "direction": direction })));
+        return;
+    }
+
+    match effects::create(positional[0], &options)
+    {
+        Some(effect) => crate::run_animation(effect),
+        None => println!("Unknown effect: {}\nEffects: {}", positional[0], effects::NAMES.join(", ")),
+    }
+}
+
+//Colors from the positional arguments, --speed n and --direction x,y[,z]
+fn effect_options(arguments: &[String], colors: &[&String]) -> Result<EffectOptions, String>
+{
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+    let mut options = EffectOptions { spectrum: animation::ColorSpectrum::load(), ..EffectOptions::default() };
+
+    for argument in colors.iter()
+    {
+        options.colors.push(Color::parse(argument).ok_or(format!("Unknown color: {}", argument))?);
+    }
+
+    if let Some(speed) = option("--speed")
+    {
+        options.speed = Some(speed.parse().map_err(|_| format!("Speed is in cycles per second, not {}", speed))?);
+    }
+
+    if let Some(direction) = option("--direction")
+    {
+        let values: Vec<f32> = direction.split(',').map(|v| v.trim().parse()).collect::<Result<_, _>>().map_err(|_| format!("Direction is x,y or x,y,z, not {}", direction))?;
+        options.direction = match values.as_slice()
+        {
+            [x, y]    => Some(Point::new(*x, *y, 0.0)),
+            [x, y, z] => Some(Point::new(*x, *y, *z)),
+            _ => return Err(format!("Direction is x,y or x,y,z, not {}", direction)),
+        };
+    }
+
+    return Ok(options);
+}
+
+fn audio(arguments: &[String])
+{
+    let positional: Vec<&String> = arguments.iter().filter(|a| !a.starts_with("--")).collect();
+    if positional.is_empty()
+    {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let source: Box<dyn AudioSource> = match positional.get(1)
+    {
+        Some(path) => match audio::WavFile::load(Path::new(path))
+        {
+            Ok(mut wav) =>
+            {
+                wav.looping = !arguments.iter().any(|a| a == "--once");
+                Box::new(wav)
+            }
+            Err(e) =>
+            {
+                println!("Unable to load wav file: {}", e);
+                return;
+            }
+        },
+        None => match capture_device()
+        {
+            Ok(device) => device,
+            Err(e) =>
+            {
+                println!("Unable to capture audio: {}", e);
+                return;
+            }
+        },
+    };
+
+    let color = Color::new(0, 128, 255);
+    let effect: Box<dyn Effect> = match positional[0].as_str()
+    {
+        "bands" => Box::new(audio::AudioBands::new(source, BandMapping::Brightness, color)),
+        "hue"   => Box::new(audio::AudioBands::new(source, BandMapping::Hue, color)),
+        "bars"  => Box::new(audio::AudioBands::new(source, BandMapping::LedCount, color)),
+        "beat"  => Box::new(audio::BeatFlash::new(source, Color::new(255, 255, 255))),
+        "vu"    => Box::new(audio::VuMeter::new(source)),
+        name =>
+        {
+            println!("Unknown audio effect: {}\n{}", name, USAGE);
+            return;
+        }
+    };
+    crate::run_animation(effect);
+}
+
+fn keys(arguments: &[String])
+{
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+    let values = positional(arguments);
+    if values.is_empty()
+    {
+        println!("{}", USAGE);
+        return;
+    }
+
+    let mut colors = Vec::new();
+    for argument in values[1..].iter()
+    {
+        match Color::parse(argument)
+        {
+            Some(color) => colors.push(color),
+            None =>
+            {
+                println!("Unknown color: {}", argument);
+                return;
+            }
+        }
+    }
+    let color = |index: usize, default: Color| colors.get(index).cloned().unwrap_or(default);
+
+    let events = match key_events(option("--device").map(|d| d.as_str()))
+    {
+        Ok(events) => events,
+        Err(e) =>
+        {
+            println!("Unable to read the keyboard: {}", e);
+            return;
+        }
+    };
+
+    let effect: Box<dyn Effect> = match values[0].as_str()
+    {
+        "ripple"  => Box::new(reactive::Ripple::new(events, color(0, Color::new(0, 200, 255)), color(1, Color::new(0, 0, 0)))),
+        "trail"   => Box::new(reactive::TypingTrail::new(events, color(0, Color::new(255, 80, 0)), color(1, Color::new(0, 0, 0)))),
+        "heatmap" => Box::new(reactive::Heatmap::new(events, color(0, Color::new(0, 0, 255)), color(1, Color::new(255, 0, 0)))),
+        name =>
+        {
+            println!("Unknown keyboard effect: {}\n{}", name, USAGE);
+            return;
+        }
+    };
+    crate::run_animation(effect);
+}
+
+fn ambient(arguments: &[String])
+{
+    let values: Vec<&String> = arguments.iter().filter(|a| !a.starts_with("--")).collect();
+
+    let source: Box<dyn FrameSource> = match values.get(0)
+    {
+        Some(path) =>
+        {
+            let frames_per_second = values.get(1).and_then(|f| f.parse().ok()).unwrap_or(30.0);
+            match screen::ImageFiles::new(Path::new(path), frames_per_second)
+            {
+                Ok(files) => Box::new(files),
+                Err(e) =>
+                {
+                    println!("Unable to load images: {}", e);
+                    return;
+                }
+            }
+        }
+        None => match screen_capture()
+        {
+            Ok(capture) => capture,
+            Err(e) =>
+            {
+                println!("Unable to capture the screen: {}", e);
+                return;
+            }
+        },
+    };
+
+    let mut effect = Ambilight::new(source);
+    if arguments.iter().any(|a| a == "--dominant")
+    {
+        effect.sampling = Sampling::Dominant;
+    }
+    crate::run_animation(Box::new(effect));
+}
+
+fn sensor(arguments: &[String])
+{
+    let sensor_config = match arguments.get(0)
+    {
+        Some(path) => match config::load_file::<SensorConfig>(Path::new(path))
+        {
+            Ok(sensor_config) => sensor_config,
+            Err(e) =>
+            {
+                println!("Unable to load sensor config: {}", e);
+                return;
+            }
+        },
+        None => SensorConfig::load(),
+    };
+
+    match SensorGradient::from_config(sensor_config)
+    {
+        Ok(effect) => crate::run_animation(Box::new(effect)),
+        Err(e) => println!("Unable to read sensor: {}", e),
+    }
+}
+
+fn notify(arguments: &[String])
+{
+    //--name value pairs
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+
+    let color = match option("--color").map(|c| Color::parse(c))
+    {
+        Some(Some(color)) => color,
+        Some(None) =>
+        {
+            println!("Unknown color: {}", option("--color").unwrap());
+            return;
+        }
+        None => Color::new(255, 255, 255),
+    };
+
+    let pattern = if let Some(count) = option("--blinks").and_then(|c| c.parse().ok())
+    {
+        Pattern::Blinks(count)
+    }
+    else if let Some(count) = option("--pulses").and_then(|c| c.parse().ok())
+    {
+        Pattern::Pulses(count)
+    }
+    else
+    {
+        Pattern::Solid
+    };
+
+    let mut notification = Notification::new(color, pattern, option("--duration").and_then(|d| d.parse().ok()).unwrap_or(2000));
+    if let Some(devices) = option("--devices")
+    {
+        notification.devices = devices.split(',').map(|d| d.to_string()).collect();
+    }
+
+    if let Ok(mut client) = Client::connect()
+    {
+        print_result(client.call("notify", json!(notification)));
+        return;
+    }
+
+    let mut compositor = Compositor::new();
+    compositor.push(Layer::new("base", Box::new(animation::ColorSpectrum::load())));
+    compositor.push(notification.into_layer());
+    crate::run_animation(Box::new(compositor));
+}
+
+fn layer(arguments: &[String])
+{
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+    let positional = positional(arguments);
+    if positional.is_empty() || (positional.len() < 2 && !arguments.iter().any(|a| a == "--remove"))
+    {
+        println!("{}\nEffects: {}", USAGE, effects::NAMES.join(", "));
+        return;
+    }
+
+    if arguments.iter().any(|a| a == "--remove")
+    {
+        send("remove-layer", json!({ "name": positional[0] }));
+        return;
+    }
+
+    let options = match effect_options(arguments, &positional[2..])
+    {
+        Ok(options) => options,
+        Err(e) =>
+        {
+            println!("{}", e);
+            return;
+        }
+    };
+
+    let list = |text: &str| -> Vec<String> { text.split(',').map(|s| s.trim().to_string()).collect() };
+    let mask = if let Some(devices) = option("--devices")
+    {
+        json!({ "devices": list(devices) })
+    }
+    else if let Some(leds) = option("--leds")
+    {
+        let (device, indices) = match leds.rsplit_once(':')
+        {
+            Some(parts) => parts,
+            None =>
+            {
+                println!("Leds are device:0,1,2, not {}", leds);
+                return;
+            }
+        };
+        let indices: Result<Vec<usize>, _> = indices.split(',').map(|i| i.trim().parse()).collect();
+        match indices
+        {
+            Ok(indices) => json!({ "leds": { "device": device, "leds": indices } }),
+            Err(_) =>
+            {
+                println!("Leds are device:0,1,2, not {}", leds);
+                return;
+            }
+        }
+    }
+    else if let Some(keys) = option("--keys")
+    {
+        json!({ "named_leds": { "device": option("--device").map_or("sk621", |d| d.as_str()), "names": list(keys) } })
+    }
+    else
+    {
+        json!("all")
+    };
+
+    let direction = options.direction.map(|d| json!({ "x": d.x, "y": d.y, "z": d.z }));
+    send("set-layer", json!(
+    {
+        "name": positional[0],
+        "effect": positional[1],
+        "colors": options.colors,
+        "speed": options.speed,
+        "direction": direction,
+        "blend_mode": option("--blend").map_or("normal", |b| b.as_str()),
+        "opacity": option("--opacity").and_then(|o| o.parse::<f32>().ok()).unwrap_or(1.0),
+        "mask": mask,
+    }));
+}
+
+fn record(arguments: &[String])
+{
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+    let positional = positional(arguments);
+    if positional.len() < 2
+    {
+        println!("{}\nEffects: {}", USAGE, effects::NAMES.join(", "));
+        return;
+    }
+
+    let options = match effect_options(arguments, &positional[2..])
+    {
+        Ok(options) => options,
+        Err(e) =>
+        {
+            println!("{}", e);
+            return;
+        }
+    };
+    let effect = match effects::create(positional[1], &options)
+    {
+        Some(effect) => effect,
+        None =>
+        {
+            println!("Unknown effect: {}\nEffects: {}", positional[1], effects::NAMES.join(", "));
+            return;
+        }
+    };
+
+    let mut devices = Vec::new();
+    for device in option("--devices").map_or("strip:60", |d| d.as_str()).split(',')
+    {
+        match device.rsplit_once(':').and_then(|(name, leds)| Some((name.to_string(), leds.parse::<usize>().ok()?)))
+        {
+            Some(device) => devices.push(device),
+            None =>
+            {
+                println!("Devices are name:leds, not {}", device);
+                return;
+            }
+        }
+    }
+    let duration = option("--duration").and_then(|d| d.parse().ok()).unwrap_or(10000);
+    let step = option("--step").and_then(|s| s.parse().ok()).unwrap_or(10);
+
+    match recording::render(effect, &Scene::load(), &devices, duration, step, Path::new(positional[0]))
+    {
+        Ok(_) => println!("Recorded {}ms of {} to {}", duration, positional[1], positional[0]),
+        Err(e) => println!("Unable to record: {}", e),
+    }
+}
+
+fn replay(arguments: &[String])
+{
+    let path = match arguments.iter().find(|a| !a.starts_with("--"))
+    {
+        Some(path) => path,
+        None =>
+        {
+            println!("{}", USAGE);
+            return;
+        }
+    };
+    let recording = match Recording::load(Path::new(path))
+    {
+        Ok(recording) => recording,
+        Err(e) =>
+        {
+            println!("Unable to load recording: {}", e);
+            return;
+        }
+    };
+
+    let mut rgb_devices: Vec<Box<dyn RgbDevice>> = if arguments.iter().any(|a| a == "--virtual")
+    {
+        recording.devices.iter().map(|(name, leds)|
+        {
+            let device: Box<dyn RgbDevice> = Box::new(VirtualDevice::new(name, *leds, true));
+            return device;
+        }).collect()
+    }
+    else
+    {
+        crate::get_rgb_devices()
+    };
+    recording::replay(&recording, &mut rgb_devices);
+}
+
+fn compare(arguments: &[String])
+{
+    let option = |name: &str| arguments.iter().position(|a| a == name).and_then(|i| arguments.get(i + 1));
+    let paths = positional(arguments);
+    if paths.len() != 2
+    {
+        println!("{}", USAGE);
+        std::process::exit(2);
+    }
+
+    let load = |path: &String| match Recording::load(Path::new(path))
+    {
+        Ok(recording) => recording,
+        Err(e) =>
+        {
+            println!("Unable to load recording: {}", e);
+            std::process::exit(2);
+        }
+    };
+    let (expected, actual) = (load(paths[0]), load(paths[1]));
+
+    let differences = recording::compare(&expected, &actual, option("--tolerance").and_then(|t| t.parse().ok()).unwrap_or(0));
+    if differences.is_empty()
+    {
+        println!("Recordings match, {} records over {}ms", expected.records.len(), expected.duration());
+        return;
+    }
+    for difference in differences.iter().take(20)
+    {
+        println!("{}", difference);
+    }
+    if differences.len() > 20
+    {
+        println!("... and {} more", differences.len() - 20);
+    }
+    std::process::exit(1);
+}
+
+//Everything that isn't a --name value option
+fn positional(arguments: &[String]) -> Vec<&String>
+{
+    return arguments.iter().enumerate()
+        .filter(|(i, a)| !a.starts_with("--") && (*i == 0 || !arguments[i - 1].starts_with("--")))
+        .map(|(_, a)| a)
+        .collect();
+}
+
+//A single call to the running instance
+fn send(method: &str, params: Value)
+{
+    match Client::connect()
+    {
+        Ok(mut client) => print_result(client.call(method, params)),
+        Err(e) => println!("No running instance: {}", e),
+    }
+}
+
+fn print_result(result: Result<Value, String>)
+{
+    match result
+    {
+        Ok(Value::Null) => {}
+        Ok(value) => println!("{}", serde_json::to_string_pretty(&value).unwrap()),
+        Err(e) => println!("Error: {}", e),
+    }
+}
+
+fn color(arguments: &[String])
+{
+    match arguments.get(0).and_then(|c| Color::parse(c))
+    {
+        Some(color) => send("set-color", json!({ "color": color })),
+        None => println!("{}", USAGE),
+    }
+}
+
+fn brightness(arguments: &[String])
+{
+    match arguments.get(0).and_then(|b| b.parse::<f32>().ok())
+    {
+        Some(percent) => send("set-brightness", json!({ "brightness": percent / 100.0 })),
+        None => println!("{}", USAGE),
+    }
+}
+
+fn subscribe()
+{
+    let mut client = match Client::connect()
+    {
+        Ok(client) => client,
+        Err(e) =>
+        {
+            println!("No running instance: {}", e);
+            return;
+        }
+    };
+
+    if let Err(e) = client.call("subscribe", Value::Null)
+    {
+        println!("Error: {}", e);
+        return;
+    }
+    loop
+    {
+        match client.next_event()
+        {
+            Ok(event) => println!("{}", event),
+            Err(e) =>
+            {
+                println!("{}", e);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(windows)]
+fn screen_capture() -> Result<Box<dyn FrameSource>, String>
+{
+    return Ok(Box::new(screen::ScreenCapture));
+}
+
+#[cfg(not(windows))]
+fn screen_capture() -> Result<Box<dyn FrameSource>, String>
+{
+    return Err("screen capture is only supported on windows, pass an image or a directory of frames instead".to_string());
+}
+
+#[cfg(feature = "audio-capture")]
+fn capture_device() -> Result<Box<dyn AudioSource>, String>
+{
+    return Ok(Box::new(audio::CaptureDevice::new()?));
+}
+
+#[cfg(not(feature = "audio-capture"))]
+fn capture_device() -> Result<Box<dyn AudioSource>, String>
+{
+    return Err("built without the audio-capture feature, pass a wav file instead".to_string());
+}
+
+#[cfg(target_os = "linux")]
+fn key_events(device: Option<&str>) -> Result<Box<dyn KeyEventSource>, String>
+{
+    let path = match device
+    {
+        Some(device) => device.into(),
+        None => reactive::EvdevKeyEvents::find_keyboard().ok_or("no keyboard in /dev/input/by-path, pass --device /dev/input/eventN")?,
+    };
+    return Ok(Box::new(reactive::EvdevKeyEvents::new(&path).map_err(|e| format!("{}: {}", path.display(), e))?));
+}
+
+#[cfg(windows)]
+fn key_events(_device: Option<&str>) -> Result<Box<dyn KeyEventSource>, String>
+{
+    return Ok(Box::new(reactive::WindowsKeyEvents::new()));
+}
+
+#[cfg(not(any(windows, target_os = "linux")))]
+fn key_events(_device: Option<&str>) -> Result<Box<dyn KeyEventSource>, String>
+{
+    return Err("key presses are only read on linux and windows".to_string());
+}
+
+fn confirm(question: &str) -> bool
+{
+    print!("{} [y/N] ", question);
+    stdout().flush().unwrap();
+
+    let mut answer = String::new();
+    if stdin().read_line(&mut answer).is_err()
+    {
+        return false;
+    }
+    return answer.trim().eq_ignore_ascii_case("y") || answer.trim().eq_ignore_ascii_case("yes");
+}
@@ -1,48 +1,112 @@
 use std::fmt::Formatter;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::atomic::{AtomicU32, Ordering};
 
 pub trait RgbDevice
 {
     fn set_color(&mut self, color: Color);
+    fn set_secondary_color(&mut self, color: Color);
     fn set_mode(&mut self, mode: RgbMode);
     fn set_speed(&mut self, speed: RgbSpeed);
-    fn set_brightness(&mut self, brightness: RgbBrightness);
+    fn set_brightness(&mut self, brightness: f32); //0.0 - 1.0, multiplied with the master brightness
     fn get_name(&self) -> &String;
     fn display(&mut self);
+
+    //Devices with individually addressable leds override these, everything else is a single led
+    fn get_led_count(&self) -> usize
+    {
+        return 1;
+    }
+
+    fn set_led_color(&mut self, _index: usize, color: Color)
+    {
+        self.set_color(color);
+    }
+
+    //Index of a named led, like a key on a keyboard
+    fn find_led(&self, _name: &str) -> Option<usize>
+    {
+        return None;
+    }
+
+    fn get_led_name(&self, _index: usize) -> Option<String>
+    {
+        return None;
+    }
+
+    //What kind of hardware the device is, for programs that show it, like openrgb clients
+    fn get_device_type(&self) -> DeviceType
+    {
+        return DeviceType::Other;
+    }
+
+    //Only for devices that animate on their own, the others have nothing to apply them to
+    fn set_color_flags(&mut self, _flags: RgbColorFlags)
+    {
+    }
+}
+
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceType
+{
+    Motherboard,
+    Gpu,
+    Keyboard,
+    Other,
 }
 
 
 
-#[allow(dead_code)]
-pub enum RgbBrightness
+//Master brightness applied on top of the brightness of every device.
+//Stored as the bits of an f32, so it can be changed from any thread without locking.
+static MASTER_BRIGHTNESS: AtomicU32 = AtomicU32::new(0x3F80_0000); //1.0
+
+pub fn set_master_brightness(brightness: f32)
+{
+    MASTER_BRIGHTNESS.store(brightness.max(0.0).min(1.0).to_bits(), Ordering::Relaxed);
+}
+
+pub fn get_master_brightness() -> f32
 {
-    Level10,
-    Level20,
-    Level30,
-    Level40,
-    Level50,
-    Level60,
-    Level70,
-    Level80,
-    Level90,
-    Level100,
+    return f32::from_bits(MASTER_BRIGHTNESS.load(Ordering::Relaxed));
 }
 
-#[allow(dead_code)]
+//The brightness a device should actually show
+pub fn effective_brightness(device_brightness: f32) -> f32
+{
+    return (device_brightness * get_master_brightness()).max(0.0).min(1.0);
+}
+
+#[derive(Clone, Copy)]
 pub enum RgbSpeed
 {
     Slow,
     Medium,
     Fast
 }
+#[derive(Clone, Copy)]
 pub enum RgbMode
 {
     Static,
+    Breathing,
+    Flashing,
+    DoubleFlashing,
+}
+
+//Let the hardware pick the colors instead of using the set colors, only used by some modes
+#[derive(Clone, Copy, Default)]
+pub struct RgbColorFlags
+{
+    pub rainbow: bool,
+    pub random: bool,
 }
 
 
 
 #[repr(C)]
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Color
 {
     pub r: u8,
@@ -61,6 +125,143 @@ impl Color
             b,
         }
     }
+
+    //Linear interpolation towards another color, amount 0.0 - 1.0
+    pub fn blend(&self, other: Color, amount: f32) -> Self
+    {
+        let amount = amount.max(0.0).min(1.0);
+        Color
+        {
+            r: (self.r as f32 + (other.r as f32 - self.r as f32) * amount).round() as u8,
+            g: (self.g as f32 + (other.g as f32 - self.g as f32) * amount).round() as u8,
+            b: (self.b as f32 + (other.b as f32 - self.b as f32) * amount).round() as u8,
+        }
+    }
+
+    //"#ff8000", "255,128,0" or a few common names
+    pub fn parse(text: &str) -> Option<Color>
+    {
+        let text = text.trim();
+
+        if let Some(hex) = text.strip_prefix('#')
+        {
+            if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit())
+            {
+                return None;
+            }
+            let value = u32::from_str_radix(hex, 16).ok()?;
+            return Some(Color::new((value >> 16) as u8, (value >> 8) as u8, value as u8));
+        }
+
+        let values: Vec<&str> = text.split(',').collect();
+        if values.len() == 3
+        {
+            return Some(Color::new(values[0].trim().parse().ok()?, values[1].trim().parse().ok()?, values[2].trim().parse().ok()?));
+        }
+
+        return match text.to_ascii_lowercase().as_str()
+        {
+            "black"   => Some(Color::new(0,   0,   0  )),
+            "white"   => Some(Color::new(255, 255, 255)),
+            "red"     => Some(Color::new(255, 0,   0  )),
+            "green"   => Some(Color::new(0,   255, 0  )),
+            "blue"    => Some(Color::new(0,   0,   255)),
+            "yellow"  => Some(Color::new(255, 255, 0  )),
+            "cyan"    => Some(Color::new(0,   255, 255)),
+            "magenta" => Some(Color::new(255, 0,   255)),
+            "orange"  => Some(Color::new(255, 128, 0  )),
+            "purple"  => Some(Color::new(128, 0,   255)),
+            _ => None,
+        };
+    }
+
+    //Hue in degrees 0.0 - 360.0, saturation and value 0.0 - 1.0
+    pub fn to_hsv(&self) -> (f32, f32, f32)
+    {
+        let r = self.r as f32 / 255.0;
+        let g = self.g as f32 / 255.0;
+        let b = self.b as f32 / 255.0;
+
+        let max = r.max(g).max(b);
+        let min = r.min(g).min(b);
+        let delta = max - min;
+
+        let hue = if delta == 0.0
+        {
+            0.0
+        }
+        else if max == r
+        {
+            60.0 * ((g - b) / delta).rem_euclid(6.0)
+        }
+        else if max == g
+        {
+            60.0 * ((b - r) / delta + 2.0)
+        }
+        else
+        {
+            60.0 * ((r - g) / delta + 4.0)
+        };
+
+        let saturation = if max == 0.0 { 0.0 } else { delta / max };
+        return (hue, saturation, max);
+    }
+
+    pub fn from_hsv(hue: f32, saturation: f32, value: f32) -> Self
+    {
+        let hue = hue.rem_euclid(360.0);
+        let chroma = value * saturation;
+        let x = chroma * (1.0 - ((hue / 60.0).rem_euclid(2.0) - 1.0).abs());
+        let m = value - chroma;
+
+        let (r, g, b) = match (hue / 60.0) as u32
+        {
+            0 => (chroma, x, 0.0),
+            1 => (x, chroma, 0.0),
+            2 => (0.0, chroma, x),
+            3 => (0.0, x, chroma),
+            4 => (x, 0.0, chroma),
+            _ => (chroma, 0.0, x),
+        };
+
+        return Color::new(((r + m) * 255.0).round() as u8, ((g + m) * 255.0).round() as u8, ((b + m) * 255.0).round() as u8);
+    }
+
+    //"#ff8000", the first format Color::parse accepts
+    pub fn to_hex(&self) -> String
+    {
+        return format!("#{:02x}{:02x}{:02x}", self.r, self.g, self.b);
+    }
+
+    //Software brightness, for devices that don't support it in hardware
+    pub fn scale(&self, brightness: f32) -> Self
+    {
+        Color
+        {
+            r: (self.r as f32 * brightness).round() as u8,
+            g: (self.g as f32 * brightness).round() as u8,
+            b: (self.b as f32 * brightness).round() as u8,
+        }
+    }
+}
+
+
+//Colors in config files are strings, see Color::parse
+impl<'de> Deserialize<'de> for Color
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error>
+    {
+        let text = String::deserialize(deserializer)?;
+        return Color::parse(&text).ok_or_else(|| serde::de::Error::custom(format!("invalid color \"{}\"", text)));
+    }
+}
+
+impl Serialize for Color
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    {
+        return serializer.serialize_str(&self.to_hex());
+    }
 }
 
 
@@ -70,4 +271,128 @@ impl std::fmt::Display for Color
     {
         write!(f, "rgb({}, {}, {})", self.r, self.g, self.b)
     }
-}
\ No newline at end of file
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::mystic_light::MsiBrightness;
+
+    //Other tests render with the master brightness, so it is only ever set to values that clamp back to 1.0
+    #[test]
+    fn brightness_is_clamped()
+    {
+        set_master_brightness(7.5);
+        assert_eq!(get_master_brightness(), 1.0);
+        set_master_brightness(f32::INFINITY);
+        assert_eq!(get_master_brightness(), 1.0);
+
+        assert_eq!(effective_brightness(0.25), 0.25);
+        assert_eq!(effective_brightness(3.0), 1.0);
+        assert_eq!(effective_brightness(-0.5), 0.0);
+    }
+
+    #[test]
+    fn brightness_quantises_to_z390_levels()
+    {
+        assert_eq!(MsiBrightness::from_fraction(0.0), MsiBrightness::Off);
+        assert_eq!(MsiBrightness::from_fraction(0.04), MsiBrightness::Off);
+        assert_eq!(MsiBrightness::from_fraction(0.06), MsiBrightness::Level10);
+        assert_eq!(MsiBrightness::from_fraction(0.44), MsiBrightness::Level40);
+        assert_eq!(MsiBrightness::from_fraction(0.5), MsiBrightness::Level50);
+        assert_eq!(MsiBrightness::from_fraction(0.96), MsiBrightness::Level100);
+        assert_eq!(MsiBrightness::from_fraction(1.0), MsiBrightness::Level100);
+        assert_eq!(MsiBrightness::from_fraction(2.0), MsiBrightness::Level100);
+        assert_eq!(MsiBrightness::from_fraction(-1.0), MsiBrightness::Off);
+        assert_eq!(MsiBrightness::from_fraction(f32::NAN), MsiBrightness::Off);
+        assert_eq!(MsiBrightness::from_fraction(effective_brightness(1.7)), MsiBrightness::Level100);
+    }
+
+    #[test]
+    fn hsv_known_values()
+    {
+        assert_eq!(Color::new(255, 0, 0).to_hsv(), (0.0, 1.0, 1.0));
+        assert_eq!(Color::new(0, 255, 0).to_hsv(), (120.0, 1.0, 1.0));
+        assert_eq!(Color::new(0, 0, 255).to_hsv(), (240.0, 1.0, 1.0));
+        assert_eq!(Color::new(0, 0, 0).to_hsv(), (0.0, 0.0, 0.0));
+        assert_eq!(Color::new(255, 255, 255).to_hsv(), (0.0, 0.0, 1.0));
+
+        assert_eq!(Color::from_hsv(60.0, 1.0, 1.0), Color::new(255, 255, 0));
+        assert_eq!(Color::from_hsv(300.0, 1.0, 1.0), Color::new(255, 0, 255));
+        assert_eq!(Color::from_hsv(0.0, 0.0, 0.5), Color::new(128, 128, 128));
+    }
+
+    #[test]
+    fn hsv_hue_wraps()
+    {
+        assert_eq!(Color::from_hsv(360.0, 1.0, 1.0), Color::new(255, 0, 0));
+        assert_eq!(Color::from_hsv(480.0, 1.0, 1.0), Color::new(0, 255, 0));
+        assert_eq!(Color::from_hsv(-120.0, 1.0, 1.0), Color::new(0, 0, 255));
+    }
+
+    #[test]
+    fn hsv_round_trips()
+    {
+        for r in (0..=255).step_by(15)
+        {
+            for g in (0..=255).step_by(15)
+            {
+                for b in (0..=255).step_by(15)
+                {
+                    let color = Color::new(r as u8, g as u8, b as u8);
+                    let (hue, saturation, value) = color.to_hsv();
+                    assert_eq!(Color::from_hsv(hue, saturation, value), color);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn scale_rounds_and_saturates()
+    {
+        let orange = Color::new(255, 128, 0);
+        assert_eq!(orange.scale(1.0), orange);
+        assert_eq!(orange.scale(0.5), Color::new(128, 64, 0));
+        assert_eq!(orange.scale(0.0), Color::new(0, 0, 0));
+        assert_eq!(orange.scale(2.0), Color::new(255, 255, 0));
+    }
+
+    #[test]
+    fn blend_is_clamped()
+    {
+        let black = Color::new(0, 0, 0);
+        let white = Color::new(255, 255, 255);
+        assert_eq!(black.blend(white, 0.5), Color::new(128, 128, 128));
+        assert_eq!(black.blend(white, 4.0), white);
+        assert_eq!(black.blend(white, -1.0), black);
+    }
+
+    #[test]
+    fn parses_every_format()
+    {
+        assert_eq!(Color::parse("#ff8000"), Some(Color::new(255, 128, 0)));
+        assert_eq!(Color::parse(" #FF8000 "), Some(Color::new(255, 128, 0)));
+        assert_eq!(Color::parse("255, 128,0"), Some(Color::new(255, 128, 0)));
+        assert_eq!(Color::parse("Orange"), Some(Color::new(255, 128, 0)));
+        assert_eq!(Color::parse("PURPLE"), Some(Color::new(128, 0, 255)));
+
+        for text in &["", "#ff80", "#ff80000", "#gg0000", "#+ff800", "256,0,0", "1,2", "1,2,3,4", "-1,0,0", "pink"]
+        {
+            assert_eq!(Color::parse(text), None, "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn hex_round_trips()
+    {
+        assert_eq!(Color::new(255, 128, 0).to_hex(), "#ff8000");
+        assert_eq!(Color::new(0, 10, 171).to_hex(), "#000aab");
+
+        for color in &[Color::new(0, 0, 0), Color::new(1, 2, 3), Color::new(255, 255, 255), Color::new(18, 52, 86)]
+        {
+            assert_eq!(Color::parse(&color.to_hex()), Some(*color));
+        }
+    }
+}
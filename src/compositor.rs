@@ -0,0 +1,319 @@
+use serde::Deserialize;
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::frame::Frame;
+
+
+//How a layer is combined with the layers below it, "normal", "add", ... in json
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlendMode
+{
+    Normal,
+    Add,
+    Multiply,
+    Screen,
+    Max,
+}
+
+impl BlendMode
+{
+    fn blend_channel(&self, below: u8, above: u8) -> u8
+    {
+        let below = below as f32 / 255.0;
+        let above = above as f32 / 255.0;
+
+        let result = match self
+        {
+            BlendMode::Normal   => above,
+            BlendMode::Add      => (below + above).min(1.0),
+            BlendMode::Multiply => below * above,
+            BlendMode::Screen   => 1.0 - (1.0 - below) * (1.0 - above),
+            BlendMode::Max      => below.max(above),
+        };
+        return (result * 255.0).round() as u8;
+    }
+
+    pub fn blend(&self, below: Color, above: Color) -> Color
+    {
+        return Color::new(
+            self.blend_channel(below.r, above.r),
+            self.blend_channel(below.g, above.g),
+            self.blend_channel(below.b, above.b)
+        );
+    }
+}
+
+
+//Which leds a layer affects. Z390 zones are separate devices, so selecting a zone means selecting a device.
+//In json: "all", { "devices": ["JRGB1"] }, { "leds": { "device": "strip", "leds": [0, 1] } } or { "named_leds": { ... } }
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Mask
+{
+    All,
+    Devices(Vec<String>),
+    Leds { device: String, leds: Vec<usize> },
+    //Leds by name, like keyboard keys: { "named_leds": { "device": "sk621", "names": ["w", "a", "s", "d"] } }
+    NamedLeds { device: String, names: Vec<String> },
+}
+
+impl Mask
+{
+    fn contains(&self, frame: &Frame, device: usize, led: usize) -> bool
+    {
+        let device_frame = &frame.devices[device];
+        return match self
+        {
+            Mask::All => true,
+            Mask::Devices(names) => names.iter().any(|name| *name == device_frame.name),
+            Mask::Leds { device, leds } => *device == device_frame.name && leds.contains(&led),
+            Mask::NamedLeds { device, names } => *device == device_frame.name && match &device_frame.led_names[led]
+            {
+                Some(led_name) => names.iter().any(|name| name.eq_ignore_ascii_case(led_name)),
+                None => false,
+            },
+        };
+    }
+}
+
+
+pub struct Layer
+{
+    pub name: String,
+    pub effect: Box<dyn Effect>,
+    pub opacity: f32, //0.0 - 1.0
+    pub blend_mode: BlendMode,
+    pub mask: Mask,
+
+    frame: Option<Frame>,       //Scratch frame the effect renders into
+    selected: Vec<Vec<bool>>,   //The mask resolved for the layout of the frame, per device per led
+}
+
+impl Layer
+{
+    pub fn new(name: &str, effect: Box<dyn Effect>) -> Self
+    {
+        Layer
+        {
+            name: name.to_string(),
+            effect,
+            opacity: 1.0,
+            blend_mode: BlendMode::Normal,
+            mask: Mask::All,
+
+            frame: None,
+            selected: Vec::new(),
+        }
+    }
+
+    pub fn with_blend_mode(mut self, blend_mode: BlendMode, opacity: f32) -> Self
+    {
+        self.blend_mode = blend_mode;
+        self.opacity = opacity;
+        return self;
+    }
+
+    pub fn with_mask(mut self, mask: Mask) -> Self
+    {
+        self.mask = mask;
+        self.frame = None;
+        return self;
+    }
+}
+
+
+//A stack of effects, rendered bottom to top. The compositor is an effect itself, so it can be used anywhere a single effect can.
+pub struct Compositor
+{
+    pub layers: Vec<Layer>,
+}
+
+impl Compositor
+{
+    pub fn new() -> Self
+    {
+        Compositor
+        {
+            layers: Vec::new(),
+        }
+    }
+
+    //On top of the existing layers
+    pub fn push(&mut self, layer: Layer)
+    {
+        self.layers.push(layer);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Layer>
+    {
+        let index = self.layers.iter().position(|l| l.name == name)?;
+        return Some(self.layers.remove(index));
+    }
+
+    pub fn find_mut(&mut self, name: &str) -> Option<&mut Layer>
+    {
+        return self.layers.iter_mut().find(|l| l.name == name);
+    }
+}
+
+impl Effect for Compositor
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        frame.fill(Color::new(0, 0, 0));
+        self.layers.retain(|l| !l.effect.is_finished());
+
+        for layer in self.layers.iter_mut()
+        {
+            if layer.frame.is_none()
+            {
+                layer.selected = frame.devices.iter().enumerate()
+                    .map(|(device, device_frame)| (0..device_frame.leds.len()).map(|led| layer.mask.contains(frame, device, led)).collect())
+                    .collect();
+                layer.frame = Some(frame.clone());
+            }
+            let layer_frame = layer.frame.as_mut().unwrap();
+            layer.effect.render(layer_frame, millis);
+            let opacity = layer.opacity * layer.effect.opacity();
+
+            for (device, device_frame) in frame.devices.iter_mut().enumerate()
+            {
+                for (led, output) in device_frame.leds.iter_mut().enumerate()
+                {
+                    if !layer.selected[device][led]
+                    {
+                        continue;
+                    }
+
+                    let above = layer_frame.devices[device].leds[led].color;
+                    let blended = layer.blend_mode.blend(output.color, above);
+                    output.color = output.color.blend(blended, opacity);
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::animation::SolidColor;
+    use crate::color::RgbDevice;
+    use crate::recording::VirtualDevice;
+    use crate::scene::Scene;
+
+    const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+    const WHITE: Color = Color { r: 255, g: 255, b: 255 };
+
+    //"strip" with 4 leds, the first, second and last named like keys, and "fan" with 2
+    fn frame() -> Frame
+    {
+        let devices: Vec<Box<dyn RgbDevice>> = vec![Box::new(VirtualDevice::new("strip", 4, false)), Box::new(VirtualDevice::new("fan", 2, false))];
+        let mut frame = Frame::new(&Scene::default(), &devices);
+        frame.devices[0].led_names = vec![Some("w".to_string()), Some("a".to_string()), None, Some("s".to_string())];
+        return frame;
+    }
+
+    //A white layer over a black base
+    fn render(layer: Layer) -> Vec<Vec<Color>>
+    {
+        let mut compositor = Compositor::new();
+        compositor.push(Layer::new("base", Box::new(SolidColor(BLACK))));
+        compositor.push(layer);
+
+        let mut frame = frame();
+        compositor.render(&mut frame, 0);
+        return frame.devices.iter().map(|d| d.leds.iter().map(|l| l.color).collect()).collect();
+    }
+
+    fn white(mask: Mask) -> Layer
+    {
+        return Layer::new("white", Box::new(SolidColor(WHITE))).with_mask(mask);
+    }
+
+    #[test]
+    fn blend_modes()
+    {
+        let below = Color::new(51, 255, 0);
+        let above = Color::new(102, 255, 255);
+        assert_eq!(BlendMode::Normal.blend(below, above), Color::new(102, 255, 255));
+        assert_eq!(BlendMode::Add.blend(below, above), Color::new(153, 255, 255));
+        assert_eq!(BlendMode::Multiply.blend(below, above), Color::new(20, 255, 0));
+        assert_eq!(BlendMode::Screen.blend(below, above), Color::new(133, 255, 255));
+        assert_eq!(BlendMode::Max.blend(below, above), Color::new(102, 255, 255));
+
+        //Add saturates instead of wrapping
+        assert_eq!(BlendMode::Add.blend(Color::new(200, 0, 0), Color::new(100, 0, 0)), Color::new(255, 0, 0));
+    }
+
+    #[test]
+    fn blend_modes_in_the_stack()
+    {
+        let gray = Color::new(128, 128, 128);
+        let mut compositor = Compositor::new();
+        compositor.push(Layer::new("base", Box::new(SolidColor(gray))));
+        compositor.push(Layer::new("red", Box::new(SolidColor(Color::new(255, 0, 0)))).with_blend_mode(BlendMode::Multiply, 1.0));
+
+        let mut frame = frame();
+        compositor.render(&mut frame, 0);
+        assert!(frame.devices.iter().all(|d| d.leds.iter().all(|l| l.color == Color::new(128, 0, 0))));
+    }
+
+    #[test]
+    fn opacity()
+    {
+        let quarter = render(white(Mask::All).with_blend_mode(BlendMode::Normal, 0.25));
+        assert_eq!(quarter[0][0], Color::new(64, 64, 64));
+
+        let hidden = render(white(Mask::All).with_blend_mode(BlendMode::Normal, 0.0));
+        assert!(hidden.iter().flatten().all(|c| *c == BLACK));
+    }
+
+    #[test]
+    fn mask_all()
+    {
+        assert!(render(white(Mask::All)).iter().flatten().all(|c| *c == WHITE));
+    }
+
+    #[test]
+    fn mask_devices()
+    {
+        let colors = render(white(Mask::Devices(vec!["fan".to_string()])));
+        assert_eq!(colors, vec![vec![BLACK; 4], vec![WHITE; 2]]);
+    }
+
+    #[test]
+    fn mask_leds()
+    {
+        let colors = render(white(Mask::Leds { device: "strip".to_string(), leds: vec![1, 3, 9] }));
+        assert_eq!(colors, vec![vec![BLACK, WHITE, BLACK, WHITE], vec![BLACK; 2]]);
+
+        let other_device = render(white(Mask::Leds { device: "fan".to_string(), leds: vec![1] }));
+        assert_eq!(other_device, vec![vec![BLACK; 4], vec![BLACK, WHITE]]);
+    }
+
+    #[test]
+    fn mask_named_leds()
+    {
+        let names = vec!["W".to_string(), "s".to_string(), "d".to_string()];
+        let colors = render(white(Mask::NamedLeds { device: "strip".to_string(), names: names.clone() }));
+        assert_eq!(colors, vec![vec![WHITE, BLACK, BLACK, WHITE], vec![BLACK; 2]]);
+
+        let unnamed = render(white(Mask::NamedLeds { device: "fan".to_string(), names }));
+        assert!(unnamed.iter().flatten().all(|c| *c == BLACK));
+    }
+
+    #[test]
+    fn parses_from_json()
+    {
+        let mask: Mask = serde_json::from_str(r#"{ "named_leds": { "device": "sk621", "names": ["w", "a", "s", "d"] } }"#).unwrap();
+        assert_eq!(mask, Mask::NamedLeds { device: "sk621".to_string(), names: vec!["w".to_string(), "a".to_string(), "s".to_string(), "d".to_string()] });
+        assert_eq!(serde_json::from_str::<Mask>(r#""all""#).unwrap(), Mask::All);
+        assert_eq!(serde_json::from_str::<Mask>(r#"{ "devices": ["fan"] }"#).unwrap(), Mask::Devices(vec!["fan".to_string()]));
+        assert_eq!(serde_json::from_str::<Mask>(r#"{ "leds": { "device": "strip", "leds": [2] } }"#).unwrap(), Mask::Leds { device: "strip".to_string(), leds: vec![2] });
+        assert_eq!(serde_json::from_str::<BlendMode>(r#""screen""#).unwrap(), BlendMode::Screen);
+    }
+}
@@ -0,0 +1,44 @@
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+
+
+//Config files live next to the executable, the service runs with system32 as working directory
+pub fn path(file_name: &str) -> PathBuf
+{
+    let mut path = std::env::current_exe().unwrap();
+    path.set_file_name(file_name);
+    return path;
+}
+
+//Missing config files are not an error, the caller falls back to its defaults
+pub fn load<T: DeserializeOwned>(file_name: &str) -> Option<T>
+{
+    let path = path(file_name);
+    if !path.exists()
+    {
+        return None;
+    }
+
+    match load_file(&path)
+    {
+        Ok(config) => return Some(config),
+        Err(e) =>
+        {
+            println!("Ignoring {}", e);
+            return None;
+        }
+    }
+}
+
+//json or toml, by extension
+pub fn load_file<T: DeserializeOwned>(path: &Path) -> Result<T, String>
+{
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+    let result = match path.extension().and_then(|e| e.to_str())
+    {
+        Some("toml") => toml::from_str(&text).map_err(|e| e.to_string()),
+        _ => serde_json::from_str(&text).map_err(|e| e.to_string()),
+    };
+    return result.map_err(|e| format!("{}: {}", path.display(), e));
+}
@@ -0,0 +1,582 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::animation::{self, Effect, LedColors};
+use crate::color::{self, Color};
+use crate::compositor::{BlendMode, Compositor, Layer, Mask};
+use crate::effects::{self, EffectOptions};
+use crate::frame::{DeviceFrame, Frame};
+use crate::notification::Notification;
+use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+
+
+//Commands for the running animation. Every remote interface (the control socket, ...) turns its requests into
+//a Request and sends it here, run_animation handles them between frames, so nothing else touches the devices.
+//
+//Methods and params:
+//    get-state                                              -> { effect, color, brightness, paused, device_brightness: { name: brightness } }
+//    set-effect      { "name": "wave", "colors": ["red"], "speed": 0.5, "direction": { "x": 0, "y": 1 } }
+//                                                           everything but the name is optional, see effects::EffectOptions
+//    set-color       { "color": "#ff8000" }
+//    set-brightness  { "brightness": 0.5 }                  0.0 - 1.0
+//    set-device-brightness { "device": "SK621", "brightness": 0.5 }
+//                                                           on top of the master brightness, 0.0 switches a single device off
+//    pause / resume                                         pause keeps the leds as they are
+//    list-devices                                           -> [ { name, type, leds, led_names } ]
+//    get-frame                                              -> [ { name, colors } ], the colors the leds showed last
+//    set-leds        { "device": "SK621", "colors": ["red", ...], "offset": 0 }
+//                                                           colors for single leds, on top of the effect until the next set-effect or set-color
+//    clear-leds      { "device": "SK621" }                  back to the effect, on every device without params
+//    notify          { "color": "red", "pattern": { "blinks": 3 }, "duration": 2000, "devices": [] }
+//    set-layer       { "name": "wasd", "effect": "solid", "colors": ["red"], "blend_mode": "add", "opacity": 0.8,
+//                      "mask": { "named_leds": { "device": "sk621", "names": ["w", "a", "s", "d"] } } }
+//                                                           an effect on top of the others, replaces the layer with the same name,
+//                                                           effect options like set-effect, blend_mode and mask see compositor.rs
+//    remove-layer    { "name": "wasd" }
+//    subscribe       { "frames": true }                     events: { "event": "state" | "notification" | "frame", "data": ... } after every change,
+//                                                           frames only when asked for, at most FRAME_EVENTS_PER_SECOND
+
+
+const FRAME_EVENTS_PER_SECOND: u128 = 30;
+
+//Layers the other methods manage, set-layer and remove-layer leave them alone
+const CONTROL_LAYERS: [&str; 3] = ["base", "leds", "device brightness"];
+
+
+pub enum Reply
+{
+    Ok(Value),
+    Err(String),
+    //The caller forwards everything from the receiver, until it hangs up
+    Subscribed(Receiver<Value>),
+}
+
+pub struct Request
+{
+    pub method: String,
+    pub params: Value,
+    pub reply: Sender<Reply>,
+}
+
+//For transports that serve a request at a time: send it and wait for the animation to handle it
+pub fn call(sender: &Sender<Request>, method: &str, params: Value) -> Reply
+{
+    let (reply, receiver) = channel();
+    let request = Request { method: method.to_string(), params, reply };
+    if sender.send(request).is_err()
+    {
+        return Reply::Err("the animation is not running".to_string());
+    }
+    return receiver.recv().unwrap_or_else(|_| Reply::Err("the animation stopped".to_string()));
+}
+
+
+#[derive(Deserialize)]
+struct SetEffect
+{
+    name: String,
+    #[serde(flatten)]
+    options: EffectOptions,
+}
+
+#[derive(Deserialize)]
+struct SetColor
+{
+    color: Color,
+}
+
+#[derive(Deserialize)]
+struct SetBrightness
+{
+    brightness: f32,
+}
+
+#[derive(Deserialize)]
+struct SetDeviceBrightness
+{
+    device: String,
+    brightness: f32,
+}
+
+#[derive(Deserialize, Default)]
+struct ClearLeds
+{
+    device: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SetLeds
+{
+    device: String,
+    colors: Vec<Color>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Deserialize)]
+struct SetLayer
+{
+    name: String,
+    effect: String,
+    #[serde(flatten)]
+    options: EffectOptions,
+    #[serde(default = "normal")]
+    blend_mode: BlendMode,
+    #[serde(default = "opaque")]
+    opacity: f32,
+    #[serde(default = "all")]
+    mask: Mask,
+}
+
+fn normal() -> BlendMode
+{
+    return BlendMode::Normal;
+}
+
+fn opaque() -> f32
+{
+    return 1.0;
+}
+
+fn all() -> Mask
+{
+    return Mask::All;
+}
+
+#[derive(Deserialize)]
+struct RemoveLayer
+{
+    name: String,
+}
+
+#[derive(Deserialize, Default)]
+struct Subscribe
+{
+    #[serde(default)]
+    frames: bool,
+}
+
+struct Subscriber
+{
+    sender: Sender<Value>,
+    frames: bool,
+}
+
+
+pub struct Control
+{
+    sender: Sender<Request>,
+    receiver: Receiver<Request>,
+    subscribers: Vec<Subscriber>,
+    last_frame_event: Option<u128>,
+
+    pub paused: bool,
+    effect: Option<String>, //None while the effect is the one run_animation started with
+    color: Option<Color>,
+    leds: HashMap<String, Vec<Color>>, //From set-leds, per device
+    device_brightness: HashMap<String, f32>,
+}
+
+impl Control
+{
+    pub fn new() -> Self
+    {
+        let (sender, receiver) = channel();
+        Control
+        {
+            sender,
+            receiver,
+            subscribers: Vec::new(),
+            last_frame_event: None,
+
+            paused: false,
+            effect: None,
+            color: None,
+            leds: HashMap::new(),
+            device_brightness: HashMap::new(),
+        }
+    }
+
+    //For the transports, every clone sends to this control
+    pub fn sender(&self) -> Sender<Request>
+    {
+        return self.sender.clone();
+    }
+
+    //Handle everything that came in since the last frame, without waiting
+    pub fn handle(&mut self, compositor: &mut Compositor, frame: &Frame)
+    {
+        loop
+        {
+            let request = match self.receiver.try_recv()
+            {
+                Ok(request) => request,
+                Err(TryRecvError::Empty) | Err(TryRecvError::Disconnected) => return,
+            };
+
+            let reply = if request.method == "subscribe"
+            {
+                let params: Subscribe = if request.params.is_null() { Subscribe::default() } else { parse(request.params).unwrap_or_default() };
+                let (sender, receiver) = channel();
+                self.subscribers.push(Subscriber { sender, frames: params.frames });
+                Reply::Subscribed(receiver)
+            }
+            else
+            {
+                match self.execute(&request.method, request.params, compositor, frame)
+                {
+                    Ok(result) => Reply::Ok(result),
+                    Err(e) => Reply::Err(e),
+                }
+            };
+
+            //The caller may have given up waiting, that's fine
+            let _ = request.reply.send(reply);
+        }
+    }
+
+    fn execute(&mut self, method: &str, params: Value, compositor: &mut Compositor, frame: &Frame) -> Result<Value, String>
+    {
+        match method
+        {
+            "get-state" => return Ok(self.state()),
+            "set-effect" =>
+            {
+                let params: SetEffect = parse(params)?;
+                let effect = effects::create(&params.name, &params.options).ok_or(format!("unknown effect {}, effects: {}", params.name, effects::NAMES.join(", ")))?;
+                self.set_base(compositor, effect);
+                self.effect = Some(params.name);
+                self.color = None;
+            }
+            "set-color" =>
+            {
+                let params: SetColor = parse(params)?;
+                self.set_base(compositor, Box::new(animation::SolidColor(params.color)));
+                self.effect = Some("solid".to_string());
+                self.color = Some(params.color);
+            }
+            "set-brightness" =>
+            {
+                let params: SetBrightness = parse(params)?;
+                color::set_master_brightness(params.brightness);
+            }
+            "set-device-brightness" =>
+            {
+                let params: SetDeviceBrightness = parse(params)?;
+                let device = frame.devices.iter().find(|d| d.name == params.device).ok_or(format!("no device {}", params.device))?;
+                self.set_device_brightness(compositor, frame, &device.name, params.brightness);
+            }
+            "pause" => self.paused = true,
+            "resume" => self.paused = false,
+            "list-devices" =>
+            {
+                return Ok(frame.devices.iter().map(|d| json!({ "name": d.name, "type": d.device_type, "leds": d.leds.len(), "led_names": d.led_names })).collect());
+            }
+            "set-leds" =>
+            {
+                let params: SetLeds = parse(params)?;
+                let device = frame.devices.iter().find(|d| d.name == params.device).ok_or(format!("no device {}", params.device))?;
+                self.set_leds(compositor, device, params);
+                return Ok(Value::Null);
+            }
+            "get-frame" => return Ok(frame_colors(frame)),
+            "clear-leds" =>
+            {
+                let params: ClearLeds = if params.is_null() { ClearLeds::default() } else { parse(params)? };
+                match params.device
+                {
+                    Some(device) => { self.leds.remove(&device); }
+                    None => self.leds.clear(),
+                }
+                self.update_leds(compositor);
+                return Ok(Value::Null);
+            }
+            "notify" =>
+            {
+                let notification: Notification = parse(params.clone())?;
+                compositor.push(notification.into_layer());
+                self.publish("notification", params);
+                return Ok(Value::Null);
+            }
+            "set-layer" =>
+            {
+                let params: SetLayer = parse(params)?;
+                layer_name(&params.name)?;
+                let effect = effects::create(&params.effect, &params.options).ok_or(format!("unknown effect {}, effects: {}", params.effect, effects::NAMES.join(", ")))?;
+                let layer = Layer::new(&params.name, effect).with_blend_mode(params.blend_mode, params.opacity.max(0.0).min(1.0)).with_mask(params.mask);
+                match compositor.find_mut(&params.name)
+                {
+                    Some(existing) => *existing = layer,
+                    None => compositor.push(layer),
+                }
+                return Ok(Value::Null);
+            }
+            "remove-layer" =>
+            {
+                let params: RemoveLayer = parse(params)?;
+                layer_name(&params.name)?;
+                compositor.remove(&params.name).ok_or(format!("no layer {}", params.name))?;
+                return Ok(Value::Null);
+            }
+            _ => return Err(format!("unknown method {}", method)),
+        }
+
+        let state = self.state();
+        self.publish("state", state);
+        return Ok(Value::Null);
+    }
+
+    fn state(&self) -> Value
+    {
+        return json!(
+        {
+            "effect": self.effect,
+            "color": self.color,
+            "brightness": color::get_master_brightness(),
+            "paused": self.paused,
+            "device_brightness": self.device_brightness,
+        });
+    }
+
+    //The base layer is the one run_animation started with, notifications stay on top of it
+    fn set_base(&mut self, compositor: &mut Compositor, effect: Box<dyn Effect>)
+    {
+        self.leds.clear();
+        compositor.remove("leds");
+
+        match compositor.find_mut("base")
+        {
+            Some(layer) => layer.effect = effect,
+            None => compositor.layers.insert(0, Layer::new("base", effect)),
+        }
+    }
+
+    //Right above the base layer, only covering the devices that got colors. Leds without a color keep the last frame.
+    fn set_leds(&mut self, compositor: &mut Compositor, device: &DeviceFrame, params: SetLeds)
+    {
+        let current = self.leds.entry(device.name.clone()).or_insert_with(|| device.leds.iter().map(|l| l.color).collect());
+        for (led, color) in current.iter_mut().skip(params.offset).zip(params.colors.into_iter())
+        {
+            *led = color;
+        }
+        self.update_leds(compositor);
+    }
+
+    fn update_leds(&mut self, compositor: &mut Compositor)
+    {
+        if self.leds.is_empty()
+        {
+            compositor.remove("leds");
+            return;
+        }
+        let mask = Mask::Devices(self.leds.keys().cloned().collect());
+        let layer = Layer::new("leds", Box::new(LedColors(self.leds.clone()))).with_mask(mask);
+        replace_layer(compositor, layer, "base");
+    }
+
+    //Multiplies the devices with a gray, above the effect and the led colors but below notifications
+    fn set_device_brightness(&mut self, compositor: &mut Compositor, frame: &Frame, device: &str, brightness: f32)
+    {
+        let brightness = brightness.max(0.0).min(1.0);
+        if brightness == 1.0
+        {
+            self.device_brightness.remove(device);
+        }
+        else
+        {
+            self.device_brightness.insert(device.to_string(), brightness);
+        }
+
+        if self.device_brightness.is_empty()
+        {
+            compositor.remove("device brightness");
+            return;
+        }
+        let grays = frame.devices.iter().filter_map(|d|
+        {
+            let brightness = self.device_brightness.get(&d.name)?;
+            let gray = (brightness * 255.0).round() as u8;
+            return Some((d.name.clone(), vec![Color::new(gray, gray, gray); d.leds.len()]));
+        }).collect();
+        let mask = Mask::Devices(self.device_brightness.keys().cloned().collect());
+        let layer = Layer::new("device brightness", Box::new(LedColors(grays))).with_blend_mode(BlendMode::Multiply, 1.0).with_mask(mask);
+        let below = if compositor.find_mut("leds").is_some() { "leds" } else { "base" };
+        replace_layer(compositor, layer, below);
+    }
+
+    //Subscribers that hung up are dropped
+    fn publish(&mut self, event: &str, data: Value)
+    {
+        let event = json!({ "event": event, "data": data });
+        self.subscribers.retain(|s| s.sender.send(event.clone()).is_ok());
+    }
+
+    //After every rendered frame, for the subscribers that want live colors
+    pub fn publish_frame(&mut self, frame: &Frame, millis: u128)
+    {
+        if !self.subscribers.iter().any(|s| s.frames)
+        {
+            return;
+        }
+        if let Some(last) = self.last_frame_event
+        {
+            if millis < last + 1000 / FRAME_EVENTS_PER_SECOND
+            {
+                return;
+            }
+        }
+        self.last_frame_event = Some(millis);
+
+        let event = json!({ "event": "frame", "data": frame_colors(frame) });
+        self.subscribers.retain(|s| !s.frames || s.sender.send(event.clone()).is_ok());
+    }
+}
+
+//Replaces the layer with the same name, or inserts it right above the named one
+fn replace_layer(compositor: &mut Compositor, layer: Layer, above: &str)
+{
+    match compositor.find_mut(&layer.name)
+    {
+        Some(existing) => *existing = layer,
+        None =>
+        {
+            let index = compositor.layers.iter().position(|l| l.name == above).map_or(0, |index| index + 1);
+            compositor.layers.insert(index, layer);
+        }
+    }
+}
+
+fn layer_name(name: &str) -> Result<(), String>
+{
+    if CONTROL_LAYERS.contains(&name)
+    {
+        return Err(format!("the {} layer is not set with set-layer", name));
+    }
+    return Ok(());
+}
+
+fn frame_colors(frame: &Frame) -> Value
+{
+    return frame.devices.iter().map(|d| json!({ "name": d.name, "colors": d.leds.iter().map(|l| l.color).collect::<Vec<Color>>() })).collect();
+}
+
+fn parse<T: serde::de::DeserializeOwned>(params: Value) -> Result<T, String>
+{
+    return serde_json::from_value(params).map_err(|e| format!("invalid params: {}", e));
+}
+
+
+//================================================================================================================================================================================================
+//Testing
+
+//Like run_animation with virtual devices instead of hardware, on its own thread, for testing the transports
+#[cfg(test)]
+pub fn spawn_virtual(devices: &[(&str, usize)]) -> Sender<Request>
+{
+    use crate::color::RgbDevice;
+    use crate::recording::VirtualDevice;
+    use crate::scene::Scene;
+    use std::thread;
+    use std::time::Duration;
+
+    let devices: Vec<(String, usize)> = devices.iter().map(|(name, led_count)| (name.to_string(), *led_count)).collect();
+    let (control_sender, receiver) = channel();
+    thread::spawn(move ||
+    {
+        let rgb_devices: Vec<Box<dyn RgbDevice>> = devices.iter().map(|(name, led_count)|
+        {
+            let device: Box<dyn RgbDevice> = Box::new(VirtualDevice::new(name, *led_count, false));
+            return device;
+        }).collect();
+        let mut frame = Frame::new(&Scene::default(), &rgb_devices);
+        let mut compositor = Compositor::new();
+        compositor.push(Layer::new("base", Box::new(animation::SolidColor(Color::new(0, 0, 0)))));
+
+        let mut control = Control::new();
+        control_sender.send(control.sender()).unwrap();
+        let mut millis = 0;
+        loop
+        {
+            control.handle(&mut compositor, &frame);
+            if !control.paused
+            {
+                compositor.render(&mut frame, millis);
+                control.publish_frame(&frame, millis);
+            }
+            millis += 10;
+            thread::sleep(Duration::from_millis(10));
+        }
+    });
+    return receiver.recv().unwrap();
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    fn ok(reply: Reply) -> Value
+    {
+        return match reply
+        {
+            Reply::Ok(value) => value,
+            Reply::Err(e) => panic!("{}", e),
+            Reply::Subscribed(_) => panic!("subscribed"),
+        };
+    }
+
+    fn wait_for_frame(sender: &Sender<Request>, expected: Value)
+    {
+        let start = Instant::now();
+        loop
+        {
+            let frame = ok(call(sender, "get-frame", Value::Null));
+            if frame == expected
+            {
+                return;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "never showed {}, last frame {}", expected, frame);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn layers_are_added_replaced_and_removed()
+    {
+        let sender = spawn_virtual(&[("strip", 3), ("fan", 1)]);
+        ok(call(&sender, "set-color", json!({ "color": "#202020" })));
+
+        ok(call(&sender, "set-layer", json!({ "name": "ends", "effect": "solid", "colors": ["#010000"], "blend_mode": "add", "mask": { "leds": { "device": "strip", "leds": [0, 2] } } })));
+        wait_for_frame(&sender, json!([{ "name": "strip", "colors": ["#212020", "#202020", "#212020"] }, { "name": "fan", "colors": ["#202020"] }]));
+
+        ok(call(&sender, "set-layer", json!({ "name": "ends", "effect": "solid", "colors": ["white"], "opacity": 0.0, "mask": { "devices": ["fan"] } })));
+        wait_for_frame(&sender, json!([{ "name": "strip", "colors": ["#202020", "#202020", "#202020"] }, { "name": "fan", "colors": ["#202020"] }]));
+
+        ok(call(&sender, "set-layer", json!({ "name": "ends", "effect": "solid", "colors": ["white"], "blend_mode": "max" })));
+        wait_for_frame(&sender, json!([{ "name": "strip", "colors": ["#ffffff", "#ffffff", "#ffffff"] }, { "name": "fan", "colors": ["#ffffff"] }]));
+
+        ok(call(&sender, "remove-layer", json!({ "name": "ends" })));
+        wait_for_frame(&sender, json!([{ "name": "strip", "colors": ["#202020", "#202020", "#202020"] }, { "name": "fan", "colors": ["#202020"] }]));
+    }
+
+    #[test]
+    fn layers_are_checked()
+    {
+        let sender = spawn_virtual(&[("strip", 3)]);
+        let error = |method: &str, params: Value| match call(&sender, method, params.clone())
+        {
+            Reply::Err(e) => e,
+            _ => panic!("{} {} should fail", method, params),
+        };
+
+        assert!(error("set-layer", json!({ "name": "base", "effect": "solid" })).contains("base"));
+        assert!(error("remove-layer", json!({ "name": "leds" })).contains("leds"));
+        assert!(error("remove-layer", json!({ "name": "wasd" })).contains("no layer wasd"));
+        assert!(error("set-layer", json!({ "name": "wasd", "effect": "sparkles" })).contains("unknown effect"));
+        assert!(error("set-layer", json!({ "name": "wasd", "effect": "solid", "blend_mode": "overlay" })).contains("invalid params"));
+        assert!(error("set-layer", json!({ "name": "wasd", "effect": "solid", "mask": { "keys": ["w"] } })).contains("invalid params"));
+    }
+}
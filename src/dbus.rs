@@ -0,0 +1,370 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use zbus::blocking::{Connection, ConnectionBuilder};
+use zbus::{dbus_interface, fdo};
+use zbus::zvariant;
+use crate::color::Color;
+use crate::config;
+use crate::control::{self, Reply, Request};
+use crate::effects;
+use crate::notification::{Notification, Pattern};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Mutex;
+use std::thread;
+
+
+//D-Bus service for linux desktops, on the session bus unless dbus.json says { "bus": "system" }, or { "address": "unix:path=..." } for any other bus.
+//The system bus needs a policy in /etc/dbus-1/system.d that allows owning the name.
+//
+//    busctl --user introspect org.rusticlight.RusticLight /org/rusticlight/RusticLight
+//    busctl --user call org.rusticlight.RusticLight /org/rusticlight/RusticLight org.rusticlight.RusticLight1 SetEffect sas wave 1 red
+//
+//Effect, Color, Brightness and Paused are properties with PropertiesChanged signals, whoever changes them.
+//Notifications are also sent as the Notification signal.
+
+const NAME: &str = "org.rusticlight.RusticLight";
+const PATH: &str = "/org/rusticlight/RusticLight";
+const INTERFACE: &str = "org.rusticlight.RusticLight1";
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Bus
+{
+    Session,
+    System,
+}
+
+#[derive(Deserialize)]
+struct DbusConfig
+{
+    #[serde(default = "default_bus")]
+    bus: Bus,
+    #[serde(default)]
+    address: Option<String>, //Instead of bus
+}
+
+fn default_bus() -> Bus
+{
+    return Bus::Session;
+}
+
+impl Default for DbusConfig
+{
+    fn default() -> Self
+    {
+        DbusConfig { bus: default_bus(), address: None }
+    }
+}
+
+
+pub fn serve(sender: Sender<Request>)
+{
+    let dbus_config: DbusConfig = config::load("dbus.json").unwrap_or_default();
+    if let Err(e) = start(&dbus_config, sender)
+    {
+        println!("Unable to start the dbus service {}: {}", NAME, e);
+    }
+}
+
+fn start(dbus_config: &DbusConfig, sender: Sender<Request>) -> zbus::Result<()>
+{
+    let lighting = Lighting { sender: Mutex::new(sender.clone()) };
+    let connection = build(dbus_config, lighting)?;
+
+    thread::spawn(move ||
+    {
+        //Blocks until the animation handles requests
+        let events = match control::call(&sender, "subscribe", Value::Null)
+        {
+            Reply::Subscribed(events) => events,
+            _ => return,
+        };
+        for event in events.iter()
+        {
+            let data = &event["data"];
+            let result = match event["event"].as_str()
+            {
+                Some("state") => properties_changed(&connection, data),
+                Some("notification") =>
+                {
+                    let color = data["color"].as_str().unwrap_or("");
+                    connection.emit_signal(None::<()>, PATH, INTERFACE, "Notification", &(color,))
+                }
+                _ => continue,
+            };
+            if let Err(e) = result
+            {
+                println!("Dbus: {}", e);
+            }
+        }
+    });
+    return Ok(());
+}
+
+fn build(dbus_config: &DbusConfig, lighting: Lighting) -> zbus::Result<Connection>
+{
+    let builder = match (&dbus_config.address, dbus_config.bus)
+    {
+        (Some(address), _) => ConnectionBuilder::address(address.as_str())?,
+        (None, Bus::Session) => ConnectionBuilder::session()?,
+        (None, Bus::System) => ConnectionBuilder::system()?,
+    };
+    return builder.name(NAME)?.serve_at(PATH, lighting)?.build();
+}
+
+//Every property at once, cheaper than finding out what changed
+fn properties_changed(connection: &Connection, state: &Value) -> zbus::Result<()>
+{
+    let mut changed: HashMap<&str, zvariant::Value> = HashMap::new();
+    changed.insert("Effect", zvariant::Value::from(state["effect"].as_str().unwrap_or("").to_string()));
+    changed.insert("Color", zvariant::Value::from(state["color"].as_str().unwrap_or("").to_string()));
+    changed.insert("Brightness", zvariant::Value::from(state["brightness"].as_f64().unwrap_or(1.0)));
+    changed.insert("Paused", zvariant::Value::from(state["paused"].as_bool().unwrap_or(false)));
+
+    return connection.emit_signal(None::<()>, PATH, "org.freedesktop.DBus.Properties", "PropertiesChanged", &(INTERFACE, changed, Vec::<&str>::new()));
+}
+
+
+//================================================================================================================================================================================================
+//Interface
+
+struct Lighting
+{
+    sender: Mutex<Sender<Request>>, //The object server wants the interface to be Sync
+}
+
+impl Lighting
+{
+    fn call(&self, method: &str, params: Value) -> fdo::Result<Value>
+    {
+        let sender = self.sender.lock().unwrap().clone();
+        return match control::call(&sender, method, params)
+        {
+            Reply::Ok(result) => Ok(result),
+            Reply::Err(e) => Err(fdo::Error::Failed(e)),
+            Reply::Subscribed(_) => Err(fdo::Error::NotSupported(method.to_string())),
+        };
+    }
+
+    fn state(&self, field: &str) -> fdo::Result<Value>
+    {
+        return Ok(self.call("get-state", Value::Null)?[field].clone());
+    }
+}
+
+#[dbus_interface(name = "org.rusticlight.RusticLight1")]
+impl Lighting
+{
+    //(name, type, led count)
+    fn list_devices(&self) -> fdo::Result<Vec<(String, String, u32)>>
+    {
+        let devices = self.call("list-devices", Value::Null)?;
+        return Ok(devices.as_array().map_or(Vec::new(), |devices| devices.iter().map(|d|
+        (
+            d["name"].as_str().unwrap_or("").to_string(),
+            d["type"].as_str().unwrap_or("").to_string(),
+            d["leds"].as_u64().unwrap_or(0) as u32,
+        )).collect()));
+    }
+
+    fn list_effects(&self) -> Vec<String>
+    {
+        return effects::NAMES.iter().map(|name| name.to_string()).collect();
+    }
+
+    fn set_effect(&self, name: &str, colors: Vec<String>) -> fdo::Result<()>
+    {
+        self.call("set-effect", json!({ "name": name, "colors": colors }))?;
+        return Ok(());
+    }
+
+    fn set_color(&self, color: &str) -> fdo::Result<()>
+    {
+        self.call("set-color", json!({ "color": color }))?;
+        return Ok(());
+    }
+
+    fn set_device_brightness(&self, device: &str, brightness: f64) -> fdo::Result<()>
+    {
+        self.call("set-device-brightness", json!({ "device": device, "brightness": brightness }))?;
+        return Ok(());
+    }
+
+    fn set_leds(&self, device: &str, offset: u32, colors: Vec<String>) -> fdo::Result<()>
+    {
+        self.call("set-leds", json!({ "device": device, "offset": offset, "colors": colors }))?;
+        return Ok(());
+    }
+
+    //An empty device for every device
+    fn clear_leds(&self, device: &str) -> fdo::Result<()>
+    {
+        let params = if device.is_empty() { Value::Null } else { json!({ "device": device }) };
+        self.call("clear-leds", params)?;
+        return Ok(());
+    }
+
+    //pattern is blinks, pulses or solid, count is ignored for solid. No devices for every device.
+    fn notify(&self, color: &str, pattern: &str, count: u32, duration: u32, devices: Vec<String>) -> fdo::Result<()>
+    {
+        let color = Color::parse(color).ok_or(fdo::Error::InvalidArgs(format!("invalid color {}", color)))?;
+        let pattern = match pattern
+        {
+            "blinks" => Pattern::Blinks(count),
+            "pulses" => Pattern::Pulses(count),
+            "solid" => Pattern::Solid,
+            _ => return Err(fdo::Error::InvalidArgs(format!("invalid pattern {}, patterns: blinks, pulses, solid", pattern))),
+        };
+        let mut notification = Notification::new(color, pattern, duration as u64);
+        notification.devices = devices;
+
+        let params = serde_json::to_value(&notification).map_err(|e| fdo::Error::Failed(e.to_string()))?;
+        self.call("notify", params)?;
+        return Ok(());
+    }
+
+    #[dbus_interface(property)]
+    fn effect(&self) -> fdo::Result<String>
+    {
+        return Ok(self.state("effect")?.as_str().unwrap_or("").to_string());
+    }
+
+    #[dbus_interface(property)]
+    fn color(&self) -> fdo::Result<String>
+    {
+        return Ok(self.state("color")?.as_str().unwrap_or("").to_string());
+    }
+
+    #[dbus_interface(property)]
+    fn brightness(&self) -> fdo::Result<f64>
+    {
+        return Ok(self.state("brightness")?.as_f64().unwrap_or(1.0));
+    }
+
+    //Setters fail with a zbus error, the getters with a fdo one
+    #[dbus_interface(property)]
+    fn set_brightness(&self, brightness: f64) -> zbus::Result<()>
+    {
+        self.call("set-brightness", json!({ "brightness": brightness })).map_err(zbus::Error::from)?;
+        return Ok(());
+    }
+
+    #[dbus_interface(property)]
+    fn paused(&self) -> fdo::Result<bool>
+    {
+        return Ok(self.state("paused")?.as_bool().unwrap_or(false));
+    }
+
+    #[dbus_interface(property)]
+    fn set_paused(&self, paused: bool) -> zbus::Result<()>
+    {
+        self.call(if paused { "pause" } else { "resume" }, Value::Null).map_err(zbus::Error::from)?;
+        return Ok(());
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use zbus::blocking::{Proxy, ProxyBuilder};
+    use zbus::CacheProperties;
+
+    //A private dbus-daemon, killed when dropped
+    struct Daemon
+    {
+        process: Child,
+        address: String,
+    }
+
+    impl Drop for Daemon
+    {
+        fn drop(&mut self)
+        {
+            let _ = self.process.kill();
+            let _ = self.process.wait();
+        }
+    }
+
+    fn start_daemon() -> Option<Daemon>
+    {
+        let mut process = Command::new("dbus-daemon").args(&["--session", "--nofork", "--print-address"]).stdout(Stdio::piped()).stderr(Stdio::null()).spawn().ok()?;
+        let mut address = String::new();
+        BufReader::new(process.stdout.take()?).read_line(&mut address).ok()?;
+        return Some(Daemon { process, address: address.trim().to_string() });
+    }
+
+    fn proxy<'a>(connection: &'a Connection) -> Proxy<'a>
+    {
+        return ProxyBuilder::new_bare(connection)
+            .destination(NAME).unwrap()
+            .path(PATH).unwrap()
+            .interface(INTERFACE).unwrap()
+            .cache_properties(CacheProperties::No)
+            .build().unwrap();
+    }
+
+    //Needs dbus-daemon. Machines without one set RUSTIC_LIGHT_SKIP_DBUS_TESTS=1, so a missing daemon doesn't pass unnoticed.
+    #[test]
+    fn methods_properties_and_signals()
+    {
+        let daemon = match start_daemon()
+        {
+            Some(daemon) => daemon,
+            None if std::env::var_os("RUSTIC_LIGHT_SKIP_DBUS_TESTS").map_or(false, |skip| skip == "1") => return,
+            None => panic!("dbus-daemon is not installed, set RUSTIC_LIGHT_SKIP_DBUS_TESTS=1 to skip this test"),
+        };
+
+        let sender = control::spawn_virtual(&[("strip", 3), ("fan", 1)]);
+        let dbus_config = DbusConfig { bus: Bus::Session, address: Some(daemon.address.clone()) };
+        start(&dbus_config, sender).unwrap();
+
+        let connection = ConnectionBuilder::address(daemon.address.as_str()).unwrap().build().unwrap();
+        let lighting = proxy(&connection);
+
+        let devices: Vec<(String, String, u32)> = lighting.call("ListDevices", &()).unwrap();
+        assert_eq!(devices.iter().map(|d| (d.0.as_str(), d.2)).collect::<Vec<_>>(), vec![("strip", 3), ("fan", 1)]);
+        let effects: Vec<String> = lighting.call("ListEffects", &()).unwrap();
+        assert!(effects.contains(&"wave".to_string()));
+
+        let () = lighting.call("SetEffect", &("wave", vec!["red", "blue"])).unwrap();
+        assert_eq!(lighting.get_property::<String>("Effect").unwrap(), "wave");
+        let () = lighting.call("SetColor", &("#ff8000",)).unwrap();
+        assert_eq!(lighting.get_property::<String>("Color").unwrap(), "#ff8000");
+        lighting.set_property("Paused", true).unwrap();
+        assert!(lighting.get_property::<bool>("Paused").unwrap());
+        lighting.set_property("Paused", false).unwrap();
+
+        //Errors from the animation come back as dbus errors
+        assert!(lighting.call::<_, _, ()>("SetLeds", &("nothing", 0u32, vec!["red"])).is_err());
+        assert!(lighting.call::<_, _, ()>("SetEffect", &("nothing", Vec::<String>::new())).is_err());
+        assert!(lighting.call::<_, _, ()>("Notify", &("red", "twirls", 3u32, 1000u32, Vec::<String>::new())).is_err());
+
+        //Signals arrive on their own thread, so a missing one fails instead of hanging
+        let (ready, subscribed) = channel();
+        let (colors, notified) = channel();
+        let address = daemon.address.clone();
+        thread::spawn(move ||
+        {
+            let connection = ConnectionBuilder::address(address.as_str()).unwrap().build().unwrap();
+            let lighting = proxy(&connection);
+            let mut notifications = lighting.receive_signal("Notification").unwrap();
+            ready.send(()).unwrap();
+            if let Some(message) = notifications.next()
+            {
+                let (color,): (String,) = message.body().unwrap();
+                let _ = colors.send(color);
+            }
+        });
+        subscribed.recv_timeout(Duration::from_secs(5)).unwrap();
+        let () = lighting.call("Notify", &("red", "blinks", 2u32, 500u32, Vec::<String>::new())).unwrap();
+        assert_eq!(notified.recv_timeout(Duration::from_secs(5)).expect("no Notification signal"), "#ff0000");
+    }
+}
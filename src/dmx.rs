@@ -0,0 +1,430 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::color::Color;
+use crate::config;
+use crate::control::{self, Reply, Request};
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+//E1.31 (sACN) and Art-Net input, so lighting software can drive the devices like any other fixture.
+//Only runs when there is a dmx.json next to the exe:
+//{
+//    "timeout": 2500,
+//    "patches":
+//    [
+//        { "universe": 1, "channel": 1,   "device": "SK621", "first_led": 0, "led_count": 170 },
+//        { "universe": 2, "channel": 1,   "device": "SK621", "first_led": 170 },
+//        { "universe": 1, "channel": 511, "device": "JRgb1" }
+//    ]
+//}
+//Channels start at 1, every led takes 3 channels, red green blue. Patched devices show the stream instead of the effect,
+//until nothing arrived for timeout milliseconds or the sender ends the stream.
+
+const E131_PORT: u16 = 5568;
+const ARTNET_PORT: u16 = 6454;
+const CHANNELS: usize = 512;
+
+
+#[derive(Deserialize)]
+pub struct Patch
+{
+    pub universe: u16,
+    pub channel: usize,
+    pub device: String,
+    #[serde(default)]
+    pub first_led: usize,
+    pub led_count: Option<usize>, //None: as many as fit in the universe
+}
+
+#[derive(Deserialize)]
+pub struct DmxConfig
+{
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    pub patches: Vec<Patch>,
+}
+
+fn default_timeout() -> u64
+{
+    return 2500;
+}
+
+
+//================================================================================================================================================================================================
+//Packets
+
+pub struct DmxPacket
+{
+    pub universe: u16,
+    pub data: Vec<u8>,      //Channel 1 first
+    pub terminated: bool,   //The sender stopped the stream
+}
+
+//E1.31 data packet, root, framing and dmp layer
+pub fn parse_e131(packet: &[u8]) -> Option<DmxPacket>
+{
+    if packet.len() < 126 || &packet[4..16] != b"ASC-E1.17\0\0\0"
+    {
+        return None;
+    }
+    let root_vector = u32::from_be_bytes([packet[18], packet[19], packet[20], packet[21]]);
+    let framing_vector = u32::from_be_bytes([packet[40], packet[41], packet[42], packet[43]]);
+    if root_vector != 0x04 || framing_vector != 0x02 || packet[117] != 0x02
+    {
+        return None;
+    }
+
+    let options = packet[112];
+    let universe = u16::from_be_bytes([packet[113], packet[114]]);
+    let value_count = u16::from_be_bytes([packet[123], packet[124]]) as usize;
+    let start_code = packet[125];
+    if start_code != 0 || value_count == 0
+    {
+        return None;
+    }
+
+    //The value count includes the start code
+    let end = (125 + value_count).min(packet.len());
+    return Some(DmxPacket
+    {
+        universe,
+        data: packet[126..end].to_vec(),
+        terminated: options & 0x40 != 0,
+    });
+}
+
+//The other direction, for sending to fixtures. Data is at most 512 channels.
+pub fn e131_packet(universe: u16, sequence: u8, source: &str, cid: &[u8; 16], data: &[u8]) -> Vec<u8>
+{
+    let data = &data[..data.len().min(CHANNELS)];
+    let length = 126 + data.len();
+    let flags_and_length = |from: usize| (0x7000 | (length - from) as u16).to_be_bytes();
+
+    let mut packet = vec![0u8; length];
+    packet[0..2].copy_from_slice(&0x0010u16.to_be_bytes());             //Preamble size
+    packet[4..16].copy_from_slice(b"ASC-E1.17\0\0\0");
+    packet[16..18].copy_from_slice(&flags_and_length(16));
+    packet[18..22].copy_from_slice(&0x04u32.to_be_bytes());              //Root vector, data
+    packet[22..38].copy_from_slice(cid);
+    packet[38..40].copy_from_slice(&flags_and_length(38));
+    packet[40..44].copy_from_slice(&0x02u32.to_be_bytes());              //Framing vector, dmp
+    let source = &source.as_bytes()[..source.len().min(63)];
+    packet[44..44 + source.len()].copy_from_slice(source);
+    packet[108] = 100;                                                   //Priority
+    packet[111] = sequence;
+    packet[113..115].copy_from_slice(&universe.to_be_bytes());
+    packet[115..117].copy_from_slice(&flags_and_length(115));
+    packet[117] = 0x02;                                                  //Set property
+    packet[118] = 0xA1;                                                  //Address and data type
+    packet[121..123].copy_from_slice(&1u16.to_be_bytes());               //Address increment
+    packet[123..125].copy_from_slice(&(data.len() as u16 + 1).to_be_bytes());
+    packet[126..].copy_from_slice(data);
+    return packet;
+}
+
+//Art-Net ArtDmx, other opcodes are ignored
+pub fn parse_artnet(packet: &[u8]) -> Option<DmxPacket>
+{
+    if packet.len() < 18 || &packet[0..8] != b"Art-Net\0"
+    {
+        return None;
+    }
+    let opcode = u16::from_le_bytes([packet[8], packet[9]]);
+    if opcode != 0x5000
+    {
+        return None;
+    }
+
+    let universe = u16::from_le_bytes([packet[14], packet[15]]) & 0x7FFF; //net, sub-net and universe
+    let length = u16::from_be_bytes([packet[16], packet[17]]) as usize;
+    let end = (18 + length).min(packet.len());
+    return Some(DmxPacket
+    {
+        universe,
+        data: packet[18..end].to_vec(),
+        terminated: false,
+    });
+}
+
+
+//================================================================================================================================================================================================
+//Receiver
+
+pub fn serve(sender: Sender<Request>)
+{
+    let dmx_config: DmxConfig = match config::load("dmx.json")
+    {
+        Some(dmx_config) => dmx_config,
+        None => return,
+    };
+
+    let (packets, received) = channel();
+
+    match UdpSocket::bind(("0.0.0.0", E131_PORT))
+    {
+        Ok(socket) =>
+        {
+            //E1.31 goes to a multicast group per universe
+            for patch in dmx_config.patches.iter()
+            {
+                let group = Ipv4Addr::new(239, 255, (patch.universe >> 8) as u8, patch.universe as u8);
+                let _ = socket.join_multicast_v4(&group, &Ipv4Addr::UNSPECIFIED);
+            }
+            receive(socket, packets.clone(), parse_e131);
+        }
+        Err(e) => println!("Unable to listen for E1.31 on port {}: {}", E131_PORT, e),
+    }
+    match UdpSocket::bind(("0.0.0.0", ARTNET_PORT))
+    {
+        Ok(socket) => receive(socket, packets, parse_artnet),
+        Err(e) => println!("Unable to listen for Art-Net on port {}: {}", ARTNET_PORT, e),
+    }
+
+    thread::spawn(move ||
+    {
+        let timeout = Duration::from_millis(dmx_config.timeout);
+        let mut last_packet: Option<Instant> = None;
+
+        loop
+        {
+            match received.recv_timeout(timeout)
+            {
+                Ok(packet) =>
+                {
+                    if packet.terminated
+                    {
+                        release(&sender, &dmx_config.patches);
+                        last_packet = None;
+                        continue;
+                    }
+                    if apply(&sender, &dmx_config.patches, &packet)
+                    {
+                        last_packet = Some(Instant::now());
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+
+            //Back to the effect
+            if last_packet.map_or(false, |last| last.elapsed() >= timeout)
+            {
+                release(&sender, &dmx_config.patches);
+                last_packet = None;
+            }
+        }
+    });
+}
+
+fn receive(socket: UdpSocket, packets: Sender<DmxPacket>, parse: fn(&[u8]) -> Option<DmxPacket>)
+{
+    thread::spawn(move ||
+    {
+        let mut buffer = [0u8; 1024];
+        loop
+        {
+            let size = match socket.recv(&mut buffer)
+            {
+                Ok(size) => size,
+                Err(_) => continue,
+            };
+            if let Some(packet) = parse(&buffer[..size])
+            {
+                if packets.send(packet).is_err()
+                {
+                    return;
+                }
+            }
+        }
+    });
+}
+
+//True when the universe is patched
+fn apply(sender: &Sender<Request>, patches: &[Patch], packet: &DmxPacket) -> bool
+{
+    let mut patched = false;
+    for patch in patches.iter().filter(|p| p.universe == packet.universe)
+    {
+        let start = patch.channel.max(1) - 1;
+        let fits = (CHANNELS.saturating_sub(start)) / 3;
+        let led_count = patch.led_count.unwrap_or(fits).min(fits);
+
+        let colors = (0..led_count).map(|led|
+        {
+            let channel = start + led * 3;
+            let value = |offset: usize| packet.data.get(channel + offset).cloned().unwrap_or(0);
+            return Color::new(value(0), value(1), value(2));
+        }).collect::<Vec<Color>>();
+
+        call(sender, "set-leds", json!({ "device": patch.device, "colors": colors, "offset": patch.first_led }));
+        patched = true;
+    }
+    return patched;
+}
+
+//Back to the effect on the patched devices, led colors others set stay
+fn release(sender: &Sender<Request>, patches: &[Patch])
+{
+    let mut devices: Vec<&str> = patches.iter().map(|p| p.device.as_str()).collect();
+    devices.sort_unstable();
+    devices.dedup();
+    for device in devices
+    {
+        call(sender, "clear-leds", json!({ "device": device }));
+    }
+}
+
+fn call(sender: &Sender<Request>, method: &str, params: Value)
+{
+    if let Reply::Err(e) = control::call(sender, method, params)
+    {
+        println!("Dmx: {}", e);
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Tests
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const CID: [u8; 16] = [7; 16];
+
+    fn artnet_packet(universe: u16, length: u16, data: &[u8]) -> Vec<u8>
+    {
+        let mut packet = b"Art-Net\0".to_vec();
+        packet.extend_from_slice(&0x5000u16.to_le_bytes());
+        packet.extend_from_slice(&14u16.to_be_bytes()); //Protocol version
+        packet.extend_from_slice(&[1, 0]);               //Sequence, physical
+        packet.extend_from_slice(&universe.to_le_bytes());
+        packet.extend_from_slice(&length.to_be_bytes());
+        packet.extend_from_slice(data);
+        return packet;
+    }
+
+    #[test]
+    fn parses_e131()
+    {
+        let packet = parse_e131(&e131_packet(3, 1, "console", &CID, &[1, 2, 3])).unwrap();
+        assert_eq!(packet.universe, 3);
+        assert_eq!(packet.data, vec![1, 2, 3]);
+        assert!(!packet.terminated);
+
+        let mut terminated = e131_packet(3, 2, "console", &CID, &[]);
+        terminated[112] |= 0x40;
+        let packet = parse_e131(&terminated).unwrap();
+        assert!(packet.terminated);
+        assert!(packet.data.is_empty());
+
+        //Only as many channels as there are
+        let mut long_count = e131_packet(1, 1, "console", &CID, &[9, 9]);
+        long_count[123..125].copy_from_slice(&513u16.to_be_bytes());
+        assert_eq!(parse_e131(&long_count).unwrap().data, vec![9, 9]);
+
+        assert_eq!(parse_e131(&e131_packet(1, 1, "console", &CID, &[0; 600])).unwrap().data.len(), CHANNELS);
+    }
+
+    #[test]
+    fn rejects_malformed_e131()
+    {
+        let packet = e131_packet(1, 1, "console", &CID, &[1, 2, 3]);
+        assert!(parse_e131(&packet[..125]).is_none());
+        assert!(parse_e131(&[]).is_none());
+
+        let broken = |index: usize, value: u8|
+        {
+            let mut broken = packet.clone();
+            broken[index] = value;
+            return parse_e131(&broken);
+        };
+        assert!(broken(4, b'X').is_none());   //Identifier
+        assert!(broken(21, 0x08).is_none());  //Root vector
+        assert!(broken(43, 0x01).is_none());  //Framing vector
+        assert!(broken(117, 0x01).is_none()); //Dmp vector
+        assert!(broken(125, 0xDD).is_none()); //Start code, not dimmer levels
+
+        //No values at all, not even the start code
+        let mut empty = packet.clone();
+        empty[123..125].copy_from_slice(&0u16.to_be_bytes());
+        assert!(parse_e131(&empty).is_none());
+    }
+
+    #[test]
+    fn parses_artnet()
+    {
+        let packet = parse_artnet(&artnet_packet(2, 3, &[4, 5, 6])).unwrap();
+        assert_eq!(packet.universe, 2);
+        assert_eq!(packet.data, vec![4, 5, 6]);
+        assert!(!packet.terminated);
+
+        //The top bit isn't part of the port address
+        assert_eq!(parse_artnet(&artnet_packet(0x8001, 0, &[])).unwrap().universe, 1);
+        //Only as many channels as there are
+        assert_eq!(parse_artnet(&artnet_packet(0, 512, &[1, 2])).unwrap().data, vec![1, 2]);
+    }
+
+    #[test]
+    fn rejects_malformed_artnet()
+    {
+        let packet = artnet_packet(0, 3, &[4, 5, 6]);
+        assert!(parse_artnet(&packet[..17]).is_none());
+        assert!(parse_artnet(&[]).is_none());
+
+        let mut name = packet.clone();
+        name[0] = b'X';
+        assert!(parse_artnet(&name).is_none());
+
+        //ArtPoll
+        let mut poll = packet.clone();
+        poll[8..10].copy_from_slice(&0x2000u16.to_le_bytes());
+        assert!(parse_artnet(&poll).is_none());
+    }
+
+    fn patch(universe: u16, device: &str) -> Patch
+    {
+        return Patch { universe, channel: 1, device: device.to_string(), first_led: 0, led_count: None };
+    }
+
+    fn colors(sender: &Sender<Request>) -> Value
+    {
+        return match control::call(sender, "get-frame", Value::Null)
+        {
+            Reply::Ok(frame) => frame.as_array().unwrap().iter().map(|d| d["colors"].clone()).collect(),
+            _ => panic!("no frame"),
+        };
+    }
+
+    #[test]
+    fn releases_only_the_patched_devices()
+    {
+        let sender = crate::control::spawn_virtual(&[("strip", 2), ("fan", 1), ("case", 1)]);
+        let patches = vec![patch(1, "strip"), patch(2, "strip"), patch(3, "fan")];
+
+        //Set by something else than dmx
+        call(&sender, "set-leds", json!({ "device": "case", "colors": ["#0000ff"] }));
+        let packet = DmxPacket { universe: 1, data: vec![255, 0, 0, 0, 255, 0], terminated: false };
+        assert!(apply(&sender, &patches, &packet));
+        assert!(!apply(&sender, &patches, &DmxPacket { universe: 9, data: vec![255; 3], terminated: false }));
+
+        let start = Instant::now();
+        while colors(&sender)[0] != json!(["#ff0000", "#00ff00"])
+        {
+            assert!(start.elapsed() < Duration::from_secs(5), "the strip never showed the packet");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        release(&sender, &patches);
+        let start = Instant::now();
+        while colors(&sender) != json!([["#000000", "#000000"], ["#000000"], ["#0000ff"]])
+        {
+            assert!(start.elapsed() < Duration::from_secs(5), "unexpected colors after the release: {}", colors(&sender));
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,620 @@
+use crate::animation::{self, ColorSpectrum, Effect, WaveShape};
+use crate::color::Color;
+use crate::frame::Frame;
+use crate::scene::Point;
+use serde::Deserialize;
+use std::f32::consts::PI;
+
+
+//Software versions of the classic hardware effects, so they look the same on every device.
+//Speeds are in cycles per second, directions are in scene coordinates, see scene.rs
+
+
+//Scene units covering the whole spectrum, half a meter when the scene is in millimeters
+const SPECTRUM_WAVELENGTH: f32 = 500.0;
+
+//What the command line and the remote interfaces can change about an effect, everything left out keeps the defaults of the effect
+#[derive(Clone, Default, Deserialize)]
+pub struct EffectOptions
+{
+    #[serde(default)]
+    pub colors: Vec<Color>,     //Effects that need fewer colors use the first ones
+    #[serde(default)]
+    pub speed: Option<f32>,     //Cycles per second
+    #[serde(default)]
+    pub direction: Option<Point>,
+    //Offsets and flow of the spectrum effect. Remote interfaces get spectrum.json, the default has neither.
+    #[serde(skip, default = "ColorSpectrum::load")]
+    pub spectrum: ColorSpectrum,
+}
+
+//Effect by name, for the command line and the remote interfaces
+pub fn create(name: &str, options: &EffectOptions) -> Option<Box<dyn Effect>>
+{
+    let colors = &options.colors;
+    let color = |index: usize, default: Color| colors.get(index).cloned().unwrap_or(default);
+    let palette = |default: Vec<Color>| if colors.is_empty() { default } else { colors.to_vec() };
+    let speed = |default: f32| options.speed.unwrap_or(default);
+    let direction = |default: Point| options.direction.unwrap_or(default);
+    let rainbow = vec![Color::new(255, 0, 0), Color::new(255, 255, 0), Color::new(0, 255, 0), Color::new(0, 255, 255), Color::new(0, 0, 255), Color::new(255, 0, 255)];
+
+    let effect: Box<dyn Effect> = match name
+    {
+        "spectrum"        => Box::new(options.spectrum.clone()),
+        //Along the direction across the scene, and outwards from the scene origin
+        "spectrum-wave"   => Box::new(animation::SpectrumWave { shape: WaveShape::Linear { direction: direction(Point::new(1.0, 0.0, 0.0)) }, wavelength: SPECTRUM_WAVELENGTH }),
+        "spectrum-radial" => Box::new(animation::SpectrumWave { shape: WaveShape::Radial { center: Point::default() }, wavelength: SPECTRUM_WAVELENGTH }),
+        "solid"           => Box::new(animation::SolidColor(color(0, Color::new(255, 255, 255)))),
+        "breathing"       =>
+        {
+            let mut breathing = Breathing::new(palette(vec![Color::new(0, 128, 255)]));
+            breathing.speed = speed(breathing.speed);
+            Box::new(breathing)
+        }
+        "strobe"          =>
+        {
+            let mut strobe = Strobe::new(color(0, Color::new(255, 255, 255)));
+            strobe.speed = speed(strobe.speed);
+            Box::new(strobe)
+        }
+        "wave"            =>
+        {
+            let mut wave = ColorWave::new(palette(rainbow));
+            wave.speed = speed(wave.speed);
+            wave.direction = direction(wave.direction);
+            Box::new(wave)
+        }
+        "comet"           =>
+        {
+            let mut comet = Comet::new(color(0, Color::new(0, 200, 255)));
+            comet.speed = speed(comet.speed);
+            comet.direction = direction(comet.direction);
+            Box::new(comet)
+        }
+        "fire"            =>
+        {
+            let mut fire = Fire::new();
+            if !colors.is_empty()
+            {
+                fire.colors = colors.to_vec();
+            }
+            fire.speed = speed(fire.speed);
+            fire.direction = direction(fire.direction);
+            Box::new(fire)
+        }
+        "twinkle"         =>
+        {
+            let mut twinkle = Twinkle::new(palette(vec![Color::new(255, 255, 255)]));
+            twinkle.speed = speed(twinkle.speed);
+            Box::new(twinkle)
+        }
+        "gradient"        =>
+        {
+            let mut gradient = Gradient::new(palette(vec![Color::new(255, 0, 128), Color::new(0, 128, 255)]));
+            gradient.speed = speed(gradient.speed);
+            gradient.direction = direction(gradient.direction);
+            Box::new(gradient)
+        }
+        "color-cycle"     =>
+        {
+            let mut color_cycle = ColorCycle::new(palette(rainbow));
+            color_cycle.speed = speed(color_cycle.speed);
+            Box::new(color_cycle)
+        }
+        _ => return None,
+    };
+    return Some(effect);
+}
+
+pub const NAMES: [&str; 12] = ["spectrum", "spectrum-wave", "spectrum-radial", "solid", "breathing", "strobe", "wave", "comet", "fire", "twinkle", "gradient", "color-cycle"];
+
+
+//================================================================================================================================================================================================
+//Helpers
+
+fn seconds(millis: u128) -> f64
+{
+    return millis as f64 / 1000.0;
+}
+
+//Fraction of the current cycle, 0.0 - 1.0
+fn phase(millis: u128, speed: f32) -> f32
+{
+    return (seconds(millis) * speed as f64).rem_euclid(1.0) as f32;
+}
+
+//Color at position 0.0 - 1.0 in a palette that wraps around
+fn palette_at(colors: &[Color], position: f32) -> Color
+{
+    if colors.is_empty()
+    {
+        return Color::new(0, 0, 0);
+    }
+
+    let scaled = position.rem_euclid(1.0) * colors.len() as f32;
+    let index = scaled as usize % colors.len();
+    return colors[index].blend(colors[(index + 1) % colors.len()], scaled.fract());
+}
+
+//Color at position 0.0 - 1.0 from the first to the last palette color, without wrapping around
+fn gradient_at(colors: &[Color], position: f32) -> Color
+{
+    if colors.len() < 2
+    {
+        return colors.first().cloned().unwrap_or(Color::new(0, 0, 0));
+    }
+
+    let scaled = position.max(0.0).min(1.0) * (colors.len() - 1) as f32;
+    let index = (scaled as usize).min(colors.len() - 2);
+    return colors[index].blend(colors[index + 1], scaled - index as f32);
+}
+
+//Position of every led along the direction, 0.0 at the first led and 1.0 at the last
+fn along(frame: &Frame, direction: Point) -> Vec<Vec<f32>>
+{
+    let distances: Vec<Vec<f32>> = frame.devices.iter().map(|d| d.leds.iter().map(|l| l.position.dot(direction)).collect()).collect();
+
+    let min = distances.iter().flatten().cloned().fold(f32::MAX, f32::min);
+    let max = distances.iter().flatten().cloned().fold(f32::MIN, f32::max);
+    let extent = (max - min).max(f32::EPSILON);
+
+    return distances.iter().map(|d| d.iter().map(|distance| (distance - min) / extent).collect()).collect();
+}
+
+//Per-led state for effects that remember something about every led
+fn led_state(frame: &Frame, state: &mut Vec<Vec<f32>>)
+{
+    if state.len() != frame.devices.len() || state.iter().zip(frame.devices.iter()).any(|(s, d)| s.len() != d.leds.len())
+    {
+        *state = frame.devices.iter().map(|d| vec![0.0; d.leds.len()]).collect();
+    }
+}
+
+//Xorshift, good enough for flickering leds and doesn't need a dependency
+struct Random(u64);
+
+impl Random
+{
+    fn new() -> Self
+    {
+        return Random(0x2545_F491_4F6C_DD1D);
+    }
+
+    //0.0 - 1.0
+    fn next(&mut self) -> f32
+    {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        return (self.0 >> 40) as f32 / (1u64 << 24) as f32;
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Effects
+
+//Fades in and out, moving to the next color of the palette every breath
+pub struct Breathing
+{
+    pub colors: Vec<Color>,
+    pub speed: f32,
+}
+
+impl Breathing
+{
+    pub fn new(colors: Vec<Color>) -> Self
+    {
+        Breathing
+        {
+            colors,
+            speed: 0.25,
+        }
+    }
+}
+
+impl Effect for Breathing
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        if self.colors.is_empty()
+        {
+            return;
+        }
+
+        let breath = (seconds(millis) * self.speed as f64) as usize;
+        let brightness = (1.0 - (phase(millis, self.speed) * 2.0 * PI).cos()) / 2.0;
+        frame.fill(self.colors[breath % self.colors.len()].scale(brightness));
+    }
+}
+
+
+//Short flashes of full color
+pub struct Strobe
+{
+    pub color: Color,
+    pub speed: f32,
+    pub duty: f32, //Part of every cycle that is lit, 0.0 - 1.0
+}
+
+impl Strobe
+{
+    pub fn new(color: Color) -> Self
+    {
+        Strobe
+        {
+            color,
+            speed: 8.0,
+            duty: 0.15,
+        }
+    }
+}
+
+impl Effect for Strobe
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let color = if phase(millis, self.speed) < self.duty { self.color } else { Color::new(0, 0, 0) };
+        frame.fill(color);
+    }
+}
+
+
+//The palette stretched over the scene, traveling along the direction
+pub struct ColorWave
+{
+    pub colors: Vec<Color>,
+    pub speed: f32,
+    pub direction: Point,
+    pub wavelength: f32, //Part of the scene covered by the whole palette
+}
+
+impl ColorWave
+{
+    pub fn new(colors: Vec<Color>) -> Self
+    {
+        ColorWave
+        {
+            colors,
+            speed: 0.2,
+            direction: Point::new(1.0, 0.0, 0.0),
+            wavelength: 1.0,
+        }
+    }
+}
+
+impl Effect for ColorWave
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let positions = along(frame, self.direction);
+        let offset = phase(millis, self.speed);
+
+        for (device, device_positions) in frame.devices.iter_mut().zip(positions.iter())
+        {
+            for (led, position) in device.leds.iter_mut().zip(device_positions.iter())
+            {
+                led.color = palette_at(&self.colors, position / self.wavelength - offset);
+            }
+        }
+    }
+}
+
+
+//A bright head with a fading tail, crossing the scene along the direction. Also known as meteor.
+pub struct Comet
+{
+    pub color: Color,
+    pub speed: f32,
+    pub direction: Point,
+    pub tail: f32, //Part of the scene covered by the tail
+}
+
+const MIN_COMET_TAIL: f32 = 0.01;
+
+impl Comet
+{
+    pub fn new(color: Color) -> Self
+    {
+        Comet
+        {
+            color,
+            speed: 0.5,
+            direction: Point::new(1.0, 0.0, 0.0),
+            tail: 0.3,
+        }
+    }
+}
+
+impl Effect for Comet
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let positions = along(frame, self.direction);
+
+        //Without a tail there would be nothing to show, and a division by zero
+        let tail = self.tail.max(MIN_COMET_TAIL);
+
+        //The head starts before the scene and ends after it, so the tail fully enters and leaves
+        let head = phase(millis, self.speed) * (1.0 + tail * 2.0) - tail;
+
+        for (device, device_positions) in frame.devices.iter_mut().zip(positions.iter())
+        {
+            for (led, position) in device.leds.iter_mut().zip(device_positions.iter())
+            {
+                let behind = head - position;
+                let brightness = if behind < 0.0 || behind > tail { 0.0 } else { 1.0 - behind / tail };
+                led.color = self.color.scale(brightness * brightness);
+            }
+        }
+    }
+}
+
+
+//Flickering flames, hottest at the bottom. The direction points up.
+pub struct Fire
+{
+    pub colors: Vec<Color>, //From cold to hot
+    pub speed: f32,         //How fast the flames flicker
+    pub direction: Point,
+    heat: Vec<Vec<f32>>,
+    random: Random,
+    last_millis: u128,
+}
+
+impl Fire
+{
+    pub fn new() -> Self
+    {
+        Fire
+        {
+            colors: vec![Color::new(0, 0, 0), Color::new(160, 0, 0), Color::new(255, 80, 0), Color::new(255, 200, 40)],
+            speed: 1.0,
+            direction: Point::new(0.0, -1.0, 0.0),
+            heat: Vec::new(),
+            random: Random::new(),
+            last_millis: 0,
+        }
+    }
+}
+
+impl Effect for Fire
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        led_state(frame, &mut self.heat);
+        let positions = along(frame, self.direction);
+        let elapsed = seconds(millis.saturating_sub(self.last_millis)) as f32 * self.speed;
+        self.last_millis = millis;
+
+        for (device, (heat, device_positions)) in frame.devices.iter_mut().zip(self.heat.iter_mut().zip(positions.iter()))
+        {
+            for (led, (led_heat, height)) in device.leds.iter_mut().zip(heat.iter_mut().zip(device_positions.iter()))
+            {
+                //Cool down, faster near the top, and randomly flare up again
+                *led_heat = (*led_heat - elapsed * (1.0 + height * 2.0)).max(0.0);
+                if self.random.next() < elapsed * 8.0
+                {
+                    *led_heat = led_heat.max((1.0 - height * 0.7) * (0.6 + self.random.next() * 0.4));
+                }
+
+                led.color = gradient_at(&self.colors, *led_heat);
+            }
+        }
+    }
+}
+
+
+//Random leds light up in a palette color and fade out, like stars
+pub struct Twinkle
+{
+    pub colors: Vec<Color>,
+    pub background: Color,
+    pub speed: f32,   //Twinkles per led per second
+    pub fade: f32,    //Seconds
+    brightness: Vec<Vec<f32>>,
+    led_colors: Vec<Vec<Color>>,
+    random: Random,
+    last_millis: u128,
+}
+
+impl Twinkle
+{
+    pub fn new(colors: Vec<Color>) -> Self
+    {
+        Twinkle
+        {
+            colors,
+            background: Color::new(0, 0, 0),
+            speed: 0.3,
+            fade: 1.0,
+            brightness: Vec::new(),
+            led_colors: Vec::new(),
+            random: Random::new(),
+            last_millis: 0,
+        }
+    }
+}
+
+impl Effect for Twinkle
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        led_state(frame, &mut self.brightness);
+        if self.led_colors.len() != self.brightness.len()
+        {
+            self.led_colors = self.brightness.iter().map(|b| vec![Color::new(0, 0, 0); b.len()]).collect();
+        }
+        let elapsed = seconds(millis.saturating_sub(self.last_millis)) as f32;
+        self.last_millis = millis;
+
+        for (device_index, device) in frame.devices.iter_mut().enumerate()
+        {
+            for (led_index, led) in device.leds.iter_mut().enumerate()
+            {
+                let brightness = &mut self.brightness[device_index][led_index];
+                *brightness = (*brightness - elapsed / self.fade).max(0.0);
+
+                if *brightness == 0.0 && !self.colors.is_empty() && self.random.next() < elapsed * self.speed
+                {
+                    *brightness = 1.0;
+                    self.led_colors[device_index][led_index] = self.colors[(self.random.next() * self.colors.len() as f32) as usize % self.colors.len()];
+                }
+
+                led.color = self.background.blend(self.led_colors[device_index][led_index], *brightness);
+            }
+        }
+    }
+}
+
+
+//The palette spread over the scene along the direction, without wrapping around. A speed above 0 slowly shifts it.
+pub struct Gradient
+{
+    pub colors: Vec<Color>,
+    pub speed: f32,
+    pub direction: Point,
+}
+
+impl Gradient
+{
+    pub fn new(colors: Vec<Color>) -> Self
+    {
+        Gradient
+        {
+            colors,
+            speed: 0.0,
+            direction: Point::new(1.0, 0.0, 0.0),
+        }
+    }
+}
+
+impl Effect for Gradient
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let positions = along(frame, self.direction);
+        let offset = phase(millis, self.speed);
+
+        for (device, device_positions) in frame.devices.iter_mut().zip(positions.iter())
+        {
+            for (led, position) in device.leds.iter_mut().zip(device_positions.iter())
+            {
+                //Bounce back and forth when shifting, so the ends never jump
+                let mut position = (position + offset * 2.0).rem_euclid(2.0);
+                if position > 1.0
+                {
+                    position = 2.0 - position;
+                }
+                led.color = gradient_at(&self.colors, position);
+            }
+        }
+    }
+}
+
+
+//Every led smoothly moves through the palette together
+pub struct ColorCycle
+{
+    pub colors: Vec<Color>,
+    pub speed: f32, //Full palette cycles per second
+}
+
+impl ColorCycle
+{
+    pub fn new(colors: Vec<Color>) -> Self
+    {
+        ColorCycle
+        {
+            colors,
+            speed: 0.05,
+        }
+    }
+}
+
+impl Effect for ColorCycle
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        frame.fill(palette_at(&self.colors, phase(millis, self.speed)));
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::color::RgbDevice;
+    use crate::recording::VirtualDevice;
+    use crate::scene::Scene;
+
+    //A strip of leds 10 apart, left to right
+    fn frame(leds: usize) -> Frame
+    {
+        let devices: Vec<Box<dyn RgbDevice>> = vec![Box::new(VirtualDevice::new("strip", leds, false))];
+        return Frame::new(&Scene::default(), &devices);
+    }
+
+    fn colors(frame: &Frame) -> Vec<Color>
+    {
+        return frame.devices[0].leds.iter().map(|led| led.color).collect();
+    }
+
+    #[test]
+    fn every_name_creates_an_effect()
+    {
+        for name in NAMES.iter()
+        {
+            let mut effect = create(name, &EffectOptions::default()).unwrap();
+            effect.render(&mut frame(8), 1000);
+        }
+        assert!(create("disco", &EffectOptions::default()).is_none());
+    }
+
+    #[test]
+    fn speed_option_is_used()
+    {
+        let white = Color::new(255, 255, 255);
+        let mut frame = frame(1);
+
+        //Lit for the first 15% of every cycle, at the default 8 per second 100ms is past that
+        create("strobe", &EffectOptions::default()).unwrap().render(&mut frame, 100);
+        assert_eq!(colors(&frame), vec![Color::new(0, 0, 0)]);
+
+        let options = EffectOptions { speed: Some(1.0), ..EffectOptions::default() };
+        create("strobe", &options).unwrap().render(&mut frame, 100);
+        assert_eq!(colors(&frame), vec![white]);
+    }
+
+    #[test]
+    fn direction_option_is_used()
+    {
+        let options = |x: f32| EffectOptions
+        {
+            colors: vec![Color::new(255, 0, 0), Color::new(0, 0, 255)],
+            direction: Some(Point::new(x, 0.0, 0.0)),
+            ..EffectOptions::default()
+        };
+        let mut frame = frame(2);
+
+        create("gradient", &options(1.0)).unwrap().render(&mut frame, 0);
+        assert_eq!(colors(&frame), vec![Color::new(255, 0, 0), Color::new(0, 0, 255)]);
+
+        create("gradient", &options(-1.0)).unwrap().render(&mut frame, 0);
+        assert_eq!(colors(&frame), vec![Color::new(0, 0, 255), Color::new(255, 0, 0)]);
+    }
+
+    #[test]
+    fn comet_without_a_tail_still_has_a_head()
+    {
+        let mut comet = Comet::new(Color::new(255, 255, 255));
+        comet.tail = 0.0;
+        let mut frame = frame(11);
+
+        //Halfway through the cycle the head is on the middle led
+        comet.render(&mut frame, 1000);
+        let lit: Vec<usize> = colors(&frame).iter().enumerate().filter(|(_, c)| c.r > 0).map(|(i, _)| i).collect();
+        assert_eq!(lit, vec![5]);
+    }
+}
@@ -0,0 +1,104 @@
+use crate::color::{Color, DeviceType, RgbDevice};
+use crate::scene::{Point, Scene};
+
+
+//The colors of every led of every device for a single moment. Effects render into a frame, which is then applied to the devices.
+#[derive(Clone)]
+pub struct Frame
+{
+    pub devices: Vec<DeviceFrame>,
+}
+
+#[derive(Clone)]
+pub struct DeviceFrame
+{
+    pub name: String,
+    pub leds: Vec<Led>,
+    pub led_names: Vec<Option<String>>, //Same index as leds, for leds like keyboard keys
+    pub device_type: DeviceType,
+}
+
+#[derive(Clone, Copy)]
+pub struct Led
+{
+    pub position: Point,
+    pub color: Color,
+}
+
+impl Frame
+{
+    pub fn new(scene: &Scene, rgb_devices: &Vec<Box<dyn RgbDevice>>) -> Self
+    {
+        let devices = rgb_devices.iter().enumerate().map(|(index, device)|
+        {
+            DeviceFrame
+            {
+                name: device.get_name().clone(),
+                leds: scene.led_positions(device.as_ref(), index).into_iter().map(|position| Led { position, color: Color::new(0, 0, 0) }).collect(),
+                led_names: (0..device.get_led_count()).map(|led| device.get_led_name(led)).collect(),
+                device_type: device.get_device_type(),
+            }
+        }).collect();
+
+        return Frame { devices };
+    }
+
+    //Devices have to be in the same order as when the frame was created
+    pub fn apply(&self, rgb_devices: &mut Vec<Box<dyn RgbDevice>>)
+    {
+        for (device, device_frame) in rgb_devices.iter_mut().zip(self.devices.iter())
+        {
+            let first = match device_frame.leds.first()
+            {
+                Some(led) => led.color,
+                None => continue,
+            };
+
+            //Devices can often do a single color cheaper than per-led
+            if device_frame.leds.iter().all(|led| led.color == first)
+            {
+                device.set_color(first);
+                continue;
+            }
+
+            for (index, led) in device_frame.leds.iter().enumerate()
+            {
+                device.set_led_color(index, led.color);
+            }
+        }
+    }
+
+    //Color every led by its position
+    pub fn paint<F: FnMut(Point) -> Color>(&mut self, mut color_at: F)
+    {
+        for device in self.devices.iter_mut()
+        {
+            for led in device.leds.iter_mut()
+            {
+                led.color = color_at(led.position);
+            }
+        }
+    }
+
+    pub fn fill(&mut self, color: Color)
+    {
+        self.paint(|_| color);
+    }
+
+    //Smallest and largest coordinates of all leds, to scale effects to the scene
+    pub fn bounds(&self) -> (Point, Point)
+    {
+        let mut leds = self.devices.iter().flat_map(|d| d.leds.iter());
+        let first = match leds.next()
+        {
+            Some(led) => led.position,
+            None => return (Point::default(), Point::default()),
+        };
+
+        return leds.fold((first, first), |(min, max), led|
+        (
+            Point::new(min.x.min(led.position.x), min.y.min(led.position.y), min.z.min(led.position.z)),
+            Point::new(max.x.max(led.position.x), max.y.max(led.position.y), max.z.max(led.position.z)),
+        ));
+    }
+}
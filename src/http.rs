@@ -0,0 +1,612 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+use crate::config;
+use crate::control::{self, Reply};
+use crate::effects;
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+
+//REST api over the same commands as the control socket, see control.rs. Configured in http.json:
+//{ "address": "127.0.0.1:8080" }
+//
+//    GET  /api/state                 GET  /api/devices              GET  /api/devices/<name>
+//    GET  /api/effects               GET  /api/frame
+//    PUT  /api/effect      { "name": "wave", "colors": ["red"] }
+//    PUT  /api/color       { "color": "#ff8000" }
+//    PUT  /api/brightness  { "brightness": 0.5 }
+//    POST /api/pause                 POST /api/resume
+//    POST /api/notify      { "color": "red", "pattern": { "blinks": 3 } }
+//    GET  /ws                        websocket, every event and 30 frames per second as json text messages
+//
+//Plain http/1.1 on std sockets, one request per connection. The websocket needs the socket itself to poll the client between events.
+//
+//Web pages on other sites must not drive the leds: PUT and POST need Content-Type: application/json, which browsers only send
+//cross-site after a preflight request that is never answered, and the websocket only accepts pages served from this address.
+
+//Limits on what a client may send
+const MAX_HEAD: usize = 16 * 1024;
+const MAX_BODY: usize = 1024 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+//How long the websocket waits for the client before forwarding events again
+const WEBSOCKET_POLL: Duration = Duration::from_millis(10);
+
+
+#[derive(Deserialize)]
+struct HttpConfig
+{
+    address: String,
+}
+
+impl Default for HttpConfig
+{
+    fn default() -> Self
+    {
+        //Only this machine, unless configured otherwise
+        HttpConfig { address: "127.0.0.1:8080".to_string() }
+    }
+}
+
+
+pub fn serve(sender: Sender<control::Request>)
+{
+    let http_config: HttpConfig = config::load("http.json").unwrap_or_default();
+
+    match listen(&http_config.address, sender)
+    {
+        Ok(address) => println!("Http api on http://{}/api", address),
+        Err(e) => println!("Unable to start the http server on {}: {}", http_config.address, e),
+    }
+}
+
+//Accepts connections on a thread of its own, returns the address actually bound
+fn listen(address: &str, sender: Sender<control::Request>) -> Result<SocketAddr, String>
+{
+    let listener = TcpListener::bind(address).map_err(|e| e.to_string())?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+
+    thread::spawn(move ||
+    {
+        for stream in listener.incoming()
+        {
+            match stream
+            {
+                Ok(stream) =>
+                {
+                    let sender = sender.clone();
+                    thread::spawn(move || connection(stream, sender));
+                }
+                Err(e) => println!("Http connection failed: {}", e),
+            }
+        }
+    });
+    return Ok(address);
+}
+
+
+//================================================================================================================================================================================================
+//Requests
+
+struct HttpRequest
+{
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl HttpRequest
+{
+    fn header(&self, name: &str) -> Option<&str>
+    {
+        return self.headers.iter().find(|(field, _)| field.eq_ignore_ascii_case(name)).map(|(_, value)| value.as_str());
+    }
+}
+
+fn connection(mut stream: TcpStream, sender: Sender<control::Request>)
+{
+    let _ = stream.set_read_timeout(Some(REQUEST_TIMEOUT));
+    let mut reader = match stream.try_clone()
+    {
+        Ok(clone) => BufReader::new(clone),
+        Err(_) => return,
+    };
+
+    let request = match read_request(&mut reader)
+    {
+        Ok(request) => request,
+        Err(e) =>
+        {
+            let _ = respond(&mut stream, 400, &json!({ "error": e }));
+            return;
+        }
+    };
+
+    if request.path.trim_end_matches('/') == "/ws"
+    {
+        //Whatever the client sent after the handshake is already in the reader
+        let unread = reader.buffer().to_vec();
+        websocket(stream, unread, &request, sender);
+        return;
+    }
+
+    let (status, result) = handle(&request, &sender);
+    let _ = respond(&mut stream, status, &result);
+}
+
+fn read_request<R: BufRead>(reader: &mut R) -> Result<HttpRequest, String>
+{
+    let mut lines = Vec::new();
+    let mut size = 0;
+    loop
+    {
+        let mut line = String::new();
+        let read = reader.by_ref().take((MAX_HEAD - size) as u64).read_line(&mut line).map_err(|e| e.to_string())?;
+        size += read;
+        if read == 0 || !line.ends_with('\n')
+        {
+            return Err(if size >= MAX_HEAD { "request head too large".to_string() } else { "incomplete request".to_string() });
+        }
+
+        let line = line.trim_end_matches(['\r', '\n']).to_string();
+        if line.is_empty()
+        {
+            break;
+        }
+        lines.push(line);
+    }
+
+    let mut request_line = lines.first().ok_or("empty request")?.split_whitespace();
+    let (method, target) = match (request_line.next(), request_line.next())
+    {
+        (Some(method), Some(target)) => (method.to_string(), target),
+        _ => return Err("malformed request line".to_string()),
+    };
+    let path = target.split('?').next().unwrap_or("").to_string();
+
+    let mut headers = Vec::new();
+    for line in &lines[1..]
+    {
+        let (field, value) = line.split_once(':').ok_or(format!("malformed header {}", line))?;
+        headers.push((field.trim().to_string(), value.trim().to_string()));
+    }
+
+    let mut request = HttpRequest { method, path, headers, body: Vec::new() };
+    if request.header("Transfer-Encoding").is_some()
+    {
+        return Err("chunked bodies are not supported, send a Content-Length".to_string());
+    }
+    if let Some(length) = request.header("Content-Length")
+    {
+        let length: usize = length.parse().map_err(|_| format!("invalid Content-Length {}", length))?;
+        if length > MAX_BODY
+        {
+            return Err("body too large".to_string());
+        }
+        request.body = vec![0; length];
+        reader.read_exact(&mut request.body).map_err(|e| e.to_string())?;
+    }
+    return Ok(request);
+}
+
+fn handle(request: &HttpRequest, sender: &Sender<control::Request>) -> (u16, Value)
+{
+    let body = match std::str::from_utf8(&request.body)
+    {
+        Ok(body) => body,
+        Err(_) => return (400, json!({ "error": "body isn't utf-8" })),
+    };
+    if (request.method == "PUT" || request.method == "POST") && !is_json(request.header("Content-Type"))
+    {
+        return (415, json!({ "error": "send the body as application/json" }));
+    }
+
+    let params = if body.trim().is_empty()
+    {
+        Value::Null
+    }
+    else
+    {
+        match serde_json::from_str(body)
+        {
+            Ok(params) => params,
+            Err(e) => return (400, json!({ "error": e.to_string() })),
+        }
+    };
+
+    let call = |method: &str, params: Value| match control::call(sender, method, params)
+    {
+        Reply::Ok(result) => (200, result),
+        Reply::Err(e) => (400, json!({ "error": e })),
+        Reply::Subscribed(_) => (500, Value::Null),
+    };
+
+    let (status, result) = match (request.method.as_str(), request.path.trim_end_matches('/'))
+    {
+        ("GET", "/api/state")      => call("get-state", Value::Null),
+        ("GET", "/api/devices")    => call("list-devices", Value::Null),
+        ("GET", "/api/effects")    => (200, json!(effects::NAMES)),
+        ("GET", "/api/frame")      => call("get-frame", Value::Null),
+        ("PUT", "/api/effect")     => call("set-effect", params),
+        ("PUT", "/api/color")      => call("set-color", params),
+        ("PUT", "/api/brightness") => call("set-brightness", params),
+        ("POST", "/api/pause")     => call("pause", Value::Null),
+        ("POST", "/api/resume")    => call("resume", Value::Null),
+        ("POST", "/api/notify")    => call("notify", params),
+        ("GET", path) if path.starts_with("/api/devices/") => match percent_decode(&path["/api/devices/".len()..])
+        {
+            Some(name) => match call("get-frame", Value::Null)
+            {
+                (200, Value::Array(devices)) => match devices.into_iter().find(|d| d["name"] == name.as_str())
+                {
+                    Some(device) => (200, device),
+                    None => (404, json!({ "error": format!("no device {}", name) })),
+                },
+                other => other,
+            },
+            None => (400, json!({ "error": "malformed device name" })),
+        },
+        _ => (404, json!({ "error": "not found" })),
+    };
+
+    //Commands without a result answer with the new state
+    let result = if status == 200 && result.is_null() { call("get-state", Value::Null).1 } else { result };
+    return (status, result);
+}
+
+//"application/json", with or without parameters like the charset
+fn is_json(content_type: Option<&str>) -> bool
+{
+    return content_type.map_or(false, |c| c.split(';').next().unwrap_or("").trim().eq_ignore_ascii_case("application/json"));
+}
+
+//Requests without an Origin don't come from a browser. Pages have to be served from the address the request went to,
+//by ip or as localhost: a name could be pointed at this machine by any site.
+fn same_origin(request: &HttpRequest) -> bool
+{
+    let origin = match request.header("Origin")
+    {
+        Some(origin) => origin,
+        None => return true,
+    };
+    let authority = match origin.strip_prefix("http://").or_else(|| origin.strip_prefix("https://"))
+    {
+        Some(authority) => authority.trim_end_matches('/'),
+        None => return false,
+    };
+    if !request.header("Host").map_or(false, |host| host.eq_ignore_ascii_case(authority))
+    {
+        return false;
+    }
+
+    let host = authority.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map_or(authority, |(host, _)| host);
+    return host.eq_ignore_ascii_case("localhost") || host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().is_ok();
+}
+
+fn respond(stream: &mut TcpStream, status: u16, body: &Value) -> io::Result<()>
+{
+    let reason = match status
+    {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        415 => "Unsupported Media Type",
+        _ => "Internal Server Error",
+    };
+    let body = body.to_string();
+    write!(stream, "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, reason, body.len(), body)?;
+    return stream.flush();
+}
+
+//Undoes the %XX escapes in a url path, None for broken escapes or names that aren't utf-8
+fn percent_decode(text: &str) -> Option<String>
+{
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len()
+    {
+        if bytes[i] == b'%'
+        {
+            let hex = bytes.get(i + 1..i + 3).filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))?;
+            decoded.push(u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok()?);
+            i += 3;
+        }
+        else
+        {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    return String::from_utf8(decoded).ok();
+}
+
+
+//================================================================================================================================================================================================
+//Websocket
+
+fn websocket(mut stream: TcpStream, unread: Vec<u8>, request: &HttpRequest, sender: Sender<control::Request>)
+{
+    let key = match (request.header("Upgrade"), request.header("Sec-WebSocket-Key"))
+    {
+        (Some(upgrade), Some(key)) if upgrade.eq_ignore_ascii_case("websocket") => key,
+        _ =>
+        {
+            let _ = respond(&mut stream, 400, &json!({ "error": "not a websocket request" }));
+            return;
+        }
+    };
+    if !same_origin(request)
+    {
+        let _ = respond(&mut stream, 403, &json!({ "error": "pages on other sites can't use the websocket" }));
+        return;
+    }
+
+    let events = match control::call(&sender, "subscribe", json!({ "frames": true }))
+    {
+        Reply::Subscribed(events) => events,
+        _ => return,
+    };
+
+    let accept = tungstenite::handshake::derive_accept_key(key.as_bytes());
+    if write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept).is_err()
+    {
+        return;
+    }
+
+    //Short reads, so events keep flowing while the client is quiet
+    if stream.set_read_timeout(Some(WEBSOCKET_POLL)).is_err()
+    {
+        return;
+    }
+    let mut socket = WebSocket::from_partially_read(stream, unread, Role::Server, None);
+
+    loop
+    {
+        //Tungstenite queues the answers to pings and closes, the flush below sends them
+        match socket.read()
+        {
+            Ok(_) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            //Closed, by either side, or broken
+            Err(_) => return,
+        }
+
+        loop
+        {
+            match events.try_recv()
+            {
+                Ok(event) =>
+                {
+                    if socket.write(Message::Text(event.to_string())).is_err()
+                    {
+                        return;
+                    }
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) =>
+                {
+                    let _ = socket.close(None);
+                    let _ = socket.flush();
+                    return;
+                }
+            }
+        }
+
+        match socket.flush()
+        {
+            Ok(()) => {}
+            Err(tungstenite::Error::Io(e)) if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::TimedOut => {}
+            Err(_) => return,
+        }
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Tests
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::control::spawn_virtual;
+    use std::io::Cursor;
+
+    fn start() -> SocketAddr
+    {
+        return listen("127.0.0.1:0", spawn_virtual(&[("left strip", 4), ("fan", 2)])).unwrap();
+    }
+
+    //With a json body, like a well behaved client
+    fn send(address: SocketAddr, method: &str, path: &str, body: &str) -> (u16, Value)
+    {
+        return exchange(address, &format!("{} {} HTTP/1.1\r\nHost: localhost\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}", method, path, body.len(), body));
+    }
+
+    //The status and json body of a raw request
+    fn exchange(address: SocketAddr, request: &str) -> (u16, Value)
+    {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(request.as_bytes()).unwrap();
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response.split_whitespace().nth(1).unwrap().parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        return (status, serde_json::from_str(body).unwrap());
+    }
+
+    #[test]
+    fn parses_requests()
+    {
+        let mut reader = Cursor::new(b"PUT /api/color?x=1 HTTP/1.1\r\ncontent-length: 5\r\nHost: a\r\n\r\nhello and more".to_vec());
+        let request = read_request(&mut reader).unwrap();
+        assert_eq!(request.method, "PUT");
+        assert_eq!(request.path, "/api/color");
+        assert_eq!(request.header("Content-Length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+
+        assert!(read_request(&mut Cursor::new(b"GET / HTTP/1.1\r\nHost: a\r\n".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(b"GET\r\n\r\n".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(b"GET / HTTP/1.1\r\nno colon\r\n\r\n".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(b"PUT / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort".to_vec())).is_err());
+        assert!(read_request(&mut Cursor::new(format!("PUT / HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1).into_bytes())).is_err());
+        assert!(read_request(&mut Cursor::new(format!("GET /{} HTTP/1.1\r\n\r\n", "a".repeat(MAX_HEAD)).into_bytes())).is_err());
+    }
+
+    #[test]
+    fn decodes_percent_escapes()
+    {
+        assert_eq!(percent_decode("left%20strip").as_deref(), Some("left strip"));
+        assert_eq!(percent_decode("caf%C3%a9").as_deref(), Some("café"));
+        assert_eq!(percent_decode("plain").as_deref(), Some("plain"));
+        assert_eq!(percent_decode("%zz"), None);
+        assert_eq!(percent_decode("%4"), None);
+        assert_eq!(percent_decode("%+1"), None);
+        assert_eq!(percent_decode("%FF"), None);
+    }
+
+    #[test]
+    fn answers_the_api()
+    {
+        let address = start();
+
+        let (status, devices) = send(address, "GET", "/api/frame", "");
+        assert_eq!(status, 200);
+        assert_eq!(devices.as_array().unwrap().len(), 2);
+
+        let (status, device) = send(address, "GET", "/api/devices/left%20strip", "");
+        assert_eq!(status, 200);
+        assert_eq!(device["name"], "left strip");
+        assert_eq!(device["colors"].as_array().unwrap().len(), 4);
+        assert_eq!(send(address, "GET", "/api/devices/nothing", "").0, 404);
+        assert_eq!(send(address, "GET", "/api/devices/%zz", "").0, 400);
+
+        let (status, state) = send(address, "PUT", "/api/effect", r#"{ "name": "wave", "colors": ["red"] }"#);
+        assert_eq!(status, 200);
+        assert_eq!(state["effect"], "wave");
+        let (status, error) = send(address, "PUT", "/api/effect", r#"{ "name": "nothing" }"#);
+        assert_eq!(status, 400);
+        assert!(error["error"].as_str().unwrap().contains("unknown effect"));
+        assert_eq!(send(address, "PUT", "/api/color", "{ not json").0, 400);
+
+        assert_eq!(send(address, "GET", "/api/effects", "").1, json!(effects::NAMES));
+        assert_eq!(send(address, "GET", "/nothing", "").0, 404);
+        assert_eq!(send(address, "POST", "/api/state", "").0, 404);
+    }
+
+    #[test]
+    fn websocket_answers_pings_and_closes()
+    {
+        let address = start();
+        let stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (mut socket, _) = tungstenite::client(format!("ws://{}/ws", address), stream).unwrap();
+
+        //Frames arrive without the client saying anything
+        let event: Value = match socket.read().unwrap()
+        {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected an event, got {:?}", other),
+        };
+        assert_eq!(event["event"], "frame");
+
+        socket.send(Message::Ping(vec![1, 2, 3])).unwrap();
+        loop
+        {
+            match socket.read().unwrap()
+            {
+                Message::Pong(payload) => { assert_eq!(payload, vec![1, 2, 3]); break; }
+                Message::Text(_) => {}
+                other => panic!("expected a pong, got {:?}", other),
+            }
+        }
+
+        socket.close(None).unwrap();
+        loop
+        {
+            match socket.read()
+            {
+                Ok(Message::Text(_)) => {}
+                Ok(Message::Close(_)) => {}
+                Err(tungstenite::Error::ConnectionClosed) => break,
+                other => panic!("expected the close handshake, got {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_plain_requests_for_the_websocket()
+    {
+        let address = start();
+        assert_eq!(send(address, "GET", "/ws", "").0, 400);
+    }
+
+    #[test]
+    fn requires_json_bodies()
+    {
+        let address = start();
+        let color = r#"{ "color": "red" }"#;
+        let put = |content_type: &str| exchange(address, &format!("PUT /api/color HTTP/1.1\r\nHost: localhost\r\n{}Content-Length: {}\r\n\r\n{}", content_type, color.len(), color)).0;
+
+        //What a form or a cross-site fetch without a preflight can send
+        assert_eq!(put(""), 415);
+        assert_eq!(put("Content-Type: text/plain\r\n"), 415);
+        assert_eq!(put("Content-Type: application/x-www-form-urlencoded\r\n"), 415);
+        assert_eq!(exchange(address, "POST /api/pause HTTP/1.1\r\nHost: localhost\r\n\r\n").0, 415);
+
+        assert_eq!(put("Content-Type: application/json\r\n"), 200);
+        assert_eq!(put("content-type: Application/JSON; charset=utf-8\r\n"), 200);
+        assert_eq!(exchange(address, "GET /api/state HTTP/1.1\r\nHost: localhost\r\n\r\n").0, 200);
+    }
+
+    fn upgrade(host: &str, origin: Option<&str>) -> HttpRequest
+    {
+        let mut headers = vec![("Host".to_string(), host.to_string())];
+        if let Some(origin) = origin
+        {
+            headers.push(("Origin".to_string(), origin.to_string()));
+        }
+        return HttpRequest { method: "GET".to_string(), path: "/ws".to_string(), headers, body: Vec::new() };
+    }
+
+    #[test]
+    fn checks_the_origin()
+    {
+        assert!(same_origin(&upgrade("127.0.0.1:8080", None)));
+        assert!(same_origin(&upgrade("127.0.0.1:8080", Some("http://127.0.0.1:8080"))));
+        assert!(same_origin(&upgrade("localhost:8080", Some("http://LOCALHOST:8080"))));
+        assert!(same_origin(&upgrade("[::1]:8080", Some("http://[::1]:8080"))));
+        assert!(same_origin(&upgrade("192.168.1.5", Some("https://192.168.1.5/"))));
+
+        assert!(!same_origin(&upgrade("127.0.0.1:8080", Some("http://example.com"))));
+        assert!(!same_origin(&upgrade("127.0.0.1:8080", Some("http://127.0.0.1:9090"))));
+        assert!(!same_origin(&upgrade("127.0.0.1:8080", Some("null"))));
+        //A site that points its own name at this machine
+        assert!(!same_origin(&upgrade("example.com:8080", Some("http://example.com:8080"))));
+    }
+
+    #[test]
+    fn websocket_rejects_other_sites()
+    {
+        let address = start();
+        let request = |origin: &str| format!("GET /ws HTTP/1.1\r\nHost: {}\r\nOrigin: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n", address, origin);
+        assert_eq!(exchange(address, &request("http://example.com")).0, 403);
+
+        //A page served from here gets the handshake
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(request(&format!("http://{}", address)).as_bytes()).unwrap();
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).unwrap();
+        assert!(status.starts_with("HTTP/1.1 101"), "{}", status);
+    }
+}
@@ -0,0 +1,662 @@
+#[cfg(not(windows))]
+use interprocess::local_socket::LocalSocketListener;
+use interprocess::local_socket::LocalSocketStream;
+use serde_json::{json, Value};
+use crate::control::{self, Reply, Request};
+use std::io::{BufRead, BufReader, Read, Write};
+#[cfg(not(windows))]
+use std::io::ErrorKind;
+#[cfg(not(windows))]
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::thread;
+use std::time::Duration;
+
+
+//Control socket for a running instance: a named pipe on windows, a unix domain socket everywhere else.
+//JSON-RPC 2.0, one message per line, methods are listed in control.rs:
+//    -> {"jsonrpc": "2.0", "id": 1, "method": "set-color", "params": {"color": "red"}}
+//    <- {"jsonrpc": "2.0", "id": 1, "result": null}
+//Requests without an id are notifications and get no response.
+//After subscribe the connection only carries events: {"jsonrpc": "2.0", "method": "event", "params": {...}}
+//and an empty line every KEEPALIVE_INTERVAL without events.
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const SERVER_ERROR: i64 = -32000;
+
+const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(5);
+
+
+#[cfg(windows)]
+pub fn socket_name() -> Result<String, String>
+{
+    return Ok("rustic_light".to_string()); //\\.\pipe\rustic_light
+}
+
+//In $XDG_RUNTIME_DIR, which only the user can get into. Without one, in a directory of the user's own in the temp dir,
+//so other users can neither talk to the instance nor put a socket of their own in its place.
+#[cfg(not(windows))]
+pub fn socket_name() -> Result<String, String>
+{
+    let directory = match std::env::var_os("XDG_RUNTIME_DIR").filter(|d| !d.is_empty())
+    {
+        Some(directory) => PathBuf::from(directory),
+        None =>
+        {
+            let directory = std::env::temp_dir().join(format!("rustic_light-{}", user_id()));
+            private_directory(&directory)?;
+            directory
+        }
+    };
+    return Ok(directory.join("rustic_light.sock").display().to_string());
+}
+
+#[cfg(not(windows))]
+fn user_id() -> u32
+{
+    return unsafe { libc::geteuid() };
+}
+
+//Creates the directory with 0700, or checks that the one already there is ours and closed to everyone else
+#[cfg(not(windows))]
+fn private_directory(directory: &Path) -> Result<(), String>
+{
+    use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+
+    match std::fs::DirBuilder::new().mode(0o700).create(directory)
+    {
+        Ok(()) => return Ok(()),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(format!("{}: {}", directory.display(), e)),
+    }
+
+    //Not following symlinks, another user could point one anywhere
+    let metadata = std::fs::symlink_metadata(directory).map_err(|e| format!("{}: {}", directory.display(), e))?;
+    if !metadata.is_dir() || metadata.uid() != user_id() || metadata.mode() & 0o077 != 0
+    {
+        return Err(format!("{} is not a directory of this user only", directory.display()));
+    }
+    return Ok(());
+}
+
+//Only sockets of this user are left behind by an instance that didn't exit cleanly, anything else stays where it is
+#[cfg(not(windows))]
+fn remove_stale_socket(name: &str) -> Result<(), String>
+{
+    use std::os::unix::fs::{FileTypeExt, MetadataExt};
+
+    let metadata = match std::fs::symlink_metadata(name)
+    {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(format!("{}: {}", name, e)),
+    };
+    if !metadata.file_type().is_socket() || metadata.uid() != user_id()
+    {
+        return Err(format!("{} is in the way and not a socket of this user", name));
+    }
+    return std::fs::remove_file(name).map_err(|e| format!("{}: {}", name, e));
+}
+
+
+//================================================================================================================================================================================================
+//Server
+
+//Accepts connections on a background thread. Only one instance can own the socket, the others run without one.
+pub fn serve(sender: Sender<Request>)
+{
+    if let Err(e) = socket_name().and_then(|name| listen(&name, sender))
+    {
+        println!("Not starting the control socket: {}", e);
+    }
+}
+
+fn listen(name: &str, sender: Sender<Request>) -> Result<(), String>
+{
+    if LocalSocketStream::connect(name).is_ok()
+    {
+        return Err(format!("another instance is listening on {}", name));
+    }
+    #[cfg(not(windows))]
+    remove_stale_socket(name)?;
+
+    #[cfg(not(windows))]
+    let listener = LocalSocketListener::bind(name).map_err(|e| format!("{}: {}", name, e))?;
+    #[cfg(windows)]
+    let listener = pipe::PipeListener::bind(name).map_err(|e| format!("{}: {}", name, e))?;
+
+    thread::spawn(move ||
+    {
+        loop
+        {
+            match listener.accept()
+            {
+                Ok(stream) =>
+                {
+                    let sender = sender.clone();
+                    thread::spawn(move || connection(stream, sender));
+                }
+                Err(e) => println!("Control socket: {}", e),
+            }
+        }
+    });
+    return Ok(());
+}
+
+//The default dacl of a named pipe only lets the creator and administrators write to it. The service runs as SYSTEM,
+//so without our own dacl the command line would need an elevated prompt. interprocess can't set one, so the server side is done here.
+#[cfg(windows)]
+mod pipe
+{
+    use std::cell::Cell;
+    use std::ffi::OsStr;
+    use std::fs::File;
+    use std::io;
+    use std::iter::once;
+    use std::mem::size_of;
+    use std::os::windows::ffi::OsStrExt;
+    use std::os::windows::io::FromRawHandle;
+    use std::ptr::null_mut;
+    use winapi::shared::minwindef::FALSE;
+    use winapi::shared::sddl::{ConvertStringSecurityDescriptorToSecurityDescriptorW, SDDL_REVISION_1};
+    use winapi::shared::winerror::ERROR_PIPE_CONNECTED;
+    use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
+    use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+    use winapi::um::namedpipeapi::{ConnectNamedPipe, CreateNamedPipeW};
+    use winapi::um::winbase::{LocalFree, FILE_FLAG_FIRST_PIPE_INSTANCE, PIPE_ACCESS_DUPLEX, PIPE_READMODE_BYTE, PIPE_REJECT_REMOTE_CLIENTS, PIPE_TYPE_BYTE, PIPE_UNLIMITED_INSTANCES, PIPE_WAIT};
+    use winapi::um::winnt::{HANDLE, PSECURITY_DESCRIPTOR};
+
+    //Full access for SYSTEM and administrators, read and write for whoever is logged in at the machine
+    const SECURITY: &str = "D:(A;;GA;;;SY)(A;;GA;;;BA)(A;;GRGW;;;IU)";
+    const BUFFER_SIZE: u32 = 4096;
+
+    pub struct PipeListener
+    {
+        path: Vec<u16>,
+        security: PSECURITY_DESCRIPTOR,
+        next: Cell<HANDLE>, //The instance the next client connects to, created when accepting
+    }
+
+    //Moved to the accepting thread once and only used there
+    unsafe impl Send for PipeListener {}
+
+    impl PipeListener
+    {
+        pub fn bind(name: &str) -> io::Result<Self>
+        {
+            let mut security: PSECURITY_DESCRIPTOR = null_mut();
+            if unsafe { ConvertStringSecurityDescriptorToSecurityDescriptorW(wide(SECURITY).as_ptr(), SDDL_REVISION_1 as u32, &mut security, null_mut()) } == FALSE
+            {
+                return Err(io::Error::last_os_error());
+            }
+
+            let listener = PipeListener
+            {
+                path: wide(&format!(r"\\.\pipe\{}", name)),
+                security,
+                next: Cell::new(INVALID_HANDLE_VALUE),
+            };
+            //The first instance claims the name, so nobody can create the pipe before us with a dacl of their own
+            listener.next.set(listener.create(true)?);
+            return Ok(listener);
+        }
+
+        fn create(&self, first: bool) -> io::Result<HANDLE>
+        {
+            let mut attributes = SECURITY_ATTRIBUTES
+            {
+                nLength: size_of::<SECURITY_ATTRIBUTES>() as u32,
+                lpSecurityDescriptor: self.security,
+                bInheritHandle: FALSE,
+            };
+            let open_mode = PIPE_ACCESS_DUPLEX | if first { FILE_FLAG_FIRST_PIPE_INSTANCE } else { 0 };
+            let pipe_mode = PIPE_TYPE_BYTE | PIPE_READMODE_BYTE | PIPE_WAIT | PIPE_REJECT_REMOTE_CLIENTS;
+
+            let handle = unsafe { CreateNamedPipeW(self.path.as_ptr(), open_mode, pipe_mode, PIPE_UNLIMITED_INSTANCES, BUFFER_SIZE, BUFFER_SIZE, 0, &mut attributes) };
+            if handle == INVALID_HANDLE_VALUE
+            {
+                return Err(io::Error::last_os_error());
+            }
+            return Ok(handle);
+        }
+
+        //Blocks until a client connects
+        pub fn accept(&self) -> io::Result<File>
+        {
+            let handle = match self.next.replace(INVALID_HANDLE_VALUE)
+            {
+                handle if handle == INVALID_HANDLE_VALUE => self.create(false)?,
+                handle => handle,
+            };
+
+            if unsafe { ConnectNamedPipe(handle, null_mut()) } == FALSE
+            {
+                //The client connected between creating the instance and waiting for it
+                let error = io::Error::last_os_error();
+                if error.raw_os_error() != Some(ERROR_PIPE_CONNECTED as i32)
+                {
+                    unsafe { CloseHandle(handle) };
+                    return Err(error);
+                }
+            }
+            return Ok(unsafe { File::from_raw_handle(handle as _) });
+        }
+    }
+
+    impl Drop for PipeListener
+    {
+        fn drop(&mut self)
+        {
+            unsafe
+            {
+                if self.next.get() != INVALID_HANDLE_VALUE
+                {
+                    CloseHandle(self.next.get());
+                }
+                LocalFree(self.security as _);
+            }
+        }
+    }
+
+    fn wide(text: &str) -> Vec<u16>
+    {
+        return OsStr::new(text).encode_wide().chain(once(0)).collect();
+    }
+}
+
+fn connection<S: Read + Write>(stream: S, sender: Sender<Request>)
+{
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    loop
+    {
+        line.clear();
+        match reader.read_line(&mut line)
+        {
+            Ok(0) | Err(_) => return,
+            Ok(_) => {}
+        }
+        if line.trim().is_empty()
+        {
+            continue;
+        }
+
+        let message: Value = match serde_json::from_str(&line)
+        {
+            Ok(message) => message,
+            Err(e) =>
+            {
+                if write_message(reader.get_mut(), &error(Value::Null, PARSE_ERROR, &e.to_string())).is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        //Requests without an id are notifications, they get no response
+        let id = message.get("id").cloned();
+        let (method, params) = match request(&message)
+        {
+            Ok(request) => request,
+            Err(e) =>
+            {
+                if write_message(reader.get_mut(), &error(id.unwrap_or(Value::Null), INVALID_REQUEST, e)).is_err()
+                {
+                    return;
+                }
+                continue;
+            }
+        };
+
+        let response = match control::call(&sender, method, params)
+        {
+            Reply::Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+            Reply::Err(e) => error(id.clone().unwrap_or(Value::Null), SERVER_ERROR, &e),
+            Reply::Subscribed(events) =>
+            {
+                if id.is_some() && write_message(reader.get_mut(), &json!({ "jsonrpc": "2.0", "id": id, "result": null })).is_err()
+                {
+                    return;
+                }
+                forward_events(reader.get_mut(), events, KEEPALIVE_INTERVAL);
+                return;
+            }
+        };
+
+        if id.is_some() && write_message(reader.get_mut(), &response).is_err()
+        {
+            return;
+        }
+    }
+}
+
+//The method and params of a JSON-RPC 2.0 request
+fn request(message: &Value) -> Result<(&str, Value), &'static str>
+{
+    if message.get("jsonrpc").and_then(|v| v.as_str()) != Some("2.0")
+    {
+        return Err("jsonrpc has to be \"2.0\"");
+    }
+    let method = message.get("method").and_then(|m| m.as_str()).ok_or("missing method")?;
+    let params = message.get("params").cloned().unwrap_or(Value::Null);
+    if !params.is_null() && !params.is_object() && !params.is_array()
+    {
+        return Err("params has to be an object or an array");
+    }
+    return Ok((method, params));
+}
+
+//Until the client or the animation goes away. A client that goes away is only noticed when writing fails,
+//so while there are no events an empty line goes out every keepalive.
+fn forward_events<W: Write>(stream: &mut W, events: Receiver<Value>, keepalive: Duration)
+{
+    loop
+    {
+        let written = match events.recv_timeout(keepalive)
+        {
+            Ok(event) => write_message(stream, &json!({ "jsonrpc": "2.0", "method": "event", "params": event })),
+            Err(RecvTimeoutError::Timeout) => stream.write_all(b"\n").and_then(|_| stream.flush()),
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if written.is_err()
+        {
+            return;
+        }
+    }
+}
+
+fn error(id: Value, code: i64, message: &str) -> Value
+{
+    return json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } });
+}
+
+fn write_message<W: Write>(stream: &mut W, message: &Value) -> std::io::Result<()>
+{
+    let mut text = message.to_string();
+    text.push('\n');
+    stream.write_all(text.as_bytes())?;
+    return stream.flush();
+}
+
+
+//================================================================================================================================================================================================
+//Client
+
+//The command line side, talking to the instance that owns the socket
+pub struct Client
+{
+    reader: BufReader<LocalSocketStream>,
+    next_id: u64,
+}
+
+impl Client
+{
+    //Fails when no instance is running
+    pub fn connect() -> Result<Self, String>
+    {
+        return Client::connect_to(&socket_name()?);
+    }
+
+    fn connect_to(name: &str) -> Result<Self, String>
+    {
+        let stream = LocalSocketStream::connect(name).map_err(|e| e.to_string())?;
+        return Ok(Client { reader: BufReader::new(stream), next_id: 1 });
+    }
+
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value, String>
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_message(self.reader.get_mut(), &json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })).map_err(|e| e.to_string())?;
+
+        let response = self.read()?;
+        if let Some(e) = response.get("error")
+        {
+            return Err(e.get("message").and_then(|m| m.as_str()).unwrap_or("unknown error").to_string());
+        }
+        return Ok(response.get("result").cloned().unwrap_or(Value::Null));
+    }
+
+    //After subscribe, blocks until the next event
+    pub fn next_event(&mut self) -> Result<Value, String>
+    {
+        let message = self.read()?;
+        return Ok(message.get("params").cloned().unwrap_or(Value::Null));
+    }
+
+    //The next message, skipping the empty keepalive lines
+    fn read(&mut self) -> Result<Value, String>
+    {
+        let mut line = String::new();
+        while line.trim().is_empty()
+        {
+            line.clear();
+            match self.reader.read_line(&mut line)
+            {
+                Ok(0) => return Err("the instance closed the connection".to_string()),
+                Ok(_) => {}
+                Err(e) => return Err(e.to_string()),
+            }
+        }
+        return serde_json::from_str(&line).map_err(|e| e.to_string());
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::control::spawn_virtual;
+    use crate::testing::TempPath;
+    use std::sync::mpsc::channel;
+    use std::time::Instant;
+
+    //A socket of its own for every test, in the temp dir. The socket file is removed with the TempPath.
+    fn start(test: &str) -> (String, Option<TempPath>)
+    {
+        #[cfg(windows)]
+        let (name, socket) = (format!("rustic_light_test_{}_{}", test, std::process::id()), None);
+        #[cfg(not(windows))]
+        let (name, socket) =
+        {
+            let socket = TempPath::new(&format!("{}.sock", test));
+            (socket.path().display().to_string(), Some(socket))
+        };
+
+        listen(&name, spawn_virtual(&[("strip", 4)])).unwrap();
+        return (name, socket);
+    }
+
+    //Raw lines, to see exactly what the server sends
+    struct Connection(BufReader<LocalSocketStream>);
+
+    impl Connection
+    {
+        fn new(name: &str) -> Self
+        {
+            return Connection(BufReader::new(LocalSocketStream::connect(name).unwrap()));
+        }
+
+        fn send(&mut self, line: &str)
+        {
+            self.0.get_mut().write_all(format!("{}\n", line).as_bytes()).unwrap();
+        }
+
+        fn receive(&mut self) -> Value
+        {
+            let mut line = String::new();
+            self.0.read_line(&mut line).unwrap();
+            return serde_json::from_str(&line).unwrap();
+        }
+    }
+
+    #[test]
+    fn calls_the_animation()
+    {
+        let (name, _socket) = start("calls");
+        let mut client = Client::connect_to(&name).unwrap();
+
+        client.call("set-color", json!({ "color": "red" })).unwrap();
+        let state = client.call("get-state", Value::Null).unwrap();
+        assert_eq!(state["effect"], "solid");
+
+        let devices = client.call("list-devices", Value::Null).unwrap();
+        assert_eq!(devices[0]["name"], "strip");
+
+        assert!(client.call("set-effect", json!({ "name": "disco" })).unwrap_err().starts_with("unknown effect disco"));
+        assert!(client.call("nothing", Value::Null).is_err());
+    }
+
+    #[test]
+    fn notifications_get_no_response()
+    {
+        let (name, _socket) = start("notifications");
+        let mut connection = Connection::new(&name);
+
+        connection.send(r#"{"jsonrpc": "2.0", "method": "pause"}"#);
+        connection.send(r#"{"jsonrpc": "2.0", "method": "nothing"}"#);
+        connection.send(r#"{"jsonrpc": "2.0", "id": "a", "method": "get-state"}"#);
+
+        //The first thing back is the response to the request, after the notification did its work
+        let response = connection.receive();
+        assert_eq!(response["id"], "a");
+        assert_eq!(response["result"]["paused"], true);
+    }
+
+    #[test]
+    fn rejects_what_is_not_json_rpc()
+    {
+        let (name, _socket) = start("invalid");
+        let mut connection = Connection::new(&name);
+
+        connection.send("{nope");
+        let response = connection.receive();
+        assert_eq!(response["id"], Value::Null);
+        assert_eq!(response["error"]["code"], PARSE_ERROR);
+
+        for (request, id) in [(r#"{"id": 1, "method": "get-state"}"#, json!(1)), (r#"{"jsonrpc": "1.0", "id": 2, "method": "get-state"}"#, json!(2)),
+                              (r#"{"jsonrpc": "2.0", "id": 3}"#, json!(3)), (r#"{"jsonrpc": "2.0", "id": 4, "method": "pause", "params": 5}"#, json!(4)),
+                              (r#"{"jsonrpc": "2.0", "method": 6}"#, Value::Null)]
+        {
+            connection.send(request);
+            let response = connection.receive();
+            assert_eq!(response["id"], id, "{}", request);
+            assert_eq!(response["error"]["code"], INVALID_REQUEST, "{}", request);
+        }
+
+        //Still talking after all that
+        connection.send(r#"{"jsonrpc": "2.0", "id": 7, "method": "get-state"}"#);
+        assert_eq!(connection.receive()["id"], 7);
+    }
+
+    #[test]
+    fn subscribers_get_events()
+    {
+        let (name, _socket) = start("subscribe");
+        let mut subscriber = Client::connect_to(&name).unwrap();
+        subscriber.call("subscribe", Value::Null).unwrap();
+
+        Client::connect_to(&name).unwrap().call("pause", Value::Null).unwrap();
+        let event = subscriber.next_event().unwrap();
+        assert_eq!(event["event"], "state");
+        assert_eq!(event["data"]["paused"], true);
+    }
+
+    //Writes fail, like a client that went away
+    struct Gone;
+
+    impl Write for Gone
+    {
+        fn write(&mut self, _buffer: &[u8]) -> std::io::Result<usize>
+        {
+            return Err(std::io::ErrorKind::BrokenPipe.into());
+        }
+
+        fn flush(&mut self) -> std::io::Result<()>
+        {
+            return Ok(());
+        }
+    }
+
+    #[test]
+    fn stops_forwarding_to_clients_that_went_away()
+    {
+        //No events at all, the keepalive finds out
+        let (_sender, events) = channel();
+        let start = Instant::now();
+        forward_events(&mut Gone, events, Duration::from_millis(20));
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        //Or the next event
+        let (sender, events) = channel();
+        sender.send(json!({ "event": "state" })).unwrap();
+        forward_events(&mut Gone, events, Duration::from_secs(60));
+
+        //Keepalives go out while there are no events
+        let (sender, events) = channel();
+        let mut written = Vec::new();
+        let forwarding = thread::spawn(move ||
+        {
+            forward_events(&mut written, events, Duration::from_millis(10));
+            return written;
+        });
+        thread::sleep(Duration::from_millis(50));
+        sender.send(json!({ "event": "state" })).unwrap();
+        drop(sender);
+        let written = String::from_utf8(forwarding.join().unwrap()).unwrap();
+        assert!(written.starts_with("\n\n"));
+        assert!(written.ends_with("{\"jsonrpc\":\"2.0\",\"method\":\"event\",\"params\":{\"event\":\"state\"}}\n"));
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn socket_directory_is_private()
+    {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+
+        let temp = TempPath::new("private");
+        let link = TempPath::new("private.link");
+        let (directory, link) = (temp.path(), link.path());
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+
+        private_directory(directory).unwrap();
+        assert_eq!(mode(directory), 0o700);
+        //Already there and ours
+        private_directory(directory).unwrap();
+
+        //Open to others, or not a directory of its own
+        std::fs::set_permissions(directory, std::fs::Permissions::from_mode(0o755)).unwrap();
+        assert!(private_directory(directory).is_err());
+        std::fs::set_permissions(directory, std::fs::Permissions::from_mode(0o700)).unwrap();
+        symlink(directory, link).unwrap();
+        assert!(private_directory(link).is_err());
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn only_stale_sockets_are_removed()
+    {
+        let socket = TempPath::new("stale.sock");
+        let name = socket.path().display().to_string();
+
+        //Left behind by an instance that is gone, listening takes it over
+        drop(std::os::unix::net::UnixListener::bind(&name).unwrap());
+        assert!(Path::new(&name).exists());
+        listen(&name, spawn_virtual(&[("strip", 4)])).unwrap();
+        let mut client = Client::connect_to(&name).unwrap();
+        assert_eq!(client.call("list-devices", Value::Null).unwrap()[0]["name"], "strip");
+        std::fs::remove_file(&name).unwrap();
+
+        //Anything that isn't a socket stays
+        std::fs::write(&name, "not a socket").unwrap();
+        assert!(listen(&name, spawn_virtual(&[("strip", 4)])).is_err());
+        assert_eq!(std::fs::read_to_string(&name).unwrap(), "not a socket");
+        std::fs::remove_file(&name).unwrap();
+
+        assert!(remove_stale_socket(&name).is_ok());
+    }
+}
@@ -0,0 +1,203 @@
+//Key layout of the sk621, shared by the sdk backend and the keyboard reactive effects.
+use crate::color::{Color, RgbDevice};
+
+//Size of the cooler master sdk color matrix, not every position has a key
+#[cfg(any(windows, test))]
+pub const ROWS: usize = 8;
+#[cfg(any(windows, test))]
+pub const COLUMNS: usize = 24;
+
+//Position of each key in the color matrix, left to right per row.
+//Every key of the sk621: ansi 60% with the arrow keys squeezed into the right of the bottom two rows, 14 + 14 + 13 + 13 + 9 keys.
+//"rustic_light leds sk621" lights the keys one at a time with their names, "--rows" a row at a time.
+pub const KEYS: [(&str, usize, usize); 63] =
+[
+    ("esc",          0,  0), ("1",            0,  1), ("2",            0,  2), ("3",            0,  3), ("4",            0,  4),
+    ("5",            0,  5), ("6",            0,  6), ("7",            0,  7), ("8",            0,  8), ("9",            0,  9),
+    ("0",            0, 10), ("minus",        0, 11), ("equals",       0, 12), ("backspace",    0, 13),
+
+    ("tab",          1,  0), ("q",            1,  1), ("w",            1,  2), ("e",            1,  3), ("r",            1,  4),
+    ("t",            1,  5), ("y",            1,  6), ("u",            1,  7), ("i",            1,  8), ("o",            1,  9),
+    ("p",            1, 10), ("left_bracket", 1, 11), ("right_bracket",1, 12), ("backslash",    1, 13),
+
+    ("caps_lock",    2,  0), ("a",            2,  1), ("s",            2,  2), ("d",            2,  3), ("f",            2,  4),
+    ("g",            2,  5), ("h",            2,  6), ("j",            2,  7), ("k",            2,  8), ("l",            2,  9),
+    ("semicolon",    2, 10), ("quote",        2, 11), ("enter",        2, 12),
+
+    ("left_shift",   3,  0), ("z",            3,  1), ("x",            3,  2), ("c",            3,  3), ("v",            3,  4),
+    ("b",            3,  5), ("n",            3,  6), ("m",            3,  7), ("comma",        3,  8), ("period",       3,  9),
+    ("slash",        3, 10), ("right_shift",  3, 11), ("up",           3, 12),
+
+    ("left_ctrl",    4,  0), ("left_win",     4,  1), ("left_alt",     4,  2), ("space",        4,  3), ("right_alt",    4,  4),
+    ("fn",           4,  5), ("left",         4,  6), ("down",         4,  7), ("right",        4,  8),
+];
+
+pub fn key_position(name: &str) -> Option<(usize, usize)>
+{
+    return KEYS.iter()
+        .find(|(key, _, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, row, column)| (*row, *column));
+}
+
+//Names of the keys in a row of the layout, left to right. Rows past the bottom one are empty.
+pub fn row_keys(row: usize) -> impl Iterator<Item = &'static str>
+{
+    return KEYS.iter().filter(move |(_, r, _)| *r == row).map(|(name, _, _)| *name);
+}
+
+pub fn row_count() -> usize
+{
+    return KEYS.iter().map(|(_, row, _)| row + 1).max().unwrap_or(0);
+}
+
+
+//Painting keys, rows and regions on any device that names its leds after the keys, like the sk621.
+//They only set the colors, call display to show them.
+
+//Returns false when the device has no key with this name
+pub fn set_key_color(device: &mut dyn RgbDevice, name: &str, color: Color) -> bool
+{
+    match device.find_led(name)
+    {
+        Some(led) =>
+        {
+            device.set_led_color(led, color);
+            return true;
+        }
+        None => return false,
+    }
+}
+
+pub fn set_row_color(device: &mut dyn RgbDevice, row: usize, color: Color)
+{
+    for name in row_keys(row)
+    {
+        set_key_color(device, name, color);
+    }
+}
+
+//Color a region, like "w", "a", "s", "d". Returns the names the device has no key for.
+pub fn set_region_color<'a>(device: &mut dyn RgbDevice, names: &[&'a str], color: Color) -> Vec<&'a str>
+{
+    return names.iter().copied().filter(|name| !set_key_color(device, name, color)).collect();
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::color::{RgbMode, RgbSpeed};
+
+    #[test]
+    fn keys_fit_the_matrix_once()
+    {
+        for (index, (name, row, column)) in KEYS.iter().enumerate()
+        {
+            assert!(*row < ROWS && *column < COLUMNS, "{} is outside the matrix", name);
+            assert!(KEYS[index + 1..].iter().all(|(other, r, c)| other != name && (r, c) != (row, column)), "{} is not unique", name);
+        }
+    }
+
+    #[test]
+    fn covers_the_whole_keyboard()
+    {
+        let rows: Vec<usize> = (0..row_count()).map(|row| row_keys(row).count()).collect();
+        assert_eq!(rows, vec![14, 14, 13, 13, 9]);
+        assert_eq!(row_keys(row_count()).count(), 0);
+
+        //Columns in a row are left to right without gaps
+        for row in 0..row_count()
+        {
+            let columns: Vec<usize> = row_keys(row).map(|name| key_position(name).unwrap().1).collect();
+            assert_eq!(columns, (0..columns.len()).collect::<Vec<usize>>());
+        }
+    }
+
+    //The matrix like the sk621 lays it out, leds indexed row by row
+    struct Matrix
+    {
+        name: String,
+        colors: Vec<Color>,
+    }
+
+    impl RgbDevice for Matrix
+    {
+        fn set_color(&mut self, color: Color)
+        {
+            self.colors = vec![color; ROWS * COLUMNS];
+        }
+        fn set_secondary_color(&mut self, _color: Color){}
+        fn set_mode(&mut self, _mode: RgbMode){}
+        fn set_speed(&mut self, _speed: RgbSpeed){}
+        fn set_brightness(&mut self, _brightness: f32){}
+        fn get_name(&self) -> &String
+        {
+            return &self.name;
+        }
+        fn display(&mut self){}
+
+        fn get_led_count(&self) -> usize
+        {
+            return ROWS * COLUMNS;
+        }
+
+        fn set_led_color(&mut self, index: usize, color: Color)
+        {
+            self.colors[index] = color;
+        }
+
+        fn find_led(&self, name: &str) -> Option<usize>
+        {
+            let (row, column) = key_position(name)?;
+            return Some(row * COLUMNS + column);
+        }
+    }
+
+    fn matrix() -> Matrix
+    {
+        return Matrix { name: "sk621".to_string(), colors: vec![BLACK; ROWS * COLUMNS] };
+    }
+
+    const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+    const RED: Color = Color { r: 255, g: 0, b: 0 };
+
+    fn lit(device: &Matrix) -> Vec<(usize, usize)>
+    {
+        return device.colors.iter().enumerate().filter(|(_, c)| **c == RED).map(|(led, _)| (led / COLUMNS, led % COLUMNS)).collect();
+    }
+
+    #[test]
+    fn paints_single_keys()
+    {
+        let mut device = matrix();
+        assert!(set_key_color(&mut device, "Esc", RED));
+        assert!(!set_key_color(&mut device, "f13", RED));
+        assert_eq!(lit(&device), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn paints_rows()
+    {
+        let mut device = matrix();
+        set_row_color(&mut device, 4, RED);
+        set_row_color(&mut device, 7, RED);
+        assert_eq!(lit(&device), (0..9).map(|column| (4, column)).collect::<Vec<(usize, usize)>>());
+    }
+
+    #[test]
+    fn paints_regions()
+    {
+        let mut device = matrix();
+        assert_eq!(set_region_color(&mut device, &["w", "a", "s", "d", "numpad_5"], RED), vec!["numpad_5"]);
+        assert_eq!(lit(&device), vec![(1, 2), (2, 1), (2, 2), (2, 3)]);
+    }
+
+    #[test]
+    fn finds_keys_by_name()
+    {
+        assert_eq!(key_position("W"), Some((1, 2)));
+        assert_eq!(key_position("space"), Some((4, 3)));
+        assert_eq!(key_position("f13"), None);
+    }
+}
@@ -1,24 +1,54 @@
-#[macro_use]
-extern crate windows_service;
-
-use std::ffi::OsString;
-use windows_service::{service_dispatcher, service_control_handler};
-
-define_windows_service!(ffi_service_main, service_main);
-
 mod color;
 mod z390;
+mod mystic_light;
+#[cfg(windows)]
 mod rtx2080;
 mod animation;
+#[cfg(windows)]
 mod sk621;
+mod keyboard;
+#[cfg(windows)]
+mod service;
+mod cli;
+mod config;
+mod scene;
+mod frame;
+mod compositor;
+mod timeline;
+mod effects;
+mod audio;
+mod screen;
+mod sensors;
+mod notification;
+mod control;
+mod ipc;
+mod openrgb;
+mod dmx;
+mod network;
+#[cfg(feature = "http")]
+mod http;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+#[cfg(all(target_os = "linux", feature = "dbus"))]
+mod dbus;
+mod reactive;
+mod recording;
+mod trace;
+#[cfg(test)]
+mod testing;
 
 use crate::color::RgbDevice;
+use crate::animation::Effect;
+use crate::frame::Frame;
+use crate::compositor::{Compositor, Layer};
+use crate::control::Control;
+use crate::scene::Scene;
+#[cfg(windows)]
 use rtx2080::Rtx2080;
+#[cfg(windows)]
 use sk621::Sk621;
 use std::thread::sleep;
 use std::time::{Duration, SystemTime};
-use windows_service::service_control_handler::ServiceControlHandlerResult;
-use windows_service::service::{ServiceControl, ServiceStatus, ServiceType, ServiceState, ServiceControlAccept, ServiceExitCode};
 //use cooler_master_sdk::CoolerMasterDevice;
 //use cooler_master_sdk::ffi::DeviceIndex;
 
@@ -50,98 +80,116 @@ fn main()
     //    }
     //}
 
-    run_animation();
+    let mut arguments: Vec<String> = std::env::args().skip(1).collect();
+
+    //Works with every command that runs the animation
+    if let Some(index) = arguments.iter().position(|a| a == "--record")
+    {
+        match arguments.get(index + 1)
+        {
+            Some(path) => recording::record_to(path.into()),
+            None => println!("--record needs a file"),
+        }
+        arguments.drain(index..(index + 2).min(arguments.len()));
+    }
+    let always_trace = arguments.iter().any(|a| a == "--trace");
+    arguments.retain(|a| a != "--trace");
+    trace::start(always_trace);
+
+    if !arguments.is_empty()
+    {
+        cli::run(&arguments);
+        return;
+    }
+
+    run_animation(Box::new(animation::ColorSpectrum::load()));
 }
 
 //Service mode
+#[cfg(windows)]
 #[allow(dead_code)] //disable warnings when running "debug" mode
 fn main2() -> Result<(), windows_service::Error> {
-    // Register generated `ffi_service_main` with the system and start the service, blocking
-    // this thread until the service is stopped.
-    service_dispatcher::start("RusticLight", ffi_service_main)?;
-    Ok(())
-}
-
-fn service_main(arguments: Vec<OsString>)
-{
-    run_service(arguments).unwrap();
+    return service::start();
 }
 
 static mut RUNNING: bool = true;
 
 
-fn run_service(_arguments: Vec<OsString>) -> Result<(), windows_service::Error> {
-
-    let event_handler = move |control_event| -> ServiceControlHandlerResult {
-        match control_event {
-            ServiceControl::Stop =>
-            {
-                unsafe { RUNNING = false; }
-                // Handle stop event and return control back to the system.
-                ServiceControlHandlerResult::NoError
-            }
-            // All services must accept Interrogate even if it's a no-op.
-            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
-            _ => ServiceControlHandlerResult::NotImplemented,
-        }
-    };
-
-    // Register system service event handler
-    let status_handle = service_control_handler::register("RusticLight", event_handler)?;
-
-    let next_status = ServiceStatus {
-        // Should match the one from system service registry
-        service_type: ServiceType::OWN_PROCESS,
-        // The new state
-        current_state: ServiceState::Running,
-        // Accept stop events when running
-        controls_accepted: ServiceControlAccept::STOP,
-        // Used to report an error when starting or stopping only, otherwise must be zero
-        exit_code: ServiceExitCode::Win32(0),
-        // Only used for pending states, otherwise must be zero
-        checkpoint: 0,
-        // Only used for pending states, otherwise must be zero
-        wait_hint: Duration::default(),
-
-        process_id: None
-    };
-
-    // Tell the system that the service is running now
-    status_handle.set_service_status(next_status.clone())?;
-
-    run_animation();
-
-    let next_status = ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Stopped,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: Duration::default(),
-        process_id: None
-    };
-    status_handle.set_service_status(next_status)?;
-
-    #[allow(unreachable_code)]
-    Ok(())
+pub fn get_rgb_devices() -> Vec<Box<dyn RgbDevice>>
+{
+    let mut rgb_devices: Vec<Box<dyn RgbDevice>> = z390::get_z390_rgb_devices();
+    //nvapi and the cooler master sdk only exist on windows
+    #[cfg(windows)]
+    rgb_devices.push(Box::new(Rtx2080::new()));
+    #[cfg(windows)]
+    rgb_devices.push(Box::new(Sk621::new()));
+    rgb_devices.extend(openrgb::get_openrgb_rgb_devices());
+    rgb_devices.extend(network::get_network_rgb_devices());
+    return rgb_devices;
 }
 
-
-fn run_animation()
+pub fn run_animation(base: Box<dyn Effect>)
 {
     let start = SystemTime::now();
 
-    let mut rgb_devices: Vec<Box<dyn RgbDevice>> = z390::get_z390_rgb_devices();
-    rgb_devices.push(Box::new(Rtx2080::new()));
-    rgb_devices.push(Box::new(Sk621::new()));
+    let mut rgb_devices = get_rgb_devices();
+    let mut recorder = None;
+    if let Some(path) = recording::output()
+    {
+        match recording::record(rgb_devices, &path)
+        {
+            Ok((recording_devices, r)) =>
+            {
+                rgb_devices = recording_devices;
+                recorder = Some(r);
+            }
+            Err(e) =>
+            {
+                println!("Unable to record: {}", e);
+                return;
+            }
+        }
+    }
+
+    let scene = Scene::load();
+    let mut frame = Frame::new(&scene, &rgb_devices);
+    let mut compositor = Compositor::new();
+    compositor.push(Layer::new("base", base));
+
+    let mut control = Control::new();
+    ipc::serve(control.sender());
+    openrgb::serve(control.sender());
+    dmx::serve(control.sender());
+    #[cfg(feature = "http")]
+    http::serve(control.sender());
+    #[cfg(feature = "mqtt")]
+    mqtt::serve(control.sender());
+    #[cfg(all(target_os = "linux", feature = "dbus"))]
+    dbus::serve(control.sender());
 
     loop
     {
-        let millis = SystemTime::now().duration_since(start).unwrap().as_millis();
-        animation::color_spectrum(&mut rgb_devices, millis);
-        for d in rgb_devices.iter_mut()
+        control.handle(&mut compositor, &frame);
+
+        if !control.paused
         {
-            d.display();
+            let millis = SystemTime::now().duration_since(start).unwrap().as_millis();
+            compositor.render(&mut frame, millis);
+            control.publish_frame(&frame, millis);
+            if let Some(recorder) = &recorder
+            {
+                recorder.borrow_mut().set_millis(millis);
+            }
+            frame.apply(&mut rgb_devices);
+            for d in rgb_devices.iter_mut()
+            {
+                d.display();
+            }
+            //Ctrl+C is the usual way to stop, so nothing may wait in the buffer
+            if let Some(recorder) = &recorder
+            {
+                recorder.borrow_mut().flush();
+            }
         }
 
         sleep(Duration::from_millis(10));
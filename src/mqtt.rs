@@ -0,0 +1,736 @@
+use rumqttc::{Client, Event, Incoming, LastWill, MqttOptions, QoS};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::color::Color;
+use crate::config;
+use crate::control::{self, Reply, Request};
+use crate::effects;
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+
+
+//Home Assistant lights over MQTT: one for everything, with the effects, and one per device. Only runs when there is a mqtt.json:
+//{ "host": "192.168.1.10", "port": 1883, "username": "", "password": "" }
+//
+//Discovery is published to homeassistant/light/rustic_light_<light>/config, the lights use the json schema:
+//    rustic_light/<light>/set      <- { "state": "ON", "brightness": 128, "color": { "r": 255, "g": 0, "b": 0 }, "effect": "wave" }
+//    rustic_light/<light>/state    -> the same fields, retained
+//    rustic_light/status           -> online / offline
+//A device light shows the animation until it gets a color, the "animation" effect switches back.
+
+const ALL: &str = "all";
+const DEVICE_EFFECTS: [&str; 2] = ["animation", "solid"];
+
+
+#[derive(Deserialize)]
+struct MqttConfig
+{
+    host: String,
+    #[serde(default = "default_port")]
+    port: u16,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    password: String,
+    #[serde(default = "default_client_id")]
+    client_id: String,      //Also the prefix of the unique ids, change it when there are more pcs
+    #[serde(default = "default_topic")]
+    topic: String,
+    #[serde(default = "default_discovery_prefix")]
+    discovery_prefix: String,
+}
+
+fn default_port() -> u16
+{
+    return 1883;
+}
+
+fn default_client_id() -> String
+{
+    return "rustic_light".to_string();
+}
+
+fn default_topic() -> String
+{
+    return "rustic_light".to_string();
+}
+
+fn default_discovery_prefix() -> String
+{
+    return "homeassistant".to_string();
+}
+
+
+#[derive(Deserialize, Clone, Copy)]
+struct RgbColor
+{
+    r: u8,
+    g: u8,
+    b: u8,
+}
+
+#[derive(Deserialize)]
+struct LightCommand
+{
+    state: Option<String>,
+    brightness: Option<u8>,
+    color: Option<RgbColor>,
+    effect: Option<String>,
+}
+
+//What a light looks like to Home Assistant
+struct Light
+{
+    id: String,
+    device: Option<String>,     //None for the light that controls everything
+    leds: usize,
+    color: Option<Color>,       //Device lights only, the color that replaces the animation
+    last_brightness: f32,       //To come back to after off
+}
+
+//Everything the worker thread reacts to
+enum Message
+{
+    Connected,
+    Command(String, Vec<u8>),
+    Event(Value),
+}
+
+
+pub fn serve(sender: Sender<Request>)
+{
+    let mqtt_config: MqttConfig = match config::load("mqtt.json")
+    {
+        Some(mqtt_config) => mqtt_config,
+        None => return,
+    };
+    start(mqtt_config, sender);
+}
+
+fn start(mqtt_config: MqttConfig, sender: Sender<Request>)
+{
+    let mut options = MqttOptions::new(mqtt_config.client_id.as_str(), mqtt_config.host.as_str(), mqtt_config.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    options.set_last_will(LastWill::new(format!("{}/status", mqtt_config.topic), "offline", QoS::AtLeastOnce, true));
+    if !mqtt_config.username.is_empty()
+    {
+        options.set_credentials(mqtt_config.username.as_str(), mqtt_config.password.as_str());
+    }
+    let (client, mut connection) = Client::new(options, 100);
+
+    let (messages, received) = channel();
+
+    //The connection has to be polled for anything to happen, including the publishes from the worker
+    let connection_messages = messages.clone();
+    let host = format!("{}:{}", mqtt_config.host, mqtt_config.port);
+    thread::spawn(move ||
+    {
+        let mut connected = true;
+        for event in connection.iter()
+        {
+            let message = match event
+            {
+                Ok(Event::Incoming(Incoming::ConnAck(_))) =>
+                {
+                    println!("Connected to mqtt broker {}", host);
+                    connected = true;
+                    Message::Connected
+                }
+                Ok(Event::Incoming(Incoming::Publish(publish))) => Message::Command(publish.topic, publish.payload.to_vec()),
+                Ok(_) => continue,
+                Err(e) =>
+                {
+                    //Reconnects on the next iteration
+                    if connected
+                    {
+                        println!("Mqtt broker {}: {}", host, e);
+                        connected = false;
+                    }
+                    thread::sleep(Duration::from_secs(5));
+                    continue;
+                }
+            };
+            if connection_messages.send(message).is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    let event_sender = sender.clone();
+    thread::spawn(move ||
+    {
+        //Blocks until the animation handles requests
+        let events = match control::call(&event_sender, "subscribe", Value::Null)
+        {
+            Reply::Subscribed(events) => events,
+            _ => return,
+        };
+        for event in events.iter()
+        {
+            if messages.send(Message::Event(event)).is_err()
+            {
+                return;
+            }
+        }
+    });
+
+    thread::spawn(move ||
+    {
+        let mut lights = match control::call(&sender, "list-devices", Value::Null)
+        {
+            Reply::Ok(Value::Array(devices)) => create_lights(&devices),
+            _ => return,
+        };
+        let mut state = get_state(&sender);
+
+        for message in received.iter()
+        {
+            match message
+            {
+                Message::Connected =>
+                {
+                    publish_discovery(&client, &mqtt_config, &lights);
+                    let _ = client.subscribe(format!("{}/+/set", mqtt_config.topic), QoS::AtLeastOnce);
+                    //Home Assistant restarted and forgot the lights
+                    let _ = client.subscribe(format!("{}/status", mqtt_config.discovery_prefix), QoS::AtLeastOnce);
+                }
+                Message::Command(topic, payload) =>
+                {
+                    if topic == format!("{}/status", mqtt_config.discovery_prefix)
+                    {
+                        if payload == b"online"
+                        {
+                            publish_discovery(&client, &mqtt_config, &lights);
+                        }
+                        continue;
+                    }
+
+                    let id = match light_id(&topic, &mqtt_config.topic)
+                    {
+                        Some(id) => id,
+                        None => continue,
+                    };
+                    let light = match lights.iter_mut().find(|l| l.id == id)
+                    {
+                        Some(light) => light,
+                        None => continue,
+                    };
+                    let base_changed = match serde_json::from_slice::<LightCommand>(&payload)
+                    {
+                        Ok(command) => execute(&sender, light, &state, command),
+                        Err(e) =>
+                        {
+                            println!("Ignoring mqtt command on {}: {}", topic, e);
+                            false
+                        }
+                    };
+                    if base_changed
+                    {
+                        for light in lights.iter_mut()
+                        {
+                            light.color = None;
+                        }
+                    }
+                    //Read back what the commands did, set-leds doesn't send a state event
+                    state = get_state(&sender);
+                }
+                Message::Event(event) =>
+                {
+                    if event["event"] != "state"
+                    {
+                        continue;
+                    }
+                    //A new effect or color from anywhere replaces the device colors, see Control::set_base
+                    let data = &event["data"];
+                    if data["effect"] != state["effect"] || data["color"] != state["color"]
+                    {
+                        for light in lights.iter_mut()
+                        {
+                            light.color = None;
+                        }
+                    }
+                    state = data.clone();
+                }
+            }
+
+            for light in lights.iter()
+            {
+                let topic = format!("{}/{}/state", mqtt_config.topic, light.id);
+                let _ = client.publish(topic, QoS::AtLeastOnce, true, light_state(light, &state).to_string());
+            }
+        }
+    });
+}
+
+fn create_lights(devices: &[Value]) -> Vec<Light>
+{
+    let mut lights = vec![Light { id: ALL.to_string(), device: None, leds: 0, color: None, last_brightness: 1.0 }];
+    for device in devices.iter()
+    {
+        let name = device["name"].as_str().unwrap_or("").to_string();
+
+        //Names that only differ in case or punctuation get a number, the first keeps the plain id
+        let mut id = object_id(&name);
+        let mut number = 2;
+        while lights.iter().any(|l| l.id == id)
+        {
+            id = format!("{}_{}", object_id(&name), number);
+            number += 1;
+        }
+
+        lights.push(Light
+        {
+            id,
+            device: Some(name),
+            leds: device["leds"].as_u64().unwrap_or(0) as usize,
+            color: None,
+            last_brightness: 1.0,
+        });
+    }
+    return lights;
+}
+
+//The id in "<topic>/<id>/set", None for every other topic
+fn light_id<'a>(topic: &'a str, base: &str) -> Option<&'a str>
+{
+    let id = topic.strip_prefix(base)?.strip_prefix('/')?.strip_suffix("/set")?;
+    return if id.is_empty() || id.contains('/') { None } else { Some(id) };
+}
+
+//Topics and ids only allow a few characters
+fn object_id(name: &str) -> String
+{
+    return name.to_lowercase().chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+}
+
+fn get_state(sender: &Sender<Request>) -> Value
+{
+    return match control::call(sender, "get-state", Value::Null)
+    {
+        Reply::Ok(state) => state,
+        _ => Value::Null,
+    };
+}
+
+fn publish_discovery(client: &Client, mqtt_config: &MqttConfig, lights: &[Light])
+{
+    let topic = |light: &Light, kind: &str| format!("{}/{}/{}", mqtt_config.topic, light.id, kind);
+
+    let _ = client.publish(format!("{}/status", mqtt_config.topic), QoS::AtLeastOnce, true, "online");
+    for light in lights.iter()
+    {
+        let unique_id = format!("{}_{}", mqtt_config.client_id, light.id);
+        let effect_list: Vec<&str> = match light.device
+        {
+            Some(_) => DEVICE_EFFECTS.to_vec(),
+            None => effects::NAMES.to_vec(),
+        };
+        let discovery = json!(
+        {
+            "name": light.device.clone().unwrap_or("All".to_string()),
+            "unique_id": unique_id,
+            "schema": "json",
+            "command_topic": topic(light, "set"),
+            "state_topic": topic(light, "state"),
+            "availability_topic": format!("{}/status", mqtt_config.topic),
+            "brightness": true,
+            "supported_color_modes": ["rgb"],
+            "effect": true,
+            "effect_list": effect_list,
+            "device": { "identifiers": [mqtt_config.client_id], "name": mqtt_config.client_id, "manufacturer": "rustic_light" },
+        });
+        let _ = client.publish(format!("{}/light/{}/config", mqtt_config.discovery_prefix, unique_id), QoS::AtLeastOnce, true, discovery.to_string());
+    }
+}
+
+fn brightness(light: &Light, state: &Value) -> f32
+{
+    let brightness = match &light.device
+    {
+        Some(device) => state["device_brightness"][device].as_f64().unwrap_or(1.0),
+        None => state["brightness"].as_f64().unwrap_or(1.0),
+    };
+    return brightness as f32;
+}
+
+fn light_state(light: &Light, state: &Value) -> Value
+{
+    let brightness = brightness(light, state);
+    let (effect, color) = match &light.device
+    {
+        Some(_) => match light.color
+        {
+            Some(color) => (json!("solid"), Some(color)),
+            None => (json!("animation"), None),
+        },
+        None => (state["effect"].clone(), state["color"].as_str().and_then(Color::parse)),
+    };
+
+    let mut light_state = json!(
+    {
+        "state": if brightness > 0.0 { "ON" } else { "OFF" },
+        "brightness": (brightness * 255.0).round() as u8,
+        "color_mode": "rgb",
+        "effect": effect,
+    });
+    if let Some(color) = color
+    {
+        light_state["color"] = json!({ "r": color.r, "g": color.g, "b": color.b });
+    }
+    return light_state;
+}
+
+//True when the effect or color of everything changed, which clears the device colors
+fn execute(sender: &Sender<Request>, light: &mut Light, state: &Value, command: LightCommand) -> bool
+{
+    let mut calls: Vec<(&str, Value)> = Vec::new();
+    let current = brightness(light, state);
+
+    let brightness = match (command.state.as_deref(), command.brightness)
+    {
+        (Some("OFF"), _) =>
+        {
+            if current > 0.0
+            {
+                light.last_brightness = current;
+            }
+            Some(0.0)
+        }
+        (_, Some(brightness)) => Some(brightness as f32 / 255.0),
+        (Some("ON"), None) if current == 0.0 => Some(light.last_brightness),
+        _ => None,
+    };
+
+    match light.device.clone()
+    {
+        None =>
+        {
+            if let Some(effect) = command.effect
+            {
+                calls.push(("set-effect", json!({ "name": effect })));
+            }
+            if let Some(c) = command.color
+            {
+                calls.push(("set-color", json!({ "color": Color::new(c.r, c.g, c.b) })));
+            }
+            if let Some(brightness) = brightness
+            {
+                calls.push(("set-brightness", json!({ "brightness": brightness })));
+            }
+        }
+        Some(device) =>
+        {
+            if let Some(c) = command.color
+            {
+                light.color = Some(Color::new(c.r, c.g, c.b));
+            }
+            match command.effect.as_deref()
+            {
+                Some("animation") => light.color = None,
+                Some("solid") if light.color.is_none() => light.color = Some(Color::new(255, 255, 255)),
+                _ => {}
+            }
+
+            if command.color.is_some() || command.effect.is_some()
+            {
+                match light.color
+                {
+                    Some(color) => calls.push(("set-leds", json!({ "device": device, "colors": vec![color; light.leds] }))),
+                    None => calls.push(("clear-leds", json!({ "device": device }))),
+                }
+            }
+            if let Some(brightness) = brightness
+            {
+                calls.push(("set-device-brightness", json!({ "device": device, "brightness": brightness })));
+            }
+        }
+    }
+
+    let mut base_changed = false;
+    for (method, params) in calls.into_iter()
+    {
+        match control::call(sender, method, params)
+        {
+            Reply::Err(e) => println!("Mqtt: {}", e),
+            _ => base_changed |= method == "set-effect" || method == "set-color",
+        }
+    }
+    return base_changed;
+}
+
+
+//================================================================================================================================================================================================
+//Tests
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::control::spawn_virtual;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::mpsc::Receiver;
+    use std::sync::{Arc, Mutex};
+    use std::time::Instant;
+
+    fn lights(names: &[&str]) -> Vec<Light>
+    {
+        let devices: Vec<Value> = names.iter().map(|name| json!({ "name": name, "leds": 2 })).collect();
+        return create_lights(&devices);
+    }
+
+    fn light(lights: Vec<Light>, id: &str) -> Light
+    {
+        return lights.into_iter().find(|l| l.id == id).unwrap();
+    }
+
+    #[test]
+    fn object_ids_are_unique()
+    {
+        assert_eq!(object_id("Desk Strip #2"), "desk_strip__2");
+        assert_eq!(object_id("JRgb1"), "jrgb1");
+
+        let lights = lights(&["Desk Strip", "desk-strip", "All", "desk_strip_2"]);
+        let ids: Vec<&str> = lights.iter().map(|l| l.id.as_str()).collect();
+        assert_eq!(ids, vec!["all", "desk_strip", "desk_strip_2", "all_2", "desk_strip_2_2"]);
+        assert_eq!(lights[2].device.as_deref(), Some("desk-strip"));
+    }
+
+    #[test]
+    fn command_topics_match_exactly()
+    {
+        assert_eq!(light_id("rustic_light/all/set", "rustic_light"), Some("all"));
+        assert_eq!(light_id("rustic_light/desk_strip_2/set", "rustic_light"), Some("desk_strip_2"));
+        //Stripped once, not as often as it repeats
+        assert_eq!(light_id("rustic_light/set/set", "rustic_light"), Some("set"));
+        assert_eq!(light_id("rustic_light/rustic_light/all/set", "rustic_light"), None);
+
+        assert_eq!(light_id("rustic_light/all/state", "rustic_light"), None);
+        assert_eq!(light_id("other/all/set", "rustic_light"), None);
+        assert_eq!(light_id("rustic_lightx/all/set", "rustic_light"), None);
+        assert_eq!(light_id("rustic_light/set", "rustic_light"), None);
+        assert_eq!(light_id("rustic_light//set", "rustic_light"), None);
+        assert_eq!(light_id("rustic_light/a/b/set", "rustic_light"), None);
+    }
+
+    #[test]
+    fn light_state_follows_the_animation_state()
+    {
+        let state = json!({ "effect": "wave", "color": null, "brightness": 0.5, "device_brightness": { "strip": 0.0 } });
+        let all = light(lights(&["strip"]), ALL);
+        assert_eq!(light_state(&all, &state), json!({ "state": "ON", "brightness": 128, "color_mode": "rgb", "effect": "wave" }));
+
+        let solid = json!({ "effect": null, "color": "#ff8000", "brightness": 1.0 });
+        assert_eq!(light_state(&all, &solid)["color"], json!({ "r": 255, "g": 128, "b": 0 }));
+
+        let mut strip = light(lights(&["strip"]), "strip");
+        assert_eq!(light_state(&strip, &state), json!({ "state": "OFF", "brightness": 0, "color_mode": "rgb", "effect": "animation" }));
+        strip.color = Some(Color::new(1, 2, 3));
+        let strip_state = light_state(&strip, &Value::Null);
+        assert_eq!(strip_state["state"], "ON");
+        assert_eq!(strip_state["effect"], "solid");
+        assert_eq!(strip_state["color"], json!({ "r": 1, "g": 2, "b": 3 }));
+    }
+
+    fn command(text: &str) -> LightCommand
+    {
+        return serde_json::from_str(text).unwrap();
+    }
+
+    #[test]
+    fn execute_turns_devices_off_and_on()
+    {
+        //The light for everything would change the master brightness of every test, only effects for that one
+        let sender = spawn_virtual(&[("strip", 2)]);
+        let mut strip = light(lights(&["strip"]), "strip");
+
+        assert!(!execute(&sender, &mut strip, &get_state(&sender), command(r#"{ "brightness": 51 }"#)));
+        assert_eq!(brightness(&strip, &get_state(&sender)), 0.2);
+        execute(&sender, &mut strip, &get_state(&sender), command(r#"{ "state": "OFF" }"#));
+        assert_eq!(brightness(&strip, &get_state(&sender)), 0.0);
+        execute(&sender, &mut strip, &get_state(&sender), command(r#"{ "state": "ON" }"#));
+        assert_eq!(brightness(&strip, &get_state(&sender)), 0.2);
+
+        assert!(!execute(&sender, &mut strip, &get_state(&sender), command(r#"{ "effect": "solid" }"#)));
+        assert_eq!(strip.color, Some(Color::new(255, 255, 255)));
+        execute(&sender, &mut strip, &get_state(&sender), command(r#"{ "effect": "animation" }"#));
+        assert_eq!(strip.color, None);
+
+        let mut all = light(lights(&["strip"]), ALL);
+        assert!(execute(&sender, &mut all, &get_state(&sender), command(r#"{ "effect": "wave" }"#)));
+        assert_eq!(get_state(&sender)["effect"], "wave");
+        assert!(!execute(&sender, &mut all, &get_state(&sender), command(r#"{ "effect": "nothing" }"#)));
+    }
+
+    //Just enough of an mqtt 3.1.1 broker for a single client: acknowledges everything, hands the publishes of the client
+    //to the test and sends the client whatever the test publishes
+    struct Broker
+    {
+        port: u16,
+        stream: Arc<Mutex<Option<TcpStream>>>,
+        published: Receiver<(String, Vec<u8>)>,
+    }
+
+    impl Broker
+    {
+        fn start() -> Self
+        {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let stream = Arc::new(Mutex::new(None));
+            let (sender, published) = channel();
+
+            let writer = stream.clone();
+            thread::spawn(move ||
+            {
+                let (mut reader, _) = listener.accept().unwrap();
+                *writer.lock().unwrap() = Some(reader.try_clone().unwrap());
+                let reply = |packet: &[u8]| writer.lock().unwrap().as_mut().unwrap().write_all(packet).unwrap();
+
+                while let Some((header, body)) = read_packet(&mut reader)
+                {
+                    match header >> 4
+                    {
+                        1 => reply(&[0x20, 2, 0, 0]), //Connect, accepted
+                        3 =>
+                        {
+                            let topic_length = u16::from_be_bytes([body[0], body[1]]) as usize;
+                            let topic = String::from_utf8(body[2..2 + topic_length].to_vec()).unwrap();
+                            let mut payload = &body[2 + topic_length..];
+                            if (header >> 1) & 3 > 0
+                            {
+                                reply(&[0x40, 2, payload[0], payload[1]]);
+                                payload = &payload[2..];
+                            }
+                            let _ = sender.send((topic, payload.to_vec()));
+                        }
+                        8 =>
+                        {
+                            //A granted qos 1 for every topic filter
+                            let mut topics = 0;
+                            let mut position = 2;
+                            while position < body.len()
+                            {
+                                position += 2 + u16::from_be_bytes([body[position], body[position + 1]]) as usize + 1;
+                                topics += 1;
+                            }
+                            let mut suback = vec![0x90, 2 + topics as u8, body[0], body[1]];
+                            suback.extend(vec![1; topics]);
+                            reply(&suback);
+                        }
+                        12 => reply(&[0xD0, 0]), //Ping
+                        _ => {}
+                    }
+                }
+            });
+            return Broker { port, stream, published };
+        }
+
+        //Qos 0, so the client doesn't have to answer. Less than 128 bytes, the length is a single byte.
+        fn publish(&self, topic: &str, payload: &str)
+        {
+            let mut packet = vec![0x30, (2 + topic.len() + payload.len()) as u8];
+            packet.extend_from_slice(&(topic.len() as u16).to_be_bytes());
+            packet.extend_from_slice(topic.as_bytes());
+            packet.extend_from_slice(payload.as_bytes());
+            self.stream.lock().unwrap().as_mut().unwrap().write_all(&packet).unwrap();
+        }
+
+        //The first publish on the topic that passes the check, the others are skipped
+        fn wait(&self, topic: &str, check: impl Fn(&Value) -> bool) -> Value
+        {
+            let start = Instant::now();
+            while start.elapsed() < Duration::from_secs(5)
+            {
+                if let Ok((published, payload)) = self.published.recv_timeout(Duration::from_millis(100))
+                {
+                    let payload = serde_json::from_slice(&payload).unwrap_or(Value::String(String::from_utf8_lossy(&payload).to_string()));
+                    if published == topic && check(&payload)
+                    {
+                        return payload;
+                    }
+                }
+            }
+            panic!("nothing published on {}", topic);
+        }
+    }
+
+    //Fixed header and the rest, None when the client went away
+    fn read_packet(stream: &mut TcpStream) -> Option<(u8, Vec<u8>)>
+    {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte).ok()?;
+        let header = byte[0];
+
+        let mut length = 0;
+        let mut shift = 0;
+        loop
+        {
+            stream.read_exact(&mut byte).ok()?;
+            length |= ((byte[0] & 0x7F) as usize) << shift;
+            shift += 7;
+            if byte[0] & 0x80 == 0
+            {
+                break;
+            }
+        }
+
+        let mut body = vec![0u8; length];
+        stream.read_exact(&mut body).ok()?;
+        return Some((header, body));
+    }
+
+    #[test]
+    fn home_assistant_lights_over_a_local_broker()
+    {
+        let broker = Broker::start();
+        let sender = spawn_virtual(&[("Desk Strip", 3), ("desk-strip", 2)]);
+        let mqtt_config = MqttConfig
+        {
+            host: "127.0.0.1".to_string(),
+            port: broker.port,
+            username: String::new(),
+            password: String::new(),
+            client_id: default_client_id(),
+            topic: default_topic(),
+            discovery_prefix: default_discovery_prefix(),
+        };
+        start(mqtt_config, sender.clone());
+
+        broker.wait("rustic_light/status", |payload| payload == "online");
+        let all = broker.wait("homeassistant/light/rustic_light_all/config", |_| true);
+        assert_eq!(all["command_topic"], "rustic_light/all/set");
+        assert_eq!(all["effect_list"], json!(effects::NAMES));
+        broker.wait("homeassistant/light/rustic_light_desk_strip/config", |discovery| discovery["name"] == "Desk Strip");
+        let second = broker.wait("homeassistant/light/rustic_light_desk_strip_2/config", |_| true);
+        assert_eq!(second["name"], "desk-strip");
+        assert_eq!(second["state_topic"], "rustic_light/desk_strip_2/state");
+
+        //A color for a device light goes to that device only
+        broker.publish("rustic_light/desk_strip_2/set", r#"{ "color": { "r": 1, "g": 2, "b": 3 } }"#);
+        broker.wait("rustic_light/desk_strip_2/state", |state| state["effect"] == "solid" && state["color"]["g"] == 2);
+        let start = Instant::now();
+        loop
+        {
+            let frame = match control::call(&sender, "get-frame", Value::Null)
+            {
+                Reply::Ok(frame) => frame,
+                _ => panic!("no frame"),
+            };
+            if frame[1]["colors"] == json!(["#010203", "#010203"])
+            {
+                assert_ne!(frame[0]["colors"][0], "#010203");
+                break;
+            }
+            assert!(start.elapsed() < Duration::from_secs(5), "the device never showed the color: {}", frame);
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        //An effect for everything takes the device back to the animation
+        broker.publish("rustic_light/all/set", r#"{ "effect": "wave" }"#);
+        broker.wait("rustic_light/all/state", |state| state["effect"] == "wave");
+        broker.wait("rustic_light/desk_strip_2/state", |state| state["effect"] == "animation");
+    }
+}
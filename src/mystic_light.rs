@@ -0,0 +1,644 @@
+use crate::color::Color;
+
+
+//================================================================================================================================================================================================
+//MSI Mystic Light 162 byte feature report, as used by the z390 gaming pro carbon.
+//Only describes the bytes on the wire, talking to the hid device is done in z390.rs
+
+pub const REPORT_ID: u8 = 0x52;
+pub const REPORT_SIZE: usize = 162;
+pub const ZONE_COUNT: usize = 16;
+const ZONE_DATA_SIZE: usize = 10;
+const SAVE_DATA_OFFSET: usize = 161;
+
+//Layout of ZoneData::speed_and_brightness_flags
+const SPEED_MASK: u8 = 0x03;
+const BRIGHTNESS_SHIFT: u8 = 2;
+const BRIGHTNESS_MASK: u8 = 0x7C;
+
+//Bits of ZoneData::color_flags. Rainbow cycles the colors of the effect, random picks a new color every cycle.
+//The bits are the ones other Mystic Light boards use, they haven't been checked against a z390 yet.
+pub const COLOR_FLAG_RANDOM : u8 = 0x40;
+pub const COLOR_FLAG_RAINBOW: u8 = 0x80;
+
+
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError
+{
+    WrongSize(usize),
+    WrongReportId(u8),
+    InvalidEffect(Zone, u8),
+    InvalidSpeed(Zone, u8),
+    InvalidBrightness(Zone, u8),
+    ReservedBitsSet(Zone, u8),
+    InvalidPadding(Zone, u8),
+    InvalidSaveData(u8),
+}
+
+impl std::fmt::Display for DecodeError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            DecodeError::WrongSize(size)                => write!(f, "expected {} bytes, got {}", REPORT_SIZE, size),
+            DecodeError::WrongReportId(id)              => write!(f, "expected report id {:#04x}, got {:#04x}", REPORT_ID, id),
+            DecodeError::InvalidEffect(zone, value)     => write!(f, "{:?}: invalid effect {}", zone, value),
+            DecodeError::InvalidSpeed(zone, value)      => write!(f, "{:?}: invalid speed {}", zone, value),
+            DecodeError::InvalidBrightness(zone, value) => write!(f, "{:?}: invalid brightness {}", zone, value),
+            DecodeError::ReservedBitsSet(zone, value)   => write!(f, "{:?}: reserved bits set in {:#04x}", zone, value),
+            DecodeError::InvalidPadding(zone, value)    => write!(f, "{:?}: padding is {:#04x} instead of 0", zone, value),
+            DecodeError::InvalidSaveData(value)         => write!(f, "invalid save flag {}", value),
+        }
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Report
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MysticLightReport
+{
+    pub zones       : [ZoneData; ZONE_COUNT],
+    pub save_data   : bool,
+}
+
+impl MysticLightReport
+{
+    pub fn new() -> Self
+    {
+        MysticLightReport
+        {
+            zones: [ZoneData::new(); ZONE_COUNT],
+            save_data: false,
+        }
+    }
+
+    pub fn zone(&self, zone: Zone) -> &ZoneData
+    {
+        return &self.zones[zone as usize];
+    }
+
+    pub fn zone_mut(&mut self, zone: Zone) -> &mut ZoneData
+    {
+        return &mut self.zones[zone as usize];
+    }
+
+    pub fn encode(&self) -> [u8; REPORT_SIZE]
+    {
+        let mut buffer: [u8; REPORT_SIZE] = [0; REPORT_SIZE];
+
+        buffer[0] = REPORT_ID;
+        for zone in Zone::ALL.iter()
+        {
+            self.zone(*zone).encode(&mut buffer[zone.offset()..zone.offset() + ZONE_DATA_SIZE]);
+        }
+        buffer[SAVE_DATA_OFFSET] = self.save_data as u8;
+
+        return buffer;
+    }
+
+    pub fn decode(buffer: &[u8]) -> Result<Self, DecodeError>
+    {
+        if buffer.len() != REPORT_SIZE
+        {
+            return Err(DecodeError::WrongSize(buffer.len()));
+        }
+        if buffer[0] != REPORT_ID
+        {
+            return Err(DecodeError::WrongReportId(buffer[0]));
+        }
+
+        let mut report = MysticLightReport::new();
+        for zone in Zone::ALL.iter()
+        {
+            *report.zone_mut(*zone) = ZoneData::decode(*zone, &buffer[zone.offset()..zone.offset() + ZONE_DATA_SIZE])?;
+        }
+
+        report.save_data = match buffer[SAVE_DATA_OFFSET]
+        {
+            0 => false,
+            1 => true,
+            value => return Err(DecodeError::InvalidSaveData(value)),
+        };
+
+        return Ok(report);
+    }
+}
+
+
+//What a report written to the device means, one line per zone, for the trace log
+pub fn describe(buffer: &[u8]) -> Vec<String>
+{
+    let report = match MysticLightReport::decode(buffer)
+    {
+        Ok(report) => report,
+        Err(e) => return vec![format!("invalid report: {}", e)],
+    };
+
+    let mut lines: Vec<String> = Zone::ALL.iter().map(|zone|
+    {
+        let data = report.zone(*zone);
+        return format!("{:?}: {:?} {}, {:?}, {:?}, color2 {}, flags {:#04x}", zone, data.effect, data.color.to_hex(), data.speed, data.brightness, data.color2.to_hex(), data.color_flags);
+    }).collect();
+    lines.push(format!("save data: {}", report.save_data));
+    return lines;
+}
+
+
+//================================================================================================================================================================================================
+//Zones
+
+//Order in which the zones appear in the report
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone
+{
+    JRgb1               = 0,
+    JRainbow1           = 1,
+    JCorsair1           = 2,
+    JCorsairOuterll120  = 3,
+    OnBoardLed          = 4,
+    OnBoardLed1         = 5,
+    OnBoardLed2         = 6,
+    OnBoardLed3         = 7,
+    OnBoardLed4         = 8,
+    OnBoardLed5         = 9,
+    OnBoardLed6         = 10,
+    OnBoardLed7         = 11,
+    OnBoardLed8         = 12,
+    OnBoardLed9         = 13,
+    OnBoardLed10        = 14,
+    JRgb2               = 15,
+}
+
+impl Zone
+{
+    pub const ALL: [Zone; ZONE_COUNT] =
+    [
+        Zone::JRgb1,
+        Zone::JRainbow1,
+        Zone::JCorsair1,
+        Zone::JCorsairOuterll120,
+        Zone::OnBoardLed,
+        Zone::OnBoardLed1,
+        Zone::OnBoardLed2,
+        Zone::OnBoardLed3,
+        Zone::OnBoardLed4,
+        Zone::OnBoardLed5,
+        Zone::OnBoardLed6,
+        Zone::OnBoardLed7,
+        Zone::OnBoardLed8,
+        Zone::OnBoardLed9,
+        Zone::OnBoardLed10,
+        Zone::JRgb2,
+    ];
+
+    //Byte offset in the report, the report id comes first
+    pub fn offset(self) -> usize
+    {
+        return 1 + self as usize * ZONE_DATA_SIZE;
+    }
+}
+
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZoneData
+{
+    pub effect      : MsiMode,
+    pub color       : Color,
+    pub speed       : MsiSpeed,
+    pub brightness  : MsiBrightness,
+    pub color2      : Color,
+    pub color_flags : u8, //COLOR_FLAG_*, other bits are kept as the controller reports them
+}
+
+impl ZoneData
+{
+    pub fn new() -> Self
+    {
+        ZoneData
+        {
+            effect: MsiMode::Static,
+            color: Color::new(0,0,0),
+            speed: MsiSpeed::Low,
+            brightness: MsiBrightness::Level100,
+            color2: Color::new(0,0,0),
+            color_flags: 0,
+        }
+    }
+
+    pub fn speed_and_brightness_flags(&self) -> u8
+    {
+        return ((self.brightness as u8) << BRIGHTNESS_SHIFT) | ((self.speed as u8) & SPEED_MASK);
+    }
+
+    fn encode(&self, buffer: &mut [u8])
+    {
+        buffer[0] = self.effect as u8;
+        buffer[1] = self.color.r;
+        buffer[2] = self.color.g;
+        buffer[3] = self.color.b;
+        buffer[4] = self.speed_and_brightness_flags();
+        buffer[5] = self.color2.r;
+        buffer[6] = self.color2.g;
+        buffer[7] = self.color2.b;
+        buffer[8] = self.color_flags;
+        buffer[9] = 0; //padding
+    }
+
+    fn decode(zone: Zone, buffer: &[u8]) -> Result<Self, DecodeError>
+    {
+        let flags = buffer[4];
+        if flags & !(SPEED_MASK | BRIGHTNESS_MASK) != 0
+        {
+            return Err(DecodeError::ReservedBitsSet(zone, flags));
+        }
+        if buffer[9] != 0
+        {
+            return Err(DecodeError::InvalidPadding(zone, buffer[9]));
+        }
+
+        return Ok(ZoneData
+        {
+            effect      : MsiMode::from_u8(buffer[0]).ok_or(DecodeError::InvalidEffect(zone, buffer[0]))?,
+            color       : Color::new(buffer[1], buffer[2], buffer[3]),
+            speed       : MsiSpeed::from_u8(flags & SPEED_MASK).ok_or(DecodeError::InvalidSpeed(zone, flags & SPEED_MASK))?,
+            brightness  : MsiBrightness::from_u8((flags & BRIGHTNESS_MASK) >> BRIGHTNESS_SHIFT).ok_or(DecodeError::InvalidBrightness(zone, (flags & BRIGHTNESS_MASK) >> BRIGHTNESS_SHIFT))?,
+            color2      : Color::new(buffer[5], buffer[6], buffer[7]),
+            color_flags : buffer[8],
+        });
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Field values
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MsiMode
+{
+    Disable                     = 0,
+    Static                      = 1,
+    Breathing                   = 2,
+    Flashing                    = 3,
+    DoubleFlashing              = 4,
+    Lightning                   = 5,
+    MsiMarquee                  = 6,
+    Meteor                      = 7,
+    WaterDrop                   = 8,
+    MsiRainbow                  = 9,
+    Pop                         = 10,
+    Rap                         = 11,
+    Jazz                        = 12,
+    Play                        = 13,
+    Movie                       = 14,
+    ColorRing                   = 15,
+    Planetary                   = 16,
+    DoubleMeteor                = 17,
+    Energy                      = 18,
+    Blink                       = 19,
+    Clock                       = 20,
+    ColorPulse                  = 21,
+    ColorShift                  = 22,
+    ColorWave                   = 23,
+    Marquee                     = 24,
+    Rainbow                     = 25,
+    RainbowWave                 = 26,
+    Visor                       = 27,
+    Jrainbow                    = 28,
+    RainbowFlashing             = 29,
+    RainbowDoubleFlashing       = 30,
+    Random                      = 31,
+    FanControl                  = 32,
+    Disable2                    = 33,
+    ColorRingFlashing           = 34,
+    ColorRingDoubleFlashing     = 35,
+    Stack                       = 36,
+    CorsairQue                  = 37,
+    Fire                        = 38,
+    Lava                        = 39,
+}
+
+impl MsiMode
+{
+    pub fn from_u8(value: u8) -> Option<Self>
+    {
+        return match value
+        {
+            0  => Some(MsiMode::Disable),
+            1  => Some(MsiMode::Static),
+            2  => Some(MsiMode::Breathing),
+            3  => Some(MsiMode::Flashing),
+            4  => Some(MsiMode::DoubleFlashing),
+            5  => Some(MsiMode::Lightning),
+            6  => Some(MsiMode::MsiMarquee),
+            7  => Some(MsiMode::Meteor),
+            8  => Some(MsiMode::WaterDrop),
+            9  => Some(MsiMode::MsiRainbow),
+            10 => Some(MsiMode::Pop),
+            11 => Some(MsiMode::Rap),
+            12 => Some(MsiMode::Jazz),
+            13 => Some(MsiMode::Play),
+            14 => Some(MsiMode::Movie),
+            15 => Some(MsiMode::ColorRing),
+            16 => Some(MsiMode::Planetary),
+            17 => Some(MsiMode::DoubleMeteor),
+            18 => Some(MsiMode::Energy),
+            19 => Some(MsiMode::Blink),
+            20 => Some(MsiMode::Clock),
+            21 => Some(MsiMode::ColorPulse),
+            22 => Some(MsiMode::ColorShift),
+            23 => Some(MsiMode::ColorWave),
+            24 => Some(MsiMode::Marquee),
+            25 => Some(MsiMode::Rainbow),
+            26 => Some(MsiMode::RainbowWave),
+            27 => Some(MsiMode::Visor),
+            28 => Some(MsiMode::Jrainbow),
+            29 => Some(MsiMode::RainbowFlashing),
+            30 => Some(MsiMode::RainbowDoubleFlashing),
+            31 => Some(MsiMode::Random),
+            32 => Some(MsiMode::FanControl),
+            33 => Some(MsiMode::Disable2),
+            34 => Some(MsiMode::ColorRingFlashing),
+            35 => Some(MsiMode::ColorRingDoubleFlashing),
+            36 => Some(MsiMode::Stack),
+            37 => Some(MsiMode::CorsairQue),
+            38 => Some(MsiMode::Fire),
+            39 => Some(MsiMode::Lava),
+            _  => None,
+        };
+    }
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MsiSpeed
+{
+    Low = 0,
+    Medium = 1,
+    High = 2,
+}
+
+impl MsiSpeed
+{
+    pub fn from_u8(value: u8) -> Option<Self>
+    {
+        return match value
+        {
+            0 => Some(MsiSpeed::Low),
+            1 => Some(MsiSpeed::Medium),
+            2 => Some(MsiSpeed::High),
+            _ => None,
+        };
+    }
+}
+
+#[allow(dead_code)]
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MsiBrightness
+{
+    Off = 0,
+    Level10 = 1,
+    Level20 = 2,
+    Level30 = 3,
+    Level40 = 4,
+    Level50 = 5,
+    Level60 = 6,
+    Level70 = 7,
+    Level80 = 8,
+    Level90 = 9,
+    Level100 = 10,
+}
+
+impl MsiBrightness
+{
+    pub fn from_u8(value: u8) -> Option<Self>
+    {
+        return match value
+        {
+            0  => Some(MsiBrightness::Off),
+            1  => Some(MsiBrightness::Level10),
+            2  => Some(MsiBrightness::Level20),
+            3  => Some(MsiBrightness::Level30),
+            4  => Some(MsiBrightness::Level40),
+            5  => Some(MsiBrightness::Level50),
+            6  => Some(MsiBrightness::Level60),
+            7  => Some(MsiBrightness::Level70),
+            8  => Some(MsiBrightness::Level80),
+            9  => Some(MsiBrightness::Level90),
+            10 => Some(MsiBrightness::Level100),
+            _  => None,
+        };
+    }
+
+    //Nearest of the eleven levels to a 0..1 brightness
+    pub fn from_fraction(brightness: f32) -> Self
+    {
+        let level = (brightness.max(0.0).min(1.0) * 10.0).round() as u8;
+        return MsiBrightness::from_u8(level).unwrap_or(MsiBrightness::Level100);
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    const SPEEDS: [MsiSpeed; 3] = [MsiSpeed::Low, MsiSpeed::Medium, MsiSpeed::High];
+
+    fn modes() -> Vec<MsiMode>
+    {
+        return (0..=u8::MAX).filter_map(MsiMode::from_u8).collect();
+    }
+
+    fn brightnesses() -> Vec<MsiBrightness>
+    {
+        return (0..=u8::MAX).filter_map(MsiBrightness::from_u8).collect();
+    }
+
+    //xorshift, so the random reports are the same on every run
+    struct Random(u64);
+
+    impl Random
+    {
+        fn next(&mut self) -> u64
+        {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            return self.0;
+        }
+
+        fn byte(&mut self) -> u8
+        {
+            return self.next() as u8;
+        }
+
+        fn pick<T: Copy>(&mut self, values: &[T]) -> T
+        {
+            return values[self.next() as usize % values.len()];
+        }
+    }
+
+    fn round_trip(report: &MysticLightReport)
+    {
+        let buffer = report.encode();
+        assert_eq!(MysticLightReport::decode(&buffer).as_ref(), Ok(report));
+    }
+
+    fn valid_buffer() -> [u8; REPORT_SIZE]
+    {
+        return MysticLightReport::new().encode();
+    }
+
+    #[test]
+    fn every_value_round_trips_in_every_zone()
+    {
+        assert_eq!(modes().len(), 40);
+        assert_eq!(brightnesses().len(), 11);
+
+        for zone in Zone::ALL.iter()
+        {
+            for mode in modes()
+            {
+                let mut report = MysticLightReport::new();
+                report.zone_mut(*zone).effect = mode;
+                round_trip(&report);
+            }
+            for speed in SPEEDS.iter()
+            {
+                for brightness in brightnesses()
+                {
+                    let mut report = MysticLightReport::new();
+                    report.zone_mut(*zone).speed = *speed;
+                    report.zone_mut(*zone).brightness = brightness;
+                    round_trip(&report);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn random_reports_round_trip()
+    {
+        let modes = modes();
+        let brightnesses = brightnesses();
+        let mut random = Random(0x2545_f491_4f6c_dd1d);
+
+        for _ in 0..2000
+        {
+            let mut report = MysticLightReport::new();
+            for zone in report.zones.iter_mut()
+            {
+                *zone = ZoneData
+                {
+                    effect      : random.pick(&modes),
+                    color       : Color::new(random.byte(), random.byte(), random.byte()),
+                    speed       : random.pick(&SPEEDS),
+                    brightness  : random.pick(&brightnesses),
+                    color2      : Color::new(random.byte(), random.byte(), random.byte()),
+                    color_flags : random.byte(),
+                };
+            }
+            report.save_data = random.next() % 2 == 0;
+            round_trip(&report);
+        }
+    }
+
+    #[test]
+    fn encodes_the_documented_layout()
+    {
+        let mut report = MysticLightReport::new();
+        report.save_data = true;
+        *report.zone_mut(Zone::JCorsair1) = ZoneData
+        {
+            effect      : MsiMode::Breathing,
+            color       : Color::new(1, 2, 3),
+            speed       : MsiSpeed::High,
+            brightness  : MsiBrightness::Level50,
+            color2      : Color::new(4, 5, 6),
+            color_flags : 0x81,
+        };
+
+        let buffer = report.encode();
+        assert_eq!(buffer[0], REPORT_ID);
+        assert_eq!(Zone::JCorsair1.offset(), 21);
+        assert_eq!(buffer[21..31], [2, 1, 2, 3, (5 << 2) | 2, 4, 5, 6, 0x81, 0]);
+        assert_eq!(buffer[SAVE_DATA_OFFSET], 1);
+        assert_eq!(Zone::JRgb2.offset() + ZONE_DATA_SIZE, SAVE_DATA_OFFSET);
+    }
+
+    #[test]
+    fn rejects_wrong_size()
+    {
+        assert_eq!(MysticLightReport::decode(&[REPORT_ID; 161]), Err(DecodeError::WrongSize(161)));
+        assert_eq!(MysticLightReport::decode(&[]), Err(DecodeError::WrongSize(0)));
+    }
+
+    #[test]
+    fn rejects_wrong_report_id()
+    {
+        let mut buffer = valid_buffer();
+        buffer[0] = 0x53;
+        assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::WrongReportId(0x53)));
+    }
+
+    #[test]
+    fn rejects_invalid_effect()
+    {
+        let mut buffer = valid_buffer();
+        buffer[Zone::OnBoardLed3.offset()] = 40;
+        assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::InvalidEffect(Zone::OnBoardLed3, 40)));
+    }
+
+    #[test]
+    fn rejects_invalid_speed()
+    {
+        let mut buffer = valid_buffer();
+        buffer[Zone::JRgb1.offset() + 4] = (10 << BRIGHTNESS_SHIFT) | 3;
+        assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::InvalidSpeed(Zone::JRgb1, 3)));
+    }
+
+    #[test]
+    fn rejects_invalid_brightness()
+    {
+        for brightness in 11..=31u8
+        {
+            let mut buffer = valid_buffer();
+            buffer[Zone::JRgb2.offset() + 4] = brightness << BRIGHTNESS_SHIFT;
+            assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::InvalidBrightness(Zone::JRgb2, brightness)));
+        }
+    }
+
+    #[test]
+    fn rejects_reserved_bits()
+    {
+        let mut buffer = valid_buffer();
+        let flags = 0x80 | (10 << BRIGHTNESS_SHIFT);
+        buffer[Zone::JRainbow1.offset() + 4] = flags;
+        assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::ReservedBitsSet(Zone::JRainbow1, flags)));
+    }
+
+    #[test]
+    fn rejects_padding()
+    {
+        let mut buffer = valid_buffer();
+        buffer[Zone::OnBoardLed10.offset() + 9] = 0x11;
+        assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::InvalidPadding(Zone::OnBoardLed10, 0x11)));
+    }
+
+    #[test]
+    fn rejects_invalid_save_data()
+    {
+        let mut buffer = valid_buffer();
+        buffer[SAVE_DATA_OFFSET] = 2;
+        assert_eq!(MysticLightReport::decode(&buffer), Err(DecodeError::InvalidSaveData(2)));
+    }
+}
@@ -0,0 +1,406 @@
+use serde::Deserialize;
+use crate::color::{Color, RgbDevice, RgbMode, RgbSpeed, DeviceType, effective_brightness};
+use crate::config;
+use crate::dmx;
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+
+//Led strips on the network, like WLED or ESPHome controllers. Configured in network.json:
+//{
+//    "devices":
+//    [
+//        { "name": "Desk",  "protocol": "ddp",  "address": "192.168.1.50",      "led_count": 60 },
+//        { "name": "Shelf", "protocol": "wled", "address": "192.168.1.51:21324", "led_count": 120 },
+//        { "name": "Room",  "protocol": "e131", "address": "192.168.1.52",      "led_count": 300, "universe": 1 }
+//    ]
+//}
+//The port is optional, every protocol has its default. E1.31 continues in the next universe after 170 leds.
+
+const DDP_PORT: u16 = 4048;
+const WLED_PORT: u16 = 21324;
+const E131_PORT: u16 = 5568;
+
+const DDP_MAX_DATA: usize = 1440;       //480 leds, fits an ethernet frame
+const WLED_MAX_LEDS: usize = 489;
+const E131_LEDS_PER_UNIVERSE: usize = 170;
+
+const WLED_DNRGB: u8 = 4;
+const WLED_TIMEOUT: u8 = 2;              //Seconds before WLED goes back to its own effect
+
+//WLED and most fixtures fall back to their own program when nothing arrives, a frame that didn't change is resent after this
+const KEEP_ALIVE: Duration = Duration::from_secs(1);
+
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Protocol
+{
+    Ddp,
+    Wled,
+    E131,
+}
+
+#[derive(Deserialize)]
+struct NetworkDeviceConfig
+{
+    name: String,
+    protocol: Protocol,
+    address: String,
+    led_count: usize,
+    #[serde(default = "default_universe")]
+    universe: u16,
+    #[serde(default = "default_device_type")]
+    device_type: DeviceType,
+}
+
+fn default_universe() -> u16
+{
+    return 1;
+}
+
+fn default_device_type() -> DeviceType
+{
+    return DeviceType::Other;
+}
+
+#[derive(Deserialize, Default)]
+struct NetworkConfig
+{
+    #[serde(default)]
+    devices: Vec<NetworkDeviceConfig>,
+}
+
+
+pub fn get_network_rgb_devices() -> Vec<Box<dyn RgbDevice>>
+{
+    let network_config: NetworkConfig = config::load("network.json").unwrap_or_default();
+    let mut rgb_devices: Vec<Box<dyn RgbDevice>> = Vec::new();
+
+    for device_config in network_config.devices.into_iter()
+    {
+        match NetworkDevice::new(device_config)
+        {
+            Ok(device) => rgb_devices.push(Box::new(device)),
+            Err(e) => println!("Skipping network device {}", e),
+        }
+    }
+    return rgb_devices;
+}
+
+
+//================================================================================================================================================================================================
+//Packets
+
+//DDP, push flag on the last packet so the receiver shows the whole frame at once
+pub fn ddp_packets(sequence: u8, colors: &[Color]) -> Vec<Vec<u8>>
+{
+    let data = rgb_bytes(colors);
+    let chunks: Vec<&[u8]> = if data.is_empty() { vec![&data[..]] } else { data.chunks(DDP_MAX_DATA).collect() };
+    let last = chunks.len() - 1;
+
+    return chunks.iter().enumerate().map(|(index, chunk)|
+    {
+        let offset = (index * DDP_MAX_DATA) as u32;
+        let mut packet = Vec::with_capacity(10 + chunk.len());
+        packet.push(if index == last { 0x41 } else { 0x40 });           //Version 1, push
+        packet.push(sequence % 15 + 1);                                 //1 to 15, 0 means no sequence numbers
+        packet.push(0x0B);                                              //Rgb, 8 bits per channel
+        packet.push(0x01);                                              //Default output device
+        packet.extend_from_slice(&offset.to_be_bytes());
+        packet.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+        packet.extend_from_slice(chunk);
+        return packet;
+    }).collect();
+}
+
+//WLED realtime udp, DNRGB: start index and colors
+pub fn wled_packets(colors: &[Color]) -> Vec<Vec<u8>>
+{
+    return colors.chunks(WLED_MAX_LEDS).enumerate().map(|(index, chunk)|
+    {
+        let start = (index * WLED_MAX_LEDS) as u16;
+        let mut packet = vec![WLED_DNRGB, WLED_TIMEOUT];
+        packet.extend_from_slice(&start.to_be_bytes());
+        packet.extend_from_slice(&rgb_bytes(chunk));
+        return packet;
+    }).collect();
+}
+
+//A universe per 170 leds, starting at the given one. Leds past the last universe are left out.
+pub fn e131_packets(universe: u16, sequence: u8, source: &str, cid: &[u8; 16], colors: &[Color]) -> Vec<Vec<u8>>
+{
+    return colors.chunks(E131_LEDS_PER_UNIVERSE).enumerate().filter_map(|(index, chunk)|
+    {
+        let universe = (universe as usize).checked_add(index).filter(|u| *u <= u16::MAX as usize)? as u16;
+        return Some(dmx::e131_packet(universe, sequence, source, cid, &rgb_bytes(chunk)));
+    }).collect();
+}
+
+fn rgb_bytes(colors: &[Color]) -> Vec<u8>
+{
+    return colors.iter().flat_map(|c| vec![c.r, c.g, c.b]).collect();
+}
+
+
+//================================================================================================================================================================================================
+//Device
+
+pub struct NetworkDevice
+{
+    name                : String,
+    device_type         : DeviceType,
+    protocol            : Protocol,
+    universe            : u16,
+    socket              : UdpSocket,
+    cid                 : [u8; 16],     //E1.31 source id, the same for as long as we run
+    sequence            : u8,
+    last_sent           : Option<Instant>,
+
+    data_writen         : bool,
+    colors              : Vec<Color>,
+    brightness          : f32,
+}
+
+impl NetworkDevice
+{
+    fn new(config: NetworkDeviceConfig) -> Result<Self, String>
+    {
+        let port = match config.protocol
+        {
+            Protocol::Ddp => DDP_PORT,
+            Protocol::Wled => WLED_PORT,
+            Protocol::E131 => E131_PORT,
+        };
+        let address = if config.address.contains(':') { config.address.clone() } else { format!("{}:{}", config.address, port) };
+
+        let socket = UdpSocket::bind("0.0.0.0:0").map_err(|e| format!("{}: {}", config.name, e))?;
+        socket.connect(address.as_str()).map_err(|e| format!("{} at {}: {}", config.name, address, e))?;
+        //Never hold up the animation for a strip
+        socket.set_nonblocking(true).map_err(|e| format!("{}: {}", config.name, e))?;
+
+        let mut cid = [0u8; 16];
+        cid[..config.name.len().min(16)].copy_from_slice(&config.name.as_bytes()[..config.name.len().min(16)]);
+
+        return Ok(NetworkDevice
+        {
+            name: config.name,
+            device_type: config.device_type,
+            protocol: config.protocol,
+            universe: config.universe,
+            socket,
+            cid,
+            sequence: 0,
+            last_sent: None,
+
+            data_writen: true,
+            colors: vec![Color::new(0, 0, 0); config.led_count],
+            brightness: 1.0,
+        });
+    }
+}
+
+impl RgbDevice for NetworkDevice
+{
+    fn set_color(&mut self, color: Color)
+    {
+        self.data_writen = true;
+        for led in self.colors.iter_mut()
+        {
+            *led = color;
+        }
+    }
+
+    fn set_secondary_color(&mut self, _color: Color)
+    {
+    }
+
+    fn set_mode(&mut self, _mode: RgbMode)
+    {
+    }
+
+    fn set_speed(&mut self, _speed: RgbSpeed)
+    {
+    }
+
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.data_writen = true;
+        self.brightness = brightness;
+    }
+
+    fn get_name(&self) -> &String
+    {
+        return &self.name;
+    }
+
+    fn display(&mut self)
+    {
+        //The master brightness can change without the device knowing, so always send when it isn't 1.0
+        let brightness = effective_brightness(self.brightness);
+        let keep_alive = self.last_sent.map_or(true, |last| last.elapsed() >= KEEP_ALIVE);
+        if !self.data_writen && brightness == 1.0 && !keep_alive
+        {
+            return;
+        }
+        self.data_writen = false;
+        self.last_sent = Some(Instant::now());
+        self.sequence = self.sequence.wrapping_add(1);
+
+        let colors: Vec<Color> = self.colors.iter().map(|c| c.scale(brightness)).collect();
+        let packets = match self.protocol
+        {
+            Protocol::Ddp => ddp_packets(self.sequence, &colors),
+            Protocol::Wled => wled_packets(&colors),
+            Protocol::E131 => e131_packets(self.universe, self.sequence, "rustic_light", &self.cid, &colors),
+        };
+
+        //A strip that is switched off shouldn't stop the others
+        for packet in packets.iter()
+        {
+            let _ = self.socket.send(packet);
+        }
+    }
+
+    fn get_led_count(&self) -> usize
+    {
+        return self.colors.len();
+    }
+
+    fn set_led_color(&mut self, index: usize, color: Color)
+    {
+        if index < self.colors.len()
+        {
+            self.data_writen = true;
+            self.colors[index] = color;
+        }
+    }
+
+    fn get_device_type(&self) -> DeviceType
+    {
+        return self.device_type;
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Tests
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn leds(count: usize) -> Vec<Color>
+    {
+        return (0..count).map(|i| Color::new(i as u8, 1, 2)).collect();
+    }
+
+    #[test]
+    fn ddp_splits_frames_and_pushes_the_last_packet()
+    {
+        let packets = ddp_packets(14, &leds(500));
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][..10], [0x40, 15, 0x0B, 0x01, 0, 0, 0, 0, 0x05, 0xA0]);
+        assert_eq!(packets[0].len(), 10 + DDP_MAX_DATA);
+        assert_eq!(packets[1][..10], [0x41, 15, 0x0B, 0x01, 0, 0, 0x05, 0xA0, 0, 60]);
+        assert_eq!(packets[1][10..13], [(480 % 256) as u8, 1, 2]);
+
+        //Sequence numbers skip 0
+        assert_eq!(ddp_packets(15, &leds(1))[0][1], 1);
+        //Nothing to show still pushes
+        assert_eq!(ddp_packets(0, &[]), vec![vec![0x41, 1, 0x0B, 0x01, 0, 0, 0, 0, 0, 0]]);
+    }
+
+    #[test]
+    fn wled_starts_every_packet_at_its_first_led()
+    {
+        let packets = wled_packets(&leds(500));
+        assert_eq!(packets.len(), 2);
+        assert_eq!(packets[0][..7], [WLED_DNRGB, WLED_TIMEOUT, 0, 0, 0, 1, 2]);
+        assert_eq!(packets[0].len(), 4 + WLED_MAX_LEDS * 3);
+        assert_eq!(packets[1][..4], [WLED_DNRGB, WLED_TIMEOUT, 0x01, 0xE9]);
+        assert_eq!(packets[1].len(), 4 + 11 * 3);
+        assert!(wled_packets(&[]).is_empty());
+    }
+
+    #[test]
+    fn e131_takes_a_universe_per_170_leds()
+    {
+        let packets = e131_packets(1, 0, "test", &[0; 16], &leds(400));
+        let parsed: Vec<dmx::DmxPacket> = packets.iter().map(|p| dmx::parse_e131(p).unwrap()).collect();
+        assert_eq!(parsed.iter().map(|p| p.universe).collect::<Vec<u16>>(), vec![1, 2, 3]);
+        assert_eq!(parsed.iter().map(|p| p.data.len()).collect::<Vec<usize>>(), vec![510, 510, 180]);
+        assert_eq!(parsed[1].data[..3], [170, 1, 2]);
+
+        //No wrapping around to universe 0
+        let packets = e131_packets(u16::MAX, 0, "test", &[0; 16], &leds(400));
+        assert_eq!(packets.len(), 1);
+        assert_eq!(dmx::parse_e131(&packets[0]).unwrap().universe, u16::MAX);
+    }
+
+    //A device sending to a socket of the test, on this machine
+    fn device(protocol: Protocol, led_count: usize) -> (NetworkDevice, UdpSocket)
+    {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let config = NetworkDeviceConfig
+        {
+            name: "strip".to_string(),
+            protocol,
+            address: receiver.local_addr().unwrap().to_string(),
+            led_count,
+            universe: 5,
+            device_type: DeviceType::Other,
+        };
+        return (NetworkDevice::new(config).unwrap(), receiver);
+    }
+
+    fn receive(receiver: &UdpSocket) -> Vec<u8>
+    {
+        let mut buffer = [0u8; 2048];
+        let size = receiver.recv(&mut buffer).unwrap();
+        return buffer[..size].to_vec();
+    }
+
+    #[test]
+    fn devices_send_frames_over_udp()
+    {
+        let (mut ddp, receiver) = device(Protocol::Ddp, 2);
+        ddp.set_color(Color::new(10, 20, 30));
+        ddp.display();
+        let packet = receive(&receiver);
+        assert_eq!(packet[0], 0x41);
+        assert_eq!(packet[10..], [10, 20, 30, 10, 20, 30]);
+
+        let (mut wled, receiver) = device(Protocol::Wled, 2);
+        wled.set_led_color(1, Color::new(1, 2, 3));
+        wled.display();
+        assert_eq!(receive(&receiver), vec![WLED_DNRGB, WLED_TIMEOUT, 0, 0, 0, 0, 0, 1, 2, 3]);
+
+        let (mut e131, receiver) = device(Protocol::E131, 200);
+        e131.set_color(Color::new(9, 9, 9));
+        e131.display();
+        let first = dmx::parse_e131(&receive(&receiver)).unwrap();
+        let second = dmx::parse_e131(&receive(&receiver)).unwrap();
+        assert_eq!((first.universe, first.data.len()), (5, 510));
+        assert_eq!((second.universe, second.data.len()), (6, 90));
+    }
+
+    #[test]
+    fn unchanged_frames_wait_for_the_keep_alive()
+    {
+        let (mut device, receiver) = device(Protocol::Wled, 1);
+        device.display();
+        receive(&receiver);
+
+        receiver.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+        device.display();
+        let mut buffer = [0u8; 16];
+        assert!(receiver.recv(&mut buffer).is_err());
+
+        device.set_led_color(0, Color::new(1, 1, 1));
+        device.display();
+        assert_eq!(receive(&receiver), vec![WLED_DNRGB, WLED_TIMEOUT, 0, 0, 1, 1, 1]);
+    }
+}
@@ -0,0 +1,207 @@
+use serde::{Deserialize, Serialize};
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::compositor::{Layer, Mask};
+use crate::frame::Frame;
+use std::f32::consts::PI;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+//Short alerts on top of the running animation, like a build that failed. Once done, the layer is removed and the animation shows again.
+
+#[derive(Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pattern
+{
+    //Hard on / off
+    Blinks(u32),
+    //Fade in and out
+    Pulses(u32),
+    //On for the whole duration
+    Solid,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Notification
+{
+    pub color: Color,
+    pub pattern: Pattern,
+    #[serde(default = "default_duration")]
+    pub duration: u64, //milliseconds
+    #[serde(default)]
+    pub devices: Vec<String>, //empty for every device
+
+    #[serde(skip)]
+    start: Option<u128>,
+    #[serde(skip)]
+    elapsed: u64,
+}
+
+fn default_duration() -> u64
+{
+    return 2000;
+}
+
+//Numbers the layers, so notifications that overlap don't share a name
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+impl Notification
+{
+    pub fn new(color: Color, pattern: Pattern, duration: u64) -> Self
+    {
+        Notification
+        {
+            color,
+            pattern,
+            duration,
+            devices: Vec::new(),
+            start: None,
+            elapsed: 0,
+        }
+    }
+
+    //A layer for the compositor, only covering the selected devices. Named "notification <n>", unique for every notification.
+    pub fn into_layer(self) -> Layer
+    {
+        let mask = if self.devices.is_empty() { Mask::All } else { Mask::Devices(self.devices.clone()) };
+        let name = format!("notification {}", NEXT_ID.fetch_add(1, Ordering::Relaxed));
+        return Layer::new(&name, Box::new(self)).with_mask(mask);
+    }
+
+    fn opacity_at(&self, elapsed: u64) -> f32
+    {
+        if elapsed >= self.duration
+        {
+            return 0.0;
+        }
+        let progress = elapsed as f32 / self.duration as f32;
+
+        return match self.pattern
+        {
+            Pattern::Blinks(count) =>
+            {
+                //On for the first half of every blink
+                if (progress * count.max(1) as f32).fract() < 0.5 { 1.0 } else { 0.0 }
+            }
+            Pattern::Pulses(count) => (1.0 - (progress * count.max(1) as f32 * 2.0 * PI).cos()) / 2.0,
+            Pattern::Solid => 1.0,
+        };
+    }
+}
+
+impl Effect for Notification
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let start = *self.start.get_or_insert(millis);
+        self.elapsed = (millis - start) as u64;
+        frame.fill(self.color);
+    }
+
+    fn opacity(&self) -> f32
+    {
+        return self.opacity_at(self.elapsed);
+    }
+
+    fn is_finished(&self) -> bool
+    {
+        return self.start.is_some() && self.elapsed >= self.duration;
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::animation::SolidColor;
+    use crate::color::RgbDevice;
+    use crate::compositor::Compositor;
+    use crate::recording::VirtualDevice;
+    use crate::scene::Scene;
+
+    const RED: Color = Color { r: 255, g: 0, b: 0 };
+
+    fn close(a: f32, b: f32) -> bool
+    {
+        return (a - b).abs() < 0.001;
+    }
+
+    #[test]
+    fn blinks_are_on_for_the_first_half()
+    {
+        let notification = Notification::new(RED, Pattern::Blinks(2), 1000);
+        let opacities: Vec<f32> = [0, 200, 249, 250, 499, 500, 749, 750, 999, 1000].iter().map(|t| notification.opacity_at(*t)).collect();
+        assert_eq!(opacities, vec![1.0, 1.0, 1.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn pulses_fade_in_and_out()
+    {
+        let one = Notification::new(RED, Pattern::Pulses(1), 1000);
+        assert!(close(one.opacity_at(0), 0.0));
+        assert!(close(one.opacity_at(250), 0.5));
+        assert!(close(one.opacity_at(500), 1.0));
+        assert!(close(one.opacity_at(750), 0.5));
+        assert!(close(one.opacity_at(1000), 0.0));
+
+        let two = Notification::new(RED, Pattern::Pulses(2), 1000);
+        assert!(close(two.opacity_at(250), 1.0));
+        assert!(close(two.opacity_at(500), 0.0));
+        assert!(close(two.opacity_at(750), 1.0));
+    }
+
+    #[test]
+    fn solid_stays_on_for_the_duration()
+    {
+        let notification = Notification::new(RED, Pattern::Solid, 1000);
+        assert_eq!(notification.opacity_at(0), 1.0);
+        assert_eq!(notification.opacity_at(999), 1.0);
+        assert_eq!(notification.opacity_at(1000), 0.0);
+
+        //Nothing to show, not even for a moment
+        assert_eq!(Notification::new(RED, Pattern::Solid, 0).opacity_at(0), 0.0);
+    }
+
+    #[test]
+    fn expires_after_the_duration()
+    {
+        let devices: Vec<Box<dyn RgbDevice>> = vec![Box::new(VirtualDevice::new("strip", 2, false))];
+        let mut frame = Frame::new(&Scene::default(), &devices);
+
+        let mut compositor = Compositor::new();
+        compositor.push(Layer::new("base", Box::new(SolidColor(Color::new(0, 0, 255)))));
+        compositor.push(Notification::new(RED, Pattern::Solid, 1000).into_layer());
+
+        //The duration counts from the first frame it is in, not from when it was created
+        compositor.render(&mut frame, 5000);
+        assert_eq!(frame.devices[0].leds[0].color, RED);
+        compositor.render(&mut frame, 5999);
+        assert_eq!(frame.devices[0].leds[0].color, RED);
+        assert!(!compositor.layers[1].effect.is_finished());
+
+        compositor.render(&mut frame, 6000);
+        assert_eq!(frame.devices[0].leds[0].color, Color::new(0, 0, 255));
+        assert!(compositor.layers[1].effect.is_finished());
+
+        //Removed on the next frame
+        compositor.render(&mut frame, 6010);
+        assert_eq!(compositor.layers.len(), 1);
+    }
+
+    #[test]
+    fn overlapping_notifications_have_their_own_layers()
+    {
+        let mut compositor = Compositor::new();
+        compositor.push(Notification::new(RED, Pattern::Solid, 1000).into_layer());
+        compositor.push(Notification::new(RED, Pattern::Blinks(3), 1000).into_layer());
+
+        let first = compositor.layers[0].name.clone();
+        let second = compositor.layers[1].name.clone();
+        assert!(first.starts_with("notification ") && second.starts_with("notification "));
+        assert_ne!(first, second);
+
+        assert!(compositor.remove(&first).is_some());
+        assert_eq!(compositor.layers[0].name, second);
+    }
+}
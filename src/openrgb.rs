@@ -0,0 +1,1022 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use crate::color::{Color, DeviceType, RgbDevice, RgbMode, RgbSpeed, effective_brightness};
+use crate::config;
+use crate::control::{self, Reply, Request};
+use crate::effects;
+use std::io::{Read, Write};
+use std::cell::RefCell;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::rc::Rc;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::time::Duration;
+
+
+//The OpenRGB network sdk, so OpenRGB clients and plugins can drive the devices without OpenRGB installed.
+//Every device is a controller with a single zone. The first mode is Direct, for per-led colors from the client,
+//the others are the effects in effects.rs. Changing the mode of any controller changes the effect of every device.
+//The other way around, the controllers of OpenRGB servers in "servers" join the animation as devices, a device per zone.
+//That brings in hardware only OpenRGB supports, like ram sticks and fans.
+//Configured in openrgb.json: { "address": "127.0.0.1:6742", "servers": ["192.168.1.20:6742"] }
+
+
+//================================================================================================================================================================================================
+//Protocol constants
+
+pub const MAGIC: &[u8; 4] = b"ORGB";
+pub const HEADER_SIZE: usize = 16;
+//The largest packets are controller data with a few hundred leds, far below this. The size comes from the peer, so it's checked before allocating.
+pub const MAX_PACKET_SIZE: usize = 1024 * 1024;
+pub const PROTOCOL_VERSION: u32 = 3;
+
+pub const REQUEST_CONTROLLER_COUNT: u32 = 0;
+pub const REQUEST_CONTROLLER_DATA: u32 = 1;
+pub const REQUEST_PROTOCOL_VERSION: u32 = 40;
+pub const SET_CLIENT_NAME: u32 = 50;
+pub const RGBCONTROLLER_RESIZEZONE: u32 = 1000;
+pub const RGBCONTROLLER_UPDATELEDS: u32 = 1050;
+pub const RGBCONTROLLER_UPDATEZONELEDS: u32 = 1051;
+pub const RGBCONTROLLER_UPDATESINGLELED: u32 = 1052;
+pub const RGBCONTROLLER_SETCUSTOMMODE: u32 = 1100;
+pub const RGBCONTROLLER_UPDATEMODE: u32 = 1101;
+pub const RGBCONTROLLER_SAVEMODE: u32 = 1102;
+
+const MODE_FLAG_HAS_PER_LED_COLOR: u32 = 1 << 5;
+const MODE_FLAG_HAS_MODE_SPECIFIC_COLOR: u32 = 1 << 6;
+const MODE_COLORS_PER_LED: u32 = 1;
+const MODE_COLORS_MODE_SPECIFIC: u32 = 2;
+const MAX_MODE_COLORS: u32 = 8;
+
+const ZONE_TYPE_SINGLE: i32 = 0;
+const ZONE_TYPE_LINEAR: i32 = 1;
+
+fn device_type(device_type: DeviceType) -> i32
+{
+    return match device_type
+    {
+        DeviceType::Motherboard => 0,
+        DeviceType::Gpu         => 2,
+        DeviceType::Keyboard    => 5,
+        DeviceType::Other       => 4, //led strip, the closest to a generic device
+    };
+}
+
+fn from_device_type(device_type: i32) -> DeviceType
+{
+    return match device_type
+    {
+        0 => DeviceType::Motherboard,
+        2 => DeviceType::Gpu,
+        5 => DeviceType::Keyboard,
+        _ => DeviceType::Other,
+    };
+}
+
+
+//================================================================================================================================================================================================
+//Packets
+
+//Little endian, strings are prefixed with their length and end with a 0, like OpenRGB sends them
+pub struct PacketWriter
+{
+    pub data: Vec<u8>,
+}
+
+impl PacketWriter
+{
+    pub fn new() -> Self
+    {
+        PacketWriter { data: Vec::new() }
+    }
+
+    pub fn u16(&mut self, value: u16)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32)
+    {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn string(&mut self, value: &str)
+    {
+        self.u16(value.len() as u16 + 1);
+        self.data.extend_from_slice(value.as_bytes());
+        self.data.push(0);
+    }
+
+    pub fn color(&mut self, color: Color)
+    {
+        self.data.extend_from_slice(&[color.r, color.g, color.b, 0]);
+    }
+
+    pub fn colors(&mut self, colors: &[Color])
+    {
+        self.u16(colors.len() as u16);
+        for color in colors
+        {
+            self.color(*color);
+        }
+    }
+}
+
+pub struct PacketReader<'a>
+{
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> PacketReader<'a>
+{
+    pub fn new(data: &'a [u8]) -> Self
+    {
+        PacketReader { data, position: 0 }
+    }
+
+    fn take(&mut self, count: usize) -> Option<&'a [u8]>
+    {
+        let bytes = self.data.get(self.position..self.position + count)?;
+        self.position += count;
+        return Some(bytes);
+    }
+
+    pub fn u16(&mut self) -> Option<u16>
+    {
+        let bytes = self.take(2)?;
+        return Some(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    pub fn u32(&mut self) -> Option<u32>
+    {
+        let bytes = self.take(4)?;
+        return Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+
+    pub fn i32(&mut self) -> Option<i32>
+    {
+        return Some(self.u32()? as i32);
+    }
+
+    pub fn string(&mut self) -> Option<String>
+    {
+        let length = self.u16()? as usize;
+        let bytes = self.take(length)?;
+        return Some(String::from_utf8_lossy(bytes).trim_end_matches('\0').to_string());
+    }
+
+    pub fn color(&mut self) -> Option<Color>
+    {
+        let bytes = self.take(4)?;
+        return Some(Color::new(bytes[0], bytes[1], bytes[2]));
+    }
+
+    pub fn colors(&mut self) -> Option<Vec<Color>>
+    {
+        let count = self.u16()?;
+        return (0..count).map(|_| self.color()).collect();
+    }
+}
+
+pub fn header(device: u32, packet_id: u32, size: usize) -> Vec<u8>
+{
+    let mut writer = PacketWriter::new();
+    writer.data.extend_from_slice(MAGIC);
+    writer.u32(device);
+    writer.u32(packet_id);
+    writer.u32(size as u32);
+    return writer.data;
+}
+
+pub fn write_packet(stream: &mut TcpStream, device: u32, packet_id: u32, data: &[u8]) -> std::io::Result<()>
+{
+    let mut packet = header(device, packet_id, data.len());
+    packet.extend_from_slice(data);
+    return stream.write_all(&packet);
+}
+
+//(device, packet id, data)
+pub fn read_packet(stream: &mut TcpStream) -> std::io::Result<(u32, u32, Vec<u8>)>
+{
+    let mut header = [0u8; HEADER_SIZE];
+    stream.read_exact(&mut header)?;
+    if &header[0..4] != MAGIC
+    {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "not an openrgb packet"));
+    }
+
+    let mut reader = PacketReader::new(&header[4..]);
+    let device = reader.u32().unwrap();
+    let packet_id = reader.u32().unwrap();
+    let size = reader.u32().unwrap() as usize;
+    if size > MAX_PACKET_SIZE
+    {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("packet of {} bytes, more than {}", size, MAX_PACKET_SIZE)));
+    }
+
+    let mut data = vec![0u8; size];
+    stream.read_exact(&mut data)?;
+    return Ok((device, packet_id, data));
+}
+
+
+//The controller description OpenRGB sends for REQUEST_CONTROLLER_DATA, device is an entry of list-devices
+fn controller_data(device: &Value, colors: &[Color], active_mode: i32, protocol: u32) -> Vec<u8>
+{
+    let name = device["name"].as_str().unwrap_or("");
+    let device_type = serde_json::from_value(device["type"].clone()).unwrap_or(DeviceType::Other);
+    let led_count = device["leds"].as_u64().unwrap_or(0) as u32;
+    let led_names: Vec<Option<String>> = serde_json::from_value(device["led_names"].clone()).unwrap_or_default();
+
+    let mut writer = PacketWriter::new();
+    writer.i32(self::device_type(device_type));
+    writer.string(name);
+    if protocol >= 1
+    {
+        writer.string("rustic_light"); //vendor
+    }
+    writer.string("rustic_light device"); //description
+    writer.string(env!("CARGO_PKG_VERSION"));
+    writer.string(""); //serial
+    writer.string(name); //location
+
+    //Modes
+    let mut modes = vec!["Direct"];
+    modes.extend(effects::NAMES.iter());
+    writer.u16(modes.len() as u16);
+    writer.i32(active_mode);
+    for (index, mode) in modes.iter().enumerate()
+    {
+        let direct = index == 0;
+        writer.string(mode);
+        writer.i32(index as i32); //value
+        writer.u32(if direct { MODE_FLAG_HAS_PER_LED_COLOR } else { MODE_FLAG_HAS_MODE_SPECIFIC_COLOR });
+        writer.u32(0); //speed min
+        writer.u32(0); //speed max
+        if protocol >= 3
+        {
+            writer.u32(0); //brightness min
+            writer.u32(0); //brightness max
+        }
+        writer.u32(0); //colors min
+        writer.u32(if direct { 0 } else { MAX_MODE_COLORS });
+        writer.u32(0); //speed
+        if protocol >= 3
+        {
+            writer.u32(0); //brightness
+        }
+        writer.u32(0); //direction
+        writer.u32(if direct { MODE_COLORS_PER_LED } else { MODE_COLORS_MODE_SPECIFIC });
+        writer.colors(&[]);
+    }
+
+    //A single zone with every led
+    writer.u16(1);
+    writer.string(name);
+    writer.i32(if led_count > 1 { ZONE_TYPE_LINEAR } else { ZONE_TYPE_SINGLE });
+    writer.u32(led_count); //min
+    writer.u32(led_count); //max
+    writer.u32(led_count);
+    writer.u16(0); //no matrix map
+
+    writer.u16(led_count as u16);
+    for index in 0..led_count as usize
+    {
+        match led_names.get(index)
+        {
+            Some(Some(led_name)) => writer.string(&format!("Key: {}", led_name)),
+            _ => writer.string(&format!("LED {}", index + 1)),
+        }
+        writer.u32(index as u32);
+    }
+    writer.colors(colors);
+
+    //The size includes itself
+    let mut data = ((writer.data.len() + 4) as u32).to_le_bytes().to_vec();
+    data.extend_from_slice(&writer.data);
+    return data;
+}
+
+//================================================================================================================================================================================================
+//Server
+
+#[derive(Deserialize)]
+struct OpenRgbConfig
+{
+    #[serde(default = "default_address")]
+    address: String,
+    #[serde(default)]
+    servers: Vec<String>,
+}
+
+fn default_address() -> String
+{
+    return "127.0.0.1:6742".to_string();
+}
+
+impl Default for OpenRgbConfig
+{
+    fn default() -> Self
+    {
+        OpenRgbConfig { address: default_address(), servers: Vec::new() }
+    }
+}
+
+pub fn serve(sender: Sender<Request>)
+{
+    let openrgb_config: OpenRgbConfig = config::load("openrgb.json").unwrap_or_default();
+
+    if let Err(e) = listen(&openrgb_config.address, sender)
+    {
+        println!("Unable to start the openrgb server on {}: {}", openrgb_config.address, e);
+    }
+}
+
+//Accepts clients on a thread of its own, returns the address actually bound
+fn listen(address: &str, sender: Sender<Request>) -> Result<SocketAddr, String>
+{
+    let listener = TcpListener::bind(address).map_err(|e| e.to_string())?;
+    let address = listener.local_addr().map_err(|e| e.to_string())?;
+
+    thread::spawn(move ||
+    {
+        for stream in listener.incoming()
+        {
+            if let Ok(stream) = stream
+            {
+                let sender = sender.clone();
+                thread::spawn(move || connection(stream, sender));
+            }
+        }
+    });
+    return Ok(address);
+}
+
+fn call(sender: &Sender<Request>, method: &str, params: Value) -> Option<Value>
+{
+    return match control::call(sender, method, params)
+    {
+        Reply::Ok(result) => Some(result),
+        Reply::Err(e) =>
+        {
+            println!("OpenRGB client: {}", e);
+            None
+        }
+        Reply::Subscribed(_) => None,
+    };
+}
+
+fn connection(mut stream: TcpStream, sender: Sender<Request>)
+{
+    let _ = stream.set_nodelay(true);
+    let mut protocol = 0;
+
+    //The devices don't change while running
+    let devices = match call(&sender, "list-devices", Value::Null)
+    {
+        Some(devices) => devices,
+        None => return,
+    };
+    let device_name = |device: u32| devices[device as usize]["name"].as_str().map(|n| n.to_string());
+
+    loop
+    {
+        let (device, packet_id, data) = match read_packet(&mut stream)
+        {
+            Ok(packet) => packet,
+            Err(_) => return,
+        };
+        let mut reader = PacketReader::new(&data);
+
+        let reply = match packet_id
+        {
+            REQUEST_CONTROLLER_COUNT =>
+            {
+                Some((devices.as_array().map_or(0, |d| d.len()) as u32).to_le_bytes().to_vec())
+            }
+            REQUEST_CONTROLLER_DATA => match devices.get(device as usize)
+            {
+                Some(description) =>
+                {
+                    let frame = call(&sender, "get-frame", Value::Null).unwrap_or_default();
+                    let state = call(&sender, "get-state", Value::Null).unwrap_or_default();
+
+                    let colors: Vec<Color> = serde_json::from_value(frame[device as usize]["colors"].clone()).unwrap_or_default();
+                    let active_mode = effects::NAMES.iter().position(|n| Some(*n) == state["effect"].as_str()).map_or(0, |index| index as i32 + 1);
+                    Some(controller_data(description, &colors, active_mode, reader.u32().unwrap_or(0).min(protocol)))
+                }
+                //An empty answer for a controller that doesn't exist, the client would wait for it forever
+                None => Some(Vec::new()),
+            },
+            REQUEST_PROTOCOL_VERSION =>
+            {
+                protocol = reader.u32().unwrap_or(0).min(PROTOCOL_VERSION);
+                Some(PROTOCOL_VERSION.to_le_bytes().to_vec())
+            }
+            SET_CLIENT_NAME =>
+            {
+                println!("OpenRGB client connected: {}", String::from_utf8_lossy(&data).trim_end_matches('\0'));
+                None
+            }
+            RGBCONTROLLER_UPDATELEDS =>
+            {
+                reader.u32(); //data size
+                if let (Some(colors), Some(name)) = (reader.colors(), device_name(device))
+                {
+                    call(&sender, "set-leds", json!({ "device": name, "colors": colors }));
+                }
+                None
+            }
+            RGBCONTROLLER_UPDATEZONELEDS =>
+            {
+                reader.u32(); //data size
+                reader.u32(); //zone, there is only one
+                if let (Some(colors), Some(name)) = (reader.colors(), device_name(device))
+                {
+                    call(&sender, "set-leds", json!({ "device": name, "colors": colors }));
+                }
+                None
+            }
+            RGBCONTROLLER_UPDATESINGLELED =>
+            {
+                if let (Some(led), Some(color), Some(name)) = (reader.i32(), reader.color(), device_name(device))
+                {
+                    call(&sender, "set-leds", json!({ "device": name, "colors": [color], "offset": led.max(0) }));
+                }
+                None
+            }
+            RGBCONTROLLER_UPDATEMODE | RGBCONTROLLER_SAVEMODE =>
+            {
+                //Data size and the mode index are enough, the rest of the mode only has colors that matter
+                reader.u32();
+                let mode = reader.i32().unwrap_or(0);
+                let colors = mode_colors(&mut reader, protocol).unwrap_or_default();
+                if mode > 0
+                {
+                    if let Some(name) = effects::NAMES.get(mode as usize - 1)
+                    {
+                        call(&sender, "set-effect", json!({ "name": name, "colors": colors }));
+                    }
+                }
+                None
+            }
+            //Direct is always available, the colors arrive with UPDATELEDS. Zones can't be resized.
+            RGBCONTROLLER_SETCUSTOMMODE | RGBCONTROLLER_RESIZEZONE => None,
+            _ => None,
+        };
+
+        if let Some(reply) = reply
+        {
+            if write_packet(&mut stream, device, packet_id, &reply).is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+//Skips to the colors of a mode in an UPDATEMODE packet, the same layout as in controller_data
+fn mode_colors(reader: &mut PacketReader, protocol: u32) -> Option<Vec<Color>>
+{
+    reader.string()?; //name
+    reader.i32()?;    //value
+    let skipped = if protocol >= 3 { 11 } else { 8 }; //flags, speed min / max, [brightness min / max], colors min / max, speed, [brightness], direction, color mode
+    for _ in 0..skipped
+    {
+        reader.u32()?;
+    }
+    return reader.colors();
+}
+
+
+//================================================================================================================================================================================================
+//Client
+
+pub struct Controller
+{
+    pub name: String,
+    pub device_type: DeviceType,
+    pub zones: Vec<(String, usize)>, //name, led count
+    pub led_names: Vec<String>,
+}
+
+pub struct OpenRgbClient
+{
+    stream: TcpStream,
+    protocol: u32,
+}
+
+impl OpenRgbClient
+{
+    pub fn connect(address: &str) -> Result<Self, String>
+    {
+        let socket_address = address.to_socket_addrs().ok().and_then(|mut a| a.next()).ok_or(format!("{}: unknown address", address))?;
+        let stream = TcpStream::connect_timeout(&socket_address, Duration::from_secs(2)).map_err(|e| format!("{}: {}", address, e))?;
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_read_timeout(Some(Duration::from_secs(2)));
+
+        let mut client = OpenRgbClient { stream, protocol: 0 };
+        let reply = client.request(0, REQUEST_PROTOCOL_VERSION, &PROTOCOL_VERSION.to_le_bytes())?;
+        client.protocol = PacketReader::new(&reply).u32().unwrap_or(0).min(PROTOCOL_VERSION);
+
+        let mut name = b"rustic_light".to_vec();
+        name.push(0);
+        write_packet(&mut client.stream, 0, SET_CLIENT_NAME, &name).map_err(|e| e.to_string())?;
+        return Ok(client);
+    }
+
+    //Sends a packet and waits for the answer, skipping notifications like DEVICE_LIST_UPDATED
+    fn request(&mut self, device: u32, packet_id: u32, data: &[u8]) -> Result<Vec<u8>, String>
+    {
+        write_packet(&mut self.stream, device, packet_id, data).map_err(|e| e.to_string())?;
+        loop
+        {
+            let (_, reply_id, reply) = read_packet(&mut self.stream).map_err(|e| e.to_string())?;
+            if reply_id == packet_id
+            {
+                return Ok(reply);
+            }
+        }
+    }
+
+    pub fn controller_count(&mut self) -> Result<u32, String>
+    {
+        let reply = self.request(0, REQUEST_CONTROLLER_COUNT, &[])?;
+        return PacketReader::new(&reply).u32().ok_or("invalid controller count".to_string());
+    }
+
+    pub fn controller(&mut self, index: u32) -> Result<Controller, String>
+    {
+        let reply = self.request(index, REQUEST_CONTROLLER_DATA, &self.protocol.to_le_bytes())?;
+        return parse_controller(&reply, self.protocol).ok_or(format!("invalid data for controller {}", index));
+    }
+
+    //Direct mode, where the colors come from UPDATEZONELEDS
+    pub fn set_custom_mode(&mut self, controller: u32) -> std::io::Result<()>
+    {
+        return write_packet(&mut self.stream, controller, RGBCONTROLLER_SETCUSTOMMODE, &[]);
+    }
+
+    pub fn update_zone_leds(&mut self, controller: u32, zone: u32, colors: &[Color]) -> std::io::Result<()>
+    {
+        let mut writer = PacketWriter::new();
+        writer.u32((4 + 4 + 2 + colors.len() * 4) as u32);
+        writer.u32(zone);
+        writer.colors(colors);
+        return write_packet(&mut self.stream, controller, RGBCONTROLLER_UPDATEZONELEDS, &writer.data);
+    }
+}
+
+//The other side of controller_data
+fn parse_controller(data: &[u8], protocol: u32) -> Option<Controller>
+{
+    let mut reader = PacketReader::new(data);
+    reader.u32()?; //size
+    let device_type = from_device_type(reader.i32()?);
+    let name = reader.string()?;
+    if protocol >= 1
+    {
+        reader.string()?; //vendor
+    }
+    for _ in 0..4
+    {
+        reader.string()?; //description, version, serial, location
+    }
+
+    let mode_count = reader.u16()?;
+    reader.i32()?; //active mode
+    for _ in 0..mode_count
+    {
+        mode_colors(&mut reader, protocol)?;
+    }
+
+    let mut zones = Vec::new();
+    for _ in 0..reader.u16()?
+    {
+        let zone_name = reader.string()?;
+        reader.i32()?; //type
+        reader.u32()?; //leds min
+        reader.u32()?; //leds max
+        let led_count = reader.u32()? as usize;
+        let matrix_size = reader.u16()? as usize;
+        reader.take(matrix_size)?;
+        zones.push((zone_name, led_count));
+    }
+
+    let mut led_names = Vec::new();
+    for _ in 0..reader.u16()?
+    {
+        led_names.push(reader.string()?);
+        reader.u32()?; //value
+    }
+
+    return Some(Controller { name, device_type, zones, led_names });
+}
+
+
+//Every zone of every controller of every server in openrgb.json, servers that can't be reached are skipped
+pub fn get_openrgb_rgb_devices() -> Vec<Box<dyn RgbDevice>>
+{
+    let openrgb_config: OpenRgbConfig = config::load("openrgb.json").unwrap_or_default();
+    let mut rgb_devices: Vec<Box<dyn RgbDevice>> = Vec::new();
+
+    for address in openrgb_config.servers.iter()
+    {
+        match get_server_devices(address)
+        {
+            Ok(devices) => rgb_devices.extend(devices.into_iter().map(|device| Box::new(device) as Box<dyn RgbDevice>)),
+            Err(e) => println!("Skipping OpenRGB server {}", e),
+        }
+    }
+    return rgb_devices;
+}
+
+fn get_server_devices(address: &str) -> Result<Vec<OpenRgbDevice>, String>
+{
+    let mut client = OpenRgbClient::connect(address)?;
+    let mut controllers = Vec::new();
+    for index in 0..client.controller_count()?
+    {
+        //A controller we can't make sense of doesn't take the others with it
+        match client.controller(index)
+        {
+            Ok(controller) =>
+            {
+                client.set_custom_mode(index).map_err(|e| e.to_string())?;
+                controllers.push((index, controller));
+            }
+            Err(e) => println!("Skipping OpenRGB controller on {}: {}", address, e),
+        }
+    }
+
+    let client = Rc::new(RefCell::new(client));
+    let mut rgb_devices = Vec::new();
+    for (index, controller) in controllers
+    {
+        let mut first_led = 0;
+        for (zone, (zone_name, led_count)) in controller.zones.iter().enumerate()
+        {
+            let led_names = controller.led_names.iter().skip(first_led).take(*led_count).cloned().collect();
+            first_led += led_count;
+            if *led_count == 0
+            {
+                continue;
+            }
+
+            rgb_devices.push(OpenRgbDevice
+            {
+                name: format!("{} {}", controller.name, zone_name),
+                device_type: controller.device_type,
+                client: client.clone(),
+                controller: index,
+                zone: zone as u32,
+                led_names,
+
+                data_writen: true,
+                colors: vec![Color::new(0, 0, 0); *led_count],
+                brightness: 1.0,
+                dead: false,
+            });
+        }
+    }
+    return Ok(rgb_devices);
+}
+
+
+//A zone of a controller on an OpenRGB server. Only direct colors, the animation does the effects.
+pub struct OpenRgbDevice
+{
+    name                : String,
+    device_type         : DeviceType,
+    client              : Rc<RefCell<OpenRgbClient>>,
+    controller          : u32,
+    zone                : u32,
+    led_names           : Vec<String>,
+
+    data_writen         : bool,
+    colors              : Vec<Color>,
+    brightness          : f32,
+    //The server went away, it stays away until restarting
+    dead                : bool,
+}
+
+impl RgbDevice for OpenRgbDevice
+{
+    fn set_color(&mut self, color: Color)
+    {
+        self.data_writen = true;
+        for led in self.colors.iter_mut()
+        {
+            *led = color;
+        }
+    }
+
+    fn set_secondary_color(&mut self, _color: Color)
+    {
+    }
+
+    fn set_mode(&mut self, _mode: RgbMode)
+    {
+    }
+
+    fn set_speed(&mut self, _speed: RgbSpeed)
+    {
+    }
+
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.data_writen = true;
+        self.brightness = brightness;
+    }
+
+    fn get_name(&self) -> &String
+    {
+        return &self.name;
+    }
+
+    fn display(&mut self)
+    {
+        //The master brightness can change without the device knowing, so always send when it isn't 1.0
+        let brightness = effective_brightness(self.brightness);
+        if self.dead || (!self.data_writen && brightness == 1.0)
+        {
+            return;
+        }
+        self.data_writen = false;
+
+        let colors: Vec<Color> = self.colors.iter().map(|c| c.scale(brightness)).collect();
+        match self.client.try_borrow_mut()
+        {
+            Ok(mut client) =>
+            {
+                //The other devices keep running
+                if let Err(e) = client.update_zone_leds(self.controller, self.zone, &colors)
+                {
+                    println!("Lost OpenRGB device {}: {}", self.name, e);
+                    self.dead = true;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn get_led_count(&self) -> usize
+    {
+        return self.colors.len();
+    }
+
+    fn set_led_color(&mut self, index: usize, color: Color)
+    {
+        if index < self.colors.len()
+        {
+            self.data_writen = true;
+            self.colors[index] = color;
+        }
+    }
+
+    fn find_led(&self, name: &str) -> Option<usize>
+    {
+        return self.led_names.iter().position(|n| n.eq_ignore_ascii_case(name) || n.strip_prefix("Key: ").map_or(false, |key| key.eq_ignore_ascii_case(name)));
+    }
+
+    fn get_led_name(&self, index: usize) -> Option<String>
+    {
+        return self.led_names.get(index).cloned();
+    }
+
+    fn get_device_type(&self) -> DeviceType
+    {
+        return self.device_type;
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Tests
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::control::spawn_virtual;
+    use std::sync::mpsc::{channel, Receiver};
+
+    fn keyboard() -> Value
+    {
+        return json!({ "name": "kb", "type": "keyboard", "leds": 2, "led_names": ["Esc", null] });
+    }
+
+    //Written out by hand rather than with PacketWriter, following the OpenRGB sources
+    fn string(bytes: &mut Vec<u8>, text: &str)
+    {
+        bytes.extend_from_slice(&(text.len() as u16 + 1).to_le_bytes());
+        bytes.extend_from_slice(text.as_bytes());
+        bytes.push(0);
+    }
+
+    fn u32s(bytes: &mut Vec<u8>, values: &[u32])
+    {
+        for value in values
+        {
+            bytes.extend_from_slice(&value.to_le_bytes());
+        }
+    }
+
+    fn expected_keyboard(protocol: u32) -> Vec<u8>
+    {
+        let mut bytes = vec![5, 0, 0, 0]; //keyboard
+        string(&mut bytes, "kb");
+        if protocol >= 1
+        {
+            string(&mut bytes, "rustic_light");
+        }
+        string(&mut bytes, "rustic_light device");
+        string(&mut bytes, env!("CARGO_PKG_VERSION"));
+        string(&mut bytes, "");
+        string(&mut bytes, "kb");
+
+        bytes.extend_from_slice(&(effects::NAMES.len() as u16 + 1).to_le_bytes());
+        u32s(&mut bytes, &[3]); //active mode
+        let modes = std::iter::once("Direct").chain(effects::NAMES.iter().copied());
+        for (index, mode) in modes.enumerate()
+        {
+            let direct = index == 0;
+            string(&mut bytes, mode);
+            u32s(&mut bytes, &[index as u32, if direct { 0x20 } else { 0x40 }, 0, 0]);
+            if protocol >= 3
+            {
+                u32s(&mut bytes, &[0, 0]);
+            }
+            u32s(&mut bytes, &[0, if direct { 0 } else { 8 }, 0]);
+            if protocol >= 3
+            {
+                u32s(&mut bytes, &[0]);
+            }
+            u32s(&mut bytes, &[0, if direct { 1 } else { 2 }]);
+            bytes.extend_from_slice(&[0, 0]); //no mode colors
+        }
+
+        bytes.extend_from_slice(&[1, 0]); //a zone
+        string(&mut bytes, "kb");
+        u32s(&mut bytes, &[1, 2, 2, 2]); //linear, 2 leds
+        bytes.extend_from_slice(&[0, 0]); //no matrix
+
+        bytes.extend_from_slice(&[2, 0]);
+        string(&mut bytes, "Key: Esc");
+        u32s(&mut bytes, &[0]);
+        string(&mut bytes, "LED 2");
+        u32s(&mut bytes, &[1]);
+        bytes.extend_from_slice(&[2, 0, 255, 0, 0, 0, 1, 2, 3, 0]);
+
+        let mut data = ((bytes.len() + 4) as u32).to_le_bytes().to_vec();
+        data.extend_from_slice(&bytes);
+        return data;
+    }
+
+    #[test]
+    fn controller_data_matches_every_protocol()
+    {
+        let colors = [Color::new(255, 0, 0), Color::new(1, 2, 3)];
+        for protocol in 0..=PROTOCOL_VERSION
+        {
+            let data = controller_data(&keyboard(), &colors, 3, protocol);
+            assert_eq!(data, expected_keyboard(protocol), "protocol {}", protocol);
+
+            let controller = parse_controller(&data, protocol).unwrap();
+            assert_eq!(controller.name, "kb");
+            assert_eq!(controller.device_type, DeviceType::Keyboard);
+            assert_eq!(controller.zones, vec![("kb".to_string(), 2)]);
+            assert_eq!(controller.led_names, vec!["Key: Esc", "LED 2"]);
+        }
+    }
+
+    #[test]
+    fn single_led_devices_are_single_zones()
+    {
+        let data = controller_data(&json!({ "name": "logo", "type": "gpu", "leds": 1 }), &[], 0, PROTOCOL_VERSION);
+        let controller = parse_controller(&data, PROTOCOL_VERSION).unwrap();
+        assert_eq!(controller.device_type, DeviceType::Gpu);
+        assert_eq!(controller.led_names, vec!["LED 1"]);
+        assert!(parse_controller(&data[..data.len() / 2], PROTOCOL_VERSION).is_none());
+    }
+
+    #[test]
+    fn server_answers_every_controller_request()
+    {
+        let address = listen("127.0.0.1:0", spawn_virtual(&[("strip", 4), ("fan", 2)])).unwrap();
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        write_packet(&mut stream, 0, REQUEST_PROTOCOL_VERSION, &PROTOCOL_VERSION.to_le_bytes()).unwrap();
+        assert_eq!(read_packet(&mut stream).unwrap(), (0, REQUEST_PROTOCOL_VERSION, PROTOCOL_VERSION.to_le_bytes().to_vec()));
+        write_packet(&mut stream, 0, REQUEST_CONTROLLER_COUNT, &[]).unwrap();
+        assert_eq!(read_packet(&mut stream).unwrap(), (0, REQUEST_CONTROLLER_COUNT, vec![2, 0, 0, 0]));
+
+        write_packet(&mut stream, 7, REQUEST_CONTROLLER_DATA, &PROTOCOL_VERSION.to_le_bytes()).unwrap();
+        assert_eq!(read_packet(&mut stream).unwrap(), (7, REQUEST_CONTROLLER_DATA, Vec::new()));
+
+        write_packet(&mut stream, 1, REQUEST_CONTROLLER_DATA, &PROTOCOL_VERSION.to_le_bytes()).unwrap();
+        let (device, packet_id, data) = read_packet(&mut stream).unwrap();
+        assert_eq!((device, packet_id), (1, REQUEST_CONTROLLER_DATA));
+        let controller = parse_controller(&data, PROTOCOL_VERSION).unwrap();
+        assert_eq!(controller.name, "fan");
+        assert_eq!(controller.zones, vec![("fan".to_string(), 2)]);
+    }
+
+    //An OpenRGB server for a single client, None is a controller with broken data. Packets that don't need an answer
+    //come out of the receiver, the server hangs up after the first UPDATEZONELEDS.
+    fn mock_server(controllers: Vec<Option<Value>>) -> (String, Receiver<(u32, u32, Vec<u8>)>)
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = channel();
+        thread::spawn(move ||
+        {
+            let (mut stream, _) = listener.accept().unwrap();
+            while let Ok((device, packet_id, data)) = read_packet(&mut stream)
+            {
+                let reply = match packet_id
+                {
+                    REQUEST_PROTOCOL_VERSION => PROTOCOL_VERSION.to_le_bytes().to_vec(),
+                    REQUEST_CONTROLLER_COUNT => (controllers.len() as u32).to_le_bytes().to_vec(),
+                    REQUEST_CONTROLLER_DATA => match &controllers[device as usize]
+                    {
+                        Some(description) => controller_data(description, &[], 0, PROTOCOL_VERSION),
+                        None => vec![1, 2, 3],
+                    },
+                    _ =>
+                    {
+                        let _ = sender.send((device, packet_id, data));
+                        if packet_id == RGBCONTROLLER_UPDATEZONELEDS
+                        {
+                            return;
+                        }
+                        continue;
+                    }
+                };
+                write_packet(&mut stream, device, packet_id, &reply).unwrap();
+            }
+        });
+        return (address, receiver);
+    }
+
+    #[test]
+    fn client_skips_broken_controllers_and_lost_servers()
+    {
+        let (address, packets) = mock_server(vec![Some(keyboard()), None, Some(json!({ "name": "ram", "type": "other", "leds": 3 }))]);
+        let mut devices = get_server_devices(&address).unwrap();
+        assert_eq!(devices.iter().map(|d| d.get_name().as_str()).collect::<Vec<&str>>(), vec!["kb kb", "ram ram"]);
+        assert_eq!(devices[1].get_led_count(), 3);
+        assert_eq!(devices[0].find_led("esc"), Some(0));
+
+        assert_eq!(packets.recv().unwrap(), (0, SET_CLIENT_NAME, b"rustic_light\0".to_vec()));
+        //Direct mode for the controllers that work
+        assert_eq!(packets.recv().unwrap(), (0, RGBCONTROLLER_SETCUSTOMMODE, Vec::new()));
+        assert_eq!(packets.recv().unwrap(), (2, RGBCONTROLLER_SETCUSTOMMODE, Vec::new()));
+
+        devices[1].set_color(Color::new(10, 20, 30));
+        devices[1].set_led_color(2, Color::new(1, 2, 3));
+        devices[1].display();
+        let (device, packet_id, data) = packets.recv().unwrap();
+        assert_eq!((device, packet_id), (2, RGBCONTROLLER_UPDATEZONELEDS));
+        let mut reader = PacketReader::new(&data);
+        assert_eq!(reader.u32(), Some(data.len() as u32));
+        assert_eq!(reader.u32(), Some(0)); //zone
+        assert_eq!(reader.colors(), Some(vec![Color::new(10, 20, 30), Color::new(10, 20, 30), Color::new(1, 2, 3)]));
+
+        //The server hung up, writing fails sooner or later and then the device gives up
+        for _ in 0..100
+        {
+            if devices[1].dead
+            {
+                break;
+            }
+            devices[1].set_color(Color::new(0, 0, 0));
+            devices[1].display();
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(devices[1].dead);
+    }
+
+    #[test]
+    fn rejects_oversized_packets()
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        //Only the header, a reader that believed it would wait for the rest
+        client.write_all(&header(0, REQUEST_CONTROLLER_DATA, MAX_PACKET_SIZE + 1)).unwrap();
+        let error = read_packet(&mut server).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+
+        write_packet(&mut client, 0, REQUEST_CONTROLLER_DATA, &[7; 16]).unwrap();
+        assert_eq!(read_packet(&mut server).unwrap(), (0, REQUEST_CONTROLLER_DATA, vec![7; 16]));
+    }
+}
@@ -0,0 +1,624 @@
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::frame::Frame;
+use crate::keyboard::key_position;
+use std::collections::HashMap;
+#[cfg(test)]
+use std::collections::VecDeque;
+
+
+//================================================================================================================================================================================================
+//Key events
+
+//Source of key presses, polled once per frame. Returns the names of the keys pressed since the last poll, see keyboard::KEYS.
+pub trait KeyEventSource
+{
+    fn poll(&mut self, millis: u128) -> Vec<String>;
+}
+
+//Replays key presses at fixed moments, to drive the effects without a keyboard
+#[cfg(test)]
+pub struct ScriptedKeyEvents
+{
+    events: VecDeque<(u128, String)>,
+}
+
+#[cfg(test)]
+impl ScriptedKeyEvents
+{
+    //Events as (millis, key name), sorted by time
+    pub fn new(events: Vec<(u128, &str)>) -> Self
+    {
+        ScriptedKeyEvents
+        {
+            events: events.into_iter().map(|(millis, key)| (millis, key.to_string())).collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+impl KeyEventSource for ScriptedKeyEvents
+{
+    fn poll(&mut self, millis: u128) -> Vec<String>
+    {
+        let mut result = Vec::new();
+        while self.events.front().map_or(false, |(event_millis, _)| *event_millis <= millis)
+        {
+            result.push(self.events.pop_front().unwrap().1);
+        }
+        return result;
+    }
+}
+
+
+#[cfg(target_os = "linux")]
+pub use self::evdev::EvdevKeyEvents;
+
+#[cfg(target_os = "linux")]
+mod evdev
+{
+    use super::KeyEventSource;
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::sync::mpsc::{channel, Receiver};
+    use std::thread;
+
+    //struct input_event on 64 bit linux: timeval (16 bytes), type (u16), code (u16), value (i32)
+    const INPUT_EVENT_SIZE: usize = 24;
+    const EV_KEY: u16 = 0x01;
+    const KEY_PRESSED: i32 = 1;
+
+    //Reads key presses from /dev/input/eventN, needs read access to the device (usually the input group)
+    pub struct EvdevKeyEvents
+    {
+        receiver: Receiver<String>,
+    }
+
+    impl EvdevKeyEvents
+    {
+        pub fn new(path: &Path) -> std::io::Result<Self>
+        {
+            let mut file = File::open(path)?;
+            let (sender, receiver) = channel();
+
+            //Reading blocks until the next event, so it gets its own thread
+            thread::spawn(move ||
+            {
+                let mut buffer = [0u8; INPUT_EVENT_SIZE];
+                while file.read_exact(&mut buffer).is_ok()
+                {
+                    let event_type = u16::from_ne_bytes([buffer[16], buffer[17]]);
+                    let code       = u16::from_ne_bytes([buffer[18], buffer[19]]);
+                    let value      = i32::from_ne_bytes([buffer[20], buffer[21], buffer[22], buffer[23]]);
+
+                    if event_type == EV_KEY && value == KEY_PRESSED
+                    {
+                        if let Some(name) = key_name(code)
+                        {
+                            if sender.send(name.to_string()).is_err()
+                            {
+                                return;
+                            }
+                        }
+                    }
+                }
+            });
+
+            return Ok(EvdevKeyEvents { receiver });
+        }
+
+        //The first keyboard udev links in /dev/input/by-path
+        pub fn find_keyboard() -> Option<PathBuf>
+        {
+            let mut keyboards: Vec<PathBuf> = std::fs::read_dir("/dev/input/by-path").ok()?
+                .filter_map(|entry| entry.ok())
+                .map(|entry| entry.path())
+                .filter(|path| path.to_string_lossy().ends_with("-event-kbd"))
+                .collect();
+            keyboards.sort();
+            return keyboards.into_iter().next();
+        }
+    }
+
+    impl KeyEventSource for EvdevKeyEvents
+    {
+        fn poll(&mut self, _millis: u128) -> Vec<String>
+        {
+            return self.receiver.try_iter().collect();
+        }
+    }
+
+    //linux/input-event-codes.h to the names in keyboard::KEYS
+    fn key_name(code: u16) -> Option<&'static str>
+    {
+        return match code
+        {
+            1   => Some("esc"),
+            2   => Some("1"),
+            3   => Some("2"),
+            4   => Some("3"),
+            5   => Some("4"),
+            6   => Some("5"),
+            7   => Some("6"),
+            8   => Some("7"),
+            9   => Some("8"),
+            10  => Some("9"),
+            11  => Some("0"),
+            12  => Some("minus"),
+            13  => Some("equals"),
+            14  => Some("backspace"),
+            15  => Some("tab"),
+            16  => Some("q"),
+            17  => Some("w"),
+            18  => Some("e"),
+            19  => Some("r"),
+            20  => Some("t"),
+            21  => Some("y"),
+            22  => Some("u"),
+            23  => Some("i"),
+            24  => Some("o"),
+            25  => Some("p"),
+            26  => Some("left_bracket"),
+            27  => Some("right_bracket"),
+            28  => Some("enter"),
+            29  => Some("left_ctrl"),
+            30  => Some("a"),
+            31  => Some("s"),
+            32  => Some("d"),
+            33  => Some("f"),
+            34  => Some("g"),
+            35  => Some("h"),
+            36  => Some("j"),
+            37  => Some("k"),
+            38  => Some("l"),
+            39  => Some("semicolon"),
+            40  => Some("quote"),
+            42  => Some("left_shift"),
+            43  => Some("backslash"),
+            44  => Some("z"),
+            45  => Some("x"),
+            46  => Some("c"),
+            47  => Some("v"),
+            48  => Some("b"),
+            49  => Some("n"),
+            50  => Some("m"),
+            51  => Some("comma"),
+            52  => Some("period"),
+            53  => Some("slash"),
+            54  => Some("right_shift"),
+            56  => Some("left_alt"),
+            57  => Some("space"),
+            58  => Some("caps_lock"),
+            100 => Some("right_alt"),
+            103 => Some("up"),
+            105 => Some("left"),
+            106 => Some("right"),
+            108 => Some("down"),
+            125 => Some("left_win"),
+            _   => None,
+        };
+    }
+}
+
+
+
+#[cfg(windows)]
+pub use self::windows::WindowsKeyEvents;
+
+#[cfg(windows)]
+mod windows
+{
+    use super::KeyEventSource;
+    use crate::keyboard::KEYS;
+    use winapi::um::winuser::GetAsyncKeyState;
+
+    //Polls the state of every key. Works in a normal session, the service runs on a different desktop and sees no keys.
+    pub struct WindowsKeyEvents
+    {
+        keys: Vec<(i32, &'static str)>,
+        down: Vec<bool>,
+    }
+
+    impl WindowsKeyEvents
+    {
+        pub fn new() -> Self
+        {
+            let keys: Vec<(i32, &'static str)> = KEYS.iter().filter_map(|(name, _, _)| Some((virtual_key(name)?, *name))).collect();
+            let down = vec![false; keys.len()];
+            return WindowsKeyEvents { keys, down };
+        }
+    }
+
+    impl KeyEventSource for WindowsKeyEvents
+    {
+        fn poll(&mut self, _millis: u128) -> Vec<String>
+        {
+            let mut pressed = Vec::new();
+            for ((key, name), down) in self.keys.iter().zip(self.down.iter_mut())
+            {
+                //The high bit is set while the key is down
+                let is_down = unsafe { GetAsyncKeyState(*key) } as u16 & 0x8000 != 0;
+                if is_down && !*down
+                {
+                    pressed.push(name.to_string());
+                }
+                *down = is_down;
+            }
+            return pressed;
+        }
+    }
+
+    //Names in keyboard::KEYS to windows virtual key codes, fn never reaches windows
+    fn virtual_key(name: &str) -> Option<i32>
+    {
+        let bytes = name.as_bytes();
+        if bytes.len() == 1 && bytes[0].is_ascii_alphanumeric()
+        {
+            return Some(bytes[0].to_ascii_uppercase() as i32);
+        }
+
+        return match name
+        {
+            "esc"           => Some(0x1B),
+            "minus"         => Some(0xBD),
+            "equals"        => Some(0xBB),
+            "backspace"     => Some(0x08),
+            "tab"           => Some(0x09),
+            "left_bracket"  => Some(0xDB),
+            "right_bracket" => Some(0xDD),
+            "backslash"     => Some(0xDC),
+            "caps_lock"     => Some(0x14),
+            "semicolon"     => Some(0xBA),
+            "quote"         => Some(0xDE),
+            "enter"         => Some(0x0D),
+            "left_shift"    => Some(0xA0),
+            "right_shift"   => Some(0xA1),
+            "comma"         => Some(0xBC),
+            "period"        => Some(0xBE),
+            "slash"         => Some(0xBF),
+            "left_ctrl"     => Some(0xA2),
+            "left_win"      => Some(0x5B),
+            "left_alt"      => Some(0xA4),
+            "space"         => Some(0x20),
+            "right_alt"     => Some(0xA5),
+            "left"          => Some(0x25),
+            "up"            => Some(0x26),
+            "right"         => Some(0x27),
+            "down"          => Some(0x28),
+            _               => None,
+        };
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Effects
+//They paint the leds named after keys, on any device that has them (the sk621), and the background everywhere else.
+
+fn paint_keys<F: FnMut((usize, usize)) -> Color>(frame: &mut Frame, background: Color, mut color_of: F)
+{
+    for device in frame.devices.iter_mut()
+    {
+        for (led, name) in device.leds.iter_mut().zip(device.led_names.iter())
+        {
+            led.color = match name.as_ref().and_then(|name| key_position(name))
+            {
+                Some(position) => color_of(position),
+                None => background,
+            };
+        }
+    }
+}
+
+//Distance between two keys in key widths
+fn key_distance(a: (usize, usize), b: (usize, usize)) -> f32
+{
+    let rows = a.0 as f32 - b.0 as f32;
+    let columns = a.1 as f32 - b.1 as f32;
+    return (rows * rows + columns * columns).sqrt();
+}
+
+fn elapsed_seconds(since: u128, millis: u128) -> f32
+{
+    return millis.saturating_sub(since) as f32 / 1000.0;
+}
+
+
+//A ring expanding from every pressed key
+pub struct Ripple
+{
+    pub color       : Color,
+    pub background  : Color,
+    pub speed       : f32, //keys per second
+    pub width       : f32, //keys
+    pub lifetime    : f32, //seconds
+    events          : Box<dyn KeyEventSource>,
+    ripples         : Vec<((usize, usize), u128)>,
+}
+
+impl Ripple
+{
+    pub fn new(events: Box<dyn KeyEventSource>, color: Color, background: Color) -> Self
+    {
+        Ripple
+        {
+            color,
+            background,
+            speed: 12.0,
+            width: 1.5,
+            lifetime: 1.5,
+            events,
+            ripples: Vec::new(),
+        }
+    }
+}
+
+impl Effect for Ripple
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        for key in self.events.poll(millis)
+        {
+            if let Some(position) = key_position(&key)
+            {
+                self.ripples.push((position, millis));
+            }
+        }
+        let lifetime = self.lifetime;
+        self.ripples.retain(|(_, start)| elapsed_seconds(*start, millis) < lifetime);
+
+        let (color, background, speed, width) = (self.color, self.background, self.speed, self.width);
+        let ripples = &self.ripples;
+        paint_keys(frame, background, |position|
+        {
+            let mut intensity: f32 = 0.0;
+            for (origin, start) in ripples.iter()
+            {
+                let elapsed = elapsed_seconds(*start, millis);
+                let radius = elapsed * speed;
+                let ring = 1.0 - (key_distance(*origin, position) - radius).abs() / width;
+                let fade = 1.0 - elapsed / lifetime;
+                intensity = intensity.max(ring * fade);
+            }
+            return background.blend(color, intensity);
+        });
+    }
+}
+
+
+//Keys light up on a press and fade out, leaving a trail of what was typed
+pub struct TypingTrail
+{
+    pub color       : Color,
+    pub background  : Color,
+    pub fade        : f32, //seconds
+    events          : Box<dyn KeyEventSource>,
+    last_pressed    : HashMap<(usize, usize), u128>,
+}
+
+impl TypingTrail
+{
+    pub fn new(events: Box<dyn KeyEventSource>, color: Color, background: Color) -> Self
+    {
+        TypingTrail
+        {
+            color,
+            background,
+            fade: 2.0,
+            events,
+            last_pressed: HashMap::new(),
+        }
+    }
+}
+
+impl Effect for TypingTrail
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        for key in self.events.poll(millis)
+        {
+            if let Some(position) = key_position(&key)
+            {
+                self.last_pressed.insert(position, millis);
+            }
+        }
+        let fade = self.fade;
+        self.last_pressed.retain(|_, start| elapsed_seconds(*start, millis) < fade);
+
+        let (color, background) = (self.color, self.background);
+        let last_pressed = &self.last_pressed;
+        paint_keys(frame, background, |position|
+        {
+            let intensity = match last_pressed.get(&position)
+            {
+                Some(start) => 1.0 - elapsed_seconds(*start, millis) / fade,
+                None => 0.0,
+            };
+            return background.blend(color, intensity);
+        });
+    }
+}
+
+
+//Keys colored by how often they are typed, relative to the most typed key. Counts slowly decay so the map follows what you're doing now.
+pub struct Heatmap
+{
+    pub cold        : Color,
+    pub hot         : Color,
+    pub half_life   : f32, //seconds
+    events          : Box<dyn KeyEventSource>,
+    counts          : HashMap<(usize, usize), f32>,
+    last_millis     : Option<u128>,
+}
+
+impl Heatmap
+{
+    pub fn new(events: Box<dyn KeyEventSource>, cold: Color, hot: Color) -> Self
+    {
+        Heatmap
+        {
+            cold,
+            hot,
+            half_life: 60.0,
+            events,
+            counts: HashMap::new(),
+            last_millis: None,
+        }
+    }
+}
+
+impl Effect for Heatmap
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        if let Some(last_millis) = self.last_millis
+        {
+            let decay = 0.5f32.powf(elapsed_seconds(last_millis, millis) / self.half_life);
+            for count in self.counts.values_mut()
+            {
+                *count *= decay;
+            }
+        }
+        self.last_millis = Some(millis);
+
+        for key in self.events.poll(millis)
+        {
+            if let Some(position) = key_position(&key)
+            {
+                *self.counts.entry(position).or_insert(0.0) += 1.0;
+            }
+        }
+
+        let max = self.counts.values().cloned().fold(0.0, f32::max);
+        let (cold, hot) = (self.cold, self.hot);
+        let counts = &self.counts;
+        paint_keys(frame, cold, |position|
+        {
+            let heat = match counts.get(&position)
+            {
+                Some(count) if max > 0.0 => count / max,
+                _ => 0.0,
+            };
+            return cold.blend(hot, heat);
+        });
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::color::DeviceType;
+    use crate::frame::{DeviceFrame, Led};
+    use crate::keyboard::KEYS;
+    use crate::scene::Point;
+
+    const COLOR: Color = Color { r: 255, g: 255, b: 255 };
+    const BACKGROUND: Color = Color { r: 0, g: 0, b: 0 };
+
+    //A keyboard with a led per key plus an unnamed one, and a strip without names
+    fn frame() -> Frame
+    {
+        let led = Led { position: Point::default(), color: Color::new(1, 2, 3) };
+        let mut led_names: Vec<Option<String>> = KEYS.iter().map(|(name, _, _)| Some(name.to_string())).collect();
+        led_names.push(None);
+
+        return Frame
+        {
+            devices: vec![
+                DeviceFrame { name: "sk621".to_string(), leds: vec![led; led_names.len()], led_names, device_type: DeviceType::Keyboard },
+                DeviceFrame { name: "strip".to_string(), leds: vec![led; 4], led_names: vec![None; 4], device_type: DeviceType::Other },
+            ],
+        };
+    }
+
+    fn key(frame: &Frame, name: &str) -> Color
+    {
+        let index = KEYS.iter().position(|(key, _, _)| *key == name).unwrap();
+        return frame.devices[0].leds[index].color;
+    }
+
+    fn events(events: Vec<(u128, &str)>) -> Box<dyn KeyEventSource>
+    {
+        return Box::new(ScriptedKeyEvents::new(events));
+    }
+
+    #[test]
+    fn scripted_events_arrive_once_when_due()
+    {
+        let mut events = ScriptedKeyEvents::new(vec![(0, "a"), (100, "b"), (100, "c"), (250, "d")]);
+        assert_eq!(events.poll(0), vec!["a"]);
+        assert!(events.poll(99).is_empty());
+        assert_eq!(events.poll(180), vec!["b", "c"]);
+        assert_eq!(events.poll(1000), vec!["d"]);
+        assert!(events.poll(2000).is_empty());
+    }
+
+    #[test]
+    fn ripple_spreads_from_the_pressed_key()
+    {
+        let mut ripple = Ripple::new(events(vec![(0, "g")]), COLOR, BACKGROUND);
+        let mut frame = frame();
+
+        ripple.render(&mut frame, 0);
+        assert_eq!(key(&frame, "g"), COLOR);
+        assert_eq!(key(&frame, "esc"), BACKGROUND);
+
+        //At 12 keys per second the ring is 3 keys out after 250ms: "t" is one row up, "s" three keys left
+        ripple.render(&mut frame, 250);
+        assert_eq!(key(&frame, "g"), BACKGROUND);
+        assert!(key(&frame, "s").r > key(&frame, "t").r);
+
+        ripple.render(&mut frame, 1500);
+        assert!(frame.devices[0].leds.iter().all(|led| led.color == BACKGROUND));
+    }
+
+    #[test]
+    fn trail_fades_out()
+    {
+        let mut trail = TypingTrail::new(events(vec![(0, "w"), (1000, "A")]), COLOR, BACKGROUND);
+        let mut frame = frame();
+
+        trail.render(&mut frame, 0);
+        assert_eq!(key(&frame, "w"), COLOR);
+
+        trail.render(&mut frame, 1000);
+        assert_eq!(key(&frame, "w"), Color::new(128, 128, 128));
+        assert_eq!(key(&frame, "a"), COLOR);
+        assert_eq!(key(&frame, "s"), BACKGROUND);
+
+        trail.render(&mut frame, 3000);
+        assert_eq!(key(&frame, "a"), BACKGROUND);
+    }
+
+    #[test]
+    fn heatmap_is_hottest_on_the_most_typed_key()
+    {
+        let hot = Color::new(255, 0, 0);
+        let mut heatmap = Heatmap::new(events(vec![(0, "e"), (0, "e"), (0, "e"), (0, "e"), (0, "r")]), BACKGROUND, hot);
+        let mut frame = frame();
+
+        heatmap.render(&mut frame, 0);
+        assert_eq!(key(&frame, "e"), hot);
+        assert_eq!(key(&frame, "r"), Color::new(64, 0, 0));
+        assert_eq!(key(&frame, "t"), BACKGROUND);
+
+        //Decaying together keeps the relative heat
+        heatmap.render(&mut frame, 60000);
+        assert_eq!(key(&frame, "e"), hot);
+    }
+
+    #[test]
+    fn leds_without_a_key_get_the_background()
+    {
+        let mut trail = TypingTrail::new(events(vec![(0, "f13"), (0, "q")]), COLOR, BACKGROUND);
+        let mut frame = frame();
+
+        trail.render(&mut frame, 0);
+        assert_eq!(key(&frame, "q"), COLOR);
+        assert_eq!(frame.devices[0].leds[KEYS.len()].color, BACKGROUND);
+        assert!(frame.devices[1].leds.iter().all(|led| led.color == BACKGROUND));
+    }
+}
@@ -0,0 +1,574 @@
+use crate::animation::Effect;
+use crate::color::{Color, RgbColorFlags, RgbDevice, RgbMode, RgbSpeed, DeviceType, effective_brightness};
+use crate::frame::Frame;
+use crate::scene::Scene;
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+
+//Recordings of what every device was told to show, to replay later or to compare against a golden file.
+//A record is only written when the output of a device changed. The file, little endian:
+//    "RLRC" version:u8
+//    device count:u16, per device: name length:u8 name led count:u16
+//    records until the end: millis:u32 device:u16 rgb for every led of the device
+//Colors are the ones the device shows, so with the device and master brightness applied.
+
+const MAGIC: &[u8; 4] = b"RLRC";
+const VERSION: u8 = 1;
+
+//Set by --record, run_animation records when there is one
+static OUTPUT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+pub fn record_to(path: PathBuf)
+{
+    *OUTPUT.lock().unwrap() = Some(path);
+}
+
+pub fn output() -> Option<PathBuf>
+{
+    return OUTPUT.lock().unwrap().clone();
+}
+
+
+pub struct Record
+{
+    pub millis: u32,
+    pub device: usize,
+    pub colors: Vec<Color>,
+}
+
+pub struct Recording
+{
+    pub devices: Vec<(String, usize)>, //Name and led count
+    pub records: Vec<Record>,
+}
+
+impl Recording
+{
+    pub fn load(path: &Path) -> Result<Self, String>
+    {
+        let mut bytes = Vec::new();
+        File::open(path).and_then(|mut file| file.read_to_end(&mut bytes)).map_err(|e| format!("{}: {}", path.display(), e))?;
+        return Recording::parse(&bytes).map_err(|e| format!("{}: {}", path.display(), e));
+    }
+
+    fn parse(bytes: &[u8]) -> Result<Self, String>
+    {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader.take(4)? != MAGIC
+        {
+            return Err("not a recording".to_string());
+        }
+        let version = reader.u8()?;
+        if version != VERSION
+        {
+            return Err(format!("unsupported version {}", version));
+        }
+
+        let device_count = reader.u16()?;
+        let mut devices = Vec::new();
+        for _ in 0..device_count
+        {
+            let name_length = reader.u8()? as usize;
+            let name = String::from_utf8_lossy(reader.take(name_length)?).to_string();
+            devices.push((name, reader.u16()? as usize));
+        }
+
+        let mut records = Vec::new();
+        while reader.position < bytes.len()
+        {
+            let millis = reader.u32()?;
+            let device = reader.u16()? as usize;
+            let led_count = devices.get(device).ok_or(format!("record for unknown device {}", device))?.1;
+            let colors = reader.take(led_count * 3)?.chunks(3).map(|rgb| Color::new(rgb[0], rgb[1], rgb[2])).collect();
+            records.push(Record { millis, device, colors });
+        }
+        return Ok(Recording { devices, records });
+    }
+
+    pub fn duration(&self) -> u32
+    {
+        return self.records.last().map_or(0, |r| r.millis);
+    }
+}
+
+struct Reader<'a>
+{
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a>
+{
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String>
+    {
+        if self.position + count > self.bytes.len()
+        {
+            return Err("truncated".to_string());
+        }
+        self.position += count;
+        return Ok(&self.bytes[self.position - count..self.position]);
+    }
+
+    fn u8(&mut self) -> Result<u8, String>
+    {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u16(&mut self) -> Result<u16, String>
+    {
+        let bytes = self.take(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn u32(&mut self) -> Result<u32, String>
+    {
+        let bytes = self.take(4)?;
+        return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Recording
+
+pub struct Recorder
+{
+    writer: BufWriter<File>,
+    millis: u128, //Of the frame that is being displayed, set by whoever drives the animation
+}
+
+impl Recorder
+{
+    pub fn create(path: &Path, devices: &[(String, usize)]) -> Result<Self, String>
+    {
+        let file = File::create(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut writer = BufWriter::new(file);
+
+        let mut header = MAGIC.to_vec();
+        header.push(VERSION);
+        header.extend_from_slice(&(devices.len() as u16).to_le_bytes());
+        for (name, led_count) in devices.iter()
+        {
+            let name = &name.as_bytes()[..name.len().min(255)];
+            header.push(name.len() as u8);
+            header.extend_from_slice(name);
+            header.extend_from_slice(&(*led_count as u16).to_le_bytes());
+        }
+        writer.write_all(&header).map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        return Ok(Recorder { writer, millis: 0 });
+    }
+
+    pub fn set_millis(&mut self, millis: u128)
+    {
+        self.millis = millis;
+    }
+
+    fn write(&mut self, device: usize, colors: &[Color])
+    {
+        let mut record = Vec::with_capacity(6 + colors.len() * 3);
+        record.extend_from_slice(&(self.millis as u32).to_le_bytes());
+        record.extend_from_slice(&(device as u16).to_le_bytes());
+        for color in colors.iter()
+        {
+            record.extend_from_slice(&[color.r, color.g, color.b]);
+        }
+        //A full disk shouldn't stop the lights
+        let _ = self.writer.write_all(&record);
+    }
+
+    pub fn flush(&mut self)
+    {
+        let _ = self.writer.flush();
+    }
+}
+
+//Wraps every device, so the recorder sees what each of them shows
+pub fn record(rgb_devices: Vec<Box<dyn RgbDevice>>, path: &Path) -> Result<(Vec<Box<dyn RgbDevice>>, Rc<RefCell<Recorder>>), String>
+{
+    let devices: Vec<(String, usize)> = rgb_devices.iter().map(|d| (d.get_name().clone(), d.get_led_count())).collect();
+    let recorder = Rc::new(RefCell::new(Recorder::create(path, &devices)?));
+
+    let rgb_devices = rgb_devices.into_iter().enumerate().map(|(index, device)|
+    {
+        let led_count = device.get_led_count();
+        let recording: Box<dyn RgbDevice> = Box::new(RecordingDevice
+        {
+            device,
+            index,
+            recorder: recorder.clone(),
+            colors: vec![Color::new(0, 0, 0); led_count],
+            brightness: 1.0,
+            last: None,
+        });
+        return recording;
+    }).collect();
+    return Ok((rgb_devices, recorder));
+}
+
+pub struct RecordingDevice
+{
+    device: Box<dyn RgbDevice>,
+    index: usize,
+    recorder: Rc<RefCell<Recorder>>,
+    colors: Vec<Color>,
+    brightness: f32,
+    last: Option<Vec<Color>>, //What was recorded last
+}
+
+impl RgbDevice for RecordingDevice
+{
+    fn set_color(&mut self, color: Color)
+    {
+        for led in self.colors.iter_mut()
+        {
+            *led = color;
+        }
+        self.device.set_color(color);
+    }
+
+    fn set_secondary_color(&mut self, color: Color)
+    {
+        self.device.set_secondary_color(color);
+    }
+
+    fn set_color_flags(&mut self, flags: RgbColorFlags)
+    {
+        self.device.set_color_flags(flags);
+    }
+
+    fn set_mode(&mut self, mode: RgbMode)
+    {
+        self.device.set_mode(mode);
+    }
+
+    fn set_speed(&mut self, speed: RgbSpeed)
+    {
+        self.device.set_speed(speed);
+    }
+
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.brightness = brightness;
+        self.device.set_brightness(brightness);
+    }
+
+    fn get_name(&self) -> &String
+    {
+        return self.device.get_name();
+    }
+
+    fn display(&mut self)
+    {
+        self.device.display();
+
+        let brightness = effective_brightness(self.brightness);
+        let shown: Vec<Color> = self.colors.iter().map(|c| c.scale(brightness)).collect();
+        if self.last.as_ref() != Some(&shown)
+        {
+            self.recorder.borrow_mut().write(self.index, &shown);
+            self.last = Some(shown);
+        }
+    }
+
+    fn get_led_count(&self) -> usize
+    {
+        return self.device.get_led_count();
+    }
+
+    fn set_led_color(&mut self, index: usize, color: Color)
+    {
+        if index < self.colors.len()
+        {
+            self.colors[index] = color;
+        }
+        self.device.set_led_color(index, color);
+    }
+
+    fn find_led(&self, name: &str) -> Option<usize>
+    {
+        return self.device.find_led(name);
+    }
+
+    fn get_led_name(&self, index: usize) -> Option<String>
+    {
+        return self.device.get_led_name(index);
+    }
+
+    fn get_device_type(&self) -> DeviceType
+    {
+        return self.device.get_device_type();
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Virtual devices
+
+//A device without hardware, for rendering and replaying without the pc it was recorded on. Prints what it shows when asked to.
+pub struct VirtualDevice
+{
+    name: String,
+    colors: Vec<Color>,
+    brightness: f32,
+    print: bool,
+}
+
+impl VirtualDevice
+{
+    pub fn new(name: &str, led_count: usize, print: bool) -> Self
+    {
+        VirtualDevice
+        {
+            name: name.to_string(),
+            colors: vec![Color::new(0, 0, 0); led_count],
+            brightness: 1.0,
+            print,
+        }
+    }
+}
+
+impl RgbDevice for VirtualDevice
+{
+    fn set_color(&mut self, color: Color)
+    {
+        for led in self.colors.iter_mut()
+        {
+            *led = color;
+        }
+    }
+
+    fn set_secondary_color(&mut self, _color: Color)
+    {
+    }
+
+    fn set_mode(&mut self, _mode: RgbMode)
+    {
+    }
+
+    fn set_speed(&mut self, _speed: RgbSpeed)
+    {
+    }
+
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.brightness = brightness;
+    }
+
+    fn get_name(&self) -> &String
+    {
+        return &self.name;
+    }
+
+    fn display(&mut self)
+    {
+        if self.print
+        {
+            let brightness = effective_brightness(self.brightness);
+            let colors: Vec<String> = self.colors.iter().map(|c| c.scale(brightness).to_hex()).collect();
+            println!("{} {}", self.name, colors.join(" "));
+        }
+    }
+
+    fn get_led_count(&self) -> usize
+    {
+        return self.colors.len();
+    }
+
+    fn set_led_color(&mut self, index: usize, color: Color)
+    {
+        if index < self.colors.len()
+        {
+            self.colors[index] = color;
+        }
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Rendering, replaying and comparing
+
+//Runs the effect on virtual devices as fast as possible, with fixed steps instead of the clock. The same effect gives the same file every time.
+pub fn render(mut effect: Box<dyn Effect>, scene: &Scene, devices: &[(String, usize)], duration: u128, step: u128, path: &Path) -> Result<(), String>
+{
+    let virtual_devices: Vec<Box<dyn RgbDevice>> = devices.iter().map(|(name, led_count)|
+    {
+        let device: Box<dyn RgbDevice> = Box::new(VirtualDevice::new(name, *led_count, false));
+        return device;
+    }).collect();
+    let (mut rgb_devices, recorder) = record(virtual_devices, path)?;
+
+    let mut frame = Frame::new(scene, &rgb_devices);
+    let mut millis = 0;
+    while millis <= duration
+    {
+        recorder.borrow_mut().set_millis(millis);
+        effect.render(&mut frame, millis);
+        frame.apply(&mut rgb_devices);
+        for device in rgb_devices.iter_mut()
+        {
+            device.display();
+        }
+        millis += step.max(1);
+    }
+    recorder.borrow_mut().flush();
+    return Ok(());
+}
+
+//At the original speed. Devices are matched by name, the ones that aren't in the recording are left alone.
+pub fn replay(recording: &Recording, rgb_devices: &mut Vec<Box<dyn RgbDevice>>)
+{
+    let targets: Vec<Option<usize>> = recording.devices.iter().map(|(name, _)| rgb_devices.iter().position(|d| d.get_name() == name)).collect();
+    for ((name, _), target) in recording.devices.iter().zip(targets.iter())
+    {
+        if target.is_none()
+        {
+            println!("Skipping {}, there is no such device", name);
+        }
+    }
+
+    let start = Instant::now();
+    for record in recording.records.iter()
+    {
+        let target = match targets[record.device]
+        {
+            Some(target) => target,
+            None => continue,
+        };
+
+        let at = Duration::from_millis(record.millis as u64);
+        let elapsed = start.elapsed();
+        if at > elapsed
+        {
+            sleep(at - elapsed);
+        }
+
+        let device = &mut rgb_devices[target];
+        for (index, color) in record.colors.iter().enumerate()
+        {
+            device.set_led_color(index, *color);
+        }
+        device.display();
+    }
+}
+
+//Differences between two recordings, empty when they match. Channels may differ by tolerance, for effects with float rounding between platforms.
+pub fn compare(expected: &Recording, actual: &Recording, tolerance: u8) -> Vec<String>
+{
+    let mut differences = Vec::new();
+    if expected.devices != actual.devices
+    {
+        differences.push(format!("devices differ: expected {:?}, got {:?}", expected.devices, actual.devices));
+        return differences;
+    }
+
+    for (e, a) in expected.records.iter().zip(actual.records.iter())
+    {
+        let name = &expected.devices[e.device].0;
+        if e.millis != a.millis || e.device != a.device
+        {
+            differences.push(format!("expected {} at {}ms, got {} at {}ms", name, e.millis, actual.devices[a.device].0, a.millis));
+            //The records don't line up anymore, the rest would only be noise
+            return differences;
+        }
+        for (led, (ec, ac)) in e.colors.iter().zip(a.colors.iter()).enumerate()
+        {
+            let off = |x: u8, y: u8| (x as i16 - y as i16).abs() > tolerance as i16;
+            if off(ec.r, ac.r) || off(ec.g, ac.g) || off(ec.b, ac.b)
+            {
+                differences.push(format!("{}ms {} led {}: expected {}, got {}", e.millis, name, led, ec.to_hex(), ac.to_hex()));
+            }
+        }
+    }
+    if expected.records.len() != actual.records.len()
+    {
+        differences.push(format!("expected {} records, got {}", expected.records.len(), actual.records.len()));
+    }
+    return differences;
+}
+
+
+//================================================================================================================================================================================================
+//Tests
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::effects::{self, EffectOptions};
+    use crate::testing::TempPath;
+
+    //Made with: rustic_light record tests/golden/<effect>.rlrc <effect> --duration 2000 --step 50 --devices strip:8,fan:4
+    //Only make them again when an effect is meant to look different.
+    const GOLDEN: [(&str, &[u8]); 12] =
+    [
+        ("spectrum",        include_bytes!("../tests/golden/spectrum.rlrc")),
+        ("spectrum-wave",   include_bytes!("../tests/golden/spectrum-wave.rlrc")),
+        ("spectrum-radial", include_bytes!("../tests/golden/spectrum-radial.rlrc")),
+        ("solid",           include_bytes!("../tests/golden/solid.rlrc")),
+        ("breathing",       include_bytes!("../tests/golden/breathing.rlrc")),
+        ("strobe",          include_bytes!("../tests/golden/strobe.rlrc")),
+        ("wave",            include_bytes!("../tests/golden/wave.rlrc")),
+        ("comet",           include_bytes!("../tests/golden/comet.rlrc")),
+        ("fire",            include_bytes!("../tests/golden/fire.rlrc")),
+        ("twinkle",         include_bytes!("../tests/golden/twinkle.rlrc")),
+        ("gradient",        include_bytes!("../tests/golden/gradient.rlrc")),
+        ("color-cycle",     include_bytes!("../tests/golden/color-cycle.rlrc")),
+    ];
+
+    #[test]
+    fn effects_match_their_golden_recordings()
+    {
+        assert_eq!(GOLDEN.iter().map(|(name, _)| *name).collect::<Vec<&str>>(), effects::NAMES.to_vec(), "every effect needs a golden recording");
+
+        let devices = vec![("strip".to_string(), 8), ("fan".to_string(), 4)];
+        for (name, golden) in GOLDEN.iter()
+        {
+            let file = TempPath::new(&format!("{}.rlrc", name));
+            let effect = effects::create(name, &EffectOptions::default()).unwrap();
+            render(effect, &Scene::default(), &devices, 2000, 50, file.path()).unwrap();
+
+            //A little room for float rounding on other platforms
+            let differences = compare(&Recording::parse(golden).unwrap(), &Recording::load(file.path()).unwrap(), 1);
+            assert!(differences.is_empty(), "{} differs from its golden recording:\n{}", name, differences.join("\n"));
+        }
+    }
+
+    #[test]
+    fn compare_finds_differences()
+    {
+        let recording = |colors: Vec<Color>| Recording
+        {
+            devices: vec![("strip".to_string(), 2)],
+            records: vec![Record { millis: 0, device: 0, colors }],
+        };
+        let expected = recording(vec![Color::new(10, 10, 10), Color::new(0, 0, 0)]);
+
+        assert!(compare(&expected, &recording(vec![Color::new(11, 9, 10), Color::new(0, 0, 1)]), 1).is_empty());
+        assert_eq!(compare(&expected, &recording(vec![Color::new(10, 10, 10), Color::new(0, 2, 0)]), 1), vec!["0ms strip led 1: expected #000000, got #000200"]);
+
+        let mut longer = recording(vec![Color::new(10, 10, 10), Color::new(0, 0, 0)]);
+        longer.records.push(Record { millis: 10, device: 0, colors: vec![Color::new(0, 0, 0); 2] });
+        assert_eq!(compare(&expected, &longer, 0), vec!["expected 1 records, got 2"]);
+    }
+
+    #[test]
+    fn rejects_broken_recordings()
+    {
+        let golden = GOLDEN[0].1;
+        assert!(Recording::parse(golden).is_ok());
+        assert!(Recording::parse(&golden[..golden.len() - 1]).is_err());
+        assert!(Recording::parse(b"RIFF").is_err());
+
+        let mut version = golden.to_vec();
+        version[4] = VERSION + 1;
+        assert!(Recording::parse(&version).is_err());
+    }
+}
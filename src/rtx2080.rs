@@ -2,7 +2,8 @@ use nvapi::sys::handles::NvPhysicalGpuHandle;
 use nvapi::sys::i2c::NV_I2C_INFO_V3;
 use nvapi::sys::nvapi_QueryInterface;
 use nvapi::Status;
-use crate::color::{Color, RgbMode, RgbSpeed, RgbDevice, RgbBrightness};
+use crate::color::{Color, RgbMode, RgbSpeed, RgbDevice, DeviceType, effective_brightness};
+use crate::trace;
 
 
 //================================================================================================================================================================================================
@@ -11,6 +12,7 @@ use crate::color::{Color, RgbMode, RgbSpeed, RgbDevice, RgbBrightness};
 const NVAPI_IC2_WRITE_EX_ADDRESS: u32 = 0x283AC65A;
 const NVAPI_IC2_READ_EX_ADDRESS: u32 = 0x4D7B0709;
 
+const RGB_FUSION_I2C_ADDRESS: u8 = 0x47;
 const RGB_FUSION_LED_COLOR_ADDRESS: u8 = 0x40;
 const RGB_FUSION_MODE_SPEED_ADDRESS: u8 = 0x88;
 
@@ -56,6 +58,7 @@ pub struct Rtx2080
     color               : Color,
     mode                : RgbMode,
     speed               : RgbSpeed,
+    brightness          : f32,
 }
 
 impl Rtx2080
@@ -87,6 +90,7 @@ impl Rtx2080
             color: Color::new(0,0,0),
             mode: RgbMode::Static,
             speed: RgbSpeed::Slow,
+            brightness: 1.0,
         }
     }
 
@@ -96,7 +100,7 @@ impl Rtx2080
 
         let mut ic2_data =  nvapi::sys::i2c::NV_I2C_INFO::zeroed();
         ic2_data.version = nvapi::sys::i2c::NV_I2C_INFO_VER3;
-        ic2_data.i2cDevAddress = 0x47 << 1;
+        ic2_data.i2cDevAddress = RGB_FUSION_I2C_ADDRESS << 1;
         ic2_data.pbData = data_buffer.as_mut_ptr();
         ic2_data.cbSize = 4;
 
@@ -109,7 +113,15 @@ impl Rtx2080
 
         let mut data_buf2 = [0; 2];
 
-        match (self.nvapi_ic2_write_ex)(self.handle, &mut ic2_data, data_buf2.as_mut_ptr())
+        let status = (self.nvapi_ic2_write_ex)(self.handle, &mut ic2_data, data_buf2.as_mut_ptr());
+        let outcome = match status
+        {
+            Status::Ok => "ok".to_string(),
+            status => format!("failed: {}", status),
+        };
+        trace::log(&self.name, &format!("i2c write to {:#04x}", RGB_FUSION_I2C_ADDRESS), &data, &outcome, || describe(data));
+
+        match status
         {
             Status::Ok => return,
             status => panic!("nvapi_ic2_write_ex error: {}", status)
@@ -117,6 +129,30 @@ impl Rtx2080
     }
 }
 
+//What a write means, for the trace log
+fn describe(data: [u8; 4]) -> Vec<String>
+{
+    let line = match data[0]
+    {
+        RGB_FUSION_LED_COLOR_ADDRESS => format!("led color {}", Color::new(data[1], data[2], data[3]).to_hex()),
+        RGB_FUSION_MODE_SPEED_ADDRESS =>
+        {
+            let mode = match data[1]
+            {
+                0x01 => "Static",
+                0x02 => "Breathing",
+                0x04 => "Flashing",
+                0x08 => "DualFlashing",
+                0x11 => "SpectrumCycle",
+                _    => "unknown mode",
+            };
+            format!("{} ({:#04x}), speed {:#04x}, {:#04x}", mode, data[1], data[2], data[3])
+        }
+        register => format!("unknown register {:#04x}", register),
+    };
+    return vec![line];
+}
+
 
 impl RgbDevice for Rtx2080
 {
@@ -125,6 +161,9 @@ impl RgbDevice for Rtx2080
         self.color = color;
     }
 
+    //The rtx 2080 only has a single color register, dual flashing uses the same color twice
+    fn set_secondary_color(&mut self, _color: Color) {}
+
     fn set_mode(&mut self, mode: RgbMode)
     {
         self.mode = mode;
@@ -135,29 +174,42 @@ impl RgbDevice for Rtx2080
         self.speed = speed;
     }
 
-    fn set_brightness(&mut self, _brightness: RgbBrightness) { /* rtx 2080 does not seem to support brightness */ }
+    //The rtx 2080 does not seem to support brightness, scale the color instead
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.brightness = brightness;
+    }
 
     fn get_name(&self) -> &String
     {
         return &self.name;
     }
 
+    fn get_device_type(&self) -> DeviceType
+    {
+        return DeviceType::Gpu;
+    }
+
     fn display(&mut self)
     {
         //Write the color
+        let color = self.color.scale(effective_brightness(self.brightness));
         let mut data_buffer =
         [
             RGB_FUSION_LED_COLOR_ADDRESS,
-            self.color.r,
-            self.color.g,
-            self.color.b,
+            color.r,
+            color.g,
+            color.b,
         ];
         self.write(data_buffer);
 
         //Convert enum types to the rtx2080 specific ones, write the mode and speed.
         let rgb_fusion_mode = match self.mode
         {
-            RgbMode::Static => RgbFusionMode::Static,
+            RgbMode::Static         => RgbFusionMode::Static,
+            RgbMode::Breathing      => RgbFusionMode::Breathing,
+            RgbMode::Flashing       => RgbFusionMode::Flashing,
+            RgbMode::DoubleFlashing => RgbFusionMode::DualFlashing,
         };
 
         let rgb_fusion_speed = match self.speed
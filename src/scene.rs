@@ -0,0 +1,206 @@
+use serde::Deserialize;
+use crate::color::RgbDevice;
+use crate::config;
+
+
+//Where every device and led sits, in case/desk coordinates. The unit doesn't matter as long as it's the same everywhere, millimeters work well.
+//Loaded from scene.json next to the executable, for example:
+//{
+//    "devices":
+//    [
+//        { "name": "NVIDIA GeForce RTX 2080", "position": { "x": 200, "y": 250 } },
+//        { "name": "JRainbow1",               "position": { "x": 50,  "y": 400 }, "leds": { "type": "line", "end": { "x": 0, "y": 300 } } },
+//        { "name": "sk621",                   "position": { "x": 300, "y": -100 }, "leds": { "type": "grid", "rows": 8, "columns": 24, "spacing": { "x": 19, "y": 19 } } }
+//    ]
+//}
+const SCENE_FILE: &str = "scene.json";
+
+//Devices missing from the scene are lined up next to each other
+const DEFAULT_DEVICE_SPACING: f32 = 100.0;
+const DEFAULT_LED_SPACING: f32 = 10.0;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+pub struct Point
+{
+    pub x: f32,
+    pub y: f32,
+    #[serde(default)]
+    pub z: f32,
+}
+
+impl Point
+{
+    pub fn new(x: f32, y: f32, z: f32) -> Self
+    {
+        Point
+        {
+            x,
+            y,
+            z,
+        }
+    }
+
+    pub fn add(&self, other: Point) -> Point
+    {
+        return Point::new(self.x + other.x, self.y + other.y, self.z + other.z);
+    }
+
+    pub fn sub(&self, other: Point) -> Point
+    {
+        return Point::new(self.x - other.x, self.y - other.y, self.z - other.z);
+    }
+
+    pub fn scale(&self, factor: f32) -> Point
+    {
+        return Point::new(self.x * factor, self.y * factor, self.z * factor);
+    }
+
+    pub fn dot(&self, other: Point) -> f32
+    {
+        return self.x * other.x + self.y * other.y + self.z * other.z;
+    }
+
+    pub fn length(&self) -> f32
+    {
+        return self.dot(*self).sqrt();
+    }
+
+    pub fn distance(&self, other: Point) -> f32
+    {
+        return self.sub(other).length();
+    }
+}
+
+
+//Led positions, relative to the position of the device
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LedLayout
+{
+    //All leds on the position of the device
+    Single,
+    //Evenly spread from the device position to end, like an addressable strip
+    Line { end: Point },
+    //Row by row, like the keys of a keyboard. Leds past rows x columns end up on the device position.
+    Grid { rows: usize, columns: usize, spacing: Point },
+    //Every led listed by hand
+    Points { points: Vec<Point> },
+}
+
+impl Default for LedLayout
+{
+    fn default() -> Self
+    {
+        return LedLayout::Single;
+    }
+}
+
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct DevicePlacement
+{
+    pub name: String,
+    pub position: Point,
+    #[serde(default)]
+    pub leds: LedLayout,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Scene
+{
+    pub devices: Vec<DevicePlacement>,
+}
+
+impl Scene
+{
+    pub fn load() -> Self
+    {
+        return config::load(SCENE_FILE).unwrap_or_default();
+    }
+
+    pub fn find(&self, name: &str) -> Option<&DevicePlacement>
+    {
+        return self.devices.iter().find(|d| d.name == name);
+    }
+
+    //Position of every led of the device, index is the led index of the device
+    pub fn led_positions(&self, device: &dyn RgbDevice, device_index: usize) -> Vec<Point>
+    {
+        let count = device.get_led_count();
+
+        let placement = match self.find(device.get_name())
+        {
+            Some(placement) => placement.clone(),
+            None => DevicePlacement
+            {
+                name: device.get_name().clone(),
+                position: Point::new(device_index as f32 * DEFAULT_DEVICE_SPACING, 0.0, 0.0),
+                leds: if count > 1 { LedLayout::Line { end: Point::new((count - 1) as f32 * DEFAULT_LED_SPACING, 0.0, 0.0) } } else { LedLayout::Single },
+            }
+        };
+
+        let relative: Vec<Point> = match &placement.leds
+        {
+            LedLayout::Single => vec![Point::default(); count],
+            LedLayout::Line { end } =>
+            {
+                let step = if count > 1 { 1.0 / (count - 1) as f32 } else { 0.0 };
+                (0..count).map(|i| end.scale(i as f32 * step)).collect()
+            }
+            LedLayout::Grid { rows, columns, spacing } =>
+            {
+                let columns = (*columns).max(1);
+                (0..count).map(|i| if i / columns < *rows { Point::new((i % columns) as f32 * spacing.x, (i / columns) as f32 * spacing.y, 0.0) } else { Point::default() }).collect()
+            }
+            LedLayout::Points { points } =>
+            {
+                //Leds that weren't listed end up on the device position
+                (0..count).map(|i| points.get(i).cloned().unwrap_or_default()).collect()
+            }
+        };
+
+        return relative.iter().map(|p| placement.position.add(*p)).collect();
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::recording::VirtualDevice;
+
+    fn scene(leds: LedLayout) -> Scene
+    {
+        return Scene { devices: vec![DevicePlacement { name: "device".to_string(), position: Point::new(100.0, 50.0, 0.0), leds }] };
+    }
+
+    #[test]
+    fn grid_fills_rows_then_stops()
+    {
+        let scene = scene(LedLayout::Grid { rows: 2, columns: 3, spacing: Point::new(10.0, 20.0, 0.0) });
+        let positions = scene.led_positions(&VirtualDevice::new("device", 8, false), 0);
+
+        assert_eq!(positions[0], Point::new(100.0, 50.0, 0.0));
+        assert_eq!(positions[2], Point::new(120.0, 50.0, 0.0));
+        assert_eq!(positions[5], Point::new(120.0, 70.0, 0.0));
+        assert_eq!(positions[6], Point::new(100.0, 50.0, 0.0));
+        assert_eq!(positions[7], Point::new(100.0, 50.0, 0.0));
+    }
+
+    #[test]
+    fn line_spreads_from_start_to_end()
+    {
+        let scene = scene(LedLayout::Line { end: Point::new(0.0, 30.0, 0.0) });
+        let positions = scene.led_positions(&VirtualDevice::new("device", 4, false), 0);
+        assert_eq!(positions, vec![Point::new(100.0, 50.0, 0.0), Point::new(100.0, 60.0, 0.0), Point::new(100.0, 70.0, 0.0), Point::new(100.0, 80.0, 0.0)]);
+    }
+
+    #[test]
+    fn missing_devices_are_lined_up()
+    {
+        let positions = Scene::default().led_positions(&VirtualDevice::new("other", 2, false), 3);
+        assert_eq!(positions, vec![Point::new(300.0, 0.0, 0.0), Point::new(310.0, 0.0, 0.0)]);
+    }
+}
@@ -0,0 +1,462 @@
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::frame::Frame;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+
+//================================================================================================================================================================================================
+//Frame sources
+
+//Captured frames are small, sampling regions doesn't need more detail and it keeps capturing cheap
+const CAPTURE_WIDTH: u32 = 64;
+const CAPTURE_HEIGHT: u32 = 36;
+
+pub struct Image
+{
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>, //Row by row, top to bottom
+}
+
+impl Image
+{
+    fn pixel(&self, x: u32, y: u32) -> Color
+    {
+        return self.pixels[(y * self.width + x) as usize];
+    }
+
+    //Region in fractions of the image, 0.0 - 1.0. Nothing for an empty image.
+    fn region(&self, left: f32, top: f32, right: f32, bottom: f32) -> impl Iterator<Item = Color> + '_
+    {
+        let to_x = |f: f32| ((f.max(0.0).min(1.0) * self.width as f32) as u32).min(self.width.saturating_sub(1));
+        let to_y = |f: f32| ((f.max(0.0).min(1.0) * self.height as f32) as u32).min(self.height.saturating_sub(1));
+        let (x0, x1, y0, y1) = (to_x(left), to_x(right), to_y(top), to_y(bottom));
+
+        let empty = self.width == 0 || self.height == 0 || self.pixels.len() < self.width as usize * self.height as usize;
+        let rows = if empty { y0..y0 } else { y0..y1 + 1 };
+        return rows.flat_map(move |y| (x0..=x1).map(move |x| self.pixel(x, y)));
+    }
+
+    fn average(&self, left: f32, top: f32, right: f32, bottom: f32) -> Color
+    {
+        let (mut r, mut g, mut b, mut count) = (0u32, 0u32, 0u32, 0u32);
+        for color in self.region(left, top, right, bottom)
+        {
+            r += color.r as u32;
+            g += color.g as u32;
+            b += color.b as u32;
+            count += 1;
+        }
+        let count = count.max(1);
+        return Color::new((r / count) as u8, (g / count) as u8, (b / count) as u8);
+    }
+
+    //The most common color, with colors grouped into 4096 buckets. Ignores near black, so letterboxing doesn't win.
+    fn dominant(&self, left: f32, top: f32, right: f32, bottom: f32) -> Color
+    {
+        let mut buckets = vec![(0u32, 0u32, 0u32, 0u32); 4096];
+        for color in self.region(left, top, right, bottom)
+        {
+            if color.r < 16 && color.g < 16 && color.b < 16
+            {
+                continue;
+            }
+            let bucket = &mut buckets[((color.r as usize >> 4) << 8) | ((color.g as usize >> 4) << 4) | (color.b as usize >> 4)];
+            bucket.0 += color.r as u32;
+            bucket.1 += color.g as u32;
+            bucket.2 += color.b as u32;
+            bucket.3 += 1;
+        }
+
+        return match buckets.iter().max_by_key(|bucket| bucket.3)
+        {
+            Some((r, g, b, count)) if *count > 0 => Color::new((r / count) as u8, (g / count) as u8, (b / count) as u8),
+            _ => Color::new(0, 0, 0),
+        };
+    }
+}
+
+
+pub trait FrameSource
+{
+    //None when there is no new frame, the effect keeps showing the last one
+    fn capture(&mut self, millis: u128) -> Option<Image>;
+}
+
+
+//A single image, or a directory of images played as a video in file name order
+pub struct ImageFiles
+{
+    files: Vec<PathBuf>,
+    pub frames_per_second: f32,
+    current: Option<usize>,
+}
+
+impl ImageFiles
+{
+    pub fn new(path: &Path, frames_per_second: f32) -> Result<Self, String>
+    {
+        let mut files = if path.is_dir()
+        {
+            std::fs::read_dir(path).map_err(|e| format!("{}: {}", path.display(), e))?
+                .filter_map(|entry| entry.ok().map(|e| e.path()))
+                .filter(|path| path.is_file())
+                .collect()
+        }
+        else
+        {
+            vec![path.to_path_buf()]
+        };
+        files.sort();
+
+        if files.is_empty()
+        {
+            return Err(format!("{}: no images", path.display()));
+        }
+
+        return Ok(ImageFiles
+        {
+            files,
+            frames_per_second,
+            current: None,
+        });
+    }
+}
+
+impl FrameSource for ImageFiles
+{
+    fn capture(&mut self, millis: u128) -> Option<Image>
+    {
+        let index = (millis as f64 / 1000.0 * self.frames_per_second as f64) as usize % self.files.len();
+        if self.current == Some(index)
+        {
+            return None;
+        }
+        self.current = Some(index);
+
+        let image = match image::open(&self.files[index])
+        {
+            Ok(image) => image.thumbnail(CAPTURE_WIDTH, CAPTURE_HEIGHT).to_rgb8(),
+            Err(e) =>
+            {
+                println!("Unable to load {}: {}", self.files[index].display(), e);
+                return None;
+            }
+        };
+
+        return Some(Image
+        {
+            width: image.width(),
+            height: image.height(),
+            pixels: image.pixels().map(|p| Color::new(p[0], p[1], p[2])).collect(),
+        });
+    }
+}
+
+
+#[cfg(windows)]
+pub use self::gdi::ScreenCapture;
+
+#[cfg(windows)]
+mod gdi
+{
+    use super::{FrameSource, Image, CAPTURE_WIDTH, CAPTURE_HEIGHT};
+    use crate::color::Color;
+    use std::mem::{size_of, zeroed};
+    use std::ptr::null_mut;
+    use winapi::um::wingdi::{BitBlt, CreateCompatibleBitmap, CreateCompatibleDC, DeleteDC, DeleteObject, GetDIBits, SelectObject, SetStretchBltMode, StretchBlt,
+                             BITMAPINFO, BITMAPINFOHEADER, BI_RGB, DIB_RGB_COLORS, HALFTONE, SRCCOPY};
+    use winapi::um::winuser::{GetDC, GetSystemMetrics, ReleaseDC, SM_CXSCREEN, SM_CYSCREEN};
+
+    //The primary monitor, scaled down by gdi while copying
+    pub struct ScreenCapture;
+
+    impl FrameSource for ScreenCapture
+    {
+        fn capture(&mut self, _millis: u128) -> Option<Image>
+        {
+            let width = CAPTURE_WIDTH as i32;
+            let height = CAPTURE_HEIGHT as i32;
+            let mut pixels = vec![0u8; (width * height * 4) as usize];
+
+            unsafe
+            {
+                let screen = GetDC(null_mut());
+                if screen.is_null()
+                {
+                    return None;
+                }
+                let memory = CreateCompatibleDC(screen);
+                let bitmap = CreateCompatibleBitmap(screen, width, height);
+                let previous = SelectObject(memory, bitmap as _);
+
+                //Halftone averages the pixels while scaling, instead of skipping them
+                SetStretchBltMode(memory, HALFTONE as _);
+                let copied = StretchBlt(memory, 0, 0, width, height, screen, 0, 0, GetSystemMetrics(SM_CXSCREEN), GetSystemMetrics(SM_CYSCREEN), SRCCOPY) != 0
+                    || BitBlt(memory, 0, 0, width, height, screen, 0, 0, SRCCOPY) != 0;
+
+                let mut info: BITMAPINFO = zeroed();
+                info.bmiHeader.biSize = size_of::<BITMAPINFOHEADER>() as u32;
+                info.bmiHeader.biWidth = width;
+                info.bmiHeader.biHeight = -height; //Negative for top to bottom
+                info.bmiHeader.biPlanes = 1;
+                info.bmiHeader.biBitCount = 32;
+                info.bmiHeader.biCompression = BI_RGB;
+                let lines = GetDIBits(memory, bitmap, 0, height as u32, pixels.as_mut_ptr() as _, &mut info, DIB_RGB_COLORS);
+
+                SelectObject(memory, previous);
+                DeleteObject(bitmap as _);
+                DeleteDC(memory);
+                ReleaseDC(null_mut(), screen);
+
+                if !copied || lines == 0
+                {
+                    return None;
+                }
+            }
+
+            return Some(Image
+            {
+                width: CAPTURE_WIDTH,
+                height: CAPTURE_HEIGHT,
+                pixels: pixels.chunks_exact(4).map(|p| Color::new(p[2], p[1], p[0])).collect(), //bgra
+            });
+        }
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Ambient lighting
+
+#[derive(Clone, Copy)]
+pub enum Sampling
+{
+    Average,
+    Dominant,
+}
+
+//Every led takes the color of the part of the screen nearest to its place in the scene.
+//The scene is mapped onto the screen, the leftmost led matches the left of the screen, the topmost led the top.
+pub struct Ambilight
+{
+    source: Box<dyn FrameSource>,
+    pub sampling: Sampling,
+    pub edges: bool,      //Sample the nearest screen edge instead of the area around the led, like a tv backlight
+    pub region: f32,      //Size of the sampled region, part of the screen
+    pub smoothing: f32,   //0.0 follows the screen immediately, closer to 1.0 fades slower
+    colors: Vec<Vec<Color>>,
+}
+
+impl Ambilight
+{
+    pub fn new(source: Box<dyn FrameSource>) -> Self
+    {
+        Ambilight
+        {
+            source,
+            sampling: Sampling::Average,
+            edges: true,
+            region: 0.2,
+            smoothing: 0.7,
+            colors: Vec::new(),
+        }
+    }
+}
+
+impl Effect for Ambilight
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        if self.colors.len() != frame.devices.len()
+        {
+            self.colors = frame.devices.iter().map(|d| vec![Color::new(0, 0, 0); d.leds.len()]).collect();
+        }
+
+        if let Some(image) = self.source.capture(millis)
+        {
+            let (min, max) = frame.bounds();
+            let width = (max.x - min.x).max(f32::EPSILON);
+            let height = (max.y - min.y).max(f32::EPSILON);
+            let half = self.region / 2.0;
+
+            for (device, colors) in frame.devices.iter().zip(self.colors.iter_mut())
+            {
+                for (led, color) in device.leds.iter().zip(colors.iter_mut())
+                {
+                    let mut x = (led.position.x - min.x) / width;
+                    let mut y = (led.position.y - min.y) / height;
+
+                    if self.edges
+                    {
+                        //Push the position onto the closest edge
+                        let distances = [x, 1.0 - x, y, 1.0 - y];
+                        let closest = (0..4).min_by(|a, b| distances[*a].partial_cmp(&distances[*b]).unwrap_or(Ordering::Equal)).unwrap_or(0);
+                        match closest
+                        {
+                            0 => x = 0.0,
+                            1 => x = 1.0,
+                            2 => y = 0.0,
+                            _ => y = 1.0,
+                        }
+                    }
+
+                    let (left, top, right, bottom) = (x - half, y - half, x + half, y + half);
+                    let sampled = match self.sampling
+                    {
+                        Sampling::Average => image.average(left, top, right, bottom),
+                        Sampling::Dominant => image.dominant(left, top, right, bottom),
+                    };
+                    *color = sampled.blend(*color, self.smoothing);
+                }
+            }
+        }
+
+        for (device, colors) in frame.devices.iter_mut().zip(self.colors.iter())
+        {
+            for (led, color) in device.leds.iter_mut().zip(colors.iter())
+            {
+                led.color = *color;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::color::RgbDevice;
+    use crate::recording::VirtualDevice;
+    use crate::scene::Scene;
+    use crate::testing::TempPath;
+    use std::fs;
+
+    const RED: Color = Color { r: 255, g: 0, b: 0 };
+    const BLUE: Color = Color { r: 0, g: 0, b: 255 };
+
+    //An image in the directory of the test, left half in one color, right half in the other
+    fn image(directory: &TempPath, name: &str, left: Color, right: Color) -> PathBuf
+    {
+        let path = directory.path().join(name);
+        image::RgbImage::from_fn(32, 18, |x, _| if x < 16 { image::Rgb([left.r, left.g, left.b]) } else { image::Rgb([right.r, right.g, right.b]) }).save(&path).unwrap();
+        return path;
+    }
+
+    //Two single led devices, lined up left to right by the default scene
+    fn frame() -> Frame
+    {
+        let devices: Vec<Box<dyn RgbDevice>> = vec![Box::new(VirtualDevice::new("left", 1, false)), Box::new(VirtualDevice::new("right", 1, false))];
+        return Frame::new(&Scene::default(), &devices);
+    }
+
+    fn colors(frame: &Frame) -> Vec<Color>
+    {
+        return frame.devices.iter().map(|d| d.leds[0].color).collect();
+    }
+
+    #[test]
+    fn empty_images_sample_black()
+    {
+        for (width, height, pixels) in [(0, 0, 0), (0, 10, 0), (10, 0, 0), (10, 10, 5)]
+        {
+            let image = Image { width, height, pixels: vec![RED; pixels] };
+            assert_eq!(image.average(0.0, 0.0, 1.0, 1.0), Color::new(0, 0, 0));
+            assert_eq!(image.dominant(0.0, 0.0, 1.0, 1.0), Color::new(0, 0, 0));
+        }
+    }
+
+    #[test]
+    fn samples_regions()
+    {
+        let mut pixels = vec![RED; 100];
+        pixels[99] = BLUE;
+        pixels[98] = Color::new(0, 0, 0);
+        let image = Image { width: 10, height: 10, pixels };
+
+        assert_eq!(image.average(0.0, 0.0, 0.5, 0.5), RED);
+        assert_eq!(image.average(0.9, 0.9, 1.0, 1.0), BLUE);
+        assert_eq!(image.average(0.8, 0.9, 1.0, 1.0), Color::new(0, 0, 127));
+
+        //Black doesn't count, the most common of the rest wins
+        assert_eq!(image.dominant(0.0, 0.8, 1.0, 1.0), RED);
+        assert_eq!(image.dominant(0.8, 0.9, 1.5, 1.5), BLUE);
+    }
+
+    #[test]
+    fn leds_follow_the_nearest_screen_edge()
+    {
+        let directory = TempPath::directory("ambient_edges");
+        let path = image(&directory, "frame.png", RED, BLUE);
+
+        let mut ambilight = Ambilight::new(Box::new(ImageFiles::new(&path, 30.0).unwrap()));
+        ambilight.smoothing = 0.0;
+        let mut frame = frame();
+        ambilight.render(&mut frame, 0);
+        assert_eq!(colors(&frame), vec![RED, BLUE]);
+
+        //A single image is only loaded once, the colors stay
+        ambilight.render(&mut frame, 1000);
+        assert_eq!(colors(&frame), vec![RED, BLUE]);
+    }
+
+    #[test]
+    fn plays_a_directory_by_the_clock()
+    {
+        let directory = TempPath::directory("ambient_frames");
+        image(&directory, "1.png", RED, RED);
+        image(&directory, "2.png", BLUE, BLUE);
+        fs::write(directory.path().join("3.png"), b"not an image").unwrap();
+
+        let mut files = ImageFiles::new(&directory.path(), 2.0).unwrap();
+        assert_eq!(files.capture(0).unwrap().pixels[0], RED);
+        assert!(files.capture(400).is_none());
+        assert_eq!(files.capture(500).unwrap().pixels[0], BLUE);
+
+        //Files that aren't images are skipped, the effect keeps the last frame
+        assert!(files.capture(1000).is_none());
+        assert_eq!(files.capture(1500).unwrap().pixels[0], RED);
+
+        fs::create_dir(directory.path().join("nothing")).unwrap();
+        assert!(ImageFiles::new(&directory.path().join("nothing"), 1.0).is_err());
+    }
+
+    #[test]
+    fn smoothing_fades_towards_the_screen()
+    {
+        let directory = TempPath::directory("ambient_smoothing");
+        image(&directory, "1.png", RED, RED);
+        image(&directory, "2.png", BLUE, BLUE);
+
+        let mut ambilight = Ambilight::new(Box::new(ImageFiles::new(&directory.path(), 1.0).unwrap()));
+        ambilight.smoothing = 0.5;
+        let mut frame = frame();
+
+        ambilight.render(&mut frame, 0);
+        assert_eq!(colors(&frame)[0], Color::new(128, 0, 0));
+        ambilight.render(&mut frame, 1000);
+        assert_eq!(colors(&frame)[0], Color::new(64, 0, 128));
+    }
+
+    #[test]
+    fn odd_positions_do_not_panic()
+    {
+        let mut ambilight = Ambilight::new(Box::new(SingleImage(Some(Image { width: 2, height: 2, pixels: vec![RED; 4] }))));
+        let mut frame = frame();
+        frame.devices[0].leds[0].position.x = f32::NAN;
+        frame.devices[1].leds[0].position.y = f32::INFINITY;
+        ambilight.render(&mut frame, 0);
+    }
+
+    struct SingleImage(Option<Image>);
+
+    impl FrameSource for SingleImage
+    {
+        fn capture(&mut self, _millis: u128) -> Option<Image>
+        {
+            return self.0.take();
+        }
+    }
+}
@@ -0,0 +1,553 @@
+use serde::Deserialize;
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::config;
+use crate::frame::Frame;
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+
+
+//Colors from hardware readings, for example the gpu temperature going from blue to red. sensor.json:
+//{
+//    "sensor": "gpu_temperature",
+//    "interval": 1000,
+//    "hysteresis": 3,
+//    "smooth": false,
+//    "stops": [ { "value": 40, "color": "blue" }, { "value": 65, "color": "yellow" }, { "value": 80, "color": "red" } ]
+//}
+//Sensors: gpu_temperature, gpu_load, gpu_fan, cpu_temperature, memory, or file:<path> for a number in a text file.
+//Without sensor.json: the gpu temperature on windows, the cpu temperature on linux.
+
+
+//================================================================================================================================================================================================
+//Sensors
+
+pub trait Sensor
+{
+    fn get_name(&self) -> String;
+
+    //None when the sensor can't be read right now
+    fn read(&mut self) -> Option<f32>;
+}
+
+pub fn create(name: &str) -> Option<Box<dyn Sensor>>
+{
+    if let Some(path) = name.strip_prefix("file:")
+    {
+        return Some(Box::new(FileSensor::new(Path::new(path))));
+    }
+
+    let sensor: Box<dyn Sensor> = match name
+    {
+        #[cfg(windows)]
+        "gpu_temperature" => Box::new(GpuSensor::new(GpuMetric::Temperature)?),
+        #[cfg(windows)]
+        "gpu_load"        => Box::new(GpuSensor::new(GpuMetric::Load)?),
+        #[cfg(windows)]
+        "gpu_fan"         => Box::new(GpuSensor::new(GpuMetric::Fan)?),
+        #[cfg(target_os = "linux")]
+        "cpu_temperature" => Box::new(CpuTemperature::new()?),
+        "memory"          => Box::new(MemoryUsage),
+        _ => return None,
+    };
+    return Some(sensor);
+}
+
+pub const NAMES: [&str; 6] = ["gpu_temperature", "gpu_load", "gpu_fan", "cpu_temperature", "memory", "file:<path>"];
+
+//A temperature that create knows on this platform, for the default config
+#[cfg(windows)]
+const DEFAULT_SENSOR: &str = "gpu_temperature";
+#[cfg(target_os = "linux")]
+const DEFAULT_SENSOR: &str = "cpu_temperature";
+#[cfg(not(any(windows, target_os = "linux")))]
+const DEFAULT_SENSOR: &str = "memory";
+
+
+#[cfg(windows)]
+#[derive(Clone, Copy)]
+pub enum GpuMetric
+{
+    Temperature, //°C
+    Load,        //%
+    Fan,         //rpm
+}
+
+//Same gpu as rtx2080.rs, through nvapi
+#[cfg(windows)]
+pub struct GpuSensor
+{
+    gpu: nvapi::PhysicalGpu,
+    metric: GpuMetric,
+}
+
+#[cfg(windows)]
+impl GpuSensor
+{
+    pub fn new(metric: GpuMetric) -> Option<Self>
+    {
+        nvapi::initialize().ok()?;
+        let gpu = nvapi::PhysicalGpu::enumerate().ok()?.into_iter().next()?;
+        return Some(GpuSensor { gpu, metric });
+    }
+}
+
+#[cfg(windows)]
+impl Sensor for GpuSensor
+{
+    fn get_name(&self) -> String
+    {
+        let name = self.gpu.full_name().unwrap_or_default();
+        return match self.metric
+        {
+            GpuMetric::Temperature => format!("{} temperature", name),
+            GpuMetric::Load        => format!("{} load", name),
+            GpuMetric::Fan         => format!("{} fan", name),
+        };
+    }
+
+    fn read(&mut self) -> Option<f32>
+    {
+        return match self.metric
+        {
+            GpuMetric::Temperature =>
+            {
+                let sensors = self.gpu.thermal_settings(None).ok()?;
+                sensors.iter().map(|s| s.current_temperature.0).max().map(|t| t as f32)
+            }
+            GpuMetric::Load =>
+            {
+                let usages = self.gpu.usages().ok()?;
+                usages.get(&nvapi::UtilizationDomain::Graphics).map(|p| p.0 as f32)
+            }
+            GpuMetric::Fan => self.gpu.tachometer().ok().map(|rpm| rpm as f32),
+        };
+    }
+}
+
+
+//Linux, the package temperature from hwmon, or a thermal zone when there is no known cpu driver
+#[cfg(target_os = "linux")]
+pub struct CpuTemperature
+{
+    path: PathBuf,
+}
+
+#[cfg(target_os = "linux")]
+impl CpuTemperature
+{
+    pub fn new() -> Option<Self>
+    {
+        let hwmon = std::fs::read_dir("/sys/class/hwmon").ok().into_iter().flatten().filter_map(|e| e.ok()).map(|e| e.path()).find(|path|
+        {
+            let name = std::fs::read_to_string(path.join("name")).unwrap_or_default();
+            return ["coretemp", "k10temp", "zenpower", "cpu_thermal"].contains(&name.trim());
+        });
+        if let Some(hwmon) = hwmon
+        {
+            return Some(CpuTemperature { path: hwmon.join("temp1_input") });
+        }
+
+        let mut zones: Vec<PathBuf> = std::fs::read_dir("/sys/class/thermal").ok()?.filter_map(|e| e.ok()).map(|e| e.path())
+            .filter(|path| path.join("temp").exists())
+            .collect();
+        zones.sort();
+        let zone = zones.iter().find(|path| std::fs::read_to_string(path.join("type")).unwrap_or_default().trim() == "x86_pkg_temp").or(zones.first())?;
+        return Some(CpuTemperature { path: zone.join("temp") });
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Sensor for CpuTemperature
+{
+    fn get_name(&self) -> String
+    {
+        return "CPU temperature".to_string();
+    }
+
+    fn read(&mut self) -> Option<f32>
+    {
+        //millidegrees
+        let value: f32 = std::fs::read_to_string(&self.path).ok()?.trim().parse().ok()?;
+        return Some(value / 1000.0);
+    }
+}
+
+
+//Memory in use, %
+pub struct MemoryUsage;
+
+impl Sensor for MemoryUsage
+{
+    fn get_name(&self) -> String
+    {
+        return "Memory".to_string();
+    }
+
+    #[cfg(windows)]
+    fn read(&mut self) -> Option<f32>
+    {
+        use winapi::um::sysinfoapi::{GlobalMemoryStatusEx, MEMORYSTATUSEX};
+
+        let mut status: MEMORYSTATUSEX = unsafe { std::mem::zeroed() };
+        status.dwLength = std::mem::size_of::<MEMORYSTATUSEX>() as u32;
+        if unsafe { GlobalMemoryStatusEx(&mut status) } == 0
+        {
+            return None;
+        }
+        return Some(status.dwMemoryLoad as f32);
+    }
+
+    #[cfg(not(windows))]
+    fn read(&mut self) -> Option<f32>
+    {
+        return memory_usage(&std::fs::read_to_string("/proc/meminfo").ok()?);
+    }
+}
+
+//From the contents of /proc/meminfo
+#[cfg(not(windows))]
+fn memory_usage(meminfo: &str) -> Option<f32>
+{
+    let field = |name: &str| meminfo.lines().find(|l| l.starts_with(name)).and_then(|l| l.split_whitespace().nth(1)).and_then(|v| v.parse::<f32>().ok());
+
+    let total = field("MemTotal:")?;
+    let available = field("MemAvailable:")?;
+    if total <= 0.0
+    {
+        return None;
+    }
+    return Some((total - available).max(0.0) / total * 100.0);
+}
+
+
+//A number in a text file, read again every time. For trying out gradients without heating up the hardware.
+pub struct FileSensor
+{
+    path: PathBuf,
+}
+
+impl FileSensor
+{
+    pub fn new(path: &Path) -> Self
+    {
+        FileSensor { path: path.to_path_buf() }
+    }
+}
+
+impl Sensor for FileSensor
+{
+    fn get_name(&self) -> String
+    {
+        return self.path.display().to_string();
+    }
+
+    fn read(&mut self) -> Option<f32>
+    {
+        return std::fs::read_to_string(&self.path).ok()?.trim().parse().ok();
+    }
+}
+
+
+//================================================================================================================================================================================================
+//Sensor gradient
+
+#[derive(Clone, Deserialize)]
+pub struct Stop
+{
+    pub value: f32,
+    pub color: Color,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct SensorConfig
+{
+    pub sensor: String,
+    #[serde(default = "default_interval")]
+    pub interval: u64,    //milliseconds between readings, nvapi and sysfs are slow to ask every frame
+    #[serde(default)]
+    pub hysteresis: f32,  //how far a reading has to go back below a stop before the color goes back
+    #[serde(default)]
+    pub smooth: bool,     //blend between stops instead of switching at them
+    pub stops: Vec<Stop>,
+}
+
+fn default_interval() -> u64
+{
+    return 1000;
+}
+
+impl Default for SensorConfig
+{
+    fn default() -> Self
+    {
+        SensorConfig
+        {
+            sensor: DEFAULT_SENSOR.to_string(),
+            interval: default_interval(),
+            hysteresis: 3.0,
+            smooth: false,
+            stops: vec![Stop { value: 0.0, color: Color::new(0, 0, 255) }, Stop { value: 60.0, color: Color::new(255, 255, 0) }, Stop { value: 80.0, color: Color::new(255, 0, 0) }],
+        }
+    }
+}
+
+impl SensorConfig
+{
+    pub fn load() -> Self
+    {
+        return config::load("sensor.json").unwrap_or_default();
+    }
+}
+
+
+pub struct SensorGradient
+{
+    sensor: Box<dyn Sensor>,
+    config: SensorConfig,
+    value: Option<f32>, //the reading the color is showing, after hysteresis
+    level: usize,       //index of the current stop, when not smooth
+    last_read: Option<u128>,
+}
+
+impl SensorGradient
+{
+    pub fn new(sensor: Box<dyn Sensor>, mut config: SensorConfig) -> Self
+    {
+        config.stops.sort_by(|a, b| a.value.partial_cmp(&b.value).unwrap_or(Ordering::Equal));
+        SensorGradient
+        {
+            sensor,
+            config,
+            value: None,
+            level: 0,
+            last_read: None,
+        }
+    }
+
+    pub fn from_config(config: SensorConfig) -> Result<Self, String>
+    {
+        if config.stops.is_empty()
+        {
+            return Err("no stops".to_string());
+        }
+        if let Some(stop) = config.stops.iter().find(|s| !s.value.is_finite())
+        {
+            return Err(format!("stop value {} is not a number", stop.value));
+        }
+        let sensor = create(&config.sensor).ok_or(format!("sensor {} is not available, sensors: {}", config.sensor, NAMES.join(", ")))?;
+        println!("Reading {}", sensor.get_name());
+        return Ok(SensorGradient::new(sensor, config));
+    }
+
+    fn update(&mut self, reading: f32)
+    {
+        let hysteresis = self.config.hysteresis.max(0.0);
+        let stops = &self.config.stops;
+
+        if self.config.smooth
+        {
+            //Ignore small changes, so the color doesn't flicker between two readings
+            match self.value
+            {
+                Some(value) if (reading - value).abs() < hysteresis => {}
+                _ => self.value = Some(reading),
+            }
+            return;
+        }
+
+        //Go up as soon as a stop is reached, go down only once the reading is hysteresis below it
+        self.value = Some(reading);
+        while self.level + 1 < stops.len() && reading >= stops[self.level + 1].value
+        {
+            self.level += 1;
+        }
+        while self.level > 0 && reading < stops[self.level].value - hysteresis
+        {
+            self.level -= 1;
+        }
+    }
+
+    pub fn color(&self) -> Color
+    {
+        let stops = &self.config.stops;
+        let value = match self.value
+        {
+            Some(value) => value,
+            None => return Color::new(0, 0, 0),
+        };
+
+        if !self.config.smooth
+        {
+            return stops[self.level].color;
+        }
+
+        return match stops.iter().position(|s| s.value > value)
+        {
+            Some(0) => stops[0].color,
+            Some(next) =>
+            {
+                let (from, to) = (&stops[next - 1], &stops[next]);
+                from.color.blend(to.color, (value - from.value) / (to.value - from.value))
+            }
+            None => stops[stops.len() - 1].color,
+        };
+    }
+}
+
+impl Effect for SensorGradient
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let due = match self.last_read
+        {
+            Some(last_read) => millis >= last_read + self.config.interval as u128,
+            None => true,
+        };
+        if due
+        {
+            self.last_read = Some(millis);
+            //A file can hold nan or inf, which no stop can be compared to
+            if let Some(reading) = self.sensor.read().filter(|r| r.is_finite())
+            {
+                self.update(reading);
+            }
+        }
+
+        frame.fill(self.color());
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::TempPath;
+    use std::fs;
+
+    const BLUE: Color = Color { r: 0, g: 0, b: 255 };
+    const YELLOW: Color = Color { r: 255, g: 255, b: 0 };
+    const RED: Color = Color { r: 255, g: 0, b: 0 };
+
+    //A file sensor on a file of its own
+    struct Reading(TempPath);
+
+    impl Reading
+    {
+        fn new(name: &str) -> Self
+        {
+            return Reading(TempPath::new(&format!("sensor_{}", name)));
+        }
+
+        fn set(&self, value: &str)
+        {
+            fs::write(self.0.path(), value).unwrap();
+        }
+    }
+
+    fn config(reading: &Reading, smooth: bool) -> SensorConfig
+    {
+        return SensorConfig
+        {
+            sensor: format!("file:{}", reading.0.path().display()),
+            interval: 0,
+            hysteresis: 3.0,
+            smooth,
+            stops: vec![Stop { value: 80.0, color: RED }, Stop { value: 40.0, color: BLUE }, Stop { value: 60.0, color: YELLOW }],
+        };
+    }
+
+    fn color_at(gradient: &mut SensorGradient, reading: &Reading, value: &str, millis: u128) -> Color
+    {
+        reading.set(value);
+        let mut frame = Frame { devices: Vec::new() };
+        gradient.render(&mut frame, millis);
+        return gradient.color();
+    }
+
+    #[test]
+    fn steps_with_hysteresis()
+    {
+        let reading = Reading::new("steps");
+        let mut gradient = SensorGradient::from_config(config(&reading, false)).unwrap();
+
+        assert_eq!(color_at(&mut gradient, &reading, "20", 0), BLUE);
+        assert_eq!(color_at(&mut gradient, &reading, "60", 1), YELLOW);
+        assert_eq!(color_at(&mut gradient, &reading, "58", 2), YELLOW);
+        assert_eq!(color_at(&mut gradient, &reading, "56.9", 3), BLUE);
+        assert_eq!(color_at(&mut gradient, &reading, "95\n", 4), RED);
+        assert_eq!(color_at(&mut gradient, &reading, "0", 5), BLUE);
+    }
+
+    #[test]
+    fn smooth_blends_between_stops()
+    {
+        let reading = Reading::new("smooth");
+        let mut gradient = SensorGradient::from_config(config(&reading, true)).unwrap();
+
+        assert_eq!(color_at(&mut gradient, &reading, "10", 0), BLUE);
+        assert_eq!(color_at(&mut gradient, &reading, "50", 1), Color::new(128, 128, 128));
+        //Within the hysteresis of the last reading nothing changes
+        assert_eq!(color_at(&mut gradient, &reading, "52", 2), Color::new(128, 128, 128));
+        assert_eq!(color_at(&mut gradient, &reading, "70", 3), Color::new(255, 128, 0));
+        assert_eq!(color_at(&mut gradient, &reading, "100", 4), RED);
+    }
+
+    #[test]
+    fn reads_at_the_interval()
+    {
+        let reading = Reading::new("interval");
+        let mut config = config(&reading, false);
+        config.interval = 1000;
+        let mut gradient = SensorGradient::from_config(config).unwrap();
+
+        assert_eq!(color_at(&mut gradient, &reading, "20", 0), BLUE);
+        assert_eq!(color_at(&mut gradient, &reading, "90", 999), BLUE);
+        assert_eq!(color_at(&mut gradient, &reading, "90", 1000), RED);
+    }
+
+    #[test]
+    fn keeps_the_color_without_a_reading()
+    {
+        let reading = Reading::new("missing");
+        let mut gradient = SensorGradient::from_config(config(&reading, false)).unwrap();
+
+        //Nothing read yet is black
+        let mut frame = Frame { devices: Vec::new() };
+        gradient.render(&mut frame, 0);
+        assert_eq!(gradient.color(), Color::new(0, 0, 0));
+
+        assert_eq!(color_at(&mut gradient, &reading, "70", 1), YELLOW);
+        assert_eq!(color_at(&mut gradient, &reading, "hot", 2), YELLOW);
+        assert_eq!(color_at(&mut gradient, &reading, "NaN", 3), YELLOW);
+        assert_eq!(color_at(&mut gradient, &reading, "-inf", 4), YELLOW);
+    }
+
+    #[test]
+    fn rejects_stops_that_are_not_numbers()
+    {
+        let reading = Reading::new("stops");
+        let mut config = config(&reading, true);
+        config.stops.push(Stop { value: f32::NAN, color: RED });
+        assert!(SensorGradient::from_config(config.clone()).is_err());
+
+        config.stops.clear();
+        assert!(SensorGradient::from_config(config).is_err());
+
+        //Used directly the stops still get sorted
+        let mut config = self::config(&reading, false);
+        config.stops.push(Stop { value: f32::INFINITY, color: RED });
+        SensorGradient::new(Box::new(FileSensor::new(reading.0.path())), config);
+    }
+
+    #[cfg(not(windows))]
+    #[test]
+    fn memory_usage_from_meminfo()
+    {
+        assert_eq!(memory_usage("MemTotal:       16000 kB\nMemFree:         1000 kB\nMemAvailable:    4000 kB\n"), Some(75.0));
+        assert_eq!(memory_usage("MemTotal:           0 kB\nMemAvailable:       0 kB\n"), None);
+        assert_eq!(memory_usage("MemTotal:       16000 kB\n"), None);
+        assert_eq!(memory_usage(""), None);
+    }
+}
@@ -0,0 +1,81 @@
+use std::ffi::OsString;
+use std::time::Duration;
+use windows_service::{define_windows_service, service_dispatcher, service_control_handler};
+use windows_service::service_control_handler::ServiceControlHandlerResult;
+use windows_service::service::{ServiceControl, ServiceStatus, ServiceType, ServiceState, ServiceControlAccept, ServiceExitCode};
+use crate::{animation, run_animation, trace, RUNNING};
+
+//The windows service, see install.bat
+
+define_windows_service!(ffi_service_main, service_main);
+
+pub fn start() -> Result<(), windows_service::Error> {
+    // Register generated `ffi_service_main` with the system and start the service, blocking
+    // this thread until the service is stopped.
+    service_dispatcher::start("RusticLight", ffi_service_main)?;
+    Ok(())
+}
+
+fn service_main(arguments: Vec<OsString>)
+{
+    run_service(arguments).unwrap();
+}
+
+
+fn run_service(_arguments: Vec<OsString>) -> Result<(), windows_service::Error> {
+
+    let event_handler = move |control_event| -> ServiceControlHandlerResult {
+        match control_event {
+            ServiceControl::Stop =>
+            {
+                unsafe { RUNNING = false; }
+                // Handle stop event and return control back to the system.
+                ServiceControlHandlerResult::NoError
+            }
+            // All services must accept Interrogate even if it's a no-op.
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        }
+    };
+
+    // Register system service event handler
+    let status_handle = service_control_handler::register("RusticLight", event_handler)?;
+
+    let next_status = ServiceStatus {
+        // Should match the one from system service registry
+        service_type: ServiceType::OWN_PROCESS,
+        // The new state
+        current_state: ServiceState::Running,
+        // Accept stop events when running
+        controls_accepted: ServiceControlAccept::STOP,
+        // Used to report an error when starting or stopping only, otherwise must be zero
+        exit_code: ServiceExitCode::Win32(0),
+        // Only used for pending states, otherwise must be zero
+        checkpoint: 0,
+        // Only used for pending states, otherwise must be zero
+        wait_hint: Duration::default(),
+
+        process_id: None
+    };
+
+    // Tell the system that the service is running now
+    status_handle.set_service_status(next_status.clone())?;
+
+    trace::start(false);
+
+    run_animation(Box::new(animation::ColorSpectrum::load()));
+
+    let next_status = ServiceStatus {
+        service_type: ServiceType::OWN_PROCESS,
+        current_state: ServiceState::Stopped,
+        controls_accepted: ServiceControlAccept::empty(),
+        exit_code: ServiceExitCode::Win32(0),
+        checkpoint: 0,
+        wait_hint: Duration::default(),
+        process_id: None
+    };
+    status_handle.set_service_status(next_status)?;
+
+    #[allow(unreachable_code)]
+    Ok(())
+}
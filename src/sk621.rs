@@ -1,13 +1,17 @@
 use cooler_master_sdk::CoolerMasterDevice;
 use cooler_master_sdk::ffi::DeviceIndex;
-use crate::color::{Color, RgbDevice, RgbMode, RgbSpeed, RgbBrightness};
+use crate::color::{Color, RgbDevice, RgbMode, RgbSpeed, DeviceType, effective_brightness};
+use crate::keyboard::{KEYS, ROWS, COLUMNS, key_position};
+use crate::trace;
 use std::thread::sleep;
 use std::time::Duration;
 
 pub struct Sk621
 {
     name: String,
-    color: Color,
+    colors: [[Color; COLUMNS]; ROWS],
+    full_color: bool, //All keys have the same color, a single set_full_color call is enough
+    brightness: f32,
     device: CoolerMasterDevice,
 }
 
@@ -19,38 +23,58 @@ impl Sk621
         let mut sk621 = Sk621
         {
             name: "sk621".to_string(),
-            color: Color::new(0, 0, 0),
+            colors: [[Color::new(0, 0, 0); COLUMNS]; ROWS],
+            full_color: true,
+            brightness: 1.0,
             device: CoolerMasterDevice::new(DeviceIndex::SK621),
         };
 
         //Constructing the CoolerMasterDevice will attempt to claim software control over the keyboard
         //However, it might fail - especially during startup of this service it might not be initialized yet
-        while
-            match sk621.device.set_led_control(true)
+        loop
+        {
+            let result = sk621.device.set_led_control(true);
+            trace::log(&sk621.name, "sdk set_led_control(true)", &[], &trace::outcome(&result), Vec::new);
+            if result.is_ok()
             {
-                Ok(_) => false,
-                Err(_) => true
+                break;
             }
-        {
             sleep(Duration::from_secs(1));
         }
 
         return sk621;
     }
+
+    //Positions outside the matrix are ignored, like leds past the end in set_led_color
+    fn set_key_color(&mut self, row: usize, column: usize, color: Color)
+    {
+        if row < ROWS && column < COLUMNS
+        {
+            self.colors[row][column] = color;
+            self.full_color = false;
+        }
+    }
 }
 
-//Sk621 an obviously set the rgb lighting per-key. I don't think it's a good idea to turn each key into an rgb device.
+//Every key is an led, the keyboard as a whole is a single rgb device. Leds are indexed row by row.
 impl RgbDevice for Sk621
 {
     fn set_color(&mut self, color: Color)
     {
-        self.color = color;
+        self.colors = [[color; COLUMNS]; ROWS];
+        self.full_color = true;
     }
 
     //Not supported:
+    fn set_secondary_color(&mut self, _color: Color){}
     fn set_mode(&mut self, _mode: RgbMode){}
     fn set_speed(&mut self, _speed: RgbSpeed){}
-    fn set_brightness(&mut self, _brightness: RgbBrightness){}
+
+    //The sdk has no brightness, scale the color instead
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.brightness = brightness;
+    }
 
     fn get_name(&self) -> &String
     {
@@ -58,6 +82,67 @@ impl RgbDevice for Sk621
     }
     fn display(&mut self)
     {
-        self.device.set_full_color(self.color.r, self.color.g, self.color.b);
+        let brightness = effective_brightness(self.brightness);
+
+        if self.full_color
+        {
+            let color = self.colors[0][0].scale(brightness);
+            //Fails like update_colors_from_matrix, the next frame will try again
+            let result = self.device.set_full_color(color.r, color.g, color.b);
+            trace::log(&self.name, "sdk set_full_color", &[color.r, color.g, color.b], &trace::outcome(&result), || vec![format!("all keys {}", color.to_hex())]);
+            return;
+        }
+
+        for row in 0..ROWS
+        {
+            for column in 0..COLUMNS
+            {
+                let color = self.colors[row][column].scale(brightness);
+                self.device.color_matrix.key_color[row][column].r = color.r;
+                self.device.color_matrix.key_color[row][column].g = color.g;
+                self.device.color_matrix.key_color[row][column].b = color.b;
+            }
+        }
+        //Can fail when another program took over control of the leds, the next frame will try again
+        let result = self.device.update_colors_from_matrix();
+        let matrix: Vec<u8> = self.device.color_matrix.key_color.iter().flat_map(|row| row.iter().flat_map(|key| vec![key.r, key.g, key.b])).collect();
+        trace::log(&self.name, "sdk update_colors_from_matrix", &matrix, &trace::outcome(&result), ||
+        {
+            //A row of keys per line, the matrix is rows of COLUMNS rgb values
+            return matrix.chunks(COLUMNS * 3).enumerate().map(|(row, keys)|
+            {
+                let colors: Vec<String> = keys.chunks(3).map(|rgb| Color::new(rgb[0], rgb[1], rgb[2]).to_hex()).collect();
+                return format!("row {}: {}", row, colors.join(" "));
+            }).collect();
+        });
+    }
+
+    fn get_led_count(&self) -> usize
+    {
+        return ROWS * COLUMNS;
+    }
+
+    fn set_led_color(&mut self, index: usize, color: Color)
+    {
+        self.set_key_color(index / COLUMNS, index % COLUMNS, color);
+    }
+
+    fn find_led(&self, name: &str) -> Option<usize>
+    {
+        let (row, column) = key_position(name)?;
+        return Some(row * COLUMNS + column);
     }
-}
\ No newline at end of file
+
+    fn get_led_name(&self, index: usize) -> Option<String>
+    {
+        return KEYS.iter()
+            .find(|(_, row, column)| row * COLUMNS + column == index)
+            .map(|(name, _, _)| name.to_string());
+    }
+
+    fn get_device_type(&self) -> DeviceType
+    {
+        return DeviceType::Keyboard;
+    }
+}
+
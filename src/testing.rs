@@ -0,0 +1,52 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+//Helpers shared by the tests of several modules.
+
+//A file or directory in the temp dir, named after the test and the process so parallel runs don't collide.
+//Whatever the test made at the path is removed when the test is done, passed or not.
+pub struct TempPath(PathBuf);
+
+impl TempPath
+{
+    //Nothing is created, name can have an extension like "golden.rlrc"
+    pub fn new(name: &str) -> Self
+    {
+        let temp = TempPath(std::env::temp_dir().join(format!("rustic_light_test_{}_{}", std::process::id(), name)));
+        temp.remove(); //Left over from a run that was killed
+        return temp;
+    }
+
+    //An empty directory
+    pub fn directory(name: &str) -> Self
+    {
+        let temp = TempPath::new(name);
+        fs::create_dir_all(&temp.0).unwrap();
+        return temp;
+    }
+
+    pub fn path(&self) -> &Path
+    {
+        return &self.0;
+    }
+
+    fn remove(&self)
+    {
+        //Symlinks are removed, not followed
+        match fs::symlink_metadata(&self.0)
+        {
+            Ok(metadata) if metadata.is_dir() => { let _ = fs::remove_dir_all(&self.0); }
+            Ok(_) => { let _ = fs::remove_file(&self.0); }
+            Err(_) => {}
+        }
+    }
+}
+
+impl Drop for TempPath
+{
+    fn drop(&mut self)
+    {
+        self.remove();
+    }
+}
@@ -0,0 +1,463 @@
+use serde::Deserialize;
+use crate::animation::Effect;
+use crate::color::Color;
+use crate::config;
+use crate::frame::Frame;
+use std::path::Path;
+
+
+//Keyframe animations, authored in json or toml instead of rust. For example:
+//{
+//    "repeat": "ping_pong",
+//    "interpolation": "hsv",
+//    "tracks":
+//    [
+//        {
+//            "devices": ["JRgb1", "JRgb2"],
+//            "keyframes":
+//            [
+//                { "time": 0,    "color": "red",     "easing": "ease_in_out" },
+//                { "time": 1500, "color": "#0080ff", "easing": { "cubic_bezier": [0.4, 0.0, 0.2, 1.0] } },
+//                { "time": 3000, "color": "0,255,0" }
+//            ]
+//        },
+//        { "keyframes": [ { "time": 0, "color": "white", "easing": "step" }, { "time": 500, "color": "black" } ] }
+//    ]
+//}
+//A track without devices applies to every device, later tracks win over earlier ones.
+
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Repeat
+{
+    Once,
+    Loop,
+    PingPong,
+}
+
+impl Default for Repeat
+{
+    fn default() -> Self
+    {
+        return Repeat::Loop;
+    }
+}
+
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Interpolation
+{
+    Rgb,
+    Hsv,
+}
+
+impl Default for Interpolation
+{
+    fn default() -> Self
+    {
+        return Interpolation::Rgb;
+    }
+}
+
+//How a keyframe transitions to the next keyframe
+#[allow(dead_code)]
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Easing
+{
+    Linear,
+    EaseIn,
+    EaseOut,
+    EaseInOut,
+    //Hold the color until the next keyframe
+    Step,
+    //Control points x1, y1, x2, y2, like css
+    CubicBezier([f32; 4]),
+}
+
+impl Default for Easing
+{
+    fn default() -> Self
+    {
+        return Easing::Linear;
+    }
+}
+
+impl Easing
+{
+    //Progress 0.0 - 1.0 in time to progress in color
+    pub fn apply(&self, t: f32) -> f32
+    {
+        return match self
+        {
+            Easing::Linear    => t,
+            Easing::EaseIn    => t * t * t,
+            Easing::EaseOut   => 1.0 - (1.0 - t).powi(3),
+            Easing::EaseInOut => if t < 0.5 { 4.0 * t * t * t } else { 1.0 - (-2.0 * t + 2.0).powi(3) / 2.0 },
+            Easing::Step      => 0.0,
+            Easing::CubicBezier([x1, y1, x2, y2]) => cubic_bezier(*x1, *y1, *x2, *y2, t),
+        };
+    }
+}
+
+fn bezier_component(a: f32, b: f32, s: f32) -> f32
+{
+    //Start and end points are fixed at 0 and 1
+    return 3.0 * a * s * (1.0 - s) * (1.0 - s) + 3.0 * b * s * s * (1.0 - s) + s * s * s;
+}
+
+fn cubic_bezier(x1: f32, y1: f32, x2: f32, y2: f32, x: f32) -> f32
+{
+    //x is monotonic in s for x1, x2 in 0..1, find s by bisection
+    let mut low = 0.0;
+    let mut high = 1.0;
+    let mut s = x;
+    for _ in 0..32
+    {
+        let value = bezier_component(x1.max(0.0).min(1.0), x2.max(0.0).min(1.0), s);
+        if (value - x).abs() < 0.0001
+        {
+            break;
+        }
+        if value < x
+        {
+            low = s;
+        }
+        else
+        {
+            high = s;
+        }
+        s = (low + high) / 2.0;
+    }
+    return bezier_component(y1, y2, s);
+}
+
+
+#[derive(Clone, Deserialize)]
+pub struct Keyframe
+{
+    pub time: u64, //milliseconds since the start of the timeline
+    pub color: Color,
+    #[serde(default)]
+    pub easing: Easing,
+}
+
+#[derive(Clone, Deserialize)]
+pub struct Track
+{
+    #[serde(default)]
+    pub devices: Vec<String>,
+    pub keyframes: Vec<Keyframe>,
+}
+
+impl Track
+{
+    fn applies_to(&self, device: &str) -> bool
+    {
+        return self.devices.is_empty() || self.devices.iter().any(|d| d == device);
+    }
+
+    fn color_at(&self, time: f32, interpolation: Interpolation) -> Color
+    {
+        let next = match self.keyframes.iter().position(|k| k.time as f32 > time)
+        {
+            Some(0) => return self.keyframes[0].color,
+            Some(next) => next,
+            None => return self.keyframes.last().map_or(Color::new(0, 0, 0), |k| k.color),
+        };
+
+        let from = &self.keyframes[next - 1];
+        let to = &self.keyframes[next];
+        let t = from.easing.apply((time - from.time as f32) / (to.time - from.time) as f32);
+
+        return match interpolation
+        {
+            Interpolation::Rgb => from.color.blend(to.color, t),
+            Interpolation::Hsv =>
+            {
+                let (h1, s1, v1) = from.color.to_hsv();
+                let (h2, s2, v2) = to.color.to_hsv();
+
+                //Take the short way around the color wheel
+                let mut hue_delta = h2 - h1;
+                if hue_delta > 180.0
+                {
+                    hue_delta -= 360.0;
+                }
+                else if hue_delta < -180.0
+                {
+                    hue_delta += 360.0;
+                }
+                Color::from_hsv(h1 + hue_delta * t, s1 + (s2 - s1) * t, v1 + (v2 - v1) * t)
+            }
+        };
+    }
+}
+
+
+#[derive(Clone, Deserialize)]
+pub struct Timeline
+{
+    #[serde(default)]
+    pub repeat: Repeat,
+    #[serde(default)]
+    pub interpolation: Interpolation,
+    pub tracks: Vec<Track>,
+}
+
+impl Timeline
+{
+    pub fn load(path: &Path) -> Result<Self, String>
+    {
+        let mut timeline: Timeline = config::load_file(path)?;
+        for track in timeline.tracks.iter_mut()
+        {
+            track.keyframes.sort_by_key(|k| k.time);
+            track.keyframes.dedup_by_key(|k| k.time);
+        }
+        return Ok(timeline);
+    }
+
+    //Time of the last keyframe
+    pub fn duration(&self) -> u64
+    {
+        return self.tracks.iter().filter_map(|t| t.keyframes.last()).map(|k| k.time).max().unwrap_or(0);
+    }
+
+    fn time_at(&self, millis: u128) -> f32
+    {
+        let duration = self.duration() as u128;
+        if duration == 0
+        {
+            return 0.0;
+        }
+
+        return match self.repeat
+        {
+            Repeat::Once => millis.min(duration) as f32,
+            Repeat::Loop => (millis % duration) as f32,
+            Repeat::PingPong =>
+            {
+                let position = millis % (duration * 2);
+                if position < duration { position as f32 } else { (duration * 2 - position) as f32 }
+            }
+        };
+    }
+}
+
+impl Effect for Timeline
+{
+    fn render(&mut self, frame: &mut Frame, millis: u128)
+    {
+        let time = self.time_at(millis);
+
+        for device in frame.devices.iter_mut()
+        {
+            let track = match self.tracks.iter().rev().find(|t| t.applies_to(&device.name))
+            {
+                Some(track) => track,
+                None => continue,
+            };
+
+            let color = track.color_at(time, self.interpolation);
+            for led in device.leds.iter_mut()
+            {
+                led.color = color;
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::TempPath;
+    use serde_json::json;
+
+    const EASINGS: [Easing; 5] = [Easing::Linear, Easing::EaseIn, Easing::EaseOut, Easing::EaseInOut, Easing::CubicBezier([0.25, 0.1, 0.25, 1.0])];
+
+    fn close(a: f32, b: f32) -> bool
+    {
+        return (a - b).abs() < 0.001;
+    }
+
+    fn timeline(value: serde_json::Value) -> Timeline
+    {
+        return serde_json::from_value(value).unwrap();
+    }
+
+    #[test]
+    fn easing_curves()
+    {
+        for easing in EASINGS.iter()
+        {
+            assert!(close(easing.apply(0.0), 0.0));
+            assert!(close(easing.apply(1.0), 1.0));
+        }
+
+        assert!(close(Easing::Linear.apply(0.3), 0.3));
+        assert!(close(Easing::EaseIn.apply(0.5), 0.125));
+        assert!(close(Easing::EaseOut.apply(0.5), 0.875));
+        assert!(close(Easing::EaseInOut.apply(0.5), 0.5));
+        assert!(close(Easing::EaseInOut.apply(0.25) + Easing::EaseInOut.apply(0.75), 1.0));
+        assert!(Easing::EaseInOut.apply(0.25) < 0.25);
+    }
+
+    #[test]
+    fn cubic_bezier_bisection()
+    {
+        //Control points on the diagonal are linear
+        let linear = Easing::CubicBezier([1.0 / 3.0, 1.0 / 3.0, 2.0 / 3.0, 2.0 / 3.0]);
+        //css ease-in-out is symmetric around the middle
+        let ease_in_out = Easing::CubicBezier([0.42, 0.0, 0.58, 1.0]);
+        for step in 0..=20
+        {
+            let t = step as f32 / 20.0;
+            assert!(close(linear.apply(t), t), "{}", t);
+            assert!(close(ease_in_out.apply(t) + ease_in_out.apply(1.0 - t), 1.0), "{}", t);
+        }
+        assert!(close(ease_in_out.apply(0.5), 0.5));
+
+        //x control points outside 0..1 are clamped, so the curve still runs from 0 to 1 without going back
+        let clamped = Easing::CubicBezier([-1.0, 0.0, 2.0, 1.0]);
+        let values: Vec<f32> = (0..=20).map(|step| clamped.apply(step as f32 / 20.0)).collect();
+        assert!(close(values[0], 0.0) && close(values[20], 1.0));
+        assert!(values.windows(2).all(|pair| pair[0] <= pair[1] + 0.001));
+    }
+
+    #[test]
+    fn repeat_modes()
+    {
+        let mut timeline = timeline(json!({ "tracks": [ { "keyframes": [ { "time": 0, "color": "black" }, { "time": 1000, "color": "white" } ] } ] }));
+        assert_eq!(timeline.duration(), 1000);
+
+        timeline.repeat = Repeat::Loop;
+        assert_eq!(timeline.time_at(250), 250.0);
+        assert_eq!(timeline.time_at(1250), 250.0);
+
+        timeline.repeat = Repeat::Once;
+        assert_eq!(timeline.time_at(250), 250.0);
+        assert_eq!(timeline.time_at(5000), 1000.0);
+
+        //Forwards, then backwards
+        timeline.repeat = Repeat::PingPong;
+        assert_eq!(timeline.time_at(250), 250.0);
+        assert_eq!(timeline.time_at(1000), 1000.0);
+        assert_eq!(timeline.time_at(1250), 750.0);
+        assert_eq!(timeline.time_at(1999), 1.0);
+        assert_eq!(timeline.time_at(2000), 0.0);
+        assert_eq!(timeline.time_at(2250), 250.0);
+    }
+
+    #[test]
+    fn step_holds_until_the_next_keyframe()
+    {
+        let timeline = timeline(json!({ "tracks": [ { "keyframes": [ { "time": 0, "color": "white", "easing": "step" }, { "time": 500, "color": "black" } ] } ] }));
+        let track = &timeline.tracks[0];
+        assert_eq!(track.color_at(0.0, Interpolation::Rgb), Color::new(255, 255, 255));
+        assert_eq!(track.color_at(499.0, Interpolation::Rgb), Color::new(255, 255, 255));
+        assert_eq!(track.color_at(500.0, Interpolation::Rgb), Color::new(0, 0, 0));
+    }
+
+    #[test]
+    fn hsv_interpolation()
+    {
+        let red_to_blue = timeline(json!({ "tracks": [ { "keyframes": [ { "time": 0, "color": "red" }, { "time": 1000, "color": "blue" } ] } ] }));
+        let track = &red_to_blue.tracks[0];
+        assert_eq!(track.color_at(500.0, Interpolation::Rgb), Color::new(128, 0, 128));
+        //The short way around the wheel from 0 to 240 degrees goes through magenta at 300
+        assert_eq!(track.color_at(500.0, Interpolation::Hsv), Color::new(255, 0, 255));
+        assert_eq!(track.color_at(250.0, Interpolation::Hsv), Color::from_hsv(330.0, 1.0, 1.0));
+
+        let red_to_green = timeline(json!({ "tracks": [ { "keyframes": [ { "time": 0, "color": "red" }, { "time": 1000, "color": "green" } ] } ] }));
+        assert_eq!(red_to_green.tracks[0].color_at(500.0, Interpolation::Hsv), Color::new(255, 255, 0));
+    }
+
+    fn load(name: &str, text: &str) -> Timeline
+    {
+        let file = TempPath::new(name);
+        std::fs::write(file.path(), text).unwrap();
+        return Timeline::load(file.path()).unwrap();
+    }
+
+    fn check_loaded(timeline: &Timeline)
+    {
+        assert!(matches!(timeline.repeat, Repeat::PingPong));
+        assert!(matches!(timeline.interpolation, Interpolation::Hsv));
+        assert_eq!(timeline.tracks.len(), 2);
+        assert_eq!(timeline.tracks[0].devices, vec!["JRgb1".to_string()]);
+
+        //Sorted by time, the second keyframe at the same time is dropped
+        let keyframes = &timeline.tracks[0].keyframes;
+        assert_eq!(keyframes.iter().map(|k| k.time).collect::<Vec<u64>>(), vec![0, 1500, 3000]);
+        assert_eq!(keyframes[0].color, Color::new(255, 0, 0));
+        assert!(matches!(keyframes[0].easing, Easing::EaseInOut));
+        assert!(matches!(keyframes[1].easing, Easing::CubicBezier([x1, _, _, y2]) if x1 == 0.4 && y2 == 1.0));
+        assert_eq!(keyframes[2].color, Color::new(0, 255, 0));
+        assert_eq!(timeline.duration(), 3000);
+
+        assert!(timeline.tracks[1].devices.is_empty());
+        assert!(matches!(timeline.tracks[1].keyframes[0].easing, Easing::Step));
+    }
+
+    #[test]
+    fn loads_json()
+    {
+        let timeline = load("timeline.json", r##"
+        {
+            "repeat": "ping_pong",
+            "interpolation": "hsv",
+            "tracks":
+            [
+                {
+                    "devices": ["JRgb1"],
+                    "keyframes":
+                    [
+                        { "time": 3000, "color": "0,255,0" },
+                        { "time": 0,    "color": "red",     "easing": "ease_in_out" },
+                        { "time": 1500, "color": "#0080ff", "easing": { "cubic_bezier": [0.4, 0.0, 0.2, 1.0] } },
+                        { "time": 3000, "color": "white" }
+                    ]
+                },
+                { "keyframes": [ { "time": 0, "color": "white", "easing": "step" }, { "time": 500, "color": "black" } ] }
+            ]
+        }"##);
+        check_loaded(&timeline);
+    }
+
+    #[test]
+    fn loads_toml()
+    {
+        let timeline = load("timeline.toml", r##"
+            repeat = "ping_pong"
+            interpolation = "hsv"
+
+            [[tracks]]
+            devices = ["JRgb1"]
+            keyframes = [
+                { time = 3000, color = "0,255,0" },
+                { time = 0, color = "red", easing = "ease_in_out" },
+                { time = 1500, color = "#0080ff", easing = { cubic_bezier = [0.4, 0.0, 0.2, 1.0] } },
+                { time = 3000, color = "white" },
+            ]
+
+            [[tracks]]
+            keyframes = [ { time = 0, color = "white", easing = "step" }, { time = 500, color = "black" } ]
+        "##);
+        check_loaded(&timeline);
+    }
+
+    #[test]
+    fn rejects_invalid_files()
+    {
+        let missing = TempPath::new("missing.json");
+        assert!(Timeline::load(missing.path()).is_err());
+
+        let broken = TempPath::new("broken.json");
+        std::fs::write(broken.path(), r#"{ "tracks": [ { "keyframes": [ { "time": 0 } ] } ] }"#).unwrap();
+        assert!(Timeline::load(broken.path()).err().unwrap().contains("color"));
+    }
+}
@@ -0,0 +1,306 @@
+use serde::Deserialize;
+use crate::config;
+use std::fmt::Display;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+//Everything sent to the hardware, for when the leds stop responding and nobody knows what was written last.
+//Off unless there is a trace.json next to the exe or --trace is given:
+//{ "file": "trace.log", "max_size": 10000000, "files": 3 }
+//When the file reaches max_size it becomes trace.log.1, the older ones shift up to trace.log.<files>.
+//Every entry is a hex dump of the bytes, followed by what they mean:
+//    1792389027.262 z390 hid feature report, 162 bytes, ok
+//        0000  52 01 ff 00 00 2b 00 00  00 00 00 01 00 00 00 2b  R....+.........+
+//        ...
+//        JRgb1: Static #ff0000, Low, Level100, color2 #000000, flags 0x00
+
+const BYTES_PER_LINE: usize = 16;
+
+
+#[derive(Deserialize)]
+struct TraceConfig
+{
+    #[serde(default = "default_file")]
+    file: String,
+    #[serde(default = "default_max_size")]
+    max_size: u64,
+    #[serde(default = "default_files")]
+    files: u32,
+}
+
+fn default_file() -> String
+{
+    return "trace.log".to_string();
+}
+
+fn default_max_size() -> u64
+{
+    return 10_000_000;
+}
+
+fn default_files() -> u32
+{
+    return 3;
+}
+
+impl Default for TraceConfig
+{
+    fn default() -> Self
+    {
+        TraceConfig { file: default_file(), max_size: default_max_size(), files: default_files() }
+    }
+}
+
+
+struct TraceLog
+{
+    path: PathBuf,
+    max_size: u64,
+    files: u32,
+    file: Option<File>,
+    size: u64,
+}
+
+static TRACE: Mutex<Option<TraceLog>> = Mutex::new(None);
+
+//With the defaults when always and there is no trace.json. Relative paths are next to the exe, like the config files.
+pub fn start(always: bool)
+{
+    let trace_config: TraceConfig = match config::load("trace.json")
+    {
+        Some(trace_config) => trace_config,
+        None if always => TraceConfig::default(),
+        None => return,
+    };
+
+    let path = PathBuf::from(&trace_config.file);
+    let path = if path.is_absolute() { path } else { config::path(&trace_config.file) };
+    println!("Tracing everything sent to the devices to {}", path.display());
+
+    *TRACE.lock().unwrap() = Some(TraceLog
+    {
+        path,
+        max_size: trace_config.max_size,
+        files: trace_config.files,
+        file: None,
+        size: 0,
+    });
+}
+
+pub fn outcome<T, E: Display>(result: &Result<T, E>) -> String
+{
+    return match result
+    {
+        Ok(_) => "ok".to_string(),
+        Err(e) => format!("failed: {}", e),
+    };
+}
+
+//Decoding only happens when tracing, so callers can do expensive things in it
+pub fn log<F: FnOnce() -> Vec<String>>(device: &str, operation: &str, bytes: &[u8], outcome: &str, decode: F)
+{
+    let mut trace = TRACE.lock().unwrap();
+    let trace = match trace.as_mut()
+    {
+        Some(trace) => trace,
+        None => return,
+    };
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let mut entry = format!("{}.{:03} {} {}, {} bytes, {}\n", now.as_secs(), now.subsec_millis(), device, operation, bytes.len(), outcome);
+    entry.push_str(&hex_dump(bytes));
+    for line in decode().iter()
+    {
+        entry.push_str(&format!("    {}\n", line));
+    }
+
+    trace.write(&entry);
+}
+
+pub fn hex_dump(bytes: &[u8]) -> String
+{
+    let mut dump = String::new();
+    for (index, line) in bytes.chunks(BYTES_PER_LINE).enumerate()
+    {
+        let hex: Vec<String> = line.iter().map(|b| format!("{:02x}", b)).collect();
+        let (first, second) = hex.split_at(hex.len().min(BYTES_PER_LINE / 2));
+        let text: String = line.iter().map(|b| if b.is_ascii_graphic() { *b as char } else { '.' }).collect();
+        dump.push_str(&format!("    {:04x}  {:<23}  {:<23}  {}\n", index * BYTES_PER_LINE, first.join(" "), second.join(" "), text));
+    }
+    return dump;
+}
+
+impl TraceLog
+{
+    fn write(&mut self, entry: &str)
+    {
+        if self.file.is_none()
+        {
+            self.open();
+        }
+        if self.size > 0 && self.size + entry.len() as u64 > self.max_size
+        {
+            self.rotate();
+            self.open();
+        }
+
+        //Tracing is for debugging, a file that can't be written never stops the lights
+        if let Some(file) = self.file.as_mut()
+        {
+            if file.write_all(entry.as_bytes()).is_ok()
+            {
+                self.size += entry.len() as u64;
+            }
+        }
+    }
+
+    fn open(&mut self)
+    {
+        if let Ok(file) = OpenOptions::new().create(true).append(true).open(&self.path)
+        {
+            self.size = file.metadata().map(|m| m.len()).unwrap_or(0);
+            self.file = Some(file);
+        }
+    }
+
+    //trace.log -> trace.log.1 -> ... -> trace.log.<files>, the oldest is dropped
+    fn rotate(&mut self)
+    {
+        self.file = None;
+        let numbered = |number: u32| PathBuf::from(format!("{}.{}", self.path.display(), number));
+
+        if self.files == 0
+        {
+            let _ = fs::remove_file(&self.path);
+            return;
+        }
+        let _ = fs::remove_file(numbered(self.files));
+        for number in (1..self.files).rev()
+        {
+            let _ = fs::rename(numbered(number), numbered(number + 1));
+        }
+        let _ = fs::rename(&self.path, numbered(1));
+    }
+}
+
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use crate::testing::TempPath;
+
+    fn trace_log(directory: &TempPath, max_size: u64, files: u32) -> TraceLog
+    {
+        return TraceLog { path: directory.path().join("trace.log"), max_size, files, file: None, size: 0 };
+    }
+
+    //Entries of 10 bytes, "aaaaaaaaa\n"
+    fn entry(letter: char) -> String
+    {
+        return format!("{}\n", letter.to_string().repeat(9));
+    }
+
+    fn read(directory: &TempPath, name: &str) -> Option<String>
+    {
+        return fs::read_to_string(directory.path().join(name)).ok();
+    }
+
+    #[test]
+    fn rotates_when_the_next_entry_would_pass_the_cap()
+    {
+        let directory = TempPath::directory("trace_cap");
+        let mut log = trace_log(&directory, 25, 3);
+        log.write(&entry('a'));
+        log.write(&entry('b'));
+        assert_eq!(read(&directory, "trace.log.1"), None);
+
+        log.write(&entry('c'));
+        assert_eq!(read(&directory, "trace.log.1").unwrap(), entry('a') + &entry('b'));
+        assert_eq!(read(&directory, "trace.log").unwrap(), entry('c'));
+    }
+
+    #[test]
+    fn an_entry_larger_than_the_cap_goes_into_an_empty_file()
+    {
+        let directory = TempPath::directory("trace_large");
+        let mut log = trace_log(&directory, 5, 3);
+        log.write(&entry('a'));
+        assert_eq!(read(&directory, "trace.log").unwrap(), entry('a'));
+        assert_eq!(read(&directory, "trace.log.1"), None);
+
+        log.write(&entry('b'));
+        assert_eq!(read(&directory, "trace.log").unwrap(), entry('b'));
+        assert_eq!(read(&directory, "trace.log.1").unwrap(), entry('a'));
+    }
+
+    #[test]
+    fn an_existing_file_counts_towards_the_cap()
+    {
+        let directory = TempPath::directory("trace_existing");
+        fs::write(directory.path().join("trace.log"), entry('a') + &entry('b')).unwrap();
+        let mut log = trace_log(&directory, 25, 3);
+        log.write(&entry('c'));
+        assert_eq!(read(&directory, "trace.log.1").unwrap(), entry('a') + &entry('b'));
+        assert_eq!(read(&directory, "trace.log").unwrap(), entry('c'));
+    }
+
+    #[test]
+    fn numbered_files_shift_and_the_oldest_is_dropped()
+    {
+        let directory = TempPath::directory("trace_shift");
+        let mut log = trace_log(&directory, 10, 3);
+        for letter in "abcde".chars()
+        {
+            log.write(&entry(letter));
+        }
+        assert_eq!(read(&directory, "trace.log").unwrap(), entry('e'));
+        assert_eq!(read(&directory, "trace.log.1").unwrap(), entry('d'));
+        assert_eq!(read(&directory, "trace.log.2").unwrap(), entry('c'));
+        assert_eq!(read(&directory, "trace.log.3").unwrap(), entry('b'));
+        assert_eq!(read(&directory, "trace.log.4"), None);
+    }
+
+    #[test]
+    fn no_numbered_files_starts_over()
+    {
+        let directory = TempPath::directory("trace_no_files");
+        let mut log = trace_log(&directory, 10, 0);
+        log.write(&entry('a'));
+        log.write(&entry('b'));
+        assert_eq!(read(&directory, "trace.log").unwrap(), entry('b'));
+        assert_eq!(fs::read_dir(directory.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn hex_dump_of_nothing_is_empty()
+    {
+        assert_eq!(hex_dump(&[]), "");
+    }
+
+    #[test]
+    fn hex_dump_pads_a_short_last_line()
+    {
+        let dump = hex_dump(b"Rustic Light\x00\x01\x02\xff z390");
+        assert_eq!(dump, concat!(
+            "    0000  52 75 73 74 69 63 20 4c  69 67 68 74 00 01 02 ff  Rustic.Light....\n",
+            "    0010  20 7a 33 39 30                                    .z390\n"));
+    }
+
+    #[test]
+    fn hex_dump_pads_a_line_shorter_than_half()
+    {
+        assert_eq!(hex_dump(&[0x52, 0x01, 0x02, 0x41, 0xff]), "    0000  52 01 02 41 ff                                    R..A.\n");
+    }
+
+    #[test]
+    fn hex_dump_of_a_full_line_has_no_second_line()
+    {
+        let bytes: Vec<u8> = (0x30..0x40).collect();
+        assert_eq!(hex_dump(&bytes), "    0000  30 31 32 33 34 35 36 37  38 39 3a 3b 3c 3d 3e 3f  0123456789:;<=>?\n");
+    }
+}
@@ -1,8 +1,12 @@
 extern crate hidapi;
 use hidapi::{HidDevice, HidApi};
-use crate::color::{Color, RgbMode, RgbSpeed, RgbDevice, RgbBrightness};
+use crate::color::{Color, RgbColorFlags, RgbMode, RgbSpeed, RgbDevice, DeviceType, effective_brightness};
+use crate::config;
+use crate::trace;
+use crate::mystic_light::{self, MysticLightReport, Zone, ZoneData, MsiMode, MsiSpeed, MsiBrightness, REPORT_ID, REPORT_SIZE, COLOR_FLAG_RAINBOW, COLOR_FLAG_RANDOM};
 use std::cell::RefCell;
 use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 
 pub fn get_z390_rgb_devices() -> Vec<Box<dyn RgbDevice>>
@@ -12,60 +16,136 @@ pub fn get_z390_rgb_devices() -> Vec<Box<dyn RgbDevice>>
     //Shared mutable state
     let data_packet = Rc::new(RefCell::new(Z390::new()));
 
-    result.push(Box::new(Z390RgbDevice::new("JRgb1"             .to_string(), ZoneIndex::JRgb1             , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("JRainbow1"         .to_string(), ZoneIndex::JRainbow1         , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("JCorsair1"         .to_string(), ZoneIndex::JCorsair1         , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("JCorsairOuterll120".to_string(), ZoneIndex::JCorsairOuterll120, data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed"        .to_string(), ZoneIndex::OnBoardLed        , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed1"       .to_string(), ZoneIndex::OnBoardLed1       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed2"       .to_string(), ZoneIndex::OnBoardLed2       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed3"       .to_string(), ZoneIndex::OnBoardLed3       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed4"       .to_string(), ZoneIndex::OnBoardLed4       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed5"       .to_string(), ZoneIndex::OnBoardLed5       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed6"       .to_string(), ZoneIndex::OnBoardLed6       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed7"       .to_string(), ZoneIndex::OnBoardLed7       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed8"       .to_string(), ZoneIndex::OnBoardLed8       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed9"       .to_string(), ZoneIndex::OnBoardLed9       , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("OnBoardLed10"      .to_string(), ZoneIndex::OnBoardLed10      , data_packet.clone())));
-    result.push(Box::new(Z390RgbDevice::new("JRgb2"             .to_string(), ZoneIndex::JRgb2             , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("JRgb1"             .to_string(), Zone::JRgb1             , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("JRainbow1"         .to_string(), Zone::JRainbow1         , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("JCorsair1"         .to_string(), Zone::JCorsair1         , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("JCorsairOuterll120".to_string(), Zone::JCorsairOuterll120, data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed"        .to_string(), Zone::OnBoardLed        , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed1"       .to_string(), Zone::OnBoardLed1       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed2"       .to_string(), Zone::OnBoardLed2       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed3"       .to_string(), Zone::OnBoardLed3       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed4"       .to_string(), Zone::OnBoardLed4       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed5"       .to_string(), Zone::OnBoardLed5       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed6"       .to_string(), Zone::OnBoardLed6       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed7"       .to_string(), Zone::OnBoardLed7       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed8"       .to_string(), Zone::OnBoardLed8       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed9"       .to_string(), Zone::OnBoardLed9       , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("OnBoardLed10"      .to_string(), Zone::OnBoardLed10      , data_packet.clone())));
+    result.push(Box::new(Z390RgbDevice::new("JRgb2"             .to_string(), Zone::JRgb2             , data_packet.clone())));
 
 
 
     return result;
 }
 
+///Read what the controller shows right now and write it back with the save flag set.
+///The controller persists this to its flash, so it is shown at POST and whenever this service isn't running.
+pub fn save_z390_boot_profile() -> Result<(), SaveError>
+{
+    //Before opening the device, so a rate limited save doesn't need the z390
+    check_save_interval(SystemTime::now())?;
+
+    let mut z390 = Z390::new();
+    z390.report = z390.read_from_device()?;
+    return z390.save_to_device();
+}
+
+
 
+//================================================================================================================================================================================================
+//Saving to flash
 
+//Every save is a write to the controller's flash, which only survives a limited amount of write cycles.
+//Saves are therefore rate limited, the timestamp of the last save is kept on disk so separate runs can't bypass the limit.
+const SAVE_DATA_MIN_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const SAVE_DATA_TIMESTAMP_FILE: &str = "z390_last_save";
 
+pub enum SaveError
+{
+    RateLimited(Duration), //Time remaining until the next save is allowed
+    Read(String),          //The current zone configuration couldn't be read, nothing was written
+    Write(String),         //The controller refused the report, nothing was saved
+}
+
+impl std::fmt::Display for SaveError
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result
+    {
+        match self
+        {
+            SaveError::RateLimited(remaining) => write!(f, "the z390 was saved to flash recently, try again in {} minutes", remaining.as_secs() / 60 + 1),
+            SaveError::Read(e)                => write!(f, "unable to read the current z390 configuration: {}", e),
+            SaveError::Write(e)               => write!(f, "unable to write to the z390: {}", e),
+        }
+    }
+}
+
+fn check_save_interval(now: SystemTime) -> Result<(), SaveError>
+{
+    if let Some(last_save) = read_last_save()
+    {
+        //A last save in the future means the clock was changed, don't lock the user out forever
+        if let Ok(elapsed) = now.duration_since(last_save)
+        {
+            if elapsed < SAVE_DATA_MIN_INTERVAL
+            {
+                return Err(SaveError::RateLimited(SAVE_DATA_MIN_INTERVAL - elapsed));
+            }
+        }
+    }
+    return Ok(());
+}
+
+fn read_last_save() -> Option<SystemTime>
+{
+    let seconds = std::fs::read_to_string(config::path(SAVE_DATA_TIMESTAMP_FILE)).ok()?.trim().parse::<u64>().ok()?;
+    return Some(UNIX_EPOCH + Duration::from_secs(seconds));
+}
+
+fn write_last_save(time: SystemTime)
+{
+    let seconds = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    if let Err(e) = std::fs::write(config::path(SAVE_DATA_TIMESTAMP_FILE), seconds.to_string())
+    {
+        println!("Unable to store z390 save timestamp: {}", e);
+    }
+}
+
+
+
+//================================================================================================================================================================================================
+//Z390 RgbDevice
 
 struct Z390RgbDevice
 {
     name                : String,
-    zone_index          : ZoneIndex,
-    z390: Rc<RefCell<Z390>>,
+    zone                : Zone,
+    z390                : Rc<RefCell<Z390>>,
 
     data_writen         : bool,
     color               : Color,
+    color2              : Option<Color>, //None: follow the primary color
     mode                : RgbMode,
     speed               : RgbSpeed,
-    brightness          : RgbBrightness,
+    brightness          : f32,
 }
 
 impl Z390RgbDevice
 {
-    pub fn new(name: String, zone_index: ZoneIndex, data_packet: Rc<RefCell<Z390>>) -> Self
+    pub fn new(name: String, zone: Zone, data_packet: Rc<RefCell<Z390>>) -> Self
     {
         Z390RgbDevice
         {
             name,
-            zone_index,
+            zone,
             z390: data_packet,
 
             data_writen: false,
             color: Color::new(0,0,0),
+            color2: None,
             mode: RgbMode::Static,
             speed: RgbSpeed::Slow,
-            brightness: RgbBrightness::Level100,
+            brightness: 1.0,
         }
     }
 }
@@ -82,84 +162,89 @@ impl RgbDevice for Z390RgbDevice
         {
             Ok(mut z390) =>
             {
-                let zone_data = z390.borrow_zone_data_indexed(self.zone_index);
+                let zone_data = z390.report.zone_mut(self.zone);
                 zone_data.color  = self.color;
-                zone_data.color2 = self.color;
+                zone_data.color2 = self.color2.unwrap_or(self.color);
             }
             _ => {}
         }
     }
 
-    fn set_mode(&mut self, mode: RgbMode)
+    fn set_secondary_color(&mut self, color: Color)
     {
         self.data_writen = true;
-        self.mode = mode;
+        self.color2 = Some(color);
 
         match self.z390.try_borrow_mut()
         {
             Ok(mut z390) =>
-                {
-                    let zone_data = z390.borrow_zone_data_indexed(self.zone_index);
+            {
+                let zone_data = z390.report.zone_mut(self.zone);
+                zone_data.color2 = color;
+            }
+            _ => {}
+        }
+    }
 
-                    let msi_mode = match self.mode
-                    {
-                        RgbMode::Static => MsiMode::Static,
-                    };
-                    zone_data.effect = msi_mode as u8;
+    fn set_color_flags(&mut self, flags: RgbColorFlags)
+    {
+        self.data_writen = true;
+
+        match self.z390.try_borrow_mut()
+        {
+            Ok(mut z390) =>
+            {
+                let zone_data = z390.report.zone_mut(self.zone);
+                let mut color_flags = zone_data.color_flags & !(COLOR_FLAG_RAINBOW | COLOR_FLAG_RANDOM);
+                if flags.rainbow
+                {
+                    color_flags |= COLOR_FLAG_RAINBOW;
+                }
+                if flags.random
+                {
+                    color_flags |= COLOR_FLAG_RANDOM;
                 }
+                zone_data.color_flags = color_flags;
+            }
             _ => {}
         }
     }
 
-    fn set_speed(&mut self, speed: RgbSpeed)
+    fn set_mode(&mut self, mode: RgbMode)
     {
         self.data_writen = true;
-        self.speed = speed;
-
+        self.mode = mode;
 
         match self.z390.try_borrow_mut()
         {
             Ok(mut z390) =>
                 {
-                    let zone_data = z390.borrow_zone_data_indexed(self.zone_index);
-
-                    let msi_speed = match self.speed
-                    {
-                        RgbSpeed::Slow   => MsiSpeed::Low,
-                        RgbSpeed::Medium => MsiSpeed::Medium,
-                        RgbSpeed::Fast   => MsiSpeed::High,
-                    };
+                    let zone_data = z390.report.zone_mut(self.zone);
 
-                    let msi_brightness = match self.brightness
+                    let msi_mode = match self.mode
                     {
-                        RgbBrightness::Level10  => MsiBrightness::Level10 ,
-                        RgbBrightness::Level20  => MsiBrightness::Level20 ,
-                        RgbBrightness::Level30  => MsiBrightness::Level30 ,
-                        RgbBrightness::Level40  => MsiBrightness::Level40 ,
-                        RgbBrightness::Level50  => MsiBrightness::Level50 ,
-                        RgbBrightness::Level60  => MsiBrightness::Level60 ,
-                        RgbBrightness::Level70  => MsiBrightness::Level70 ,
-                        RgbBrightness::Level80  => MsiBrightness::Level80 ,
-                        RgbBrightness::Level90  => MsiBrightness::Level90 ,
-                        RgbBrightness::Level100 => MsiBrightness::Level100,
+                        RgbMode::Static         => MsiMode::Static,
+                        RgbMode::Breathing      => MsiMode::Breathing,
+                        RgbMode::Flashing       => MsiMode::Flashing,
+                        RgbMode::DoubleFlashing => MsiMode::DoubleFlashing,
                     };
-
-                    zone_data.speed_and_brightness_flags =  ((msi_brightness as u8) << 2 ) | ((msi_speed as u8) & 0x03);
+                    zone_data.effect = msi_mode;
                 }
             _ => {}
         }
     }
 
-    fn set_brightness(&mut self, brightness: RgbBrightness)
+    fn set_speed(&mut self, speed: RgbSpeed)
     {
         self.data_writen = true;
-        self.brightness = brightness;
+        self.speed = speed;
+
 
         match self.z390.try_borrow_mut()
         {
             Ok(mut z390) =>
                 {
-                    let zone_data = z390.borrow_zone_data_indexed(self.zone_index);
+                    let zone_data = z390.report.zone_mut(self.zone);
 
                     let msi_speed = match self.speed
                     {
@@ -168,44 +253,53 @@ impl RgbDevice for Z390RgbDevice
                         RgbSpeed::Fast   => MsiSpeed::High,
                     };
 
-                    let msi_brightness = match self.brightness
-                    {
-                        RgbBrightness::Level10  => MsiBrightness::Level10 ,
-                        RgbBrightness::Level20  => MsiBrightness::Level20 ,
-                        RgbBrightness::Level30  => MsiBrightness::Level30 ,
-                        RgbBrightness::Level40  => MsiBrightness::Level40 ,
-                        RgbBrightness::Level50  => MsiBrightness::Level50 ,
-                        RgbBrightness::Level60  => MsiBrightness::Level60 ,
-                        RgbBrightness::Level70  => MsiBrightness::Level70 ,
-                        RgbBrightness::Level80  => MsiBrightness::Level80 ,
-                        RgbBrightness::Level90  => MsiBrightness::Level90 ,
-                        RgbBrightness::Level100 => MsiBrightness::Level100,
-                    };
-
-                    zone_data.speed_and_brightness_flags =  ((msi_brightness as u8) << 2 ) | ((msi_speed as u8) & 0x03);
+                    zone_data.speed = msi_speed;
                 }
             _ => {}
         }
     }
 
+    //Quantized to the 10 hardware steps when displaying, since the master brightness can change in between
+    fn set_brightness(&mut self, brightness: f32)
+    {
+        self.data_writen = true;
+        self.brightness = brightness;
+    }
+
     fn get_name(&self) -> &String
     {
         return &self.name;
     }
 
+    fn get_device_type(&self) -> DeviceType
+    {
+        return DeviceType::Motherboard;
+    }
+
     fn display(&mut self)
     {
-        if self.data_writen
+        match self.z390.try_borrow_mut()
         {
-            match self.z390.try_borrow_mut()
+            Ok(mut z390) =>
             {
-                Ok(z390) =>
+                let msi_brightness = MsiBrightness::from_fraction(effective_brightness(self.brightness));
+                let zone_data = z390.report.zone_mut(self.zone);
+                if zone_data.brightness != msi_brightness
                 {
-                    z390.write_to_device();
+                    zone_data.brightness = msi_brightness;
+                    self.data_writen = true;
                 }
-                Err(_) => {}
-            }
 
+                //A failed write is tried again with the next change
+                if self.data_writen
+                {
+                    if let Err(e) = z390.write_to_device()
+                    {
+                        println!("{}: {}", self.name, e);
+                    }
+                }
+            }
+            Err(_) => {}
         }
         self.data_writen = false;
     }
@@ -264,113 +358,13 @@ fn find_hid_device(vendor_id: u16, product_id: u16) -> HidDevice
 
 
 #[allow(dead_code)]
-#[repr(u8)]
-enum MsiZone
-{
-    None                = 0,
-    JRgb1               = 1,
-    JRgb2               = 2,
-    JPipe1              = 3,
-    JPipe2              = 4,
-    JRainbow1           = 5,
-    JRainbow2           = 6,
-    JCorsair            = 7,
-    JCorsairOuterll120  = 8,
-    OnBoardLed0         = 9,
-    OnBoardLed1         = 10,
-    OnBoardLed2         = 11,
-    OnBoardLed3         = 12,
-    OnBoardLed4         = 13,
-    OnBoardLed5         = 14,
-    OnBoardLed6         = 15,
-    OnBoardLed7         = 16,
-    OnBoardLed8         = 17,
-    OnBoardLed9         = 18,
-    OnBoardLed10        = 19
-}
-
-
-#[repr(C)]
-#[derive(Clone, Copy)]
-struct ZoneData
-{
-    effect: u8,
-    color: Color,
-    speed_and_brightness_flags: u8,
-    color2: Color,
-    color_flags: u8,
-    padding    : u8,
-}
-
-impl ZoneData
-{
-    pub fn new() -> Self
-    {
-        ZoneData
-        {
-            effect: MsiMode::Static as u8,
-            color: Color::new(0,0,0),
-            speed_and_brightness_flags: ((MsiBrightness::Level100 as u8) << 2 ) | ((MsiSpeed::Low as u8) & 0x03),
-            color2: Color::new(0,0,0),
-            color_flags: 0,
-            padding: 0,
-        }
-    }
-}
-
-
-
-#[allow(dead_code)]
-#[repr(C)]
 struct Z390
 {
-    report_id            : u8,                      // Report ID
-    j_rgb_1              : ZoneData,                // 1
-    j_rainbow_1          : ZoneData,                // 11
-    j_corsair_1          : ZoneData,                // 21
-    j_corsair_outerll120 : ZoneData,                // 31
-    on_board_led         : ZoneData,                // 41
-    on_board_led_1       : ZoneData,                // 51
-    on_board_led_2       : ZoneData,                // 61
-    on_board_led_3       : ZoneData,                // 71
-    on_board_led_4       : ZoneData,                // 81
-    on_board_led_5       : ZoneData,                // 91
-    on_board_led_6       : ZoneData,                // 101
-    on_board_led_7       : ZoneData,                // 111
-    on_board_led_8       : ZoneData,                // 121
-    on_board_led_9       : ZoneData,                // 131
-    on_board_led_10      : ZoneData,                // 141
-    j_rgb_2              : ZoneData,                // 151
-    save_data            : u8,                      // 161
-
-    device               : HidDevice,
-}
-
-
-#[derive(Clone, Copy)]
-enum ZoneIndex
-{
-    JRgb1               = 1,
-    JRainbow1           = 11,
-    JCorsair1           = 21,
-    JCorsairOuterll120  = 31,
-    OnBoardLed          = 41,
-    OnBoardLed1         = 51,
-    OnBoardLed2         = 61,
-    OnBoardLed3         = 71,
-    OnBoardLed4         = 81,
-    OnBoardLed5         = 91,
-    OnBoardLed6         = 101,
-    OnBoardLed7         = 111,
-    OnBoardLed8         = 121,
-    OnBoardLed9         = 131,
-    OnBoardLed10        = 141,
-    JRgb2               = 151,
+    report  : MysticLightReport,
+    device  : HidDevice,
 }
 
 
-
-
 impl Z390
 {
     pub fn new() -> Self
@@ -379,143 +373,55 @@ impl Z390
 
         Z390
         {
-            report_id           : 0x52,
-            j_rgb_1             : ZoneData::new(),
-            j_rainbow_1         : ZoneData::new(),
-            j_corsair_1         : ZoneData::new(),
-            j_corsair_outerll120: ZoneData::new(),
-            on_board_led        : ZoneData::new(),
-            on_board_led_1      : ZoneData::new(),
-            on_board_led_2      : ZoneData::new(),
-            on_board_led_3      : ZoneData::new(),
-            on_board_led_4      : ZoneData::new(),
-            on_board_led_5      : ZoneData::new(),
-            on_board_led_6      : ZoneData::new(),
-            on_board_led_7      : ZoneData::new(),
-            on_board_led_8      : ZoneData::new(),
-            on_board_led_9      : ZoneData::new(),
-            on_board_led_10     : ZoneData::new(),
-            j_rgb_2             : ZoneData::new(),
-            save_data           : 0,
-
+            report: MysticLightReport::new(),
             device,
         }
     }
 
-    pub fn borrow_zone_data_indexed(&mut self, zone_index: ZoneIndex) -> &mut ZoneData
-    {
-        match zone_index
-        {
-            ZoneIndex::JRgb1                => &mut self.j_rgb_1             ,
-            ZoneIndex::JRainbow1            => &mut self.j_rainbow_1         ,
-            ZoneIndex::JCorsair1            => &mut self.j_corsair_1         ,
-            ZoneIndex::JCorsairOuterll120   => &mut self.j_corsair_outerll120,
-            ZoneIndex::OnBoardLed           => &mut self.on_board_led        ,
-            ZoneIndex::OnBoardLed1          => &mut self.on_board_led_1      ,
-            ZoneIndex::OnBoardLed2          => &mut self.on_board_led_2      ,
-            ZoneIndex::OnBoardLed3          => &mut self.on_board_led_3      ,
-            ZoneIndex::OnBoardLed4          => &mut self.on_board_led_4      ,
-            ZoneIndex::OnBoardLed5          => &mut self.on_board_led_5      ,
-            ZoneIndex::OnBoardLed6          => &mut self.on_board_led_6      ,
-            ZoneIndex::OnBoardLed7          => &mut self.on_board_led_7      ,
-            ZoneIndex::OnBoardLed8          => &mut self.on_board_led_8      ,
-            ZoneIndex::OnBoardLed9          => &mut self.on_board_led_9      ,
-            ZoneIndex::OnBoardLed10         => &mut self.on_board_led_10     ,
-            ZoneIndex::JRgb2                => &mut self.j_rgb_2             ,
-        }
-    }
-
     #[allow(dead_code)]
-    pub fn write_zone_data_indexed(&mut self, zone_index: ZoneIndex, zone_data: ZoneData)
+    pub fn write_zone_data_all(&mut self, zone_data: ZoneData)
     {
-        match zone_index
+        for zone in Zone::ALL.iter()
         {
-            ZoneIndex::JRgb1                => self.j_rgb_1               = zone_data,
-            ZoneIndex::JRainbow1            => self.j_rainbow_1           = zone_data,
-            ZoneIndex::JCorsair1            => self.j_corsair_1           = zone_data,
-            ZoneIndex::JCorsairOuterll120   => self.j_corsair_outerll120  = zone_data,
-            ZoneIndex::OnBoardLed           => self.on_board_led          = zone_data,
-            ZoneIndex::OnBoardLed1          => self.on_board_led_1        = zone_data,
-            ZoneIndex::OnBoardLed2          => self.on_board_led_2        = zone_data,
-            ZoneIndex::OnBoardLed3          => self.on_board_led_3        = zone_data,
-            ZoneIndex::OnBoardLed4          => self.on_board_led_4        = zone_data,
-            ZoneIndex::OnBoardLed5          => self.on_board_led_5        = zone_data,
-            ZoneIndex::OnBoardLed6          => self.on_board_led_6        = zone_data,
-            ZoneIndex::OnBoardLed7          => self.on_board_led_7        = zone_data,
-            ZoneIndex::OnBoardLed8          => self.on_board_led_8        = zone_data,
-            ZoneIndex::OnBoardLed9          => self.on_board_led_9        = zone_data,
-            ZoneIndex::OnBoardLed10         => self.on_board_led_10       = zone_data,
-            ZoneIndex::JRgb2                => self.j_rgb_2               = zone_data,
+            *self.report.zone_mut(*zone) = zone_data;
         }
     }
 
-    #[allow(dead_code)]
-    pub fn write_zone_data_all(&mut self, zone_data: ZoneData)
+    pub fn write_to_device(&self) -> Result<(), SaveError>
     {
-        self.j_rgb_1              = zone_data;
-        self.j_rainbow_1          = zone_data;
-        self.j_corsair_1          = zone_data;
-        self.j_corsair_outerll120 = zone_data;
-        self.on_board_led         = zone_data;
-        self.on_board_led_1       = zone_data;
-        self.on_board_led_2       = zone_data;
-        self.on_board_led_3       = zone_data;
-        self.on_board_led_4       = zone_data;
-        self.on_board_led_5       = zone_data;
-        self.on_board_led_6       = zone_data;
-        self.on_board_led_7       = zone_data;
-        self.on_board_led_8       = zone_data;
-        self.on_board_led_9       = zone_data;
-        self.on_board_led_10      = zone_data;
-        self.j_rgb_2              = zone_data;
+        let buffer = self.report.encode();
+        let result = self.device.send_feature_report(&buffer);
+        trace::log("z390", "hid feature report", &buffer, &trace::outcome(&result), || mystic_light::describe(&buffer));
+        return result.map_err(|e| SaveError::Write(e.to_string()));
     }
 
-    #[allow(dead_code)]
-    pub fn write_to_device(&self)
+    ///The report the controller shows right now. Anything that doesn't decode is refused, it would end up in the flash otherwise.
+    pub fn read_from_device(&self) -> Result<MysticLightReport, SaveError>
     {
-        let buffer = self.to_bytes();
-        self.device.send_feature_report(&buffer).unwrap();
+        let mut buffer = [0u8; REPORT_SIZE];
+        buffer[0] = REPORT_ID;
+        let result = self.device.get_feature_report(&mut buffer);
+        let size = *result.as_ref().unwrap_or(&0);
+        trace::log("z390", "hid get feature report", &buffer[..size], &trace::outcome(&result), || mystic_light::describe(&buffer[..size]));
+
+        result.map_err(|e| SaveError::Read(e.to_string()))?;
+        return MysticLightReport::decode(&buffer[..size]).map_err(|e| SaveError::Read(e.to_string()));
     }
 
-
-    fn to_bytes(&self) -> [u8; 162]
+    ///Write the current zone data with the save flag set, see SAVE_DATA_MIN_INTERVAL.
+    pub fn save_to_device(&mut self) -> Result<(), SaveError>
     {
-        let mut buffer: [u8; 162] = [0; 162];
-
-        buffer[0] = self.report_id;
-        Z390::write_zone_data(&self.j_rgb_1, ZoneIndex::JRgb1, &mut buffer);
-        Z390::write_zone_data(&self.j_rainbow_1, ZoneIndex::JRainbow1, &mut buffer);
-        Z390::write_zone_data(&self.j_corsair_1, ZoneIndex::JCorsair1, &mut buffer);
-        Z390::write_zone_data(&self.j_corsair_outerll120, ZoneIndex::JCorsairOuterll120, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led, ZoneIndex::OnBoardLed, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_1, ZoneIndex::OnBoardLed1, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_2, ZoneIndex::OnBoardLed2, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_3, ZoneIndex::OnBoardLed3, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_4, ZoneIndex::OnBoardLed4, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_5, ZoneIndex::OnBoardLed5, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_6, ZoneIndex::OnBoardLed6, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_7, ZoneIndex::OnBoardLed7, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_8, ZoneIndex::OnBoardLed8, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_9, ZoneIndex::OnBoardLed9, &mut buffer);
-        Z390::write_zone_data(&self.on_board_led_10, ZoneIndex::OnBoardLed10, &mut buffer);
-        Z390::write_zone_data(&self.j_rgb_2, ZoneIndex::JRgb2, &mut buffer);
-        buffer[161] = self.save_data;
-
-        return buffer;
-    }
+        let now = SystemTime::now();
+        check_save_interval(now)?;
 
-    fn write_zone_data(zone_data: &ZoneData, zone_index: ZoneIndex, buffer: &mut [u8])
-    {
-        buffer[zone_index as usize + 0] = zone_data.effect;
-        buffer[zone_index as usize + 1] = zone_data.color.r;
-        buffer[zone_index as usize + 2] = zone_data.color.g;
-        buffer[zone_index as usize + 3] = zone_data.color.b;
-        buffer[zone_index as usize + 4] = zone_data.speed_and_brightness_flags;
-        buffer[zone_index as usize + 5] = zone_data.color2.r;
-        buffer[zone_index as usize + 6] = zone_data.color2.g;
-        buffer[zone_index as usize + 7] = zone_data.color2.b;
-        buffer[zone_index as usize + 8] = zone_data.color_flags;
-        buffer[zone_index as usize + 9] = zone_data.padding;
+        self.report.save_data = true;
+        let result = self.write_to_device();
+        self.report.save_data = false;
+
+        //Only a save that went through counts against the flash, a failed one can be retried right away
+        result?;
+        write_last_save(now);
+        return Ok(());
     }
 }
 
@@ -525,78 +431,3 @@ impl Z390
 
 const MSI_VENDOR_ID: u16 = 0x1462;
 const MPG_Z390_GAMING_PRO_CARBON: u16 = 0x7b17;
-
-
-
-
-#[allow(dead_code)]
-#[repr(u8)]
-enum MsiMode
-{
-    Disable                     = 0,
-    Static                      = 1,
-    Breathing                   = 2,
-    Flashing                    = 3,
-    DoubleFlashing              = 4,
-    Lightning                   = 5,
-    MsiMarquee                  = 6,
-    Meteor                      = 7,
-    WaterDrop                   = 8,
-    MsiRainbow                  = 9,
-    Pop                         = 10,
-    Rap                         = 11,
-    Jazz                        = 12,
-    Play                        = 13,
-    Movie                       = 14,
-    ColorRing                   = 15,
-    Planetary                   = 16,
-    DoubleMeteor                = 17,
-    Energy                      = 18,
-    Blink                       = 19,
-    Clock                       = 20,
-    ColorPulse                  = 21,
-    ColorShift                  = 22,
-    ColorWave                   = 23,
-    Marquee                     = 24,
-    Rainbow                     = 25,
-    RainbowWave                 = 26,
-    Visor                       = 27,
-    Jrainbow                    = 28,
-    RainbowFlashing             = 29,
-    RainbowDoubleFlashing       = 30,
-    Random                      = 31,
-    FanControl                  = 32,
-    Disable2                    = 33,
-    ColorRingFlashing           = 34,
-    ColorRingDoubleFlashing     = 35,
-    Stack                       = 36,
-    CorsairQue                  = 37,
-    Fire                        = 38,
-    Lava                        = 39,
-}
-
-#[allow(dead_code)]
-#[repr(u8)]
-enum MsiSpeed
-{
-    Low = 0,
-    Medium = 1,
-    High = 2,
-}
-
-#[allow(dead_code)]
-#[repr(u8)]
-enum MsiBrightness
-{
-    Off = 0,
-    Level10 = 1,
-    Level20 = 2,
-    Level30 = 3,
-    Level40 = 4,
-    Level50 = 5,
-    Level60 = 6,
-    Level70 = 7,
-    Level80 = 8,
-    Level90 = 9,
-    Level100 = 10,
-}